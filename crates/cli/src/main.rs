@@ -2,7 +2,7 @@ use clap::Parser;
 use clap::Subcommand;
 use clap_num::number_range;
 use gevulot_node::rpc_client::RpcClientBuilder;
-use gevulot_node::types::program::ResourceRequest;
+use gevulot_node::types::program::{ResourceRequest, MILLICORES_PER_CPU};
 use gevulot_node::types::Hash;
 use gevulot_node::types::TransactionTree;
 use libsecp256k1::PublicKey;
@@ -169,7 +169,7 @@ fn resource_requirements(
 
     let mut req = ResourceRequest::default();
     if cpus.is_some() {
-        req.cpus = cpus.unwrap();
+        req.cpus = cpus.unwrap() * MILLICORES_PER_CPU;
     }
 
     if mem.is_some() {
@@ -331,7 +331,7 @@ mod tests {
         assert_eq!(
             resource_requirements(Some(16), None, None),
             Some(ResourceRequest {
-                cpus: 16,
+                cpus: 16 * MILLICORES_PER_CPU,
                 ..Default::default()
             })
         );
@@ -364,7 +364,7 @@ mod tests {
         assert_eq!(
             resource_requirements(Some(4), Some(4096), None),
             Some(ResourceRequest {
-                cpus: 4,
+                cpus: 4 * MILLICORES_PER_CPU,
                 mem: 4096,
                 ..Default::default()
             })
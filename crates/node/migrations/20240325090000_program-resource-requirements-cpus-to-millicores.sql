@@ -0,0 +1,2 @@
+-- CPU resource requirements are expressed in millicores (1000 = one core).
+UPDATE program_resource_requirements SET cpus = cpus * 1000;
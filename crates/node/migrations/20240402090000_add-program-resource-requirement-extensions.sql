@@ -0,0 +1,14 @@
+-- Resource requirements besides memory, CPUs and GPUs. Amounts are in the
+-- units of `ResourceRequest`; affinity keys are stored as their digests.
+ALTER TABLE program_resource_requirements
+    ADD COLUMN disk_bytes BIGINT NOT NULL DEFAULT 0,
+    ADD COLUMN gpu_mem BIGINT NOT NULL DEFAULT 0,
+    ADD COLUMN gpu_mem_fraction DOUBLE PRECISION,
+    ADD COLUMN net_bps BIGINT NOT NULL DEFAULT 0,
+    ADD COLUMN priority SMALLINT NOT NULL DEFAULT 0,
+    ADD COLUMN gpu_uuid UUID,
+    ADD COLUMN mem_limit BIGINT,
+    ADD COLUMN cpus_limit BIGINT,
+    ADD COLUMN peak_mem BIGINT,
+    ADD COLUMN affinity_key BIGINT,
+    ADD COLUMN anti_affinity_key BIGINT;
@@ -1,6 +1,8 @@
 use std::{net::SocketAddr, path::PathBuf};
 
 use clap::{Args, Parser, Subcommand};
+use gevulot_node::types::program::{parse_bytes, ResourceRequest, MILLICORES_PER_CPU};
+use uuid::Uuid;
 
 #[derive(Debug, Args)]
 pub struct Config {
@@ -119,14 +121,257 @@ pub struct Config {
 
     #[arg(
         long,
-        long_help = "Amount of memory available (in GBs)",
+        long_help = "Count physical CPU cores instead of logical ones when detecting CPUs, so that SMT threads of a core count as one CPU",
+        env = "GEVULOT_COUNT_PHYSICAL_CORES",
+        default_value_t = false
+    )]
+    pub count_physical_cores: bool,
+
+    #[arg(
+        long,
+        long_help = "Place the CPUs and memory of each task on a single NUMA node when one has room, as detected in /sys/devices/system/node",
+        env = "GEVULOT_NUMA_AWARE",
+        default_value_t = false
+    )]
+    pub numa_aware: bool,
+
+    #[arg(
+        long,
+        long_help = "With --numa-aware, fail allocations that no single NUMA node has room for, rather than spreading them across nodes",
+        env = "GEVULOT_NUMA_STRICT",
+        default_value_t = false
+    )]
+    pub numa_strict: bool,
+
+    #[arg(
+        long,
+        long_help = "List every resource an allocation is short of in its error, rather than just the first found",
+        env = "GEVULOT_REPORT_ALL_DEFICITS",
+        default_value_t = false
+    )]
+    pub report_all_deficits: bool,
+
+    #[arg(
+        long,
+        long_help = "Amount of memory available, with a binary (KiB, MiB, GiB, TiB) or decimal (KB, MB, GB, TB) unit, e.g. \"16GiB\"",
+        env = "GEVULOT_MEM",
+        value_parser = parse_bytes,
+        conflicts_with_all = ["mem_gb", "mem_percent"]
+    )]
+    pub mem: Option<u64>,
+
+    #[arg(
+        long,
+        long_help = "Amount of memory available (in GBs). Deprecated: use --mem instead.",
         env = "GEVULOT_MEM_GB"
     )]
     pub mem_gb: Option<u64>,
 
+    #[arg(
+        long,
+        long_help = "Amount of memory available (in percents of physical memory)",
+        env = "GEVULOT_MEM_PERCENT",
+        value_parser = clap::value_parser!(u8).range(1..=100),
+        conflicts_with = "mem_gb"
+    )]
+    pub mem_percent: Option<u8>,
+
+    #[arg(
+        long,
+        long_help = "Refuse to start when configured CPUs or memory exceed what the host has, or the host's memory can't be detected, instead of warning",
+        env = "GEVULOT_STRICT_RESOURCES",
+        default_value_t = false
+    )]
+    pub strict_resources: bool,
+
+    #[arg(
+        long,
+        long_help = "Amount of memory (in MBs) left for the OS and the node itself when available memory is detected",
+        env = "GEVULOT_RESERVE_MEM_MB",
+        default_value_t = 1024
+    )]
+    pub reserve_mem_mb: u64,
+
+    #[arg(
+        long,
+        long_help = "Resources set aside for the node's own tasks, such as proof verification, that user tasks can't allocate, e.g. \"mem=512,cpus=0.5\" for 512 MiB and half a CPU",
+        env = "GEVULOT_RESERVE_SYSTEM",
+        value_parser = parse_reserve_system,
+        default_value = "mem=0,cpus=0"
+    )]
+    pub reserve_system: ResourceRequest,
+
+    #[arg(
+        long,
+        long_help = "Memory overcommit ratio. Values above 1.0 allow allocating more memory than available.",
+        env = "GEVULOT_OVERCOMMIT_MEM",
+        value_parser = parse_overcommit_ratio,
+        default_value_t = 1.0
+    )]
+    pub overcommit_mem: f64,
+
+    #[arg(
+        long,
+        long_help = "Resource utilization (in percents) at which the node is considered under medium pressure",
+        env = "GEVULOT_MEDIUM_PRESSURE_PERCENT",
+        value_parser = clap::value_parser!(u8).range(0..=100),
+        default_value_t = 70
+    )]
+    pub medium_pressure_percent: u8,
+
+    #[arg(
+        long,
+        long_help = "Resource utilization (in percents) at which the node is considered under high pressure and stops admitting low priority tasks",
+        env = "GEVULOT_HIGH_PRESSURE_PERCENT",
+        value_parser = clap::value_parser!(u8).range(0..=100),
+        default_value_t = 90
+    )]
+    pub high_pressure_percent: u8,
+
+    #[arg(
+        long,
+        long_help = "Priority gained per second by tasks waiting for resources, so that low priority tasks are eventually served",
+        env = "GEVULOT_AGING_RATE",
+        default_value_t = 0.01
+    )]
+    pub aging_rate: f64,
+
+    #[arg(
+        long,
+        long_help = "Weight (0 < weight <= 1) of the latest utilization in the moving averages of MEM and CPU utilization exported as metrics. Lower weights give smoother averages.",
+        env = "GEVULOT_UTILIZATION_EWMA_ALPHA",
+        default_value_t = 0.2
+    )]
+    pub utilization_ewma_alpha: f64,
+
+    #[arg(
+        long,
+        long_help = "Time (in milliseconds) to wait before retrying a task that found no resources free, when no lease is about to expire",
+        env = "GEVULOT_DEFAULT_RETRY_AFTER_MS",
+        default_value_t = 500
+    )]
+    pub default_retry_after_ms: u64,
+
+    #[arg(
+        long,
+        long_help = "Time (in seconds) after which resources of a remote task are freed if it has sent no heartbeat",
+        env = "GEVULOT_HEARTBEAT_STALENESS_SECS",
+        default_value_t = 30
+    )]
+    pub heartbeat_staleness_secs: u64,
+
+    #[arg(
+        long,
+        long_help = "Time (in seconds) a preempted task is given to checkpoint its progress before its resources are freed",
+        env = "GEVULOT_CHECKPOINT_TIMEOUT_SECS",
+        default_value_t = 30
+    )]
+    pub checkpoint_timeout_secs: u64,
+
+    #[arg(
+        long,
+        long_help = "Interval (in milliseconds) at which tasks waiting for resources check whether they have been freed, in addition to being woken up when they are",
+        env = "GEVULOT_ALLOCATION_POLL_INTERVAL_MS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        default_value_t = 250
+    )]
+    pub allocation_poll_interval_ms: u64,
+
+    #[arg(
+        long,
+        long_help = "Strategy for choosing where to place tasks",
+        env = "GEVULOT_PLACEMENT_STRATEGY",
+        value_parser = ["first-fit", "best-fit", "worst-fit", "weighted"],
+        default_value = "first-fit"
+    )]
+    pub placement_strategy: String,
+
+    #[arg(
+        long,
+        long_help = "Weight of memory when scoring candidates with the weighted placement strategy",
+        env = "GEVULOT_PLACEMENT_WEIGHT_MEM",
+        default_value_t = 1.0
+    )]
+    pub placement_weight_mem: f64,
+
+    #[arg(
+        long,
+        long_help = "Weight of CPUs when scoring candidates with the weighted placement strategy",
+        env = "GEVULOT_PLACEMENT_WEIGHT_CPUS",
+        default_value_t = 1.0
+    )]
+    pub placement_weight_cpus: f64,
+
+    #[arg(
+        long,
+        long_help = "Weight of GPUs when scoring candidates with the weighted placement strategy",
+        env = "GEVULOT_PLACEMENT_WEIGHT_GPUS",
+        default_value_t = 1.0
+    )]
+    pub placement_weight_gpus: f64,
+
+    #[arg(
+        long,
+        long_help = "Disable GPUs. GPU devices are not detected and tasks requesting GPUs are rejected.",
+        env = "GEVULOT_DISABLE_GPU",
+        default_value_t = false
+    )]
+    pub disable_gpu: bool,
+
     #[arg(long, long_help = "GPU PCI devices", env = "GEVULOT_GPU_DEVICES")]
     pub gpu_devices: Option<String>,
 
+    #[arg(
+        long,
+        long_help = "Vendor of the GPU devices, which decides how their count and memory are detected",
+        env = "GEVULOT_GPU_VENDOR",
+        value_parser = ["nvidia", "amd"],
+        default_value = "nvidia"
+    )]
+    pub gpu_vendor: String,
+
+    #[arg(
+        long,
+        long_help = "Interval (in seconds) at which utilization of GPU devices is sampled for metrics",
+        env = "GEVULOT_GPU_SAMPLE_INTERVAL_SECS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        default_value_t = 15
+    )]
+    pub gpu_sample_interval_secs: u64,
+
+    #[arg(
+        long,
+        long_help = "Most allocation attempts each account may make per --allocation-rate-window-secs, counting failed ones. Not limited by default",
+        env = "GEVULOT_ALLOCATION_RATE_LIMIT"
+    )]
+    pub allocation_rate_limit: Option<u32>,
+
+    #[arg(
+        long,
+        long_help = "Window (in seconds) of --allocation-rate-limit",
+        env = "GEVULOT_ALLOCATION_RATE_WINDOW_SECS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        default_value_t = 1
+    )]
+    pub allocation_rate_window_secs: u64,
+
+    #[arg(
+        long,
+        long_help = "UUIDs of the GPU devices, in the same order as GPU PCI devices",
+        env = "GEVULOT_GPU_UUIDS",
+        value_delimiter = ',',
+        value_parser = parse_gpu_uuid
+    )]
+    pub gpu_uuids: Vec<Uuid>,
+
+    #[arg(
+        long,
+        long_help = "Network bandwidth available for tasks (in Mbps)",
+        env = "GEVULOT_NET_MBPS",
+        default_value_t = 1000
+    )]
+    pub net_mbps: u64,
+
     #[arg(
         long,
         long_help = "Healthcheck listen address",
@@ -141,6 +386,60 @@ pub struct Config {
         env = "GEVULOT_METRICS_LISTEN_ADDR"
     )]
     pub http_metrics_listen_addr: Option<SocketAddr>,
+
+    #[arg(
+        long,
+        long_help = "Listen address of the HTTP endpoint listing live resource allocations as JSON",
+        env = "GEVULOT_ALLOCATIONS_LISTEN_ADDR"
+    )]
+    pub http_allocations_listen_addr: Option<SocketAddr>,
+}
+
+/// Parses GPU UUID as reported by `nvidia-smi -L`, with or without the
+/// `GPU-` prefix.
+fn parse_gpu_uuid(arg: &str) -> Result<Uuid, uuid::Error> {
+    Uuid::parse_str(arg.trim().trim_start_matches("GPU-"))
+}
+
+/// Parses a memory overcommit ratio, which must be a finite number of at
+/// least 1.0.
+fn parse_overcommit_ratio(arg: &str) -> Result<f64, String> {
+    match arg.trim().parse::<f64>() {
+        Ok(ratio) if ratio.is_finite() && ratio >= 1.0 => Ok(ratio),
+        _ => Err(format!(
+            "invalid overcommit ratio {arg:?}, expected a number of at least 1.0"
+        )),
+    }
+}
+
+/// Parses resources given as comma separated `mem=<MiB>`, `cpus=<CPUs>` and
+/// `gpus=<count>`, where CPUs may be fractional. Resources not given are
+/// none.
+fn parse_reserve_system(arg: &str) -> Result<ResourceRequest, String> {
+    let mut request = ResourceRequest {
+        mem: 0,
+        cpus: 0,
+        ..Default::default()
+    };
+    for field in arg.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+        let Some((name, value)) = field.split_once('=') else {
+            return Err(format!("expected <resource>=<amount>, got {field:?}"));
+        };
+        let invalid = || format!("invalid amount {value:?} of {name}");
+        match name.trim() {
+            "mem" => request.mem = value.trim().parse().map_err(|_| invalid())?,
+            "cpus" => {
+                let cpus: f64 = value.trim().parse().map_err(|_| invalid())?;
+                if !cpus.is_finite() || cpus < 0.0 {
+                    return Err(invalid());
+                }
+                request.cpus = (cpus * MILLICORES_PER_CPU as f64).round() as u64;
+            }
+            "gpus" => request.gpus = value.trim().parse().map_err(|_| invalid())?,
+            _ => return Err(format!("unknown resource {name:?}")),
+        }
+    }
+    Ok(request)
 }
 
 #[derive(Debug, Args)]
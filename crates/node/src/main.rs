@@ -8,6 +8,7 @@ use cli::{
 };
 use eyre::Result;
 use gevulot_node::types;
+use gevulot_node::types::program::{BYTES_PER_MIB, MILLICORES_PER_CPU};
 use gevulot_node::types::transaction::Received;
 use libsecp256k1::{PublicKey, SecretKey};
 use pea2pea::Pea2Pea;
@@ -262,14 +263,14 @@ async fn run(config: Arc<Config>) -> Result<()> {
             node_key,
             tx_sender.clone(),
         )
-        .await;
+        .await?;
 
         // Run Scheduler in its own task.
         tokio::spawn(async move { scheduler.run(scheduler_watchdog_sender).await });
     }
 
     let public_node_key = PublicKey::from_secret_key(&node_key);
-    let node_resources = scheduler::get_configured_resources(&config);
+    let resources = scheduler::get_configured_resources(&config, &scheduler::HostSystem::new())?;
     let p2p = Arc::new(
         networking::P2P::new(
             "gevulot-p2p-network",
@@ -281,7 +282,11 @@ async fn run(config: Arc<Config>) -> Result<()> {
             http_peer_list,
             mempool::TxEventSender::<mempool::P2pSender>::build(tx_sender.clone()),
             p2p_stream,
-            node_resources,
+            (
+                resources.cpus / MILLICORES_PER_CPU,
+                resources.mem * BYTES_PER_MIB,
+                resources.gpus,
+            ),
         )
         .await,
     );
@@ -3,7 +3,10 @@ use prometheus_hyper::Server;
 use std::{net::SocketAddr, sync::Arc};
 
 use lazy_static::lazy_static;
-use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounter, IntGauge, Registry};
+use prometheus::{
+    Counter, Encoder, Gauge, GaugeVec, Histogram, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+};
 
 lazy_static! {
     pub static ref REGISTRY: Arc<Registry> = Arc::new(Registry::new());
@@ -39,24 +42,132 @@ lazy_static! {
             .expect("metric can be created");
 
     // Resources metrics.
-    pub static ref CPUS_AVAILABLE: IntGauge =
-        IntGauge::new("gevulot_cpus_available", "Available CPUs in Gevulot")
+    pub static ref CPUS_AVAILABLE: Gauge =
+        Gauge::new("gevulot_cpus_available", "Available CPUs in Gevulot")
             .expect("metric can be created");
     pub static ref MEM_AVAILABLE: IntGauge =
-        IntGauge::new("gevulot_mem_available", "Available MEM in Gevulot")
+        IntGauge::new("gevulot_mem_available", "Available MEM (MiB) in Gevulot")
             .expect("metric can be created");
     pub static ref GPUS_AVAILABLE: IntGauge =
         IntGauge::new("gevulot_gpus_available", "Available GPUs in Gevulot")
             .expect("metric can be created");
-    pub static ref CPUS_TOTAL: IntGauge =
-        IntGauge::new("gevulot_cpus_total", "Total number of CPUs in Gevulot")
+    pub static ref CPUS_RESERVED: Gauge =
+        Gauge::new("gevulot_cpus_reserved", "CPUs held by allocations in Gevulot")
+            .expect("metric can be created");
+    pub static ref MEM_RESERVED: IntGauge =
+        IntGauge::new("gevulot_mem_reserved", "MEM (MiB) held by allocations in Gevulot")
+            .expect("metric can be created");
+    pub static ref GPUS_RESERVED: IntGauge =
+        IntGauge::new("gevulot_gpus_reserved", "GPUs held by allocations in Gevulot")
+            .expect("metric can be created");
+    pub static ref CPUS_PEAK: Gauge =
+        Gauge::new("gevulot_cpus_peak", "Most CPUs held by allocations at once in Gevulot")
+            .expect("metric can be created");
+    pub static ref MEM_PEAK: IntGauge =
+        IntGauge::new("gevulot_mem_peak", "Most MEM (MiB) held by allocations at once in Gevulot")
+            .expect("metric can be created");
+    pub static ref GPUS_PEAK: IntGauge =
+        IntGauge::new("gevulot_gpus_peak", "Most GPUs held by allocations at once in Gevulot")
+            .expect("metric can be created");
+    pub static ref MEM_UTIL_EWMA: Gauge =
+        Gauge::new("gevulot_mem_util_ewma", "Moving average of MEM utilization (%) in Gevulot")
+            .expect("metric can be created");
+    pub static ref CPUS_UTIL_EWMA: Gauge =
+        Gauge::new("gevulot_cpus_util_ewma", "Moving average of CPU utilization (%) in Gevulot")
+            .expect("metric can be created");
+    pub static ref GPU_MEM_AVAILABLE: IntGauge =
+        IntGauge::new("gevulot_gpu_mem_available", "Available GPU MEM in Gevulot")
+            .expect("metric can be created");
+    pub static ref DISK_AVAILABLE: IntGauge =
+        IntGauge::new("gevulot_disk_available", "Available DISK in Gevulot")
+            .expect("metric can be created");
+    pub static ref NET_AVAILABLE: IntGauge =
+        IntGauge::new("gevulot_net_available", "Available NET bandwidth (bps) in Gevulot")
+            .expect("metric can be created");
+    pub static ref CPUS_TOTAL: Gauge =
+        Gauge::new("gevulot_cpus_total", "Total number of CPUs in Gevulot")
             .expect("metric can be created");
     pub static ref MEM_TOTAL: IntGauge =
-        IntGauge::new("gevulot_mem_total", "Total amount of MEM in Gevulot")
+        IntGauge::new("gevulot_mem_total", "Total amount of MEM (MiB) in Gevulot")
+            .expect("metric can be created");
+    pub static ref MEM_OVERCOMMIT_TOTAL: IntGauge =
+        IntGauge::new("gevulot_mem_overcommit_total", "Total amount of allocatable MEM (MiB) in Gevulot, including overcommit")
             .expect("metric can be created");
     pub static ref GPUS_TOTAL: IntGauge =
         IntGauge::new("gevulot_gpus_total", "Total number of GPUs in Gevulot")
             .expect("metric can be created");
+    pub static ref GPU_MEM_TOTAL: IntGauge =
+        IntGauge::new("gevulot_gpu_mem_total", "Total amount of GPU MEM in Gevulot")
+            .expect("metric can be created");
+    pub static ref DISK_TOTAL: IntGauge =
+        IntGauge::new("gevulot_disk_total", "Total amount of DISK in Gevulot")
+            .expect("metric can be created");
+    pub static ref NET_TOTAL: IntGauge =
+        IntGauge::new("gevulot_net_total", "Total NET bandwidth (bps) in Gevulot")
+            .expect("metric can be created");
+    pub static ref ALLOCATION_WAIT_SECONDS: Histogram = Histogram::with_opts(
+        HistogramOpts::new("gevulot_allocation_wait_seconds", "Time waited for resources to be allocated (s)")
+            .buckets(vec![0.001, 0.01, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0])
+    )
+    .expect("metric can be created");
+    pub static ref ALLOCATION_LIFETIME_SECONDS: Histogram = Histogram::with_opts(
+        HistogramOpts::new("gevulot_allocation_lifetime_seconds", "Time resources were held by an allocation (s)")
+            .buckets(vec![1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 600.0, 1800.0, 3600.0, 7200.0])
+    )
+    .expect("metric can be created");
+    pub static ref ALLOCATION_FAILURES_TOTAL: IntCounterVec = IntCounterVec::new(
+        Opts::new("gevulot_allocation_failures_total", "Failed resource allocations by resource kind"),
+        &["kind"]
+    )
+    .expect("metric can be created");
+    pub static ref ALLOCATION_QUEUE_DEPTH: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("gevulot_allocation_queue_depth", "Requests waiting for resources to be allocated, by resource pool"),
+        &["pool"]
+    )
+    .expect("metric can be created");
+    pub static ref ALLOCATIONS_TOTAL: IntCounterVec = IntCounterVec::new(
+        Opts::new("gevulot_allocations_total", "Resource allocations made or failed, by resource pool"),
+        &["pool", "result"]
+    )
+    .expect("metric can be created");
+    pub static ref MEM_BYTE_SECONDS_TOTAL: Counter =
+        Counter::new("gevulot_mem_byte_seconds_total", "Memory held by allocations over time (byte-seconds)")
+            .expect("metric can be created");
+    pub static ref CPU_SECONDS_TOTAL: Counter =
+        Counter::new("gevulot_cpu_seconds_total", "CPUs held by allocations over time (core-seconds)")
+            .expect("metric can be created");
+    pub static ref GPU_SECONDS_TOTAL: Counter =
+        Counter::new("gevulot_gpu_seconds_total", "GPUs held by allocations over time (GPU-seconds)")
+            .expect("metric can be created");
+    pub static ref POOL_TOTAL: GaugeVec = GaugeVec::new(
+        Opts::new("gevulot_pool_total", "Total amount of resources in a named resource pool"),
+        &["pool", "kind"]
+    )
+    .expect("metric can be created");
+    pub static ref POOL_AVAILABLE: GaugeVec = GaugeVec::new(
+        Opts::new("gevulot_pool_available", "Available resources in a named resource pool"),
+        &["pool", "kind"]
+    )
+    .expect("metric can be created");
+    pub static ref GPU_UTILIZATION: GaugeVec = GaugeVec::new(
+        Opts::new("gevulot_gpu_utilization", "Share of time a GPU device was busy (%)"),
+        &["device"]
+    )
+    .expect("metric can be created");
+    pub static ref GPU_MEM_USED: GaugeVec = GaugeVec::new(
+        Opts::new("gevulot_gpu_mem_used", "Memory in use on a GPU device (bytes)"),
+        &["device"]
+    )
+    .expect("metric can be created");
+    pub static ref LEASES_EXPIRED_TOTAL: IntCounter =
+        IntCounter::new("gevulot_leases_expired_total", "Leased allocations freed because their lease expired")
+            .expect("metric can be created");
+    pub static ref HEARTBEATS_LOST_TOTAL: IntCounter =
+        IntCounter::new("gevulot_heartbeats_lost_total", "Allocations of remote tasks freed because their heartbeats stopped")
+            .expect("metric can be created");
+    pub static ref DRAINING: IntGauge =
+        IntGauge::new("gevulot_draining", "Whether the node is draining and not accepting new allocations (0/1)")
+            .expect("metric can be created");
 }
 
 pub(crate) fn register_metrics() {
@@ -94,15 +205,105 @@ pub(crate) fn register_metrics() {
     REGISTRY
         .register(Box::new(GPUS_AVAILABLE.clone()))
         .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(CPUS_RESERVED.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(MEM_RESERVED.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(GPUS_RESERVED.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(CPUS_PEAK.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(MEM_PEAK.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(GPUS_PEAK.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(MEM_UTIL_EWMA.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(CPUS_UTIL_EWMA.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(GPU_MEM_AVAILABLE.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(DISK_AVAILABLE.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(NET_AVAILABLE.clone()))
+        .expect("collector can be registered");
     REGISTRY
         .register(Box::new(CPUS_TOTAL.clone()))
         .expect("collector can be registered");
     REGISTRY
         .register(Box::new(MEM_TOTAL.clone()))
         .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(MEM_OVERCOMMIT_TOTAL.clone()))
+        .expect("collector can be registered");
     REGISTRY
         .register(Box::new(GPUS_TOTAL.clone()))
         .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(GPU_MEM_TOTAL.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(DISK_TOTAL.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(NET_TOTAL.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(ALLOCATION_WAIT_SECONDS.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(ALLOCATION_LIFETIME_SECONDS.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(ALLOCATION_FAILURES_TOTAL.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(ALLOCATIONS_TOTAL.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(ALLOCATION_QUEUE_DEPTH.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(MEM_BYTE_SECONDS_TOTAL.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(CPU_SECONDS_TOTAL.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(GPU_SECONDS_TOTAL.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(POOL_TOTAL.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(POOL_AVAILABLE.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(DRAINING.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(LEASES_EXPIRED_TOTAL.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(HEARTBEATS_LOST_TOTAL.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(GPU_UTILIZATION.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(GPU_MEM_USED.clone()))
+        .expect("collector can be registered");
 }
 
 pub(crate) async fn serve_metrics(bind_addr: SocketAddr) -> Result<()> {
@@ -152,7 +353,7 @@ mod tests {
 
         // Then set some values.
         P2P_PROTOCOL_VERSION.set(1);
-        CPUS_TOTAL.set(4);
+        CPUS_TOTAL.set(4.0);
         MEM_TOTAL.set(512);
         GPUS_TOTAL.set(0);
 
@@ -121,8 +121,8 @@ impl P2P {
                 pin!(propagate_tx_stream);
                 while let Some(tx) = propagate_tx_stream.next().await {
                     let tx_hash = tx.hash;
-                    let msg = protocol::v0::Message::V0(protocol::v0::MessageV0::Transaction(tx));
-                    let bs = match bincode::serialize(&msg) {
+                    let msg = protocol::internal::Message::Transaction(tx);
+                    let bs = match protocol::serialize_msg(msg) {
                         Ok(bs) => bs,
                         Err(err) => {
                             tracing::error!(
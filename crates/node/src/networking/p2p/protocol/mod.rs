@@ -1,4 +1,6 @@
 use eyre::{eyre, Result};
+use gevulot_node::types::{program::WireResourceRequest, transaction::Payload};
+use serde::{Deserialize, Serialize};
 
 pub mod internal;
 pub mod v0;
@@ -41,39 +43,95 @@ pub fn deserialize_handshake(bs: &[u8]) -> Result<internal::Handshake> {
     }
 }
 
+/// Resource requirements of the programs of a Deploy transaction, with all
+/// fields. Messages carry only the fields of `ResourceRequest` all nodes
+/// know, so the rest follows the message, where nodes predating them skip
+/// it as trailing bytes.
+#[derive(Debug, Deserialize, Serialize)]
+struct DeployResources {
+    prover: Option<WireResourceRequest>,
+    verifier: Option<WireResourceRequest>,
+}
+
+fn append_deploy_resources(msg: &internal::Message, bs: &mut Vec<u8>) -> Result<()> {
+    if let internal::Message::Transaction(tx) = msg {
+        if let Payload::Deploy {
+            prover, verifier, ..
+        } = &tx.payload
+        {
+            let resources = DeployResources {
+                prover: prover.resource_requirements.map(Into::into),
+                verifier: verifier.resource_requirements.map(Into::into),
+            };
+            bincode::serialize_into(bs, &resources)?;
+        }
+    }
+    Ok(())
+}
+
+/// Restores the resource requirements following `msg` in `bs`, which was
+/// parsed from the start of `bs` as protocol version `protocol_version`.
+fn restore_deploy_resources(
+    protocol_version: u64,
+    mut msg: internal::Message,
+    bs: &[u8],
+) -> Result<internal::Message> {
+    let len = match protocol_version {
+        0 => bincode::serialized_size(&v0::Message::from(msg.clone()))?,
+        _ => bincode::serialized_size(&v1::Message::from(msg.clone()))?,
+    };
+    let trailer = bs.get(len as usize..).unwrap_or_default();
+    if trailer.is_empty() {
+        return Ok(msg);
+    }
+
+    if let internal::Message::Transaction(tx) = &mut msg {
+        if let Payload::Deploy {
+            prover, verifier, ..
+        } = &mut tx.payload
+        {
+            match bincode::deserialize::<DeployResources>(trailer) {
+                Ok(resources) => {
+                    prover.resource_requirements = resources.prover.map(Into::into);
+                    verifier.resource_requirements = resources.verifier.map(Into::into);
+                }
+                Err(err) => tracing::warn!("ignoring invalid deploy resources: {}", err),
+            }
+        }
+    }
+    Ok(msg)
+}
+
 pub fn serialize_msg(msg: internal::Message) -> Result<Vec<u8>> {
-    let msg = v0::Message::from(msg);
-    bincode::serialize(&msg).map_err(|e| e.into())
+    let mut bs = bincode::serialize(&v0::Message::from(msg.clone()))?;
+    append_deploy_resources(&msg, &mut bs)?;
+    Ok(bs)
 }
 
 pub fn new_serialize_msg(protocol_version: u64, msg: internal::Message) -> Result<Vec<u8>> {
+    let mut data = protocol_version.to_be_bytes().to_vec();
     match protocol_version {
-        0 => bincode::serialize(&v0::Message::from(msg)),
-        1 => bincode::serialize(&v1::Message::from(msg)),
+        0 => bincode::serialize_into(&mut data, &v0::Message::from(msg.clone()))?,
+        1 => bincode::serialize_into(&mut data, &v1::Message::from(msg.clone()))?,
         ver => return Err(eyre!("unknown protocol version: {ver}")),
     }
-    .map(|mut bs| {
-        let mut data = Vec::with_capacity(bs.len() + 8);
-        data.append(&mut protocol_version.to_be_bytes().to_vec());
-        data.append(&mut bs);
-        data
-    })
-    .map_err(|e| e.into())
+    append_deploy_resources(&msg, &mut data)?;
+    Ok(data)
 }
 
 pub fn deserialize_msg(bs: &[u8]) -> Result<internal::Message> {
-    let res = v0::Message::parse(bs);
-    if res.is_ok() {
-        return res;
+    if let Ok(msg) = v0::Message::parse(bs) {
+        return restore_deploy_resources(0, msg, bs);
     }
 
     if bs.len() < 9 {
         return Err(eyre!("invalid protocol message: too short"));
     }
 
+    let body = &bs[8..];
     match u64::from_be_bytes(bs[0..8].try_into().expect("convert slice to array")) {
-        0 => v0::Message::parse(&bs[8..]),
-        1 => v1::Message::parse(&bs[8..]),
+        0 => restore_deploy_resources(0, v0::Message::parse(body)?, body),
+        1 => restore_deploy_resources(1, v1::Message::parse(body)?, body),
         ver => Err(eyre!("unknown protocol version: {ver}")),
     }
 }
@@ -83,7 +141,8 @@ mod tests {
     use super::*;
 
     use gevulot_node::types::{
-        transaction::{Created, Payload, Validated},
+        program::ResourceRequest,
+        transaction::{Created, ProgramMetadata, Validated},
         Transaction,
     };
     use libsecp256k1::SecretKey;
@@ -167,10 +226,58 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_deploy_resources_round_trip() {
+        let limits = ResourceRequest {
+            mem: 4096,
+            cpus: 1500,
+            gpus: 1,
+            priority: 5,
+            peak_mem: Some(8192),
+            ..Default::default()
+        };
+        let orig_tx = new_tx_with(Payload::Deploy {
+            name: String::from("deploy"),
+            prover: ProgramMetadata {
+                resource_requirements: Some(limits),
+                ..Default::default()
+            },
+            verifier: ProgramMetadata::default(),
+        });
+
+        for bs in [
+            serialize_msg(internal::Message::Transaction(orig_tx.clone())).unwrap(),
+            new_serialize_msg(1, internal::Message::Transaction(orig_tx.clone())).unwrap(),
+        ] {
+            let internal::Message::Transaction(tx) = deserialize_msg(&bs).unwrap() else {
+                panic!("test failed: Couldn't deserialize transaction correctly.");
+            };
+            assert_eq!(orig_tx, tx);
+        }
+
+        // Nodes predating the extensions read the message alone, with CPUs
+        // in whole cores.
+        let bs = serialize_msg(internal::Message::Transaction(orig_tx)).unwrap();
+        let v0::Message::V0(v0::MessageV0::Transaction(tx)) = bincode::deserialize(&bs).unwrap()
+        else {
+            panic!("test failed: Couldn't deserialize transaction correctly.");
+        };
+        let Payload::Deploy { prover, .. } = tx.payload else {
+            panic!("test failed: Couldn't deserialize transaction correctly.");
+        };
+        let legacy = prover.resource_requirements.unwrap();
+        assert_eq!((legacy.mem, legacy.cpus, legacy.gpus), (4096, 2000, 1));
+        assert_eq!(legacy.peak_mem, None);
+    }
+
     fn new_tx() -> Transaction<Validated> {
+        new_tx_with(Payload::Empty)
+    }
+
+    fn new_tx_with(payload: Payload) -> Transaction<Validated> {
         let rng = &mut StdRng::from_entropy();
 
-        let tx = Transaction::<Created>::new(Payload::Empty, &SecretKey::random(rng));
+        let tx = Transaction::<Created>::new(payload, &SecretKey::random(rng));
 
         Transaction {
             author: tx.author,
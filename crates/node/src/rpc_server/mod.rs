@@ -490,11 +490,45 @@ mod tests {
             provider: "qemu".to_string(),
             vsock_listen_port: 8080,
             num_cpus: None,
+            count_physical_cores: false,
+            numa_aware: false,
+            numa_strict: false,
+            report_all_deficits: false,
+            allocation_rate_limit: None,
+            allocation_rate_window_secs: 1,
+            mem: None,
             mem_gb: None,
+            mem_percent: None,
+            strict_resources: false,
+            reserve_mem_mb: 1024,
+            reserve_system: gevulot_node::types::program::ResourceRequest {
+                mem: 0,
+                cpus: 0,
+                ..Default::default()
+            },
+            overcommit_mem: 1.0,
+            medium_pressure_percent: 70,
+            high_pressure_percent: 90,
+            aging_rate: 0.01,
+            utilization_ewma_alpha: 0.2,
+            heartbeat_staleness_secs: 30,
+            checkpoint_timeout_secs: 30,
+            default_retry_after_ms: 500,
+            allocation_poll_interval_ms: 250,
+            placement_strategy: "first-fit".to_string(),
+            placement_weight_mem: 1.0,
+            placement_weight_cpus: 1.0,
+            placement_weight_gpus: 1.0,
+            disable_gpu: false,
             gpu_devices: None,
+            gpu_vendor: "nvidia".to_string(),
+            gpu_sample_interval_secs: 15,
+            gpu_uuids: vec![],
+            net_mbps: 1000,
             http_download_port: 0,
             http_healthcheck_listen_addr: "127.0.0.1:8888".parse().unwrap(),
             http_metrics_listen_addr: None,
+            http_allocations_listen_addr: None,
         });
 
         let db = Arc::new(Database::new(&cfg.db_url).await.unwrap());
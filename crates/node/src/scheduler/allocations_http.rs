@@ -0,0 +1,187 @@
+use super::resource_manager::{AllocationInfo, ResourceManager};
+use eyre::Result;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+/// Body of `GET /allocations`. Fields are only ever added, so tooling can
+/// rely on the ones present.
+#[derive(Debug, Serialize)]
+struct AllocationsBody {
+    allocations: Vec<AllocationView>,
+}
+
+#[derive(Debug, Serialize)]
+struct AllocationView {
+    id: u64,
+    // Hex encoded.
+    program_id: Option<String>,
+    task_id: Option<String>,
+    // MiB.
+    mem: u64,
+    // Millicores.
+    cpus: u64,
+    gpus: u64,
+    age_secs: u64,
+}
+
+impl From<AllocationInfo> for AllocationView {
+    fn from(info: AllocationInfo) -> Self {
+        Self {
+            id: info.id,
+            program_id: info.program_id.map(|id| id.to_string()),
+            task_id: info.task_id.map(|id| id.to_string()),
+            mem: info.mem,
+            cpus: info.cpus,
+            gpus: info.gpus,
+            age_secs: info.age.as_secs(),
+        }
+    }
+}
+
+/// Handles a request to the allocations endpoint. The allocations are read
+/// at once and serialized after, so allocating isn't held up by slow
+/// clients.
+fn handle<B>(resource_manager: &ResourceManager, req: &Request<B>) -> Response<String> {
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/allocations") => {
+            let body = AllocationsBody {
+                allocations: resource_manager
+                    .list_allocations()
+                    .into_iter()
+                    .map(AllocationView::from)
+                    .collect(),
+            };
+            match serde_json::to_string(&body) {
+                Ok(json) => Response::builder()
+                    .status(StatusCode::OK)
+                    .header(hyper::header::CONTENT_TYPE, "application/json")
+                    .body(json),
+                Err(err) => {
+                    tracing::error!("failed to serialize allocations: {}", err);
+                    Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body(String::new())
+                }
+            }
+        }
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(String::new()),
+    }
+    .expect("valid response")
+}
+
+/// Serves the live allocations of `resource_manager` as JSON at
+/// `GET /allocations` on `bind_addr`.
+pub async fn serve_allocations(
+    bind_addr: SocketAddr,
+    resource_manager: Arc<ResourceManager>,
+) -> Result<()> {
+    let listener = TcpListener::bind(bind_addr).await?;
+    tracing::info!(
+        "allocations listening for http at {}",
+        listener.local_addr()?
+    );
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _from)) => {
+                    let resource_manager = resource_manager.clone();
+                    tokio::spawn(async move {
+                        let service = service_fn(|req| {
+                            let res = handle(&resource_manager, &req);
+                            async move { Ok::<_, std::convert::Infallible>(res) }
+                        });
+                        if let Err(err) = http1::Builder::new()
+                            .serve_connection(TokioIo::new(stream), service)
+                            .await
+                        {
+                            tracing::error!("error serving allocations connection: {}", err);
+                        }
+                    });
+                }
+                Err(err) => tracing::error!("error accepting allocations connection: {}", err),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::resource_manager::DetectedResources;
+    use crate::types::program::ResourceRequest;
+    use crate::types::Hash;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_list_allocations_json() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 8192,
+            cpus: 4000,
+            ..Default::default()
+        }));
+        let req = ResourceRequest {
+            mem: 1024,
+            cpus: 1000,
+            gpus: 0,
+            ..Default::default()
+        };
+        let program_id = Hash::random(&mut rand::thread_rng());
+        let task_id = Uuid::new_v4();
+        let _ra1 = ResourceManager::try_allocate_for(
+            rm.clone(),
+            &req,
+            Some(program_id),
+            Some(task_id),
+            None,
+        )
+        .unwrap();
+        let _ra2 = ResourceManager::try_allocate(rm.clone(), &ResourceRequest { mem: 512, ..req })
+            .unwrap();
+
+        let get = |path: &str| {
+            let req = Request::get(path).body(()).unwrap();
+            handle(&rm, &req)
+        };
+        assert_eq!(get("/").status(), StatusCode::NOT_FOUND);
+
+        let res = get("/allocations");
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(res.body()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "allocations": [
+                    {
+                        "id": 0,
+                        "program_id": program_id.to_string(),
+                        "task_id": task_id.to_string(),
+                        "mem": 1024,
+                        "cpus": 1000,
+                        "gpus": 0,
+                        "age_secs": 0,
+                    },
+                    {
+                        "id": 1,
+                        "program_id": null,
+                        "task_id": null,
+                        "mem": 512,
+                        "cpus": 1000,
+                        "gpus": 0,
+                        "age_secs": 0,
+                    },
+                ]
+            })
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use crate::{
+    entity::PublicKey,
+    metrics,
+    types::{Hash, TaskId},
+};
+use std::fmt;
+use std::time::Duration;
+
+/// Resources consumed by an allocation over a period it held the same
+/// amounts, reported to the `BillingSink` of its `ResourceManager` when the
+/// allocation is resized or dropped.
+#[derive(Clone, Debug)]
+pub struct ResourceUsage {
+    pub allocation_id: u64,
+    pub program_id: Option<Hash>,
+    pub task_id: Option<TaskId>,
+    pub account: Option<PublicKey>,
+    /// How long the amounts were held.
+    pub duration: Duration,
+    /// Bytes of memory held times seconds.
+    pub mem_byte_seconds: f64,
+    /// Whole CPU cores held times seconds.
+    pub cpu_seconds: f64,
+    /// GPU devices held times seconds.
+    pub gpu_seconds: f64,
+}
+
+/// Receives the resource consumption of every allocation for billing.
+pub trait BillingSink: fmt::Debug + Send + Sync {
+    fn record(&self, usage: &ResourceUsage);
+}
+
+/// Discards resource consumption.
+#[derive(Debug, Default)]
+pub struct NoopBillingSink;
+
+impl BillingSink for NoopBillingSink {
+    fn record(&self, _usage: &ResourceUsage) {}
+}
+
+/// Adds resource consumption up in node-wide Prometheus counters.
+#[derive(Debug, Default)]
+pub struct MetricsBillingSink;
+
+impl BillingSink for MetricsBillingSink {
+    fn record(&self, usage: &ResourceUsage) {
+        metrics::MEM_BYTE_SECONDS_TOTAL.inc_by(usage.mem_byte_seconds);
+        metrics::CPU_SECONDS_TOTAL.inc_by(usage.cpu_seconds);
+        metrics::GPU_SECONDS_TOTAL.inc_by(usage.gpu_seconds);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::resource_manager::{DetectedResources, ResourceManager};
+    use crate::types::program::ResourceRequest;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    #[derive(Debug, Default)]
+    struct MockBillingSink {
+        recorded: Mutex<Vec<ResourceUsage>>,
+    }
+
+    impl BillingSink for MockBillingSink {
+        fn record(&self, usage: &ResourceUsage) {
+            self.recorded.lock().push(usage.clone());
+        }
+    }
+
+    #[test]
+    fn test_release_frees_once() {
+        let sink = Arc::new(MockBillingSink::default());
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 4096,
+                cpus: 4000,
+                ..Default::default()
+            })
+            .with_billing_sink(sink.clone()),
+        );
+        let req = ResourceRequest {
+            mem: 1024,
+            cpus: 1500,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let ra = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+        let held = ra.release();
+        assert_eq!((held.mem, held.cpus, held.gpus), (1024, 1500, 0));
+        assert_eq!(rm.available_mem(), 4096);
+        assert_eq!(rm.available_cpus(), 4000);
+        assert!(rm.list_allocations().is_empty());
+        assert_eq!(sink.recorded.lock().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drop_reports_resource_seconds() {
+        let sink = Arc::new(MockBillingSink::default());
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 4096,
+                cpus: 4000,
+                gpus: 2,
+                ..Default::default()
+            })
+            .with_billing_sink(sink.clone()),
+        );
+        let req = &ResourceRequest {
+            mem: 1024,
+            cpus: 1500,
+            gpus: 1,
+            ..Default::default()
+        };
+
+        let ra = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+        let id = ra.id();
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert!(sink.recorded.lock().is_empty());
+        drop(ra);
+
+        let recorded = sink.recorded.lock();
+        assert_eq!(recorded.len(), 1);
+        let usage = &recorded[0];
+        assert_eq!(usage.allocation_id, id);
+        assert_eq!(usage.duration, Duration::from_secs(60));
+        assert_eq!(usage.mem_byte_seconds, 1024.0 * 1024.0 * 1024.0 * 60.0);
+        assert_eq!(usage.cpu_seconds, 1.5 * 60.0);
+        assert_eq!(usage.gpu_seconds, 60.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_resize_bills_usage_so_far() {
+        let sink = Arc::new(MockBillingSink::default());
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 4096,
+                cpus: 4000,
+                ..Default::default()
+            })
+            .with_billing_sink(sink.clone()),
+        );
+        let req = ResourceRequest {
+            mem: 1024,
+            cpus: 1000,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let mut ra = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        ra.resize(&ResourceRequest { mem: 2048, ..req }).unwrap();
+        assert_eq!(sink.recorded.lock().len(), 1);
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        drop(ra);
+
+        let recorded = sink.recorded.lock();
+        assert_eq!(recorded.len(), 2);
+        assert_eq!(recorded[0].duration, Duration::from_secs(60));
+        assert_eq!(
+            recorded[0].mem_byte_seconds,
+            1024.0 * 1024.0 * 1024.0 * 60.0
+        );
+        assert_eq!(recorded[0].cpu_seconds, 60.0);
+        assert_eq!(recorded[1].duration, Duration::from_secs(30));
+        assert_eq!(
+            recorded[1].mem_byte_seconds,
+            2048.0 * 1024.0 * 1024.0 * 30.0
+        );
+        assert_eq!(recorded[1].cpu_seconds, 30.0);
+    }
+}
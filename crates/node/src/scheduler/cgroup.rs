@@ -0,0 +1,188 @@
+use super::resource_manager::ResourceAllocation;
+use crate::types::program::MILLICORES_PER_CPU;
+use eyre::Result;
+use std::path::{Path, PathBuf};
+
+/// Root of the cgroup v2 hierarchy.
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Period (in microseconds) the CPU quota of `cpu.max` is given over.
+const CPU_PERIOD_US: u64 = 100_000;
+
+/// Has the kernel enforce resource allocations on the processes they are
+/// made for, by placing each process in a cgroup of its own limited to the
+/// memory and CPU limits of its allocation, which burstable allocations
+/// have above what they hold. Does nothing on platforms other than
+/// Linux.
+#[derive(Debug)]
+pub struct CgroupEnforcer {
+    // Cgroup the per allocation cgroups are created in.
+    parent: PathBuf,
+}
+
+impl CgroupEnforcer {
+    /// Creates cgroups under `parent`, which must be a cgroup v2 directory
+    /// the node may write to.
+    pub fn new(parent: impl Into<PathBuf>) -> Self {
+        Self {
+            parent: parent.into(),
+        }
+    }
+
+    /// Moves process `pid` to a new cgroup limited to the hard limits of
+    /// `allocation`. The cgroup is removed when the returned handle is dropped,
+    /// which succeeds once the process has exited.
+    #[cfg(target_os = "linux")]
+    pub fn enforce(&self, allocation: &ResourceAllocation, pid: u32) -> Result<Cgroup> {
+        let path = self
+            .parent
+            .join(format!("gevulot-allocation-{}", allocation.id()));
+        std::fs::create_dir(&path)?;
+        let cgroup = Cgroup { path };
+
+        std::fs::write(
+            cgroup.path.join("memory.max"),
+            memory_max(allocation.mem_limit()),
+        )?;
+        std::fs::write(
+            cgroup.path.join("cpu.max"),
+            cpu_max(allocation.cpus_limit()),
+        )?;
+        std::fs::write(cgroup.path.join("cgroup.procs"), pid.to_string())?;
+
+        tracing::debug!(
+            "process {} limited to allocation {} in {}",
+            pid,
+            allocation.id(),
+            cgroup.path.display()
+        );
+        Ok(cgroup)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn enforce(&self, _allocation: &ResourceAllocation, _pid: u32) -> Result<Cgroup> {
+        Ok(Cgroup {
+            path: PathBuf::new(),
+        })
+    }
+}
+
+impl Default for CgroupEnforcer {
+    fn default() -> Self {
+        Self::new(CGROUP_ROOT)
+    }
+}
+
+/// Cgroup created for an allocation by `CgroupEnforcer`.
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_dir(&self.path) {
+            tracing::warn!("failed to remove cgroup {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+/// Value of `memory.max` limiting memory to `mem` MiB.
+fn memory_max(mem: u64) -> String {
+    mem.saturating_mul(1024 * 1024).to_string()
+}
+
+/// Value of `cpu.max` limiting CPU time to `cpus` millicores. No CPUs
+/// means no limit.
+fn cpu_max(cpus: u64) -> String {
+    if cpus == 0 {
+        return format!("max {CPU_PERIOD_US}");
+    }
+    let quota = cpus.saturating_mul(CPU_PERIOD_US) / MILLICORES_PER_CPU;
+    format!("{quota} {CPU_PERIOD_US}")
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::scheduler::resource_manager::{DetectedResources, ResourceManager};
+    use crate::types::program::ResourceRequest;
+    use std::sync::Arc;
+
+    fn allocation() -> ResourceAllocation {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 8192,
+            cpus: 4 * MILLICORES_PER_CPU,
+            ..Default::default()
+        }));
+        let req = ResourceRequest {
+            mem: 512,
+            cpus: 1500,
+            gpus: 0,
+            ..Default::default()
+        };
+        ResourceManager::try_allocate(rm, &req).unwrap()
+    }
+
+    #[test]
+    fn test_limits() {
+        assert_eq!(memory_max(512), "536870912");
+        assert_eq!(cpu_max(1500), "150000 100000");
+        assert_eq!(cpu_max(250), "25000 100000");
+        assert_eq!(cpu_max(0), "max 100000");
+    }
+
+    #[test]
+    fn test_enforce_writes_limits() {
+        // Plain directory standing in for the parent cgroup.
+        let parent = std::env::temp_dir().join(format!("gevulot-cgroups-{}", std::process::id()));
+        std::fs::create_dir_all(&parent).unwrap();
+
+        let ra = allocation();
+        let cgroup = CgroupEnforcer::new(&parent).enforce(&ra, 4242).unwrap();
+        let read = |file| std::fs::read_to_string(cgroup.path().join(file)).unwrap();
+        let limits = (read("memory.max"), read("cpu.max"), read("cgroup.procs"));
+        std::fs::remove_dir_all(&parent).unwrap();
+
+        assert_eq!(limits.0, "536870912");
+        assert_eq!(limits.1, "150000 100000");
+        assert_eq!(limits.2, "4242");
+    }
+
+    // Needs root and the memory and cpu controllers enabled for the
+    // children of the root cgroup v2.
+    #[ignore]
+    #[test]
+    fn test_enforce_in_scratch_cgroup() {
+        let parent = Path::new(CGROUP_ROOT).join(format!("gevulot-test-{}", std::process::id()));
+        std::fs::create_dir(&parent).unwrap();
+        std::fs::write(parent.join("cgroup.subtree_control"), "+memory +cpu").unwrap();
+
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let ra = allocation();
+        let cgroup = CgroupEnforcer::new(&parent)
+            .enforce(&ra, child.id())
+            .unwrap();
+        let read = |file| std::fs::read_to_string(cgroup.path().join(file)).unwrap();
+        let limits = (read("memory.max"), read("cpu.max"), read("cgroup.procs"));
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        drop(cgroup);
+        std::fs::remove_dir(&parent).unwrap();
+
+        assert_eq!(limits.0.trim(), "536870912");
+        assert_eq!(limits.1.trim(), "150000 100000");
+        assert_eq!(limits.2.trim(), child.id().to_string());
+    }
+}
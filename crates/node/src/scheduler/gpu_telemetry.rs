@@ -0,0 +1,192 @@
+use crate::cli::Config;
+use crate::metrics;
+use eyre::{eyre, Result};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Utilization of one GPU device at a point in time.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GpuSample {
+    pub index: u32,
+    /// Share of time the device was busy (in percent).
+    pub utilization: f64,
+    /// Device memory in use (in bytes).
+    pub mem_used: u64,
+}
+
+/// Source of GPU utilization samples, such as the GPU driver.
+pub trait GpuSource: Send + Sync {
+    fn sample(&self) -> Result<Vec<GpuSample>>;
+}
+
+/// Samples NVIDIA GPUs with `nvidia-smi`.
+#[derive(Debug, Default)]
+pub struct NvidiaSmi;
+
+impl GpuSource for NvidiaSmi {
+    fn sample(&self) -> Result<Vec<GpuSample>> {
+        let output = std::process::Command::new("nvidia-smi")
+            .args([
+                "--query-gpu=index,utilization.gpu,memory.used",
+                "--format=csv,noheader,nounits",
+            ])
+            .output()?;
+        if !output.status.success() {
+            return Err(eyre!("nvidia-smi failed with {}", output.status));
+        }
+        parse_nvidia_smi(&String::from_utf8_lossy(&output.stdout))
+    }
+}
+
+/// Parses the output of `nvidia-smi
+/// --query-gpu=index,utilization.gpu,memory.used
+/// --format=csv,noheader,nounits`: one line per GPU, such as "0, 87, 40123"
+/// with memory in MiB.
+fn parse_nvidia_smi(output: &str) -> Result<Vec<GpuSample>> {
+    output
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            let [index, utilization, mem_used] = fields[..] else {
+                return Err(eyre!("unexpected nvidia-smi output {:?}", line));
+            };
+            Ok(GpuSample {
+                index: index.parse()?,
+                utilization: utilization.parse()?,
+                mem_used: mem_used.parse::<u64>()? * 1024 * 1024,
+            })
+        })
+        .collect()
+}
+
+/// Samples `source` once, exporting the utilization of each device.
+pub fn record_sample(source: &dyn GpuSource) {
+    match source.sample() {
+        Ok(samples) => {
+            for sample in samples {
+                let index = sample.index.to_string();
+                metrics::GPU_UTILIZATION
+                    .with_label_values(&[&index])
+                    .set(sample.utilization);
+                metrics::GPU_MEM_USED
+                    .with_label_values(&[&index])
+                    .set(sample.mem_used as f64);
+            }
+        }
+        Err(err) => tracing::warn!("failed to sample GPU utilization: {}", err),
+    }
+}
+
+/// Spawns a task sampling the utilization of the node's GPUs at the
+/// configured interval. Nothing is sampled when the node has no GPUs, or
+/// GPUs of a vendor not supported.
+pub fn spawn_gpu_sampler(config: &Config) -> Option<tokio::task::JoinHandle<()>> {
+    if config.disable_gpu || config.gpu_devices.is_none() {
+        return None;
+    }
+    if config.gpu_vendor != "nvidia" {
+        tracing::info!("GPU utilization is sampled only for NVIDIA GPUs");
+        return None;
+    }
+
+    let interval = Duration::from_secs(config.gpu_sample_interval_secs);
+    Some(spawn_sampler(Arc::new(NvidiaSmi), interval))
+}
+
+fn spawn_sampler(source: Arc<dyn GpuSource>, interval: Duration) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            // Sampling runs a process, keep it off the async workers.
+            let source = source.clone();
+            if let Err(err) = tokio::task::spawn_blocking(move || record_sample(&*source)).await {
+                tracing::error!("GPU sampler failed: {}", err);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FakeSource(Vec<GpuSample>);
+
+    impl GpuSource for FakeSource {
+        fn sample(&self) -> Result<Vec<GpuSample>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn test_record_sample() {
+        let source = FakeSource(vec![
+            GpuSample {
+                index: 40,
+                utilization: 87.0,
+                mem_used: 4096,
+            },
+            GpuSample {
+                index: 41,
+                utilization: 0.0,
+                mem_used: 0,
+            },
+        ]);
+        record_sample(&source);
+
+        assert_eq!(
+            metrics::GPU_UTILIZATION.with_label_values(&["40"]).get(),
+            87.0
+        );
+        assert_eq!(
+            metrics::GPU_MEM_USED.with_label_values(&["40"]).get(),
+            4096.0
+        );
+        assert_eq!(
+            metrics::GPU_UTILIZATION.with_label_values(&["41"]).get(),
+            0.0
+        );
+    }
+
+    #[test]
+    fn test_parse_nvidia_smi() {
+        let samples = parse_nvidia_smi("0, 87, 40123\n1, 3, 0\n").unwrap();
+        assert_eq!(
+            samples,
+            vec![
+                GpuSample {
+                    index: 0,
+                    utilization: 87.0,
+                    mem_used: 40123 * 1024 * 1024,
+                },
+                GpuSample {
+                    index: 1,
+                    utilization: 3.0,
+                    mem_used: 0,
+                },
+            ]
+        );
+
+        assert!(parse_nvidia_smi("0, [N/A], 0\n").is_err());
+        assert!(parse_nvidia_smi("0, 87\n").is_err());
+    }
+
+    #[test]
+    fn test_no_sampler_without_gpus() {
+        use crate::cli::{Cli, Command};
+        use clap::Parser;
+
+        let config = |args: &[&str]| {
+            let args = ["gevulot", "run"].iter().chain(args);
+            let Command::Run { config } = Cli::parse_from(args).subcommand else {
+                panic!("expected run command");
+            };
+            config
+        };
+
+        assert!(spawn_gpu_sampler(&config(&[])).is_none());
+        assert!(spawn_gpu_sampler(&config(&["--gpu-devices", "0", "--disable-gpu"])).is_none());
+    }
+}
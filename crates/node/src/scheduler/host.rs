@@ -0,0 +1,864 @@
+use super::numa::parse_cpulist;
+use super::resource_manager::DetectedResources;
+use crate::types::program::{parse_bytes, BYTES_PER_MIB, MILLICORES_PER_CPU};
+use eyre::{eyre, Result};
+use std::path::Path;
+use systemstat::{ByteSize, Platform, System};
+
+const SYSFS_PCI_DEVICES: &str = "/sys/bus/pci/devices";
+pub(super) const SYSFS_CPU_ONLINE: &str = "/sys/devices/system/cpu/online";
+const CGROUP_CPU_MAX: &str = "/sys/fs/cgroup/cpu.max";
+const CGROUP_MEMORY_MAX: &str = "/sys/fs/cgroup/memory.max";
+
+/// Information about the machine the node runs on.
+pub trait SystemInfo {
+    /// Total physical memory (in bytes).
+    fn total_memory(&self) -> Result<u64>;
+    /// Currently free memory (in bytes).
+    fn free_memory(&self) -> Option<u64>;
+    /// Number of logical CPU cores, counting each SMT thread.
+    fn cpu_count(&self) -> u64;
+    /// Number of physical CPU cores.
+    fn physical_cpu_count(&self) -> u64;
+    /// Number of GPUs made by `vendor` in the comma separated list of
+    /// `devices`.
+    fn gpu_count(&self, vendor: GpuVendor, devices: &str) -> u64;
+    /// Total memory (in bytes) of the GPU `devices` made by `vendor`.
+    fn gpu_memory(&self, vendor: GpuVendor, devices: &str) -> u64;
+    /// Free space (in bytes) of the filesystem holding `path`.
+    fn disk_space(&self, path: &Path) -> u64;
+    /// Memory limit (in bytes) of the container the node runs in, if any.
+    fn container_memory_limit(&self) -> Option<u64>;
+    /// CPU quota (in millicores) of the container the node runs in, if any.
+    fn container_cpu_quota(&self) -> Option<u64>;
+}
+
+/// `SystemInfo` of the host, as reported by the OS.
+pub struct HostSystem {
+    sys: System,
+}
+
+impl HostSystem {
+    pub fn new() -> Self {
+        HostSystem { sys: System::new() }
+    }
+}
+
+impl SystemInfo for HostSystem {
+    fn total_memory(&self) -> Result<u64> {
+        let mem = self
+            .sys
+            .memory()
+            .map_err(|err| eyre!("failed to lookup system memory: {}", err))?;
+        Ok(mem.total.as_u64())
+    }
+
+    fn free_memory(&self) -> Option<u64> {
+        self.sys.memory().ok().map(|mem| mem.free.as_u64())
+    }
+
+    fn cpu_count(&self) -> u64 {
+        num_cpus::get() as u64
+    }
+
+    fn physical_cpu_count(&self) -> u64 {
+        num_cpus::get_physical() as u64
+    }
+
+    fn gpu_count(&self, vendor: GpuVendor, devices: &str) -> u64 {
+        let configured = gpu_count(devices);
+        match vendor.detect_gpus() {
+            Some(vram) if configured > vram.len() as u64 => {
+                tracing::warn!(
+                    "{} GPU devices configured, but {} reports only {}",
+                    configured,
+                    vendor.smi(),
+                    vram.len()
+                );
+                vram.len() as u64
+            }
+            _ => configured,
+        }
+    }
+
+    fn gpu_memory(&self, vendor: GpuVendor, devices: &str) -> u64 {
+        match vendor.detect_gpus() {
+            Some(vram) => vram.iter().take(gpu_count(devices) as usize).sum(),
+            None => gpu_memory(Path::new(SYSFS_PCI_DEVICES), devices),
+        }
+    }
+
+    fn disk_space(&self, path: &Path) -> u64 {
+        scratch_space(&self.sys, path)
+    }
+
+    fn container_memory_limit(&self) -> Option<u64> {
+        cgroup_mem_limit(Path::new(CGROUP_MEMORY_MAX))
+    }
+
+    fn container_cpu_quota(&self) -> Option<u64> {
+        cgroup_cpu_quota(Path::new(CGROUP_CPU_MAX))
+    }
+}
+
+/// Maker of the GPUs of the node, which decides how they are detected.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GpuVendor {
+    #[default]
+    Nvidia,
+    Amd,
+}
+
+impl GpuVendor {
+    /// Returns the vendor configured by `name`, as accepted by the
+    /// `--gpu-vendor` option.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "nvidia" => Some(GpuVendor::Nvidia),
+            "amd" => Some(GpuVendor::Amd),
+            _ => None,
+        }
+    }
+
+    /// Tool the GPUs are detected with.
+    fn smi(&self) -> &'static str {
+        match self {
+            GpuVendor::Nvidia => "nvidia-smi",
+            GpuVendor::Amd => "rocm-smi",
+        }
+    }
+
+    /// Returns the VRAM (in bytes) of each GPU of this vendor on the host,
+    /// or `None` if they can't be detected.
+    fn detect_gpus(&self) -> Option<Vec<u64>> {
+        let args: &[&str] = match self {
+            GpuVendor::Nvidia => &["--query-gpu=count,memory.total", "--format=csv,noheader"],
+            GpuVendor::Amd => &["--showmeminfo", "vram", "--csv"],
+        };
+
+        let output = match std::process::Command::new(self.smi()).args(args).output() {
+            Ok(output) if output.status.success() => output,
+            Ok(output) => {
+                tracing::warn!(
+                    "{} failed with {}, using configured GPUs",
+                    self.smi(),
+                    output.status
+                );
+                return None;
+            }
+            Err(err) => {
+                tracing::warn!(
+                    "failed to run {}, using configured GPUs: {}",
+                    self.smi(),
+                    err
+                );
+                return None;
+            }
+        };
+
+        let output = String::from_utf8_lossy(&output.stdout);
+        let vram = match self {
+            GpuVendor::Nvidia => parse_nvidia_smi(&output),
+            GpuVendor::Amd => parse_rocm_smi(&output),
+        };
+        if vram.is_none() {
+            tracing::warn!(
+                "failed to parse {} output, using configured GPUs",
+                self.smi()
+            );
+        }
+        vram
+    }
+}
+
+/// Returns the resources to hand out, as configured or detected on the
+/// host. Configured CPUs or memory beyond what the host has are warned
+/// about, or rejected with `strict_resources`.
+pub fn get_configured_resources(
+    config: &crate::cli::Config,
+    sys: &impl SystemInfo,
+) -> Result<DetectedResources> {
+    let gpu_vendor = GpuVendor::from_name(&config.gpu_vendor)
+        .ok_or_else(|| eyre!("unknown GPU vendor {}", config.gpu_vendor))?;
+    let gpu_devices = match config.gpu_devices {
+        Some(ref devices) if config.disable_gpu => {
+            tracing::warn!("ignoring GPU devices {}, GPUs are disabled", devices);
+            None
+        }
+        ref devices => devices.as_deref(),
+    };
+    let num_gpus = match gpu_devices {
+        Some(devices) => sys.gpu_count(gpu_vendor, devices),
+        None => 0,
+    };
+    let available_gpu_mem = match gpu_devices {
+        Some(devices) => sys.gpu_memory(gpu_vendor, devices),
+        None => 0,
+    };
+    let cpu_count = if config.count_physical_cores {
+        sys.physical_cpu_count()
+    } else {
+        sys.cpu_count()
+    };
+    let num_cpus = match config.num_cpus {
+        Some(cpus) => {
+            check_configured(config, "CPUs", cpus, cpu_count)?;
+            cpus * MILLICORES_PER_CPU
+        }
+        None => {
+            let host_cpus = cpu_count * MILLICORES_PER_CPU;
+            match sys.container_cpu_quota() {
+                Some(quota) if quota < host_cpus => {
+                    tracing::info!("capping CPUs to cgroup quota of {} millicores", quota);
+                    quota
+                }
+                _ => host_cpus,
+            }
+        }
+    };
+    let available_mem = configured_mem(config, || {
+        let host_mem = host_memory(config, sys)?;
+        Ok(match sys.container_memory_limit() {
+            Some(limit) if limit < host_mem => {
+                tracing::info!("capping memory to cgroup limit of {} bytes", limit);
+                limit
+            }
+            _ => host_mem,
+        })
+    })?;
+    if config.mem.is_some() || config.mem_gb.is_some() {
+        match sys.total_memory() {
+            Ok(physical) => check_configured(config, "bytes of memory", available_mem, physical)?,
+            Err(err) if config.strict_resources => {
+                return Err(err.wrap_err("failed to check configured memory against the host"));
+            }
+            Err(err) => tracing::warn!("not checking configured memory against the host: {}", err),
+        }
+    }
+    let available_disk = sys.disk_space(&config.data_directory);
+    let available_net = config.net_mbps * 1_000_000;
+
+    Ok(DetectedResources {
+        mem: available_mem / BYTES_PER_MIB,
+        cpus: num_cpus,
+        gpus: num_gpus,
+        disk: available_disk,
+        gpu_mem: available_gpu_mem,
+        net: available_net,
+    })
+}
+
+/// Checks a `configured` amount of a resource against the `physical`
+/// amount the host has. Going over is an error with `strict_resources`,
+/// and a warning otherwise.
+fn check_configured(
+    config: &crate::cli::Config,
+    resource: &str,
+    configured: u64,
+    physical: u64,
+) -> Result<()> {
+    if configured <= physical {
+        return Ok(());
+    }
+
+    let msg = format!("configured {configured} {resource} but the host only has {physical}");
+    if config.strict_resources {
+        return Err(eyre!(msg));
+    }
+    tracing::warn!("{}; tasks will be oversubscribed", msg);
+    Ok(())
+}
+
+/// Memory (in bytes) assumed when the host's can't be detected, unless
+/// the node runs in a container with a memory limit.
+const FALLBACK_MEM: u64 = 2 * 1024 * 1024 * 1024;
+
+/// Returns the physical memory (in bytes) of the host. If it can't be
+/// detected, that's an error with `strict_resources`, and otherwise the
+/// container memory limit or `FALLBACK_MEM` is assumed.
+fn host_memory(config: &crate::cli::Config, sys: &impl SystemInfo) -> Result<u64> {
+    match sys.total_memory() {
+        Ok(mem) => Ok(mem),
+        Err(err) if config.strict_resources => {
+            Err(err.wrap_err("failed to detect system memory, set --mem to the amount to hand out"))
+        }
+        Err(err) => {
+            let fallback = sys.container_memory_limit().unwrap_or(FALLBACK_MEM);
+            tracing::warn!(
+                "{}; assuming {}, set --mem to the amount to hand out",
+                err,
+                ByteSize(fallback).to_string_as(true)
+            );
+            Ok(fallback)
+        }
+    }
+}
+
+/// Returns the amount of memory (in bytes) to hand out, either as
+/// configured or derived from the `physical` memory of the machine.
+fn configured_mem(
+    config: &crate::cli::Config,
+    physical: impl FnOnce() -> Result<u64>,
+) -> Result<u64> {
+    if let Some(mem) = config.mem {
+        return Ok(mem);
+    }
+
+    Ok(match (config.mem_gb, config.mem_percent) {
+        (Some(mem_gb), _) => {
+            tracing::warn!("--mem-gb is deprecated, use --mem {}GiB instead", mem_gb);
+            mem_gb * 1024 * 1024 * 1024
+        }
+        (None, Some(percent)) => (physical()? as u128 * percent as u128 / 100) as u64,
+        (None, None) => without_reserved_mem(physical()?, config.reserve_mem_mb),
+    })
+}
+
+/// Returns the cgroup v2 CPU quota (in millicores) read from `path`, if
+/// the node runs in a cgroup that has one.
+fn cgroup_cpu_quota(path: &Path) -> Option<u64> {
+    let cpu_max = std::fs::read_to_string(path).ok()?;
+    let mut fields = cpu_max.split_whitespace();
+    let quota: u64 = match fields.next()? {
+        "max" => return None,
+        quota => quota.parse().ok()?,
+    };
+    let period: u64 = fields.next()?.parse().ok()?;
+    if period == 0 {
+        return None;
+    }
+    Some(quota * MILLICORES_PER_CPU / period)
+}
+
+/// Returns the ids of the online CPU cores listed in `path`.
+pub(super) fn online_cpus(path: &Path) -> Option<Vec<usize>> {
+    let ids = std::fs::read_to_string(path)
+        .ok()
+        .and_then(|list| parse_cpulist(&list));
+    if ids.is_none() {
+        tracing::warn!("failed to read online CPUs from {}", path.display());
+    }
+    ids
+}
+
+/// Returns the cgroup v2 memory limit (in bytes) read from `path`, if the
+/// node runs in a cgroup that has one.
+fn cgroup_mem_limit(path: &Path) -> Option<u64> {
+    let limit = std::fs::read_to_string(path).ok()?;
+    match limit.trim() {
+        "max" | "-1" => None,
+        limit => limit.parse().ok(),
+    }
+}
+
+/// Subtracts memory reserved for the OS and the node process from the
+/// detected `total` (in bytes).
+fn without_reserved_mem(total: u64, reserve_mem_mb: u64) -> u64 {
+    total.saturating_sub(reserve_mem_mb.saturating_mul(BYTES_PER_MIB))
+}
+
+/// Returns the number of GPUs in a comma separated list of devices. Index
+/// ranges such as `0-3` count as all the devices in the range.
+fn gpu_count(devices: &str) -> u64 {
+    devices
+        .split(',')
+        .map(str::trim)
+        .filter(|dev| !dev.is_empty())
+        .map(|dev| {
+            let range = dev.split_once('-').and_then(|(first, last)| {
+                Some((first.parse::<u64>().ok()?, last.parse::<u64>().ok()?))
+            });
+            match range {
+                Some((first, last)) if first <= last => last - first + 1,
+                Some(_) => {
+                    tracing::warn!("ignoring empty GPU device range {}", dev);
+                    0
+                }
+                None => 1,
+            }
+        })
+        .sum()
+}
+
+/// Returns total VRAM (in bytes) of the given comma separated GPU PCI
+/// devices, as reported by the kernel driver under `sysfs_root`.
+fn gpu_memory(sysfs_root: &Path, devices: &str) -> u64 {
+    devices
+        .split(',')
+        .map(str::trim)
+        .filter(|dev| !dev.is_empty())
+        .map(|dev| {
+            // Sysfs device names always carry the PCI domain.
+            let dev = if dev.matches(':').count() == 1 {
+                format!("0000:{dev}")
+            } else {
+                dev.to_string()
+            };
+
+            let path = sysfs_root.join(&dev).join("mem_info_vram_total");
+            match std::fs::read_to_string(&path)
+                .ok()
+                .and_then(|vram| vram.trim().parse::<u64>().ok())
+            {
+                Some(vram) => vram,
+                None => {
+                    tracing::warn!("failed to lookup GPU memory for device {}", dev);
+                    0
+                }
+            }
+        })
+        .sum()
+}
+
+/// Parses the output of `nvidia-smi --query-gpu=count,memory.total
+/// --format=csv,noheader`: one line per GPU, each with the GPU count and
+/// the VRAM of that GPU, such as "2, 81920 MiB".
+fn parse_nvidia_smi(output: &str) -> Option<Vec<u64>> {
+    let lines: Vec<&str> = output
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+    let vram = lines
+        .iter()
+        .map(|line| {
+            let (count, mem) = line.split_once(',')?;
+            let count: usize = count.trim().parse().ok()?;
+            if count != lines.len() {
+                return None;
+            }
+            parse_bytes(mem).ok()
+        })
+        .collect::<Option<Vec<u64>>>()?;
+    (!vram.is_empty()).then_some(vram)
+}
+
+/// Parses the output of `rocm-smi --showmeminfo vram --csv`: a header and
+/// then one line per GPU, starting with the device and its total VRAM in
+/// bytes, such as "card0,68702699520,10960896".
+fn parse_rocm_smi(output: &str) -> Option<Vec<u64>> {
+    let vram = output
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with("card"))
+        .map(|line| line.split(',').nth(1)?.trim().parse().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    (!vram.is_empty()).then_some(vram)
+}
+
+/// Returns free space (in bytes) of the filesystem holding `path`.
+fn scratch_space(sys: &System, path: &Path) -> u64 {
+    let path = path.canonicalize().unwrap_or(path.to_path_buf());
+
+    // `mount_at()` only matches exact mount points, so walk up from the
+    // scratch path until the filesystem containing it is found.
+    match path.ancestors().find_map(|p| sys.mount_at(p).ok()) {
+        Some(fs) => fs.avail.as_u64(),
+        None => {
+            tracing::warn!("failed to lookup free disk space for {:#?}", path);
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::scheduler::resource_manager::ResourceManager;
+
+    pub(crate) struct FakeSystem {
+        // `None` when memory can't be detected.
+        pub(crate) mem: Option<u64>,
+        pub(crate) cpus: u64,
+        pub(crate) container_mem: Option<u64>,
+        pub(crate) container_cpus: Option<u64>,
+    }
+
+    impl SystemInfo for FakeSystem {
+        fn total_memory(&self) -> Result<u64> {
+            self.mem.ok_or_else(|| eyre!("no memory info"))
+        }
+
+        fn free_memory(&self) -> Option<u64> {
+            self.mem
+        }
+
+        fn cpu_count(&self) -> u64 {
+            self.cpus
+        }
+
+        // Fake host has two SMT threads per core.
+        fn physical_cpu_count(&self) -> u64 {
+            self.cpus / 2
+        }
+
+        fn gpu_count(&self, _vendor: GpuVendor, devices: &str) -> u64 {
+            gpu_count(devices)
+        }
+
+        fn gpu_memory(&self, _vendor: GpuVendor, _devices: &str) -> u64 {
+            0
+        }
+
+        fn disk_space(&self, _path: &Path) -> u64 {
+            1024
+        }
+
+        fn container_memory_limit(&self) -> Option<u64> {
+            self.container_mem
+        }
+
+        fn container_cpu_quota(&self) -> Option<u64> {
+            self.container_cpus
+        }
+    }
+
+    pub(crate) fn run_config(args: &[&str]) -> crate::cli::Config {
+        use crate::cli::{Cli, Command};
+        use clap::Parser;
+
+        let args = ["gevulot", "run"].iter().chain(args);
+        let Command::Run { config } = Cli::parse_from(args).subcommand else {
+            panic!("expected run command");
+        };
+        config
+    }
+
+    #[test]
+    fn test_gpu_count() {
+        assert_eq!(gpu_count("0,1,2,3"), 4);
+        assert_eq!(gpu_count("0-3"), 4);
+        assert_eq!(gpu_count("0-1,4"), 3);
+        assert_eq!(gpu_count("0000:01:00.0,02:00.0"), 2);
+        assert_eq!(gpu_count(""), 0);
+    }
+
+    #[test]
+    fn test_parse_nvidia_smi() {
+        // Captured on a host with two A100 GPUs.
+        const OUTPUT: &str = "2, 81920 MiB\n2, 81920 MiB\n";
+        assert_eq!(
+            parse_nvidia_smi(OUTPUT),
+            Some(vec![81920 * 1024 * 1024, 81920 * 1024 * 1024])
+        );
+
+        assert_eq!(
+            parse_nvidia_smi("1, 24576 MiB\n"),
+            Some(vec![24576 * 1024 * 1024])
+        );
+        assert_eq!(parse_nvidia_smi(""), None);
+        assert_eq!(parse_nvidia_smi("No devices were found\n"), None);
+        assert_eq!(parse_nvidia_smi("1, [N/A]\n"), None);
+        // Count disagreeing with the number of GPUs listed.
+        assert_eq!(parse_nvidia_smi("2, 81920 MiB\n"), None);
+    }
+
+    #[test]
+    fn test_parse_rocm_smi() {
+        // Captured on a host with two MI210 GPUs.
+        const OUTPUT: &str = "\
+============================ ROCm System Management Interface ============================
+device,VRAM Total Memory (B),VRAM Total Used Memory (B)
+card0,68702699520,10960896
+card1,68702699520,10960896
+================================== End of ROCm SMI Log ===================================
+";
+        let vram = parse_rocm_smi(OUTPUT).unwrap();
+        assert_eq!(vram.len(), 2);
+        assert_eq!(vram.iter().sum::<u64>(), 2 * 68702699520);
+
+        assert_eq!(parse_rocm_smi(""), None);
+        assert_eq!(parse_rocm_smi("card0,N/A,N/A\n"), None);
+    }
+
+    #[test]
+    fn test_gpu_vendor_from_name() {
+        assert_eq!(GpuVendor::from_name("amd"), Some(GpuVendor::Amd));
+        assert_eq!(GpuVendor::from_name("intel"), None);
+    }
+
+    #[test]
+    fn test_gpu_memory_from_sysfs() {
+        let sysfs_root = std::env::temp_dir().join(format!("gevulot-sysfs-{}", std::process::id()));
+        for (dev, vram) in [
+            ("0000:01:00.0", "42949672960\n"),
+            ("0000:02:00.0", "1024\n"),
+        ] {
+            std::fs::create_dir_all(sysfs_root.join(dev)).unwrap();
+            std::fs::write(sysfs_root.join(dev).join("mem_info_vram_total"), vram).unwrap();
+        }
+
+        let vram = gpu_memory(&sysfs_root, "0000:01:00.0,02:00.0,0000:03:00.0");
+        std::fs::remove_dir_all(&sysfs_root).unwrap();
+
+        assert_eq!(vram, 42949672960 + 1024);
+    }
+
+    #[test]
+    fn test_reserved_mem() {
+        use crate::cli::{Cli, Command};
+        use clap::Parser;
+
+        let Command::Run { config } = Cli::parse_from(["gevulot", "run"]).subcommand else {
+            panic!("expected run command");
+        };
+        assert_eq!(config.reserve_mem_mb, 1024);
+
+        let gib = 1024 * 1024 * 1024;
+        assert_eq!(
+            without_reserved_mem(16 * gib, config.reserve_mem_mb),
+            15 * gib
+        );
+        assert_eq!(without_reserved_mem(16 * gib, 4096), 12 * gib);
+        assert_eq!(without_reserved_mem(gib, 4096), 0);
+    }
+
+    #[test]
+    fn test_configured_mem_percent() {
+        use crate::cli::{Cli, Command};
+        use clap::Parser;
+
+        let Command::Run { config } =
+            Cli::parse_from(["gevulot", "run", "--mem-percent", "80"]).subcommand
+        else {
+            panic!("expected run command");
+        };
+
+        let gib = 1024 * 1024 * 1024;
+        let mem = configured_mem(&config, || Ok(16 * gib)).unwrap();
+        assert_eq!(mem, 16 * gib * 80 / 100);
+        assert!((mem as f64 / gib as f64 - 12.8).abs() < 0.01);
+
+        assert!(
+            Cli::try_parse_from(["gevulot", "run", "--mem-percent", "80", "--mem-gb", "8"])
+                .is_err()
+        );
+        assert!(Cli::try_parse_from(["gevulot", "run", "--mem-percent", "120"]).is_err());
+    }
+
+    #[test]
+    fn test_configured_mem_with_unit() {
+        use crate::cli::Cli;
+        use clap::Parser;
+
+        let gib = 1024 * 1024 * 1024;
+        assert_eq!(
+            configured_mem(&run_config(&["--mem", "16GiB"]), || Ok(0)).unwrap(),
+            16 * gib
+        );
+        assert_eq!(
+            configured_mem(&run_config(&["--mem", "16GB"]), || Ok(0)).unwrap(),
+            16_000_000_000
+        );
+        assert_eq!(
+            configured_mem(&run_config(&["--mem-gb", "8"]), || Ok(0)).unwrap(),
+            8 * gib
+        );
+
+        assert!(Cli::try_parse_from(["gevulot", "run", "--mem", "16Gb"]).is_err());
+        assert!(
+            Cli::try_parse_from(["gevulot", "run", "--mem", "16GiB", "--mem-gb", "8"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_cgroup_mem_limit() {
+        let dir = std::env::temp_dir().join(format!("gevulot-cgroup-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let memory_max = dir.join("memory.max");
+
+        std::fs::write(&memory_max, "4294967296\n").unwrap();
+        let limited = cgroup_mem_limit(&memory_max);
+        std::fs::write(&memory_max, "max\n").unwrap();
+        let unlimited = cgroup_mem_limit(&memory_max);
+        std::fs::write(&memory_max, "-1\n").unwrap();
+        let unlimited_v1 = cgroup_mem_limit(&memory_max);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(limited, Some(4294967296));
+        assert_eq!(unlimited, None);
+        assert_eq!(unlimited_v1, None);
+        assert_eq!(cgroup_mem_limit(&memory_max), None);
+    }
+
+    #[test]
+    fn test_cgroup_cpu_quota() {
+        let dir = std::env::temp_dir().join(format!("gevulot-cgroup-cpu-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cpu_max = dir.join("cpu.max");
+
+        std::fs::write(&cpu_max, "200000 100000\n").unwrap();
+        let limited = cgroup_cpu_quota(&cpu_max);
+        std::fs::write(&cpu_max, "50000 100000\n").unwrap();
+        let fractional = cgroup_cpu_quota(&cpu_max);
+        std::fs::write(&cpu_max, "max 100000\n").unwrap();
+        let unlimited = cgroup_cpu_quota(&cpu_max);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(limited, Some(2 * MILLICORES_PER_CPU));
+        assert_eq!(fractional, Some(500));
+        assert_eq!(unlimited, None);
+        assert_eq!(cgroup_cpu_quota(&cpu_max), None);
+    }
+
+    #[test]
+    fn test_get_configured_resources_from_system() {
+        let gib = 1024 * 1024 * 1024;
+        let sys = FakeSystem {
+            mem: Some(16 * gib),
+            cpus: 8,
+            container_mem: None,
+            container_cpus: None,
+        };
+
+        let resources =
+            get_configured_resources(&run_config(&["--gpu-devices", "0-3"]), &sys).unwrap();
+        assert_eq!(resources.cpus, 8 * MILLICORES_PER_CPU);
+        assert_eq!(resources.mem, 15 * 1024);
+        assert_eq!(resources.gpus, 4);
+        assert_eq!(resources.disk, 1024);
+
+        let resources = get_configured_resources(
+            &run_config(&["--gpu-devices", "0-3", "--disable-gpu"]),
+            &sys,
+        )
+        .unwrap();
+        assert_eq!(resources.gpus, 0);
+
+        let resources =
+            get_configured_resources(&run_config(&["--num-cpus", "2", "--mem-gb", "4"]), &sys)
+                .unwrap();
+        assert_eq!(resources.cpus, 2 * MILLICORES_PER_CPU);
+        assert_eq!(resources.mem, 4 * 1024);
+    }
+
+    #[test]
+    fn test_get_configured_resources_counts_physical_cores() {
+        let sys = FakeSystem {
+            mem: Some(16 * 1024 * 1024 * 1024),
+            cpus: 16,
+            container_mem: None,
+            container_cpus: None,
+        };
+
+        let resources = get_configured_resources(&run_config(&[]), &sys).unwrap();
+        assert_eq!(resources.cpus, 16 * MILLICORES_PER_CPU);
+
+        let resources =
+            get_configured_resources(&run_config(&["--count-physical-cores"]), &sys).unwrap();
+        assert_eq!(resources.cpus, 8 * MILLICORES_PER_CPU);
+
+        // Configured CPUs are checked against the physical cores.
+        let config = run_config(&[
+            "--count-physical-cores",
+            "--num-cpus",
+            "12",
+            "--strict-resources",
+        ]);
+        assert!(get_configured_resources(&config, &sys).is_err());
+    }
+
+    #[test]
+    fn test_get_configured_resources_in_container() {
+        let gib = 1024 * 1024 * 1024;
+        let sys = FakeSystem {
+            mem: Some(16 * gib),
+            cpus: 64,
+            container_mem: Some(8 * gib),
+            container_cpus: Some(2500),
+        };
+
+        let resources =
+            get_configured_resources(&run_config(&["--reserve-mem-mb", "0"]), &sys).unwrap();
+        assert_eq!(resources.cpus, 2500);
+        assert_eq!(resources.mem, 8 * 1024);
+
+        let resources =
+            get_configured_resources(&run_config(&["--mem-percent", "50"]), &sys).unwrap();
+        assert_eq!(resources.mem, 4 * 1024);
+    }
+
+    #[test]
+    fn test_detected_mem_is_not_transposed() {
+        let gib = 1024 * 1024 * 1024;
+        let sys = FakeSystem {
+            mem: Some(16 * gib),
+            cpus: 8,
+            container_mem: None,
+            container_cpus: None,
+        };
+
+        let resources =
+            get_configured_resources(&run_config(&["--reserve-mem-mb", "0"]), &sys).unwrap();
+        assert_eq!(resources.mem, 16 * 1024);
+        assert_eq!(resources.cpus, 8 * MILLICORES_PER_CPU);
+
+        let rm = ResourceManager::new(resources);
+        assert_eq!(rm.available_mem(), 16 * 1024);
+        assert_eq!(rm.available_cpus(), 8 * MILLICORES_PER_CPU);
+    }
+
+    #[test]
+    fn test_configured_resources_beyond_host() {
+        let gib = 1024 * 1024 * 1024;
+        let sys = FakeSystem {
+            mem: Some(16 * gib),
+            cpus: 8,
+            container_mem: None,
+            container_cpus: None,
+        };
+
+        // Only warned about by default.
+        let resources =
+            get_configured_resources(&run_config(&["--num-cpus", "16", "--mem", "32GiB"]), &sys)
+                .unwrap();
+        assert_eq!(resources.cpus, 16 * MILLICORES_PER_CPU);
+        assert_eq!(resources.mem, 32 * 1024);
+
+        let strict = |args: &[&str]| {
+            let args = [&["--strict-resources"], args].concat();
+            get_configured_resources(&run_config(&args), &sys)
+        };
+        let err = strict(&["--num-cpus", "16"]).unwrap_err();
+        assert!(err.to_string().contains("16 CPUs"), "{err}");
+        let err = strict(&["--mem-gb", "32"]).unwrap_err();
+        assert!(err.to_string().contains("memory"), "{err}");
+        strict(&["--num-cpus", "8", "--mem", "16GiB"]).unwrap();
+    }
+
+    #[test]
+    fn test_get_configured_resources_without_memory_info() {
+        let gib = 1024 * 1024 * 1024;
+        let sys = FakeSystem {
+            mem: None,
+            cpus: 8,
+            container_mem: None,
+            container_cpus: None,
+        };
+
+        // A conservative amount is assumed, unless that must not be.
+        let resources = get_configured_resources(&run_config(&[]), &sys).unwrap();
+        // Less the default reserve for the system.
+        assert_eq!(resources.mem, (FALLBACK_MEM - gib) / BYTES_PER_MIB);
+        let err = get_configured_resources(&run_config(&["--strict-resources"]), &sys).unwrap_err();
+        assert!(err.to_string().contains("set --mem"), "{err}");
+
+        // Memory configured explicitly needs no detection, but can't be
+        // checked against the host either.
+        let resources = get_configured_resources(&run_config(&["--mem", "4GiB"]), &sys).unwrap();
+        assert_eq!(resources.mem, 4 * 1024);
+        assert!(get_configured_resources(
+            &run_config(&["--mem", "4GiB", "--strict-resources"]),
+            &sys
+        )
+        .is_err());
+
+        // The container limit is a better guess than the fallback.
+        let sys = FakeSystem {
+            container_mem: Some(8 * gib),
+            ..sys
+        };
+        let resources =
+            get_configured_resources(&run_config(&["--mem-percent", "50"]), &sys).unwrap();
+        assert_eq!(resources.mem, 4 * 1024);
+    }
+}
@@ -0,0 +1,563 @@
+use super::clock::Clock;
+use super::resource_manager::{ResourceAllocation, ResourceError};
+use crate::metrics;
+use eyre::Result;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Resources set aside by `ResourceManager::reserve()`. The reservation is
+/// turned into an allocation with `commit()`; cancelling or dropping it
+/// returns the resources back to the manager.
+pub struct Reservation {
+    allocation: Arc<Mutex<Option<ResourceAllocation>>>,
+    clock: Arc<dyn Clock>,
+    // When the reservation expires, if it has a TTL.
+    expires_at: Option<Instant>,
+}
+
+impl Reservation {
+    pub(super) fn new(allocation: ResourceAllocation, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            allocation: Arc::new(Mutex::new(Some(allocation))),
+            clock,
+            expires_at: None,
+        }
+    }
+
+    /// Frees the resources of the reservation unless it's committed within
+    /// `ttl`. Must be called within a Tokio runtime.
+    pub(super) fn expire_after(&mut self, ttl: Duration) {
+        self.expires_at = Some(self.clock.now() + ttl);
+
+        let allocation = Arc::downgrade(&self.allocation);
+        tokio::spawn(async move {
+            tokio::time::sleep(ttl).await;
+            if let Some(allocation) = allocation.upgrade() {
+                // Drop outside of the reservation lock.
+                let expired = allocation.lock().take();
+                if expired.is_some() {
+                    tracing::debug!("resource reservation expired after {:?}", ttl);
+                }
+            }
+        });
+    }
+
+    pub fn commit(self) -> Result<ResourceAllocation> {
+        let allocation = self
+            .allocation
+            .lock()
+            .take()
+            .ok_or(ResourceError::ReservationExpired)?;
+
+        // The reservation may have expired without its timer having fired
+        // yet, such as with a mock clock.
+        let now = self.clock.now();
+        if self.expires_at.is_some_and(|expires_at| now >= expires_at) {
+            return Err(ResourceError::ReservationExpired.into());
+        }
+        Ok(allocation)
+    }
+
+    pub fn cancel(self) {}
+}
+
+/// Allocation made by `ResourceManager::try_allocate_lease()`. Unless
+/// renewed within its TTL, the lease expires and the lease reaper frees its
+/// resources. Dropping the lease frees them right away.
+pub struct Lease {
+    registry: Arc<LeaseRegistry>,
+    clock: Arc<dyn Clock>,
+    id: u64,
+    ttl: Duration,
+    allocation: Arc<Mutex<Option<ResourceAllocation>>>,
+}
+
+impl Lease {
+    /// Identifier of the leased allocation.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Extends the lease to a full TTL from now. Fails with
+    /// `ResourceError::LeaseExpired` if the lease has been reaped already.
+    pub fn renew(&self) -> Result<()> {
+        if self.allocation.lock().is_none() {
+            return Err(ResourceError::LeaseExpired.into());
+        }
+        let expires_at = self.clock.now() + self.ttl;
+        match self.registry.leases.lock().get_mut(&self.id) {
+            Some(entry) => {
+                entry.expires_at = expires_at;
+                Ok(())
+            }
+            None => Err(ResourceError::LeaseExpired.into()),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.allocation.lock().is_none()
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.registry.leases.lock().remove(&self.id);
+    }
+}
+
+// Lease entry, for the lease reaper.
+#[derive(Debug)]
+struct LeaseEntry {
+    expires_at: Instant,
+    allocation: Weak<Mutex<Option<ResourceAllocation>>>,
+}
+
+/// Allocation made by `ResourceManager::try_allocate_heartbeat()` for a
+/// remote task. Unless `heartbeat()` is called within the heartbeat
+/// staleness of the resource manager, the reaper frees its resources.
+/// Dropping the allocation frees them right away.
+pub struct HeartbeatAllocation {
+    registry: Arc<LeaseRegistry>,
+    clock: Arc<dyn Clock>,
+    id: u64,
+    allocation: Arc<Mutex<Option<ResourceAllocation>>>,
+}
+
+impl HeartbeatAllocation {
+    /// Identifier of the allocation.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Records that the remote task was seen just now. Fails with
+    /// `ResourceError::HeartbeatLost` if the allocation has been reaped
+    /// already.
+    pub fn heartbeat(&self) -> Result<()> {
+        if self.allocation.lock().is_none() {
+            return Err(ResourceError::HeartbeatLost.into());
+        }
+        let now = self.clock.now();
+        match self.registry.heartbeats.lock().get_mut(&self.id) {
+            Some(entry) => {
+                entry.last_seen = now;
+                Ok(())
+            }
+            None => Err(ResourceError::HeartbeatLost.into()),
+        }
+    }
+
+    pub fn is_reaped(&self) -> bool {
+        self.allocation.lock().is_none()
+    }
+}
+
+impl Drop for HeartbeatAllocation {
+    fn drop(&mut self) {
+        self.registry.heartbeats.lock().remove(&self.id);
+    }
+}
+
+// Heartbeat entry, for the reaper.
+#[derive(Debug)]
+struct HeartbeatEntry {
+    last_seen: Instant,
+    allocation: Weak<Mutex<Option<ResourceAllocation>>>,
+}
+
+/// Outstanding leases and allocations kept alive by heartbeats, by
+/// allocation ID, for the reapers to free those that went away.
+#[derive(Debug, Default)]
+pub(super) struct LeaseRegistry {
+    leases: Mutex<HashMap<u64, LeaseEntry>>,
+    heartbeats: Mutex<HashMap<u64, HeartbeatEntry>>,
+}
+
+impl LeaseRegistry {
+    /// Holds `allocation` as a lease that expires `ttl` from now.
+    pub(super) fn lease(
+        self: &Arc<Self>,
+        allocation: ResourceAllocation,
+        ttl: Duration,
+        clock: Arc<dyn Clock>,
+    ) -> Lease {
+        let id = allocation.id();
+        let allocation = Arc::new(Mutex::new(Some(allocation)));
+        self.leases.lock().insert(
+            id,
+            LeaseEntry {
+                expires_at: clock.now() + ttl,
+                allocation: Arc::downgrade(&allocation),
+            },
+        );
+
+        Lease {
+            registry: self.clone(),
+            clock,
+            id,
+            ttl,
+            allocation,
+        }
+    }
+
+    /// Holds `allocation` for as long as heartbeats keep coming, starting
+    /// with one now.
+    pub(super) fn heartbeat_allocation(
+        self: &Arc<Self>,
+        allocation: ResourceAllocation,
+        clock: Arc<dyn Clock>,
+    ) -> HeartbeatAllocation {
+        let id = allocation.id();
+        let allocation = Arc::new(Mutex::new(Some(allocation)));
+        self.heartbeats.lock().insert(
+            id,
+            HeartbeatEntry {
+                last_seen: clock.now(),
+                allocation: Arc::downgrade(&allocation),
+            },
+        );
+
+        HeartbeatAllocation {
+            registry: self.clone(),
+            clock,
+            id,
+            allocation,
+        }
+    }
+
+    /// Frees the resources of leases that have expired by `now`, returning
+    /// how many were freed.
+    pub(super) fn reap_expired_leases(&self, now: Instant) -> usize {
+        let expired: Vec<LeaseEntry> = {
+            let mut leases = self.leases.lock();
+            let ids: Vec<u64> = leases
+                .iter()
+                .filter(|(_, entry)| entry.expires_at <= now)
+                .map(|(id, _)| *id)
+                .collect();
+            ids.iter().filter_map(|id| leases.remove(id)).collect()
+        };
+
+        let mut reaped = 0;
+        for entry in expired {
+            // Taking the allocation out makes sure it's freed only once, by
+            // whoever takes it. Drop outside of the lease lock.
+            let allocation = entry.allocation.upgrade().and_then(|a| a.lock().take());
+            if let Some(allocation) = allocation {
+                tracing::info!("lease of allocation {} expired", allocation.id());
+                drop(allocation);
+                metrics::LEASES_EXPIRED_TOTAL.inc();
+                reaped += 1;
+            }
+        }
+        reaped
+    }
+
+    /// Frees the resources of allocations whose last heartbeat is at least
+    /// `staleness` older than `now`, returning how many were freed.
+    pub(super) fn reap_stale_heartbeats(&self, now: Instant, staleness: Duration) -> usize {
+        let stale: Vec<HeartbeatEntry> = {
+            let mut heartbeats = self.heartbeats.lock();
+            let ids: Vec<u64> = heartbeats
+                .iter()
+                .filter(|(_, entry)| now.saturating_duration_since(entry.last_seen) >= staleness)
+                .map(|(id, _)| *id)
+                .collect();
+            ids.iter().filter_map(|id| heartbeats.remove(id)).collect()
+        };
+
+        let mut reaped = 0;
+        for entry in stale {
+            // As with leases, whoever takes the allocation out frees it.
+            let allocation = entry.allocation.upgrade().and_then(|a| a.lock().take());
+            if let Some(allocation) = allocation {
+                tracing::info!(
+                    "no heartbeat for allocation {} in {:?}",
+                    allocation.id(),
+                    staleness
+                );
+                drop(allocation);
+                metrics::HEARTBEATS_LOST_TOTAL.inc();
+                reaped += 1;
+            }
+        }
+        reaped
+    }
+
+    /// Time from `now` until the next lease expires, if any is held.
+    pub(super) fn next_expiry(&self, now: Instant) -> Option<Duration> {
+        self.leases
+            .lock()
+            .values()
+            .map(|entry| entry.expires_at.saturating_duration_since(now))
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::clock::MockClock;
+    use crate::scheduler::resource_manager::{DetectedResources, ResourceManager};
+    use crate::types::program::ResourceRequest;
+
+    #[test]
+    fn test_reservation_commit_and_cancel() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let req = &ResourceRequest {
+            mem: 2048,
+            cpus: 4,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        // Reservation takes the resources immediately.
+        let reservation = ResourceManager::reserve(rm.clone(), req).unwrap();
+        assert!(ResourceManager::try_allocate(rm.clone(), req).is_err());
+
+        // ...and they stay taken once committed.
+        let ra = reservation.commit().unwrap();
+        assert!(ResourceManager::try_allocate(rm.clone(), req).is_err());
+        drop(ra);
+
+        // Cancelled and dropped reservations return the resources.
+        ResourceManager::reserve(rm.clone(), req).unwrap().cancel();
+        drop(ResourceManager::reserve(rm.clone(), req).unwrap());
+        ResourceManager::try_allocate(rm.clone(), req).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reservation_expires() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let req = &ResourceRequest {
+            mem: 2048,
+            cpus: 4,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let reservation =
+            ResourceManager::reserve_with_ttl(rm.clone(), req, Duration::from_secs(30)).unwrap();
+        assert!(ResourceManager::try_allocate(rm.clone(), req).is_err());
+
+        tokio::time::sleep(Duration::from_secs(31)).await;
+
+        ResourceManager::try_allocate(rm.clone(), req).unwrap();
+        assert!(reservation.commit().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reservation_expires_on_mock_clock() {
+        let clock = Arc::new(MockClock::new());
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 2048,
+                cpus: 4,
+                ..Default::default()
+            })
+            .with_clock(clock.clone()),
+        );
+        let req = &ResourceRequest {
+            mem: 2048,
+            cpus: 4,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let reservation =
+            ResourceManager::reserve_with_ttl(rm.clone(), req, Duration::from_secs(30)).unwrap();
+        clock.advance(Duration::from_secs(31));
+
+        let Err(err) = reservation.commit() else {
+            panic!("reservation should have expired");
+        };
+        assert!(matches!(
+            err.downcast_ref::<ResourceError>(),
+            Some(ResourceError::ReservationExpired)
+        ));
+        assert_eq!(rm.available_mem(), 2048);
+    }
+
+    #[test]
+    fn test_unrenewed_lease_is_reaped() {
+        let clock = Arc::new(MockClock::new());
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 2048,
+                cpus: 4,
+                ..Default::default()
+            })
+            .with_clock(clock.clone()),
+        );
+        let req = &ResourceRequest {
+            mem: 1024,
+            cpus: 1,
+            gpus: 0,
+            ..Default::default()
+        };
+        let ttl = Duration::from_secs(30);
+
+        let renewed = ResourceManager::try_allocate_lease(rm.clone(), req, ttl).unwrap();
+        let abandoned = ResourceManager::try_allocate_lease(rm.clone(), req, ttl).unwrap();
+        assert_eq!(rm.available_mem(), 0);
+
+        clock.advance(Duration::from_secs(20));
+        renewed.renew().unwrap();
+        assert_eq!(rm.reap_expired_leases(), 0);
+
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(rm.reap_expired_leases(), 1);
+        assert_eq!(rm.available_mem(), 1024);
+        assert!(abandoned.is_expired());
+        assert!(!renewed.is_expired());
+
+        // Reaped only once, and can't be renewed anymore.
+        assert_eq!(rm.reap_expired_leases(), 0);
+        assert!(abandoned.renew().is_err());
+        drop(abandoned);
+        assert_eq!(rm.available_mem(), 1024);
+
+        drop(renewed);
+        assert_eq!(rm.available_mem(), 2048);
+    }
+
+    #[test]
+    fn test_retry_after_next_lease_expiry() {
+        let clock = Arc::new(MockClock::new());
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 2048,
+                cpus: 4,
+                ..Default::default()
+            })
+            .with_clock(clock.clone())
+            .with_default_retry_after(Duration::from_millis(500)),
+        );
+        let req = &ResourceRequest {
+            mem: 1024,
+            cpus: 2,
+            gpus: 0,
+            ..Default::default()
+        };
+        let retry_after = |res: Result<ResourceAllocation>| {
+            let Err(err) = res else {
+                panic!("allocation should have failed");
+            };
+            err.downcast_ref::<ResourceError>().unwrap().retry_after()
+        };
+
+        let _lease =
+            ResourceManager::try_allocate_lease(rm.clone(), req, Duration::from_secs(5)).unwrap();
+        let _ra = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+        assert_eq!(
+            retry_after(ResourceManager::try_allocate(rm.clone(), req)),
+            Some(Duration::from_secs(5))
+        );
+
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(
+            retry_after(ResourceManager::try_allocate(rm.clone(), req)),
+            Some(Duration::from_secs(3))
+        );
+
+        // Capacity won't change by waiting.
+        let too_big = &ResourceRequest { mem: 4096, ..*req };
+        assert_eq!(
+            retry_after(ResourceManager::try_allocate(rm.clone(), too_big)),
+            None
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lease_reaper_task() {
+        let clock = Arc::new(MockClock::new());
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 2048,
+                cpus: 4,
+                ..Default::default()
+            })
+            .with_clock(clock.clone()),
+        );
+        let req = &ResourceRequest {
+            mem: 2048,
+            cpus: 4,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let reaper = ResourceManager::spawn_reaper(&rm, Duration::from_secs(1));
+        let lease =
+            ResourceManager::try_allocate_lease(rm.clone(), req, Duration::from_secs(30)).unwrap();
+        clock.advance(Duration::from_secs(31));
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        assert!(lease.is_expired());
+        assert_eq!(rm.available_mem(), 2048);
+
+        // The reaper stops with the resource manager.
+        drop(lease);
+        drop(rm);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(reaper.is_finished());
+    }
+
+    #[test]
+    fn test_stale_heartbeat_allocation_is_reaped() {
+        let clock = Arc::new(MockClock::new());
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 2048,
+                cpus: 4,
+                ..Default::default()
+            })
+            .with_clock(clock.clone())
+            .with_heartbeat_staleness(Duration::from_secs(30)),
+        );
+        let req = &ResourceRequest {
+            mem: 1024,
+            cpus: 1,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let alive = ResourceManager::try_allocate_heartbeat(rm.clone(), req).unwrap();
+        let silent = ResourceManager::try_allocate_heartbeat(rm.clone(), req).unwrap();
+        assert_eq!(rm.available_mem(), 0);
+
+        clock.advance(Duration::from_secs(20));
+        alive.heartbeat().unwrap();
+        assert_eq!(rm.reap_stale_heartbeats(), 0);
+
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(rm.reap_stale_heartbeats(), 1);
+        assert_eq!(rm.available_mem(), 1024);
+        assert!(silent.is_reaped());
+        assert!(!alive.is_reaped());
+
+        // Reaped only once, and late heartbeats don't bring it back.
+        assert_eq!(rm.reap_stale_heartbeats(), 0);
+        let Err(err) = silent.heartbeat() else {
+            panic!("heartbeat after reaping should fail");
+        };
+        assert!(matches!(
+            err.downcast_ref::<ResourceError>(),
+            Some(ResourceError::HeartbeatLost)
+        ));
+        drop(silent);
+        assert_eq!(rm.available_mem(), 1024);
+
+        drop(alive);
+        assert_eq!(rm.available_mem(), 2048);
+    }
+}
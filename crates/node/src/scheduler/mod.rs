@@ -1,5 +1,19 @@
+mod allocations_http;
+mod billing;
+mod cgroup;
+mod clock;
+mod gpu_telemetry;
+mod host;
+mod lease;
+mod numa;
+mod placement;
 mod program_manager;
+mod quota;
+mod rate_limit;
 mod resource_manager;
+mod resource_metrics;
+mod resource_registry;
+pub mod retry;
 
 use crate::cli::Config;
 use crate::mempool;
@@ -28,7 +42,7 @@ use gevulot_node::types::{TaskKind, Transaction};
 use libsecp256k1::SecretKey;
 pub use program_manager::ProgramManager;
 use rand::RngCore;
-pub use resource_manager::ResourceManager;
+pub use resource_manager::{HealthStatus, ResourceManager};
 use std::path::PathBuf;
 use std::time::Instant;
 use std::{
@@ -36,7 +50,6 @@ use std::{
     sync::Arc,
     time::Duration,
 };
-use systemstat::ByteSize;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::UnboundedSender;
 use tokio::{
@@ -45,16 +58,30 @@ use tokio::{
 };
 use tonic::transport::Server;
 
+use self::placement::{PlacementStrategy, ResourceWeights};
 use self::program_manager::{ProgramError, ProgramHandle};
 use self::resource_manager::ResourceError;
 
-pub use self::resource_manager::get_configured_resources;
+pub use self::host::{get_configured_resources, HostSystem};
 
 // If VM doesn't have running task within `MAX_VM_IDLE_RUN_TIME`, it will be terminated.
 const MAX_VM_IDLE_RUN_TIME: Duration = Duration::from_secs(10);
 // MAX_VM_RUN_TIME is the maximum time a VM can run no matter what.
 // The proof must be generated within this time limit.
 const MAX_VM_RUN_TIME: Duration = Duration::from_secs(1800);
+// Bounds of how long the scheduling loop waits before retrying a program
+// that found no resources free. The lower bound keeps a lease past its
+// expiry, but not yet reaped, from making the loop spin.
+const MIN_RETRY_DELAY: Duration = Duration::from_millis(100);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Time to wait before retrying after `err`, going by its hint of when
+/// resources are expected to be freed.
+fn retry_delay(err: &ResourceError) -> Duration {
+    err.retry_after()
+        .unwrap_or(Duration::from_millis(500))
+        .clamp(MIN_RETRY_DELAY, MAX_RETRY_DELAY)
+}
 
 struct RunningTask {
     task: Task,
@@ -100,6 +127,13 @@ pub struct Scheduler {
     data_directory: PathBuf,
     http_download_host: String,
     tx_sender: TxEventSender<TxResultSender>,
+
+    // Chooses among resource pools to place tasks on, with
+    // `ResourceRegistry::place()`, once tasks are spread over several.
+    placement: Box<dyn PlacementStrategy>,
+
+    // Reported to the watchdog, to tell whether the node has room for work.
+    resource_manager: Arc<ResourceManager>,
 }
 
 pub async fn start_scheduler(
@@ -108,21 +142,13 @@ pub async fn start_scheduler(
     mempool: Arc<RwLock<Mempool>>,
     node_key: SecretKey,
     tx_sender: UnboundedSender<(Transaction<Received>, Option<CallbackSender>)>,
-) -> Arc<Scheduler> {
-    let (num_cpus, available_mem, num_gpus) = get_configured_resources(&config);
-
-    tracing::info!(
-        "node configured with {} CPUs, {} MEM and {} GPUs",
-        num_cpus,
-        ByteSize(available_mem).to_string_as(true),
-        num_gpus
-    );
-
-    let resource_manager = Arc::new(std::sync::Mutex::new(ResourceManager::new(
-        available_mem,
-        num_cpus,
-        num_gpus,
-    )));
+) -> Result<Arc<Scheduler>> {
+    let resource_manager = ResourceManager::from_config(&config)?;
+    ResourceManager::spawn_reaper(&resource_manager, Duration::from_secs(1));
+    gpu_telemetry::spawn_gpu_sampler(&config);
+    if let Some(bind_addr) = config.http_allocations_listen_addr {
+        allocations_http::serve_allocations(bind_addr, resource_manager.clone()).await?;
+    }
 
     // TODO(tuommaki): Handle provider from config.
     let qemu_provider = Qemu::new(config.clone());
@@ -148,6 +174,15 @@ pub async fn start_scheduler(
         config.data_directory.clone(),
         download_url_prefix,
         mempool::TxEventSender::<mempool::TxResultSender>::build(tx_sender.clone()),
+        placement::from_name(
+            &config.placement_strategy,
+            ResourceWeights {
+                mem: config.placement_weight_mem,
+                cpus: config.placement_weight_cpus,
+                gpus: config.placement_weight_gpus,
+            },
+        )
+        .expect("validated placement strategy"),
     ));
 
     let vm_server = VMServer::new(scheduler.clone(), provider, config.data_directory.clone());
@@ -159,7 +194,7 @@ pub async fn start_scheduler(
             .serve_with_incoming(vsock_stream)
             .await
     });
-    scheduler
+    Ok(scheduler)
 }
 
 impl Scheduler {
@@ -173,7 +208,9 @@ impl Scheduler {
         data_directory: PathBuf,
         http_download_host: String,
         tx_sender: TxEventSender<TxResultSender>,
+        placement: Box<dyn PlacementStrategy>,
     ) -> Self {
+        let resource_manager = program_manager.resource_manager().clone();
         Self {
             database,
             workflow_engine,
@@ -183,6 +220,8 @@ impl Scheduler {
             data_directory,
             http_download_host,
             tx_sender,
+            placement,
+            resource_manager,
         }
     }
 
@@ -205,16 +244,29 @@ impl Scheduler {
 
                     match state
                         .program_manager
-                        .start_program(tx_hash, program_id, None)
+                        .start_program(tx_hash, program_id, None, None)
                         .await
                     {
                         Ok(p) => {
                             state.running_vms.insert(tx_hash, p);
                         }
+                        Err(e)
+                            if e.downcast_ref::<ResourceError>()
+                                .is_some_and(ResourceError::is_permanent) =>
+                        {
+                            // Retrying would never succeed on this node.
+                            tracing::error!(
+                                "tx {} - program {} can never run on this node: {e}",
+                                tx_hash,
+                                program_id
+                            );
+                            state.task_queue.remove(&tx_hash);
+                            self.mark_tx_failed(&tx_hash).await;
+                        }
                         Err(e) if e.is::<ResourceError>() => {
                             let err = e.downcast_ref::<ResourceError>().unwrap();
                             tracing::info!("resources unavailable: {}", err);
-                            sleep(Duration::from_millis(500)).await;
+                            sleep(retry_delay(err)).await;
 
                             // Return the popped program_id back to pending queue.
                             state.pending_programs.push_back((tx_hash, program_id));
@@ -242,6 +294,14 @@ impl Scheduler {
             {
                 tracing::error!("Watchdog channel send return an error:{err}");
             }
+            if let Err(err) = watchdog_sender
+                .send(HealthCheckSignal::ResourceHealth(
+                    self.resource_manager.health(),
+                ))
+                .await
+            {
+                tracing::error!("Watchdog channel send return an error:{err}");
+            }
 
             let (mut task, mempool_size) = match self.pick_task().await {
                 (Some(t), size) => {
@@ -300,13 +360,28 @@ impl Scheduler {
             // Start the program.
             match state
                 .program_manager
-                .start_program(task.tx, task.program_id, None)
+                .start_program(task.tx, task.program_id, Some(task.id), None)
                 .await
             {
                 Ok(p) => {
                     state.running_vms.insert(task.tx, p);
                 }
                 Err(ref err) => {
+                    if let Some(err) = err
+                        .downcast_ref::<ResourceError>()
+                        .filter(|err| err.is_permanent())
+                    {
+                        tracing::error!(
+                            "task {} can never run on this node: {}",
+                            task.id.to_string(),
+                            err
+                        );
+
+                        state.task_queue.remove(&task.tx);
+                        self.mark_tx_failed(&task.tx).await;
+                        continue;
+                    }
+
                     if let Some(err) = err.downcast_ref::<ProgramError>() {
                         let ProgramError::ProgramNotFound(msg) = err;
                         tracing::error!("failed to schedule task {}: {}", task.id.to_string(), msg);
@@ -331,6 +406,18 @@ impl Scheduler {
         }
     }
 
+    /// Marks `tx` executed when it can never be run on this node, so that
+    /// it isn't picked up again, including after a restart.
+    async fn mark_tx_failed(&self, tx: &Hash) {
+        if let Err(err) = self.database.mark_tx_executed(tx).await {
+            tracing::error!(
+                "failed to update transaction.executed => true - tx.hash: {}: {}",
+                tx,
+                err
+            );
+        }
+    }
+
     async fn pick_task(&self) -> (Option<Task>, usize) {
         let state = self.state.lock().await;
 
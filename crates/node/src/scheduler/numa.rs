@@ -0,0 +1,519 @@
+use crate::types::program::MILLICORES_PER_CPU;
+use std::path::Path;
+
+pub const SYSFS_NUMA_NODES: &str = "/sys/devices/system/node";
+
+/// CPUs and local memory of one NUMA node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NumaNode {
+    pub id: u32,
+    /// CPUs of the node (in millicores).
+    pub cpus: u64,
+    /// Memory local to the node (in MiB).
+    pub mem: u64,
+}
+
+/// CPUs and memory an allocation holds on each NUMA node.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NumaPlacement {
+    parts: Vec<NumaNode>,
+}
+
+impl NumaPlacement {
+    /// The node holding all of the allocation, unless it spans nodes or
+    /// holds no CPUs or memory.
+    pub fn node(&self) -> Option<u32> {
+        match self.parts[..] {
+            [part] => Some(part.id),
+            _ => None,
+        }
+    }
+
+    /// Amounts held on each node.
+    pub fn parts(&self) -> &[NumaNode] {
+        &self.parts
+    }
+
+    fn add(&mut self, other: NumaPlacement) {
+        for part in other.parts {
+            match self.parts.iter_mut().find(|p| p.id == part.id) {
+                Some(p) => {
+                    p.cpus += part.cpus;
+                    p.mem += part.mem;
+                }
+                None => self.parts.push(part),
+            }
+        }
+    }
+
+    /// Removes `cpus` and `mem` from the parts, last placed first, and
+    /// returns what was removed from each node.
+    fn split_off(&mut self, mut cpus: u64, mut mem: u64) -> NumaPlacement {
+        let mut removed = NumaPlacement::default();
+        for part in self.parts.iter_mut().rev() {
+            let (part_cpus, part_mem) = (part.cpus.min(cpus), part.mem.min(mem));
+            part.cpus -= part_cpus;
+            part.mem -= part_mem;
+            cpus -= part_cpus;
+            mem -= part_mem;
+            if part_cpus > 0 || part_mem > 0 {
+                removed.parts.push(NumaNode {
+                    id: part.id,
+                    cpus: part_cpus,
+                    mem: part_mem,
+                });
+            }
+        }
+        self.parts.retain(|p| p.cpus > 0 || p.mem > 0);
+        removed
+    }
+}
+
+/// Free CPUs and memory on each NUMA node. This is bookkeeping of where
+/// allocations are placed; whether there is enough of a resource at all is
+/// still decided by the `ResourceManager` totals.
+#[derive(Debug)]
+pub(super) struct NumaPools {
+    free: Vec<NumaNode>,
+    // Whether placements must fit on a single node, rather than being
+    // spread across nodes when none has room.
+    pub(super) strict: bool,
+}
+
+impl NumaPools {
+    pub(super) fn new(nodes: Vec<NumaNode>) -> Self {
+        Self {
+            free: nodes,
+            strict: false,
+        }
+    }
+
+    /// The most CPUs and memory a single placement can currently get:
+    /// those of the node with the most free when placements must fit on a
+    /// single node. `None` when they may be spread across nodes, as they
+    /// then can take everything free.
+    pub(super) fn largest(&self) -> Option<(u64, u64)> {
+        if !self.strict {
+            return None;
+        }
+        Some(
+            self.free
+                .iter()
+                .map(|node| (node.cpus, node.mem))
+                .max()
+                .unwrap_or_default(),
+        )
+    }
+
+    /// Takes `cpus` and `mem` from a single node if one has enough of both,
+    /// picking the one with the fewest free CPUs left, so that larger
+    /// requests still fit elsewhere. Otherwise, spreads them over the nodes
+    /// with the most free first, unless placements are strict, in which
+    /// case nothing is taken and `None` returned.
+    pub(super) fn place(&mut self, cpus: u64, mem: u64) -> Option<NumaPlacement> {
+        if cpus == 0 && mem == 0 {
+            return Some(NumaPlacement::default());
+        }
+
+        let fitting = self
+            .free
+            .iter_mut()
+            .filter(|node| node.cpus >= cpus && node.mem >= mem)
+            .min_by_key(|node| (node.cpus, node.mem, node.id));
+        if let Some(node) = fitting {
+            node.cpus -= cpus;
+            node.mem -= mem;
+            return Some(NumaPlacement {
+                parts: vec![NumaNode {
+                    id: node.id,
+                    cpus,
+                    mem,
+                }],
+            });
+        }
+        if self.strict {
+            return None;
+        }
+
+        let mut placement = NumaPlacement::default();
+        let (mut cpus, mut mem) = (cpus, mem);
+        let mut nodes: Vec<&mut NumaNode> = self.free.iter_mut().collect();
+        nodes.sort_by_key(|node| std::cmp::Reverse((node.cpus, node.mem)));
+        for node in nodes {
+            let (node_cpus, node_mem) = (node.cpus.min(cpus), node.mem.min(mem));
+            if node_cpus == 0 && node_mem == 0 {
+                continue;
+            }
+            node.cpus -= node_cpus;
+            node.mem -= node_mem;
+            cpus -= node_cpus;
+            mem -= node_mem;
+            placement.parts.push(NumaNode {
+                id: node.id,
+                cpus: node_cpus,
+                mem: node_mem,
+            });
+        }
+        if cpus > 0 || mem > 0 {
+            tracing::debug!(cpus, mem, "NUMA nodes short of placing request");
+        }
+        Some(placement)
+    }
+
+    /// Takes `cpus` and `mem` more for `placement`, on the nodes it is on
+    /// if they have room. Strict placements only grow on their node, and
+    /// are left as they were if it has no room, returning `false`.
+    pub(super) fn grow(&mut self, placement: &mut NumaPlacement, cpus: u64, mem: u64) -> bool {
+        let on_node = placement.node().and_then(|id| {
+            self.free
+                .iter_mut()
+                .find(|node| node.id == id && node.cpus >= cpus && node.mem >= mem)
+        });
+        let added = match on_node {
+            Some(node) => {
+                node.cpus -= cpus;
+                node.mem -= mem;
+                NumaPlacement {
+                    parts: vec![NumaNode {
+                        id: node.id,
+                        cpus,
+                        mem,
+                    }],
+                }
+            }
+            None if self.strict && !placement.parts.is_empty() => return false,
+            None => match self.place(cpus, mem) {
+                Some(added) => added,
+                None => return false,
+            },
+        };
+        placement.add(added);
+        true
+    }
+
+    /// Gives `cpus` and `mem` of `placement` back to their nodes.
+    pub(super) fn shrink(&mut self, placement: &mut NumaPlacement, cpus: u64, mem: u64) {
+        let removed = placement.split_off(cpus, mem);
+        self.release(&removed);
+    }
+
+    /// Gives all of `placement` back to its nodes.
+    pub(super) fn release(&mut self, placement: &NumaPlacement) {
+        for part in &placement.parts {
+            if let Some(node) = self.free.iter_mut().find(|node| node.id == part.id) {
+                node.cpus += part.cpus;
+                node.mem += part.mem;
+            }
+        }
+    }
+}
+
+/// Reads the NUMA topology from sysfs at `root`, normally
+/// `/sys/devices/system/node`. Returns `None` unless the host has more
+/// than one node, as there is nothing to choose between otherwise.
+pub fn detect_numa_topology(root: &Path) -> Option<Vec<NumaNode>> {
+    let mut nodes = vec![];
+    for entry in std::fs::read_dir(root).ok()? {
+        let path = entry.ok()?.path();
+        let Some(id) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("node"))
+            .and_then(|id| id.parse().ok())
+        else {
+            continue;
+        };
+        let cpulist = std::fs::read_to_string(path.join("cpulist")).ok()?;
+        let meminfo = std::fs::read_to_string(path.join("meminfo")).ok()?;
+        nodes.push(NumaNode {
+            id,
+            cpus: parse_cpulist(&cpulist)?.len() as u64 * MILLICORES_PER_CPU,
+            mem: parse_node_mem_total(&meminfo)?,
+        });
+    }
+    nodes.sort_by_key(|node| node.id);
+
+    (nodes.len() > 1).then_some(nodes)
+}
+
+/// Returns the ids of the CPUs in a sysfs CPU list, such as "0-3,8-11".
+pub(super) fn parse_cpulist(cpulist: &str) -> Option<Vec<usize>> {
+    let cpulist = cpulist.trim();
+    if cpulist.is_empty() {
+        return Some(vec![]);
+    }
+    let mut ids = vec![];
+    for range in cpulist.split(',') {
+        match range.split_once('-') {
+            Some((first, last)) => {
+                let (first, last): (usize, usize) = (first.parse().ok()?, last.parse().ok()?);
+                if last < first {
+                    return None;
+                }
+                ids.extend(first..=last);
+            }
+            None => ids.push(range.parse().ok()?),
+        }
+    }
+    Some(ids)
+}
+
+/// Returns the total memory (in MiB) from a node's meminfo, which has
+/// lines such as "Node 0 MemTotal:       32768000 kB".
+fn parse_node_mem_total(meminfo: &str) -> Option<u64> {
+    meminfo.lines().find_map(|line| {
+        let (_, value) = line.split_once("MemTotal:")?;
+        let kb: u64 = value.trim().strip_suffix("kB")?.trim().parse().ok()?;
+        Some(kb / 1024)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::resource_manager::{DetectedResources, ResourceError, ResourceManager};
+    use crate::types::program::ResourceRequest;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_parse_cpulist() {
+        assert_eq!(parse_cpulist("0-2,8-9\n"), Some(vec![0, 1, 2, 8, 9]));
+        assert_eq!(parse_cpulist("5"), Some(vec![5]));
+        assert_eq!(parse_cpulist("0,2,4-5"), Some(vec![0, 2, 4, 5]));
+        assert_eq!(parse_cpulist("\n"), Some(vec![]));
+        assert_eq!(parse_cpulist("3-1"), None);
+        assert_eq!(parse_cpulist("a-b"), None);
+    }
+
+    #[test]
+    fn test_detect_numa_topology() {
+        let root = std::env::temp_dir().join(format!("gevulot-numa-{}", std::process::id()));
+        for (id, cpulist, kb) in [(0, "0-3", 4194304), (1, "4-7", 8388608)] {
+            let node = root.join(format!("node{}", id));
+            std::fs::create_dir_all(&node).unwrap();
+            std::fs::write(node.join("cpulist"), format!("{}\n", cpulist)).unwrap();
+            std::fs::write(
+                node.join("meminfo"),
+                format!("Node {id} MemTotal:       {kb} kB\nNode {id} MemFree:        1024 kB\n"),
+            )
+            .unwrap();
+        }
+        std::fs::write(root.join("possible"), "0-1\n").unwrap();
+        let detected = detect_numa_topology(&root);
+        std::fs::remove_dir_all(root.join("node1")).unwrap();
+        let single = detect_numa_topology(&root);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            detected,
+            Some(vec![
+                NumaNode {
+                    id: 0,
+                    cpus: 4 * MILLICORES_PER_CPU,
+                    mem: 4096,
+                },
+                NumaNode {
+                    id: 1,
+                    cpus: 4 * MILLICORES_PER_CPU,
+                    mem: 8192,
+                },
+            ])
+        );
+        assert_eq!(single, None);
+        assert_eq!(detect_numa_topology(&root), None);
+    }
+
+    /// Manager of a host with two NUMA nodes of 4 CPUs and 4 GiB each, as
+    /// detected from sysfs.
+    fn two_numa_nodes() -> Arc<ResourceManager> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let root = std::env::temp_dir().join(format!(
+            "gevulot-rm-numa-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        for (id, cpulist) in [(0, "0-3"), (1, "4-7")] {
+            let node = root.join(format!("node{id}"));
+            std::fs::create_dir_all(&node).unwrap();
+            std::fs::write(node.join("cpulist"), cpulist).unwrap();
+            std::fs::write(
+                node.join("meminfo"),
+                format!("Node {id} MemTotal:       4194304 kB\n"),
+            )
+            .unwrap();
+        }
+        let nodes = detect_numa_topology(&root).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 8192,
+                cpus: 8000,
+                ..Default::default()
+            })
+            .with_pool_name("test-numa".to_string())
+            .with_numa_topology(nodes),
+        )
+    }
+
+    #[test]
+    fn test_numa_fits_memory() {
+        let rm = two_numa_nodes();
+        let req = |cpus, mem| ResourceRequest {
+            mem,
+            cpus,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let ra1 = ResourceManager::try_allocate(rm.clone(), &req(1000, 3584)).unwrap();
+        assert_eq!(ra1.numa_node(), Some(0));
+        // Node 0 has the CPUs left, but only 512 MiB of memory.
+        let ra2 = ResourceManager::try_allocate(rm.clone(), &req(1000, 1024)).unwrap();
+        assert_eq!(ra2.numa_node(), Some(1));
+    }
+
+    #[test]
+    fn test_numa_prefers_single_node() {
+        let rm = two_numa_nodes();
+        let req = |cpus, mem| ResourceRequest {
+            mem,
+            cpus,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let ra1 = ResourceManager::try_allocate(rm.clone(), &req(3000, 3072)).unwrap();
+        assert_eq!(ra1.numa_node(), Some(0));
+        // Node 0 has 1000 CPUs left, too few.
+        let ra2 = ResourceManager::try_allocate(rm.clone(), &req(2000, 2048)).unwrap();
+        assert_eq!(ra2.numa_node(), Some(1));
+        // Both nodes fit; the fuller one is used.
+        let ra3 = ResourceManager::try_allocate(rm.clone(), &req(1000, 1024)).unwrap();
+        assert_eq!(ra3.numa_node(), Some(0));
+        // Mostly memory still lands on a node.
+        let ra4 = ResourceManager::try_allocate(rm.clone(), &req(1, 1024)).unwrap();
+        assert_eq!(ra4.numa_node(), Some(1));
+    }
+
+    #[test]
+    fn test_numa_falls_back_to_cross_node() {
+        let rm = two_numa_nodes();
+        let req = |cpus, mem| ResourceRequest {
+            mem,
+            cpus,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let ra1 = ResourceManager::try_allocate(rm.clone(), &req(2000, 2048)).unwrap();
+        assert_eq!(ra1.numa_node(), Some(0));
+        let mut ra2 = ResourceManager::try_allocate(rm.clone(), &req(5000, 5120)).unwrap();
+        assert_eq!(ra2.numa_node(), None);
+        assert_eq!(
+            ra2.numa_placement().parts(),
+            &[
+                NumaNode {
+                    id: 1,
+                    cpus: 4000,
+                    mem: 4096,
+                },
+                NumaNode {
+                    id: 0,
+                    cpus: 1000,
+                    mem: 1024,
+                },
+            ]
+        );
+
+        // Shrinking gives back what was placed last first.
+        ra2.resize(&req(4000, 4096)).unwrap();
+        assert_eq!(ra2.numa_node(), Some(1));
+
+        drop(ra1);
+        drop(ra2);
+        let ra3 = ResourceManager::try_allocate(rm.clone(), &req(4000, 4096)).unwrap();
+        assert_eq!(ra3.numa_node(), Some(0));
+        let ra4 = ResourceManager::try_allocate(rm.clone(), &req(4000, 4096)).unwrap();
+        assert_eq!(ra4.numa_node(), Some(1));
+    }
+
+    #[test]
+    fn test_no_numa_node_without_topology() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 4096,
+            cpus: 4000,
+            ..Default::default()
+        }));
+        let ra = ResourceManager::try_allocate(
+            rm,
+            &ResourceRequest {
+                mem: 1024,
+                cpus: 1000,
+                gpus: 0,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(ra.numa_node(), None);
+    }
+
+    #[test]
+    fn test_fragmented_numa_nodes() {
+        let node = |id| NumaNode {
+            id,
+            cpus: 4000,
+            mem: 4096,
+        };
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 8192,
+                cpus: 8000,
+                ..Default::default()
+            })
+            .with_pool_name("test-numa-strict".to_string())
+            .with_numa_topology(vec![node(0), node(1)])
+            .with_strict_numa(true),
+        );
+        let req = |cpus, mem| ResourceRequest {
+            mem,
+            cpus,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let _ra1 = ResourceManager::try_allocate(rm.clone(), &req(2000, 2048)).unwrap();
+        let mut ra2 = ResourceManager::try_allocate(rm.clone(), &req(1000, 1024)).unwrap();
+        assert_eq!(ra2.numa_node(), Some(0));
+        let largest = rm.largest_allocatable();
+        assert_eq!((largest.cpus, largest.mem), (4000, 4096));
+
+        // 5000 CPUs are free, but no node has more than 4000.
+        let Err(err) = ResourceManager::try_allocate(rm.clone(), &req(4500, 1024)) else {
+            panic!("allocation should have failed");
+        };
+        match err.downcast_ref::<ResourceError>() {
+            Some(ResourceError::Fragmented { requested, largest }) => {
+                assert_eq!(requested.cpus, 4500);
+                assert_eq!((largest.cpus, largest.mem), (4000, 4096));
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+        assert_eq!(rm.available_cpus(), 5000);
+        assert_eq!(rm.available_mem(), 5120);
+
+        // Growing past the room on its node fails too.
+        let Err(err) = ra2.resize(&req(2500, 1024)) else {
+            panic!("resize should have failed");
+        };
+        assert!(matches!(
+            err.downcast_ref::<ResourceError>(),
+            Some(ResourceError::Fragmented { .. })
+        ));
+        assert_eq!(ra2.cpus(), 1000);
+        assert_eq!(rm.available_cpus(), 5000);
+        ra2.resize(&req(2000, 1024)).unwrap();
+        assert_eq!(ra2.numa_node(), Some(0));
+    }
+}
@@ -0,0 +1,234 @@
+use super::resource_manager::ResourceSnapshot;
+use crate::types::program::ResourceRequest;
+use std::fmt;
+
+/// Chooses where to place a task among candidate resource pools, given a
+/// snapshot of each.
+pub trait PlacementStrategy: fmt::Debug + Send + Sync {
+    /// Returns the index of the candidate to place `request` on, or `None`
+    /// if it fits on none of them.
+    fn place(&self, request: &ResourceRequest, candidates: &[ResourceSnapshot]) -> Option<usize>;
+}
+
+/// Places on the first candidate the request fits on.
+#[derive(Debug, Default)]
+pub struct FirstFit;
+
+impl PlacementStrategy for FirstFit {
+    fn place(&self, request: &ResourceRequest, candidates: &[ResourceSnapshot]) -> Option<usize> {
+        candidates
+            .iter()
+            .position(|candidate| fits(request, candidate))
+    }
+}
+
+/// Places on the candidate left with the least free capacity, packing
+/// tasks tightly and keeping other candidates free for large requests.
+#[derive(Debug, Default)]
+pub struct BestFit;
+
+impl PlacementStrategy for BestFit {
+    fn place(&self, request: &ResourceRequest, candidates: &[ResourceSnapshot]) -> Option<usize> {
+        fitting(request, candidates)
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(index, _)| index)
+    }
+}
+
+/// Places on the candidate left with the most free capacity, spreading
+/// tasks out.
+#[derive(Debug, Default)]
+pub struct WorstFit;
+
+impl PlacementStrategy for WorstFit {
+    fn place(&self, request: &ResourceRequest, candidates: &[ResourceSnapshot]) -> Option<usize> {
+        // `max_by` picks the last of equal elements, prefer the first one.
+        fitting(request, candidates)
+            .rev()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(index, _)| index)
+    }
+}
+
+/// How much each resource counts in `ResourceSnapshot::score()`.
+#[derive(Clone, Copy, Debug)]
+pub struct ResourceWeights {
+    pub mem: f64,
+    pub cpus: f64,
+    pub gpus: f64,
+}
+
+impl Default for ResourceWeights {
+    fn default() -> Self {
+        ResourceWeights {
+            mem: 1.0,
+            cpus: 1.0,
+            gpus: 1.0,
+        }
+    }
+}
+
+/// Places on the candidate with the highest `ResourceSnapshot::score()`,
+/// keeping the most of the weighted resources free.
+#[derive(Debug, Default)]
+pub struct WeightedFit {
+    pub weights: ResourceWeights,
+}
+
+impl PlacementStrategy for WeightedFit {
+    fn place(&self, request: &ResourceRequest, candidates: &[ResourceSnapshot]) -> Option<usize> {
+        // `max_by` picks the last of equal elements, prefer the first one.
+        candidates
+            .iter()
+            .enumerate()
+            .filter(|(_, candidate)| fits(request, candidate))
+            .map(|(index, candidate)| (index, candidate.score(request, &self.weights)))
+            .rev()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(index, _)| index)
+    }
+}
+
+/// Returns the strategy configured by `name`, as accepted by the
+/// `--placement-strategy` option. `weights` are used by the "weighted"
+/// strategy.
+pub fn from_name(name: &str, weights: ResourceWeights) -> Option<Box<dyn PlacementStrategy>> {
+    match name {
+        "first-fit" => Some(Box::new(FirstFit)),
+        "best-fit" => Some(Box::new(BestFit)),
+        "worst-fit" => Some(Box::new(WorstFit)),
+        "weighted" => Some(Box::new(WeightedFit { weights })),
+        _ => None,
+    }
+}
+
+fn fits(request: &ResourceRequest, candidate: &ResourceSnapshot) -> bool {
+    request.mem <= candidate.available_mem
+        && request.cpus <= candidate.available_cpus
+        && request.gpus <= candidate.available_gpus
+}
+
+/// Candidates `request` fits on, by index, along with the share of their
+/// capacity that would be left free.
+fn fitting<'a>(
+    request: &'a ResourceRequest,
+    candidates: &'a [ResourceSnapshot],
+) -> impl DoubleEndedIterator<Item = (usize, f64)> + 'a {
+    candidates
+        .iter()
+        .enumerate()
+        .filter(|(_, candidate)| fits(request, candidate))
+        .map(|(index, candidate)| (index, leftover(request, candidate)))
+}
+
+/// Sum of the shares of memory, CPUs and GPUs that would be left free on
+/// `candidate` after placing `request`. Resources the candidate doesn't
+/// have don't count.
+fn leftover(request: &ResourceRequest, candidate: &ResourceSnapshot) -> f64 {
+    [
+        (request.mem, candidate.available_mem, candidate.total_mem),
+        (request.cpus, candidate.available_cpus, candidate.total_cpus),
+        (request.gpus, candidate.available_gpus, candidate.total_gpus),
+    ]
+    .into_iter()
+    .filter(|(_, _, total)| *total > 0)
+    .map(|(requested, available, total)| (available - requested) as f64 / total as f64)
+    .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(available_mem: u64, available_cpus: u64) -> ResourceSnapshot {
+        ResourceSnapshot {
+            total_mem: 8192,
+            available_mem,
+            total_cpus: 8,
+            available_cpus,
+            ..Default::default()
+        }
+    }
+
+    fn candidates() -> Vec<ResourceSnapshot> {
+        vec![
+            snapshot(512, 8),
+            snapshot(4096, 4),
+            snapshot(8192, 8),
+            snapshot(2048, 2),
+        ]
+    }
+
+    fn request(mem: u64, cpus: u64) -> ResourceRequest {
+        ResourceRequest {
+            mem,
+            cpus,
+            gpus: 0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_first_fit() {
+        assert_eq!(FirstFit.place(&request(1024, 2), &candidates()), Some(1));
+        assert_eq!(FirstFit.place(&request(256, 1), &candidates()), Some(0));
+        assert_eq!(FirstFit.place(&request(16384, 1), &candidates()), None);
+    }
+
+    #[test]
+    fn test_best_fit() {
+        assert_eq!(BestFit.place(&request(1024, 2), &candidates()), Some(3));
+        assert_eq!(BestFit.place(&request(4096, 4), &candidates()), Some(1));
+        assert_eq!(BestFit.place(&request(16384, 1), &candidates()), None);
+    }
+
+    #[test]
+    fn test_worst_fit() {
+        assert_eq!(WorstFit.place(&request(1024, 2), &candidates()), Some(2));
+        assert_eq!(WorstFit.place(&request(16384, 1), &candidates()), None);
+
+        // Ties go to the first candidate.
+        let tied = vec![snapshot(4096, 4), snapshot(4096, 4)];
+        assert_eq!(WorstFit.place(&request(1024, 1), &tied), Some(0));
+    }
+
+    #[test]
+    fn test_from_name() {
+        assert!(from_name("best-fit", ResourceWeights::default()).is_some());
+        assert!(from_name("random", ResourceWeights::default()).is_none());
+    }
+
+    #[test]
+    fn test_score_weights() {
+        // Placing leaves a quarter of the memory and three quarters of the
+        // CPUs free on the first, and half of both on the second.
+        let mem_bound = snapshot(4096, 8);
+        let balanced = snapshot(6144, 6);
+        let req = request(2048, 2);
+
+        let even = ResourceWeights::default();
+        assert_eq!(mem_bound.score(&req, &even), 0.625);
+        assert_eq!(balanced.score(&req, &even), 0.5);
+
+        let mem_heavy = ResourceWeights { mem: 10.0, ..even };
+        assert_eq!(mem_bound.score(&req, &mem_heavy), 1.1875);
+        assert_eq!(balanced.score(&req, &mem_heavy), 2.75);
+    }
+
+    #[test]
+    fn test_weighted_fit() {
+        let candidates = vec![snapshot(4096, 8), snapshot(6144, 6)];
+        let req = request(2048, 2);
+
+        let even = WeightedFit::default();
+        assert_eq!(even.place(&req, &candidates), Some(0));
+        let mem_heavy = WeightedFit {
+            weights: ResourceWeights {
+                mem: 10.0,
+                ..Default::default()
+            },
+        };
+        assert_eq!(mem_heavy.place(&req, &candidates), Some(1));
+        assert_eq!(mem_heavy.place(&request(16384, 1), &candidates), None);
+    }
+}
@@ -1,5 +1,5 @@
 use eyre::Result;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::time::Duration;
 use thiserror::Error;
 use tokio::sync::Mutex as TMutex;
@@ -7,7 +7,7 @@ use tokio::sync::Mutex as TMutex;
 use crate::scheduler::resource_manager::{ResourceAllocation, ResourceManager};
 use crate::storage::Database;
 use crate::types::program::ResourceRequest;
-use crate::types::Hash;
+use crate::types::{Hash, TaskId};
 use crate::vmm::{Provider, VMHandle, VMId};
 
 #[allow(clippy::enum_variant_names)]
@@ -38,7 +38,7 @@ impl ProgramHandle {
 
 pub struct ProgramManager {
     storage: Arc<Database>,
-    resource_manager: Arc<Mutex<ResourceManager>>,
+    resource_manager: Arc<ResourceManager>,
     vm_provider: Arc<TMutex<dyn Provider>>,
 }
 
@@ -46,7 +46,7 @@ impl ProgramManager {
     pub fn new(
         storage: Arc<Database>,
         vm_provider: Arc<TMutex<dyn Provider>>,
-        resource_manager: Arc<Mutex<ResourceManager>>,
+        resource_manager: Arc<ResourceManager>,
     ) -> Self {
         Self {
             storage,
@@ -55,10 +55,16 @@ impl ProgramManager {
         }
     }
 
+    pub fn resource_manager(&self) -> &Arc<ResourceManager> {
+        &self.resource_manager
+    }
+
+    #[tracing::instrument(level = "info", skip(self, limits))]
     pub async fn start_program(
         &mut self,
         tx_hash: Hash,
         program_id: Hash,
+        task_id: Option<TaskId>,
         limits: Option<ResourceRequest>,
     ) -> Result<ProgramHandle> {
         let program = match self.storage.find_program(&program_id).await? {
@@ -67,8 +73,13 @@ impl ProgramManager {
         };
 
         let req = limits.unwrap_or(program.limits.unwrap_or(ResourceRequest::default()));
-        let resource_allocation =
-            ResourceManager::try_allocate(self.resource_manager.clone(), &req)?;
+        let resource_allocation = ResourceManager::try_allocate_for(
+            self.resource_manager.clone(),
+            &req,
+            Some(program_id),
+            task_id,
+            None,
+        )?;
         let vm_handle = self
             .vm_provider
             .lock()
@@ -0,0 +1,183 @@
+use super::resource_manager::{zero_request, ResourceError, ResourceKind};
+use crate::entity::PublicKey;
+use crate::types::program::ResourceRequest;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+
+/// Most each account may hold at once across its allocations, and what it
+/// currently holds. Accounts without a quota are neither limited nor
+/// tracked.
+#[derive(Debug, Default)]
+pub(super) struct AccountQuotas {
+    quotas: HashMap<PublicKey, ResourceRequest>,
+    // What accounts with a quota currently hold.
+    usage: Mutex<HashMap<PublicKey, ResourceRequest>>,
+}
+
+impl AccountQuotas {
+    pub(super) fn new(quotas: HashMap<PublicKey, ResourceRequest>) -> Self {
+        Self {
+            quotas,
+            usage: Mutex::default(),
+        }
+    }
+
+    /// Adds `request` to the usage of `account`, unless that would exceed
+    /// the account's quota.
+    pub(super) fn charge(
+        &self,
+        account: &PublicKey,
+        request: &ResourceRequest,
+    ) -> Result<(), ResourceError> {
+        let Some(quota) = self.quotas.get(account) else {
+            return Ok(());
+        };
+
+        let mut usage = self.usage.lock();
+        let used = usage.entry(account.clone()).or_insert_with(zero_request);
+        for kind in ResourceKind::ALL {
+            let remaining = kind.requested(quota).saturating_sub(kind.requested(used));
+            if kind.requested(request) > remaining {
+                return Err(ResourceError::QuotaExceeded {
+                    kind,
+                    requested: kind.requested(request),
+                    remaining,
+                });
+            }
+        }
+        *used += *request;
+        Ok(())
+    }
+
+    /// Removes `request` from the usage of `account`.
+    pub(super) fn refund(&self, account: &PublicKey, request: &ResourceRequest) {
+        let mut usage = self.usage.lock();
+        if let Some(used) = usage.get_mut(account) {
+            *used -= *request;
+            if ResourceKind::ALL
+                .iter()
+                .all(|kind| kind.requested(used) == 0)
+            {
+                usage.remove(account);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::resource_manager::{DetectedResources, ResourceManager};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_account_quota() {
+        let alice =
+            PublicKey::from_secret_key(&libsecp256k1::SecretKey::random(&mut rand::thread_rng()));
+        let bob =
+            PublicKey::from_secret_key(&libsecp256k1::SecretKey::random(&mut rand::thread_rng()));
+        let quota = ResourceRequest {
+            mem: 2048,
+            cpus: 2,
+            gpus: 0,
+            ..Default::default()
+        };
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 8192,
+                cpus: 8,
+                ..Default::default()
+            })
+            .with_account_quotas(HashMap::from([
+                (alice.clone(), quota),
+                (bob.clone(), quota),
+            ])),
+        );
+        let req = &ResourceRequest {
+            mem: 1024,
+            cpus: 1,
+            gpus: 0,
+            ..Default::default()
+        };
+        let allocate = |account: &PublicKey| {
+            ResourceManager::try_allocate_for(rm.clone(), req, None, None, Some(account.clone()))
+        };
+
+        let _ra1 = allocate(&alice).unwrap();
+        let ra2 = allocate(&alice).unwrap();
+        assert_eq!(ra2.account(), Some(&alice));
+
+        // Alice is at her quota, while Bob and accounts without a quota can
+        // still allocate.
+        let Err(err) = allocate(&alice) else {
+            panic!("allocation should have failed");
+        };
+        assert!(
+            matches!(
+                err.downcast_ref::<ResourceError>(),
+                Some(ResourceError::QuotaExceeded {
+                    kind: ResourceKind::Mem,
+                    requested: 1024,
+                    remaining: 0,
+                })
+            ),
+            "unexpected error: {err}"
+        );
+        let _ra3 = allocate(&bob).unwrap();
+        let _ra4 = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+
+        // Freeing an allocation gives the quota back.
+        drop(ra2);
+        let _ra5 = allocate(&alice).unwrap();
+    }
+
+    #[test]
+    fn test_account_quota_is_refunded_on_failure() {
+        let alice =
+            PublicKey::from_secret_key(&libsecp256k1::SecretKey::random(&mut rand::thread_rng()));
+        let quota = ResourceRequest {
+            mem: 2048,
+            cpus: 2,
+            gpus: 0,
+            ..Default::default()
+        };
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 2048,
+                cpus: 8,
+                ..Default::default()
+            })
+            .with_account_quotas(HashMap::from([(alice.clone(), quota)])),
+        );
+        let req = &ResourceRequest {
+            mem: 1024,
+            cpus: 1,
+            gpus: 0,
+            ..Default::default()
+        };
+        let allocate =
+            || ResourceManager::try_allocate_for(rm.clone(), req, None, None, Some(alice.clone()));
+
+        let ra = ResourceManager::try_allocate(rm.clone(), &ResourceRequest { mem: 2048, ..*req })
+            .unwrap();
+        // Fails on node capacity, not on quota.
+        let Err(err) = allocate() else {
+            panic!("allocation should have failed");
+        };
+        assert!(
+            matches!(
+                err.downcast_ref::<ResourceError>(),
+                Some(ResourceError::NotEnoughResources {
+                    kind: ResourceKind::Mem,
+                    ..
+                })
+            ),
+            "unexpected error: {err}"
+        );
+
+        // The whole quota is left once the node has room.
+        drop(ra);
+        let _ra1 = allocate().unwrap();
+        let _ra2 = allocate().unwrap();
+    }
+}
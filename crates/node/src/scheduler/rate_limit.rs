@@ -0,0 +1,167 @@
+use super::resource_manager::ResourceError;
+use crate::entity::PublicKey;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+// Allocations an account may still make under the rate limit.
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Allocation attempts each account may make, as a token bucket per
+/// account. Buckets refill continuously, at the limit per window, up to
+/// the limit.
+#[derive(Debug, Default)]
+pub(super) struct RateLimiter {
+    // Most allocations an account may make per window, if limited.
+    limit: Option<(u32, Duration)>,
+    // Allocations left to each account.
+    buckets: Mutex<HashMap<PublicKey, TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub(super) fn new(allocations: u32, window: Duration) -> Self {
+        Self {
+            limit: Some((allocations, window)),
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Takes one of the allocations `account` may make as of `now`, if
+    /// there is a limit.
+    pub(super) fn take(&self, account: &PublicKey, now: Instant) -> Result<(), ResourceError> {
+        let Some((limit, window)) = self.limit else {
+            return Ok(());
+        };
+        let limit = f64::from(limit);
+        let per_sec = limit / window.as_secs_f64();
+
+        let mut buckets = self.buckets.lock();
+        let bucket = buckets.entry(account.clone()).or_insert(TokenBucket {
+            tokens: limit,
+            refilled_at: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.refilled_at);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * per_sec).min(limit);
+        bucket.refilled_at = now;
+
+        if bucket.tokens < 1.0 {
+            return Err(ResourceError::RateLimited {
+                retry_after: Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec),
+            });
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::clock::MockClock;
+    use crate::scheduler::resource_manager::{DetectedResources, ResourceManager};
+    use crate::types::program::ResourceRequest;
+    use hyper::StatusCode;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_rate_limit_refills_over_time() {
+        let alice =
+            PublicKey::from_secret_key(&libsecp256k1::SecretKey::random(&mut rand::thread_rng()));
+        let bob =
+            PublicKey::from_secret_key(&libsecp256k1::SecretKey::random(&mut rand::thread_rng()));
+        let clock = Arc::new(MockClock::new());
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 8192,
+                cpus: 8000,
+                ..Default::default()
+            })
+            .with_clock(clock.clone())
+            .with_rate_limit(2, Duration::from_secs(10)),
+        );
+        let req = &ResourceRequest {
+            mem: 1,
+            cpus: 1,
+            gpus: 0,
+            ..Default::default()
+        };
+        let allocate = |account: &PublicKey| {
+            ResourceManager::try_allocate_for(rm.clone(), req, None, None, Some(account.clone()))
+        };
+
+        allocate(&alice).unwrap();
+        allocate(&alice).unwrap();
+        let Err(err) = allocate(&alice) else {
+            panic!("allocation should have been rate limited");
+        };
+        let err = err.downcast_ref::<ResourceError>().unwrap();
+        assert!(matches!(err, ResourceError::RateLimited { .. }));
+        assert_eq!(err.retry_after(), Some(Duration::from_secs(5)));
+        assert_eq!(StatusCode::from(err), StatusCode::TOO_MANY_REQUESTS);
+        // Other accounts, and allocations without one, are not held back.
+        allocate(&bob).unwrap();
+        ResourceManager::try_allocate(rm.clone(), req).unwrap();
+
+        clock.advance(Duration::from_secs(4));
+        assert!(allocate(&alice).is_err());
+        clock.advance(Duration::from_secs(1));
+        allocate(&alice).unwrap();
+        assert!(allocate(&alice).is_err());
+
+        // The bucket fills up to the limit, no further.
+        clock.advance(Duration::from_secs(60));
+        allocate(&alice).unwrap();
+        allocate(&alice).unwrap();
+        assert!(allocate(&alice).is_err());
+    }
+
+    #[test]
+    fn test_rate_limit_counts_failed_attempts() {
+        let alice =
+            PublicKey::from_secret_key(&libsecp256k1::SecretKey::random(&mut rand::thread_rng()));
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 1024,
+                cpus: 8000,
+                ..Default::default()
+            })
+            .with_clock(Arc::new(MockClock::new()))
+            .with_rate_limit(5, Duration::from_secs(10)),
+        );
+        let allocate = |mem| {
+            let req = ResourceRequest {
+                mem,
+                cpus: 1,
+                gpus: 0,
+                ..Default::default()
+            };
+            ResourceManager::try_allocate_for(rm.clone(), &req, None, None, Some(alice.clone()))
+        };
+
+        // Flooding with requests that can never be served is held back
+        // like any other.
+        for _ in 0..5 {
+            let Err(err) = allocate(4096) else {
+                panic!("allocation should have failed");
+            };
+            assert!(matches!(
+                err.downcast_ref::<ResourceError>(),
+                Some(ResourceError::ExceedsCapacity { .. })
+            ));
+        }
+        for mem in [4096, 1] {
+            let Err(err) = allocate(mem) else {
+                panic!("allocation should have been rate limited");
+            };
+            assert!(matches!(
+                err.downcast_ref::<ResourceError>(),
+                Some(ResourceError::RateLimited { .. })
+            ));
+        }
+    }
+}
@@ -1,199 +1,6164 @@
-use crate::{metrics, types::program::ResourceRequest};
+use super::billing::{BillingSink, MetricsBillingSink, NoopBillingSink, ResourceUsage};
+use super::clock::{Clock, SystemClock};
+use super::host::{
+    get_configured_resources, online_cpus, HostSystem, SystemInfo, SYSFS_CPU_ONLINE,
+};
+use super::lease::{HeartbeatAllocation, Lease, LeaseRegistry, Reservation};
+use super::numa::{detect_numa_topology, NumaNode, NumaPlacement, NumaPools, SYSFS_NUMA_NODES};
+use super::placement::ResourceWeights;
+use super::quota::AccountQuotas;
+use super::rate_limit::RateLimiter;
+use super::resource_metrics::{
+    cores, gauge_value, metric_value, set_available_metrics, set_peak_metrics,
+    set_reserved_metrics, set_total_metrics, Peaks, UtilizationEwma,
+};
+use crate::{
+    entity::PublicKey,
+    metrics,
+    types::{
+        program::{AffinityKey, RequestError, ResourceRequest, BYTES_PER_MIB, MILLICORES_PER_CPU},
+        Hash, TaskId,
+    },
+};
 use eyre::Result;
-use std::sync::{Arc, Mutex};
-use systemstat::{Platform, System};
+use hyper::header::HeaderValue;
+use hyper::StatusCode;
+use parking_lot::{Mutex, RwLock};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use systemstat::ByteSize;
 use thiserror::Error;
+use tokio::sync::{watch, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+/// Request waiting in `ResourceManager::allocate()`, or in
+/// `ResourceAllocation::grow()` for more resources.
+#[derive(Debug)]
+struct Waiter {
+    request: ResourceRequest,
+    since: tokio::time::Instant,
+    wakeup: Arc<Notify>,
+    // Allocation the request is growing, if any.
+    growing: Option<u64>,
+}
+
+/// Removes a waiter from the queue when it is allocated or gives up, and
+/// wakes up the next one that fits.
+struct QueuedWaiter<'a> {
+    resource_manager: &'a ResourceManager,
+    id: u64,
+    wakeup: Arc<Notify>,
+}
+
+impl Drop for QueuedWaiter<'_> {
+    fn drop(&mut self) {
+        if self
+            .resource_manager
+            .waiters
+            .lock()
+            .remove(&self.id)
+            .is_some()
+        {
+            self.resource_manager.queue_depth().dec();
+        }
+        self.resource_manager.wake_next_waiter();
+    }
+}
+
+/// Gives back what `ResourceManager::make_allocation()` has taken for a
+/// request, when it fails before the allocation is made.
+struct Rollback<'a> {
+    resource_manager: &'a ResourceManager,
+    request: &'a ResourceRequest,
+    // Account charged the request.
+    quota: Option<&'a PublicKey>,
+    taken: Vec<ResourceKind>,
+    numa: Option<NumaPlacement>,
+}
+
+impl Rollback<'_> {
+    /// Keeps what was taken, as the allocation is made, and returns its
+    /// NUMA placement.
+    fn complete(mut self) -> NumaPlacement {
+        self.quota = None;
+        self.taken.clear();
+        self.numa.take().unwrap_or_default()
+    }
+}
+
+impl Drop for Rollback<'_> {
+    fn drop(&mut self) {
+        let resource_manager = self.resource_manager;
+        if let (Some(pools), Some(numa)) = (&resource_manager.numa, &self.numa) {
+            pools.lock().release(numa);
+        }
+        resource_manager.give_back(self.request, &self.taken);
+        if let Some(account) = self.quota {
+            resource_manager.quotas.refund(account, self.request);
+        }
+    }
+}
 
 pub struct ResourceAllocation {
-    pub(self) resource_manager: Arc<Mutex<ResourceManager>>,
+    pub(self) resource_manager: Arc<ResourceManager>,
+    pub(self) id: u64,
+    pub(self) program_id: Option<Hash>,
+    pub(self) task_id: Option<TaskId>,
+    pub(self) account: Option<PublicKey>,
     pub(self) mem: u64,
+    // Memory expected in steady state, at most `mem`.
+    pub(self) steady_mem: u64,
     pub(self) cpus: u64,
     pub(self) gpus: u64,
+    pub(self) assigned_gpus: Vec<u32>,
+    pub(self) assigned_cores: Vec<usize>,
+    pub(self) disk: u64,
+    pub(self) gpu_mem: u64,
+    pub(self) net: u64,
+    // Hard limits, at least the amounts held.
+    pub(self) mem_limit: u64,
+    pub(self) cpus_limit: u64,
+    // CPUs and memory held on each NUMA node, if the manager tracks them.
+    pub(self) numa: NumaPlacement,
+    // CPU slots held, for allocations made with
+    // `ResourceManager::allocate_with_cpu_slots()`.
+    pub(self) cpu_slots: Option<OwnedSemaphorePermit>,
+    // Run by `preempt()` before the resources are freed.
+    pub(self) checkpoint: Option<Arc<dyn Checkpoint>>,
+    pub(self) freed: AtomicBool,
+    pub(self) created_at: tokio::time::Instant,
+    // Start of the period whose usage isn't billed yet, during which the
+    // amounts held haven't changed.
+    pub(self) billed_since: tokio::time::Instant,
+}
+
+impl ResourceAllocation {
+    /// Identifier of the allocation within its `ResourceManager`.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn program_id(&self) -> Option<Hash> {
+        self.program_id
+    }
+
+    pub fn task_id(&self) -> Option<TaskId> {
+        self.task_id
+    }
+
+    /// Account the allocation counts against, if any.
+    pub fn account(&self) -> Option<&PublicKey> {
+        self.account.as_ref()
+    }
+
+    /// Memory held by this allocation (in MiB), up to the peak requested.
+    pub fn mem(&self) -> u64 {
+        self.mem
+    }
+
+    /// Memory the holder is expected to use most of the time (in MiB),
+    /// below `mem()` when a peak was requested.
+    pub fn expected_mem(&self) -> u64 {
+        self.steady_mem
+    }
+
+    /// CPUs held by this allocation (in millicores).
+    pub fn cpus(&self) -> u64 {
+        self.cpus
+    }
+
+    /// Memory the holder may burst to (in MiB). Only `mem()` is taken from
+    /// the manager; the rest is overcommitted.
+    pub fn mem_limit(&self) -> u64 {
+        self.mem_limit
+    }
+
+    /// CPUs the holder may burst to (in millicores). Only `cpus()` is taken
+    /// from the manager; the rest is overcommitted.
+    pub fn cpus_limit(&self) -> u64 {
+        self.cpus_limit
+    }
+
+    /// Indices of the GPU devices assigned to this allocation.
+    pub fn assigned_gpus(&self) -> &[u32] {
+        &self.assigned_gpus
+    }
+
+    /// Ids of the logical CPU cores assigned to this allocation, one for
+    /// each whole CPU held, for pinning the task to. No other live
+    /// allocation is assigned the same cores.
+    pub fn assigned_cores(&self) -> &[usize] {
+        &self.assigned_cores
+    }
+
+    /// NUMA node holding all of the allocation's CPUs and memory. `None`
+    /// when they span nodes, or the manager doesn't track NUMA nodes.
+    pub fn numa_node(&self) -> Option<u32> {
+        self.numa.node()
+    }
+
+    /// CPUs and memory held on each NUMA node.
+    pub fn numa_placement(&self) -> &NumaPlacement {
+        &self.numa
+    }
+
+    /// Name of the pool the allocation was made from, if its manager is a
+    /// pool of a `ResourceRegistry`.
+    pub fn pool(&self) -> Option<&str> {
+        self.resource_manager.pool_name()
+    }
+
+    /// Returns a handle to look the allocation up by while it lives, which
+    /// doesn't keep it, or its manager, alive.
+    pub fn downgrade(&self) -> WeakAllocation {
+        WeakAllocation {
+            resource_manager: Arc::downgrade(&self.resource_manager),
+            id: self.id,
+        }
+    }
+
+    /// Frees the resources right away, rather than when the allocation goes
+    /// out of scope, and returns the amounts that were held.
+    pub fn release(self) -> ResourceRequest {
+        let held = self.held_request();
+        // Dropping frees, and the `freed` flag keeps it from happening twice.
+        drop(self);
+        held
+    }
+
+    /// Sets what `preempt()` runs to let the task persist its progress.
+    pub fn set_checkpoint(&mut self, checkpoint: impl Checkpoint + 'static) {
+        self.checkpoint = Some(Arc::new(checkpoint));
+    }
+
+    /// Frees the resources of an allocation picked by
+    /// `ResourceManager::try_allocate_preempt()`, once its checkpoint, if
+    /// any, has completed or run for longer than the manager's checkpoint
+    /// timeout. Failed checkpoints are logged, and don't keep the resources
+    /// from being freed.
+    pub async fn preempt(self) -> ResourceRequest {
+        if let Some(checkpoint) = &self.checkpoint {
+            let timeout = self.resource_manager.checkpoint_timeout;
+            match tokio::time::timeout(timeout, checkpoint.checkpoint()).await {
+                Ok(Ok(())) => {
+                    tracing::debug!(id = self.id, "checkpointed preempted allocation")
+                }
+                Ok(Err(err)) => tracing::warn!(
+                    "checkpoint of preempted allocation {} failed: {}",
+                    self.id,
+                    err
+                ),
+                Err(_) => tracing::warn!(
+                    "checkpoint of preempted allocation {} timed out after {:?}",
+                    self.id,
+                    timeout
+                ),
+            }
+        }
+        self.release()
+    }
+
+    /// Changes the resources held to those of `request`, taking more from
+    /// the manager or giving back the surplus, without freeing what is
+    /// held in between. If more can't be taken, the allocation is left as
+    /// it was. Added GPUs are any free devices, and removed GPUs are the
+    /// last of the assigned ones.
+    pub fn resize(&mut self, request: &ResourceRequest) -> Result<()> {
+        let resource_manager = self.resource_manager.clone();
+        Ok(resource_manager.resize(self, request)?)
+    }
+
+    /// Like `resize()`, but waits for the resources to grow by in the queue
+    /// of `allocate()`, rather than failing when they are not available.
+    /// While it waits, the allocation inherits the priority of higher
+    /// priority waiters it holds resources from, so that it isn't held up
+    /// by requests of priority in between them.
+    pub async fn grow(&mut self, request: &ResourceRequest) -> Result<()> {
+        let resource_manager = self.resource_manager.clone();
+        Ok(resource_manager.grow(self, request).await?)
+    }
+
+    /// Reports the resources held since `billed_since` to the billing sink
+    /// of the manager, and starts the next period at `now`. Called before
+    /// the amounts held change, so that each period is billed at the
+    /// amounts held during it.
+    fn bill_usage(&mut self, now: tokio::time::Instant) {
+        let duration = now.saturating_duration_since(self.billed_since);
+        self.billed_since = now;
+
+        let secs = duration.as_secs_f64();
+        let usage = ResourceUsage {
+            allocation_id: self.id,
+            program_id: self.program_id,
+            task_id: self.task_id,
+            account: self.account.clone(),
+            duration,
+            mem_byte_seconds: (self.mem * BYTES_PER_MIB) as f64 * secs,
+            cpu_seconds: cores(self.cpus) * secs,
+            gpu_seconds: self.gpus as f64 * secs,
+        };
+        without_panic("billing sink", || {
+            self.resource_manager.billing.record(&usage)
+        });
+    }
+
+    /// Resources held by this allocation, as a request.
+    fn held_request(&self) -> ResourceRequest {
+        ResourceRequest {
+            mem: self.mem,
+            cpus: self.cpus,
+            gpus: self.gpus,
+            disk_bytes: self.disk,
+            gpu_mem: self.gpu_mem,
+            net_bps: self.net,
+            mem_limit: (self.mem_limit > self.mem).then_some(self.mem_limit),
+            cpus_limit: (self.cpus_limit > self.cpus).then_some(self.cpus_limit),
+            ..zero_request()
+        }
+    }
+
+    /// Returns the amount of `kind` held by this allocation.
+    fn held(&self, kind: ResourceKind) -> u64 {
+        match kind {
+            ResourceKind::Mem => self.mem,
+            ResourceKind::Cpus => self.cpus,
+            ResourceKind::Gpus => self.gpus,
+            ResourceKind::GpuMem => self.gpu_mem,
+            ResourceKind::Disk => self.disk,
+            ResourceKind::Net => self.net,
+        }
+    }
 }
 
 impl Drop for ResourceAllocation {
     fn drop(&mut self) {
-        self.resource_manager
-            .clone()
-            .lock()
-            .expect("acquire resource manager instance lock")
-            .free(self);
+        // Allocations rolled back by `try_allocate_batch()` are freed
+        // already, and are not accounted for.
+        if self.freed.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let now = self.resource_manager.clock.now();
+        let held_for = now.saturating_duration_since(self.created_at);
+        metrics::ALLOCATION_LIFETIME_SECONDS.observe(held_for.as_secs_f64());
+        self.resource_manager.free(self);
+        self.resource_manager.publish_changes();
+        self.bill_usage(now);
+    }
+}
+
+/// Runs `f`, which is code from outside the resource manager run while
+/// dropping an allocation, logging rather than propagating a panic. A
+/// panic in drop would take down the task dropping the allocation, or
+/// abort the process if it was already unwinding.
+fn without_panic(what: &str, f: impl FnOnce()) {
+    if std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).is_err() {
+        tracing::error!("{} panicked while freeing an allocation", what);
+    }
+}
+
+/// Handle to an allocation that doesn't own it, for observing it while it
+/// lives. See `ResourceAllocation::downgrade()`.
+#[derive(Clone, Debug)]
+pub struct WeakAllocation {
+    resource_manager: Weak<ResourceManager>,
+    id: u64,
+}
+
+impl WeakAllocation {
+    /// Identifier of the allocation within its `ResourceManager`.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Details of the allocation, or `None` once it is freed.
+    pub fn info(&self) -> Option<AllocationInfo> {
+        self.resource_manager.upgrade()?.allocation_info(self.id)
+    }
+}
+
+/// Allocation owned by several components together. Resources are freed
+/// when the last clone is dropped.
+#[derive(Clone)]
+pub struct SharedAllocation(Arc<ResourceAllocation>);
+
+impl From<ResourceAllocation> for SharedAllocation {
+    fn from(allocation: ResourceAllocation) -> Self {
+        SharedAllocation(Arc::new(allocation))
+    }
+}
+
+impl std::ops::Deref for SharedAllocation {
+    type Target = ResourceAllocation;
+
+    fn deref(&self) -> &ResourceAllocation {
+        &self.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResourceKind {
+    Mem,
+    Cpus,
+    Gpus,
+    GpuMem,
+    Disk,
+    Net,
+}
+
+impl ResourceKind {
+    pub const ALL: [ResourceKind; 6] = [
+        ResourceKind::Mem,
+        ResourceKind::Cpus,
+        ResourceKind::Gpus,
+        ResourceKind::GpuMem,
+        ResourceKind::Disk,
+        ResourceKind::Net,
+    ];
+
+    /// Name of the resource as a metric label.
+    pub fn label(&self) -> &'static str {
+        match self {
+            ResourceKind::Mem => "mem",
+            ResourceKind::Cpus => "cpus",
+            ResourceKind::Gpus => "gpus",
+            ResourceKind::GpuMem => "gpu_mem",
+            ResourceKind::Disk => "disk",
+            ResourceKind::Net => "net",
+        }
+    }
+
+    /// Returns the amount of this resource in `request`.
+    pub fn requested(&self, request: &ResourceRequest) -> u64 {
+        match self {
+            ResourceKind::Mem => request.mem,
+            ResourceKind::Cpus => request.cpus,
+            ResourceKind::Gpus => request.gpus,
+            ResourceKind::GpuMem => request.gpu_mem,
+            ResourceKind::Disk => request.disk_bytes,
+            ResourceKind::Net => request.net_bps,
+        }
+    }
+}
+
+impl fmt::Display for ResourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResourceKind::Mem => write!(f, "mem"),
+            ResourceKind::Cpus => write!(f, "cpus"),
+            ResourceKind::Gpus => write!(f, "gpus"),
+            ResourceKind::GpuMem => write!(f, "gpu mem"),
+            ResourceKind::Disk => write!(f, "disk"),
+            ResourceKind::Net => write!(f, "net"),
+        }
     }
 }
 
 #[allow(clippy::enum_variant_names)]
 #[derive(Error, Debug)]
 pub enum ResourceError {
-    #[error("not enough resources: {0}")]
-    NotEnoughResources(String),
+    #[error(
+        "not enough {kind}: requested {requested}, available {available}{}",
+        also_short(.deficits)
+    )]
+    NotEnoughResources {
+        kind: ResourceKind,
+        requested: u64,
+        available: u64,
+        /// Every resource short, starting with `kind`. Only `kind` unless
+        /// the manager reports all deficits, see
+        /// `ResourceManager::with_report_all_deficits()`.
+        deficits: Vec<ResourceKind>,
+        /// Estimate of when to retry, see `ResourceError::retry_after()`.
+        retry_after: Option<Duration>,
+    },
+    #[error("{kind} request of {requested} exceeds node capacity of {capacity}")]
+    ExceedsCapacity {
+        kind: ResourceKind,
+        requested: u64,
+        capacity: u64,
+    },
+    #[error("timed out after {0:?} waiting for resources")]
+    Timeout(Duration),
+    #[error("reservation expired")]
+    ReservationExpired,
+    #[error("GPU {0} is busy")]
+    GpuBusy(Uuid),
+    #[error("unknown GPU {0}")]
+    UnknownGpu(Uuid),
+    #[error("unknown resource pool {0:?}")]
+    UnknownPool(String),
+    #[error("node under {0} resource pressure")]
+    UnderPressure(ResourcePressure),
+    #[error("node is draining")]
+    Draining,
+    #[error("{kind} quota exceeded: requested {requested}, remaining {remaining}")]
+    QuotaExceeded {
+        kind: ResourceKind,
+        requested: u64,
+        remaining: u64,
+    },
+    #[error("GPUs are disabled on this node")]
+    GpuDisabled,
+    #[error("lease expired")]
+    LeaseExpired,
+    #[error("heartbeats stopped")]
+    HeartbeatLost,
+    #[error("allocation cancelled")]
+    Cancelled,
+    #[error(
+        "no NUMA node can host {} CPUs and {} MEM: largest allocatable is {} CPUs and {} MEM",
+        .requested.cpus,
+        .requested.mem,
+        .largest.cpus,
+        .largest.mem
+    )]
+    Fragmented {
+        requested: Box<ResourceRequest>,
+        /// What `ResourceManager::largest_allocatable()` reported.
+        largest: Box<ResourceRequest>,
+    },
+    #[error("allocation rate limit exceeded, retry after {retry_after:?}")]
+    RateLimited { retry_after: Duration },
+    #[error(transparent)]
+    InvalidRequest(RequestError),
+    #[error("{kind} total of {total} is below the {allocated} allocated")]
+    BelowAllocated {
+        kind: ResourceKind,
+        total: u64,
+        allocated: u64,
+    },
 }
 
-#[derive(Debug)]
-pub struct ResourceManager {
-    available_mem: u64,
-    available_cpus: u64,
-    available_gpus: u64,
+/// Names the resources short besides the first, for the message of
+/// `ResourceError::NotEnoughResources`.
+fn also_short(deficits: &[ResourceKind]) -> String {
+    match deficits {
+        [] | [_] => String::new(),
+        [_, rest @ ..] => format!(
+            " (also short of {})",
+            rest.iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
 }
 
-impl ResourceManager {
-    pub fn new(total_mem: u64, total_cpus: u64, total_gpus: u64) -> Self {
-        // Set total amount of resources.
-        metrics::CPUS_TOTAL.set(total_cpus as i64);
-        metrics::MEM_TOTAL.set(total_mem as i64);
-        metrics::GPUS_TOTAL.set(total_gpus as i64);
+impl ResourceError {
+    /// Whether retrying the same request later can't succeed, as opposed
+    /// to resources being unavailable just for now.
+    pub fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            ResourceError::ExceedsCapacity { .. }
+                | ResourceError::Draining
+                | ResourceError::GpuDisabled
+                | ResourceError::InvalidRequest(_)
+        )
+    }
 
-        ResourceManager {
-            available_mem: total_mem,
-            available_cpus: total_cpus,
-            available_gpus: total_gpus,
+    /// How long to wait before retrying a request that failed for lack of
+    /// resources, estimated from when the next lease expires, or the
+    /// configured default if no lease is held. `None` when there's no
+    /// estimate, and for permanent errors.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ResourceError::NotEnoughResources { retry_after, .. } => *retry_after,
+            ResourceError::RateLimited { retry_after } => Some(*retry_after),
+            _ => None,
         }
     }
 
-    pub fn try_allocate(
-        resource_manager: Arc<Mutex<Self>>,
-        request: &ResourceRequest,
-    ) -> Result<ResourceAllocation> {
-        let rm = resource_manager.clone();
-        let mut rm = rm.lock().expect("acquire resource manager instance lock");
+    /// Value of the `Retry-After` header to respond with along with the
+    /// status of this error, in whole seconds.
+    pub fn retry_after_header(&self) -> Option<HeaderValue> {
+        let retry_after = match self {
+            ResourceError::Draining => DRAINING_RETRY_AFTER,
+            _ => self.retry_after()?,
+        };
+        let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        Some(HeaderValue::from(secs))
+    }
+}
+
+/// Default of `ResourceManager::with_utilization_ewma_alpha()`.
+const DEFAULT_EWMA_ALPHA: f64 = 0.2;
+
+/// Default of `ResourceManager::with_heartbeat_staleness()`.
+const DEFAULT_HEARTBEAT_STALENESS: Duration = Duration::from_secs(30);
+
+/// Default of `ResourceManager::with_checkpoint_timeout()`.
+const DEFAULT_CHECKPOINT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default of `ResourceManager::with_poll_interval()`.
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long clients are told to wait before retrying on a draining node.
+const DRAINING_RETRY_AFTER: Duration = Duration::from_secs(60);
 
-        if rm.available_mem < request.mem {
-            return Err(ResourceError::NotEnoughResources("memory".to_string()).into());
+impl From<&ResourceError> for StatusCode {
+    fn from(err: &ResourceError) -> Self {
+        match err {
+            ResourceError::NotEnoughResources { .. }
+            | ResourceError::GpuBusy(_)
+            | ResourceError::UnderPressure(_)
+            | ResourceError::Draining
+            | ResourceError::Cancelled
+            | ResourceError::Fragmented { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            ResourceError::ExceedsCapacity { .. }
+            | ResourceError::QuotaExceeded { .. }
+            | ResourceError::UnknownGpu(_)
+            | ResourceError::UnknownPool(_)
+            | ResourceError::GpuDisabled
+            | ResourceError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            ResourceError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ResourceError::BelowAllocated { .. } => StatusCode::CONFLICT,
+            ResourceError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            ResourceError::ReservationExpired
+            | ResourceError::LeaseExpired
+            | ResourceError::HeartbeatLost => StatusCode::GONE,
         }
+    }
+}
+
+/// How close a node is to running out of resources, going by the most
+/// utilized resource kind.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum ResourcePressure {
+    Low,
+    Medium,
+    High,
+}
 
-        if rm.available_cpus < request.cpus {
-            return Err(ResourceError::NotEnoughResources("cpus".to_string()).into());
+impl fmt::Display for ResourcePressure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResourcePressure::Low => write!(f, "low"),
+            ResourcePressure::Medium => write!(f, "medium"),
+            ResourcePressure::High => write!(f, "high"),
         }
+    }
+}
+
+/// Whether a node should be given more work, as reported by
+/// `ResourceManager::health()`. Unlike liveness, this says nothing about
+/// whether the node is working, only whether it has room for more.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum HealthStatus {
+    #[default]
+    Healthy,
+    /// Under high resource pressure, so better to place work elsewhere.
+    Degraded,
+    /// Out of memory or CPUs, or draining, so no work can be placed here.
+    Unhealthy,
+}
 
-        if rm.available_gpus < request.gpus {
-            return Err(ResourceError::NotEnoughResources("gpus".to_string()).into());
+impl fmt::Display for HealthStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HealthStatus::Healthy => write!(f, "healthy"),
+            HealthStatus::Degraded => write!(f, "degraded"),
+            HealthStatus::Unhealthy => write!(f, "unhealthy"),
         }
+    }
+}
 
-        rm.available_mem -= request.mem;
-        rm.available_cpus -= request.cpus;
-        rm.available_gpus -= request.gpus;
+/// Lets a task persist its progress before its allocation is preempted,
+/// see `ResourceAllocation::set_checkpoint()`.
+#[async_trait::async_trait]
+pub trait Checkpoint: Send + Sync {
+    async fn checkpoint(&self) -> Result<()>;
+}
 
-        // Update metrics.
-        metrics::CPUS_AVAILABLE.set(rm.available_cpus as i64);
-        metrics::MEM_AVAILABLE.set(rm.available_mem as i64);
-        metrics::GPUS_AVAILABLE.set(rm.available_gpus as i64);
+/// Outcome of `ResourceManager::try_allocate_preempt()`.
+#[allow(clippy::large_enum_variant)]
+pub enum Preemption {
+    /// Requested resources were available and are now allocated.
+    Allocated(ResourceAllocation),
+    /// Requested resources would become available if the allocations with
+    /// the listed IDs were dropped. It's up to the caller to do so, with
+    /// `ResourceAllocation::preempt()` to let them checkpoint first.
+    Candidates(Vec<u64>),
+}
 
-        Ok(ResourceAllocation {
-            resource_manager: resource_manager.clone(),
-            mem: request.mem,
-            cpus: request.cpus,
-            gpus: request.gpus,
-        })
+/// Live allocation as returned by `ResourceManager::list_allocations()`.
+#[derive(Clone, Debug, Serialize)]
+pub struct AllocationInfo {
+    pub id: u64,
+    pub program_id: Option<Hash>,
+    pub task_id: Option<TaskId>,
+    pub account: Option<PublicKey>,
+    pub mem: u64,
+    pub cpus: u64,
+    pub gpus: u64,
+    /// Time since the allocation was made.
+    pub age: Duration,
+}
+
+/// Callback run as allocations are freed, see `ResourceManager::on_free()`.
+pub type OnFree = Arc<dyn Fn(&AllocationInfo) + Send + Sync>;
+
+// Callbacks registered with `ResourceManager::on_free()`.
+#[derive(Default)]
+struct OnFreeCallbacks(RwLock<Vec<OnFree>>);
+
+impl fmt::Debug for OnFreeCallbacks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} OnFree callbacks", self.0.read().len())
     }
+}
+
+// Whose allocations are being made: the system reserve is left alone by
+// user allocations.
+#[derive(Clone, Copy, Debug)]
+enum Tier {
+    User,
+    System,
+}
 
-    pub(self) fn free(&mut self, allocation: &ResourceAllocation) {
-        self.available_mem += allocation.mem;
-        self.available_cpus += allocation.cpus;
-        self.available_gpus += allocation.gpus;
+// Registry entry of a live allocation.
+#[derive(Debug)]
+struct AllocationEntry {
+    request: ResourceRequest,
+    program_id: Option<Hash>,
+    task_id: Option<TaskId>,
+    account: Option<PublicKey>,
+    created_at: tokio::time::Instant,
+}
 
-        // Update metrics.
-        metrics::CPUS_AVAILABLE.set(self.available_cpus as i64);
-        metrics::MEM_AVAILABLE.set(self.available_mem as i64);
-        metrics::GPUS_AVAILABLE.set(self.available_gpus as i64);
+impl AllocationEntry {
+    fn info(&self, id: u64, now: tokio::time::Instant) -> AllocationInfo {
+        AllocationInfo {
+            id,
+            program_id: self.program_id,
+            task_id: self.task_id,
+            account: self.account.clone(),
+            mem: self.request.mem,
+            cpus: self.request.cpus,
+            gpus: self.request.gpus,
+            age: now.saturating_duration_since(self.created_at),
+        }
     }
 }
 
-pub fn get_configured_resources(config: &crate::cli::Config) -> (u64, u64, u64) {
-    let sys = System::new();
-    let num_gpus = if config.gpu_devices.is_some() { 1 } else { 0 };
-    let num_cpus = match config.num_cpus {
-        Some(cpus) => cpus,
-        None => num_cpus::get() as u64,
-    };
-    let available_mem = match config.mem_gb {
-        Some(mem_gb) => mem_gb * 1024 * 1024 * 1024,
-        None => {
-            let mem = sys
-                .memory()
-                .expect("failed to lookup available system memory");
-            mem.total.as_u64()
+/// Point in time view of the resources managed by a `ResourceManager`.
+/// Utilization is given in percent of the total.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ResourceSnapshot {
+    pub total_mem: u64,
+    pub available_mem: u64,
+    pub mem_utilization: f64,
+    pub total_cpus: u64,
+    pub available_cpus: u64,
+    pub cpus_utilization: f64,
+    pub total_gpus: u64,
+    pub available_gpus: u64,
+    pub gpus_utilization: f64,
+}
+
+impl ResourceSnapshot {
+    /// What changed from this snapshot to `other`, taken later: each field
+    /// of the delta is the one of `other` minus the one of this snapshot.
+    pub fn diff(&self, other: &Self) -> ResourceDelta {
+        ResourceDelta {
+            total_mem: signed_delta(self.total_mem, other.total_mem),
+            available_mem: signed_delta(self.available_mem, other.available_mem),
+            mem_utilization: other.mem_utilization - self.mem_utilization,
+            total_cpus: signed_delta(self.total_cpus, other.total_cpus),
+            available_cpus: signed_delta(self.available_cpus, other.available_cpus),
+            cpus_utilization: other.cpus_utilization - self.cpus_utilization,
+            total_gpus: signed_delta(self.total_gpus, other.total_gpus),
+            available_gpus: signed_delta(self.available_gpus, other.available_gpus),
+            gpus_utilization: other.gpus_utilization - self.gpus_utilization,
         }
-    };
+    }
 
-    (num_cpus, available_mem, num_gpus)
+    /// Scores how much capacity would be left free after placing `request`:
+    /// the sum of the squared shares of memory, CPUs and GPUs left free,
+    /// each multiplied by its weight. Resources not managed don't count.
+    pub fn score(&self, request: &ResourceRequest, weights: &ResourceWeights) -> f64 {
+        [
+            (weights.mem, request.mem, self.available_mem, self.total_mem),
+            (
+                weights.cpus,
+                request.cpus,
+                self.available_cpus,
+                self.total_cpus,
+            ),
+            (
+                weights.gpus,
+                request.gpus,
+                self.available_gpus,
+                self.total_gpus,
+            ),
+        ]
+        .into_iter()
+        .filter(|(_, _, _, total)| *total > 0)
+        .map(|(weight, requested, available, total)| {
+            let free = available.saturating_sub(requested) as f64 / total as f64;
+            weight * free * free
+        })
+        .sum()
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Change between two `ResourceSnapshot`s, see `ResourceSnapshot::diff()`.
+/// Utilization deltas are in percentage points.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct ResourceDelta {
+    pub total_mem: i64,
+    pub available_mem: i64,
+    pub mem_utilization: f64,
+    pub total_cpus: i64,
+    pub available_cpus: i64,
+    pub cpus_utilization: f64,
+    pub total_gpus: i64,
+    pub available_gpus: i64,
+    pub gpus_utilization: f64,
+}
 
-    #[test]
-    fn test_try_allocate_succeeds() {
-        let rm = Arc::new(Mutex::new(ResourceManager::new(2048, 4, 0)));
+/// Returns `to - from`, saturating at the bounds of `i64`.
+fn signed_delta(from: u64, to: u64) -> i64 {
+    if to >= from {
+        i64::try_from(to - from).unwrap_or(i64::MAX)
+    } else {
+        i64::try_from(from - to).map_or(i64::MIN, |delta| -delta)
+    }
+}
 
-        let req = &ResourceRequest {
-            mem: 1024,
-            cpus: 1,
-            gpus: 0,
+/// Keeps track of the resources available for running programs.
+///
+/// Available amounts are atomic counters, so that allocating and freeing
+/// don't serialize behind a lock. A request is checked and taken one
+/// resource kind at a time, in the order of `ResourceKind::ALL`, with a
+/// compare-and-swap loop per kind. If any kind falls short, the kinds taken
+/// so far are given back and the request fails. Counters therefore never
+/// over-allocate, but a request racing with another one may fail while
+/// the other one is rolled back, even though there would have been room
+/// for it.
+///
+/// Only the registry of live allocations is behind a lock. Allocating and
+/// freeing take it for writing, briefly, to insert or remove an entry.
+/// Monitoring reads such as `list_allocations()` only take it for reading,
+/// so they don't block each other.
+#[derive(Debug)]
+pub struct ResourceManager {
+    // Totals may change at runtime, see `set_total()`.
+    total_mem: AtomicU64,
+    total_cpus: AtomicU64,
+    total_gpus: AtomicU64,
+    total_disk: AtomicU64,
+    total_gpu_mem: AtomicU64,
+    total_net: AtomicU64,
+
+    // Amount of memory that can be handed out, including overcommit.
+    mem_limit: AtomicU64,
+    // Amount of memory that can be handed out right now. Lowered from
+    // `mem_limit` when the system runs low on memory.
+    mem_ceiling: AtomicU64,
+
+    available_mem: AtomicU64,
+    available_cpus: AtomicU64,
+    available_gpus: AtomicU64,
+    available_disk: AtomicU64,
+    available_gpu_mem: AtomicU64,
+    available_net: AtomicU64,
+
+    // Outstanding allocations by ID, along with what they requested.
+    allocations: RwLock<HashMap<u64, AllocationEntry>>,
+    // ID of the next allocation made. IDs only go up, so are never reused
+    // and can correlate logs of an allocation across its lifetime.
+    next_allocation_id: AtomicU64,
+
+    // Indices of GPU devices not assigned to any allocation. There are
+    // always at least as many as `available_gpus` says.
+    free_gpus: Mutex<BTreeSet<u32>>,
+    // UUIDs of GPU devices by index, if known.
+    gpu_uuids: Vec<Uuid>,
+    // Ids of CPU cores not assigned to any allocation. There are always
+    // at least as many as whole CPUs available.
+    free_cores: Mutex<BTreeSet<usize>>,
+    // Free CPUs and memory on each NUMA node, when placement on NUMA nodes
+    // is tracked.
+    numa: Option<Mutex<NumaPools>>,
+    // Whether `NotEnoughResources` lists every resource short rather than
+    // the first found.
+    report_all_deficits: bool,
+
+    // A permit for each millicore, for `allocate_with_cpu_slots()`.
+    cpu_slots: Arc<Semaphore>,
+
+    // Requests waiting in `allocate()`, by the order they started waiting.
+    // The first one that fits is woken up whenever resources are freed.
+    waiters: Mutex<BTreeMap<u64, Waiter>>,
+    next_waiter_id: AtomicU64,
+    // Priority gained per second by requests waiting in `allocate()`.
+    aging_rate: f64,
+    // Interval at which requests waiting in `allocate()` check for free
+    // resources, in case a wakeup is lost.
+    poll_interval: Duration,
+    // Most memory, CPUs and GPUs held at once since start or
+    // `reset_peaks()`.
+    peaks: Peaks,
+    // Moving averages of memory and CPU utilization, from the first
+    // allocation or free on.
+    utilization_ewma: UtilizationEwma,
+    // Resources only `try_allocate_system()` may allocate.
+    system_reserve: ResourceRequest,
+    // Retry hint for `NotEnoughResources` when no lease is held.
+    default_retry_after: Option<Duration>,
+    // Latest snapshot, for `subscribe()`.
+    changes: watch::Sender<ResourceSnapshot>,
+
+    // Utilization percentages at which pressure turns medium and high.
+    medium_pressure: u8,
+    high_pressure: u8,
+
+    // Set while the node is draining; no new allocations are made.
+    draining: AtomicBool,
+
+    // Most each account may hold at once, and what it holds.
+    quotas: AccountQuotas,
+    // Allocations each account may still make.
+    rate_limiter: RateLimiter,
+
+    // Where the consumption of dropped allocations is reported.
+    billing: Arc<dyn BillingSink>,
+    // Run for every allocation freed.
+    on_free: OnFreeCallbacks,
+
+    // Name of the pool in a `ResourceRegistry`, to label metrics with.
+    pool: Option<String>,
+
+    // Set when GPU requests are rejected outright.
+    gpu_disabled: bool,
+
+    // Where timestamps are taken from.
+    clock: Arc<dyn Clock>,
+
+    // Outstanding leases and allocations kept alive by heartbeats.
+    leases: Arc<LeaseRegistry>,
+    // How long an allocation may go without a heartbeat before it's reaped.
+    heartbeat_staleness: Duration,
+    // How long preemption waits for a checkpoint.
+    checkpoint_timeout: Duration,
+    // Number of live allocations by affinity and anti-affinity key.
+    affinity: Mutex<HashMap<AffinityKey, usize>>,
+    anti_affinity: Mutex<HashMap<AffinityKey, usize>>,
+}
+
+impl ResourceManager {
+    pub fn new(resources: DetectedResources) -> Self {
+        let DetectedResources {
+            mem: total_mem,
+            cpus: total_cpus,
+            gpus: total_gpus,
+            disk: total_disk,
+            gpu_mem: total_gpu_mem,
+            net: total_net,
+        } = resources;
+
+        // Set total amount of resources.
+        set_total_metrics(resources, total_mem);
+
+        let rm = ResourceManager {
+            total_mem: AtomicU64::new(total_mem),
+            total_cpus: AtomicU64::new(total_cpus),
+            total_gpus: AtomicU64::new(total_gpus),
+            total_disk: AtomicU64::new(total_disk),
+            total_gpu_mem: AtomicU64::new(total_gpu_mem),
+            total_net: AtomicU64::new(total_net),
+
+            mem_limit: AtomicU64::new(total_mem),
+            mem_ceiling: AtomicU64::new(total_mem),
+
+            available_mem: AtomicU64::new(total_mem),
+            available_cpus: AtomicU64::new(total_cpus),
+            available_gpus: AtomicU64::new(total_gpus),
+            available_disk: AtomicU64::new(total_disk),
+            available_gpu_mem: AtomicU64::new(total_gpu_mem),
+            available_net: AtomicU64::new(total_net),
+
+            allocations: RwLock::new(HashMap::new()),
+            next_allocation_id: AtomicU64::new(0),
+
+            free_gpus: Mutex::new((0..total_gpus as u32).collect()),
+            gpu_uuids: vec![],
+            free_cores: Mutex::new((0..whole_cpus(total_cpus)).collect()),
+            numa: None,
+            report_all_deficits: false,
+
+            cpu_slots: Arc::new(Semaphore::new(
+                (total_cpus as usize).min(Semaphore::MAX_PERMITS),
+            )),
+
+            waiters: Mutex::new(BTreeMap::new()),
+            next_waiter_id: AtomicU64::new(0),
+            aging_rate: 0.0,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            peaks: Peaks::default(),
+            utilization_ewma: UtilizationEwma::new(DEFAULT_EWMA_ALPHA),
+            system_reserve: zero_request(),
+            default_retry_after: None,
+            changes: watch::Sender::new(ResourceSnapshot::default()),
+
+            medium_pressure: 70,
+            high_pressure: 90,
+
+            draining: AtomicBool::new(false),
+
+            quotas: AccountQuotas::default(),
+            rate_limiter: RateLimiter::default(),
+
+            billing: Arc::new(NoopBillingSink),
+            on_free: OnFreeCallbacks::default(),
+
+            pool: None,
+
+            gpu_disabled: false,
+
+            clock: Arc::new(SystemClock),
+
+            leases: Arc::default(),
+            affinity: Mutex::new(HashMap::new()),
+            anti_affinity: Mutex::new(HashMap::new()),
+            heartbeat_staleness: DEFAULT_HEARTBEAT_STALENESS,
+            checkpoint_timeout: DEFAULT_CHECKPOINT_TIMEOUT,
         };
+        rm.changes.send_replace(rm.snapshot());
+        rm
+    }
 
-        ResourceManager::try_allocate(rm.clone(), req).unwrap();
-        ResourceManager::try_allocate(rm.clone(), req).unwrap();
+    /// Creates a resource manager for the node, with the resources detected
+    /// on this host and the limits given in `config`.
+    pub fn from_config(config: &crate::cli::Config) -> Result<Arc<Self>> {
+        Self::from_system_config(config, &HostSystem::new())
     }
 
-    #[test]
-    fn test_free_succeeds() {
-        let rm = Arc::new(Mutex::new(ResourceManager::new(2048, 4, 0)));
+    fn from_system_config(config: &crate::cli::Config, sys: &impl SystemInfo) -> Result<Arc<Self>> {
+        let resources = get_configured_resources(config, sys)?;
 
-        let req = &ResourceRequest {
-            mem: 2048,
-            cpus: 4,
-            gpus: 0,
+        tracing::info!(
+            "node configured with {} CPUs, {} MEM, {} GPUs ({} GPU MEM), {} DISK and {} Mbps NET",
+            cores(resources.cpus),
+            ByteSize(resources.mem * BYTES_PER_MIB).to_string_as(true),
+            resources.gpus,
+            ByteSize(resources.gpu_mem).to_string_as(true),
+            ByteSize(resources.disk).to_string_as(true),
+            resources.net / 1_000_000
+        );
+
+        let mut resource_manager = ResourceManager::new(resources)
+            .with_mem_overcommit(config.overcommit_mem)
+            .with_gpu_uuids(config.gpu_uuids.clone())
+            .with_gpu_disabled(config.disable_gpu)
+            .with_billing_sink(Arc::new(MetricsBillingSink))
+            .with_pressure_thresholds(config.medium_pressure_percent, config.high_pressure_percent)
+            .with_aging_rate(config.aging_rate)
+            .with_poll_interval(Duration::from_millis(config.allocation_poll_interval_ms))
+            .with_default_retry_after(Duration::from_millis(config.default_retry_after_ms))
+            .with_heartbeat_staleness(Duration::from_secs(config.heartbeat_staleness_secs))
+            .with_checkpoint_timeout(Duration::from_secs(config.checkpoint_timeout_secs))
+            .with_system_reserve(config.reserve_system)
+            .with_utilization_ewma_alpha(config.utilization_ewma_alpha)
+            .with_report_all_deficits(config.report_all_deficits);
+        if let Some(limit) = config.allocation_rate_limit {
+            resource_manager = resource_manager.with_rate_limit(
+                limit,
+                Duration::from_secs(config.allocation_rate_window_secs),
+            );
+        }
+        if let Some(ids) = online_cpus(Path::new(SYSFS_CPU_ONLINE)) {
+            resource_manager = resource_manager.with_cpu_cores(ids);
+        }
+        if config.numa_aware {
+            match detect_numa_topology(Path::new(SYSFS_NUMA_NODES)) {
+                Some(nodes) => {
+                    resource_manager = resource_manager
+                        .with_numa_topology(nodes)
+                        .with_strict_numa(config.numa_strict)
+                }
+                None => tracing::info!("no NUMA nodes to place allocations on"),
+            }
+        }
+
+        Ok(Arc::new(resource_manager))
+    }
+
+    /// Allows allocating `ratio` times the physical memory, replacing any
+    /// ratio set before. Ratios below 1.0, or not finite, are ignored, as
+    /// overcommit is never used to reduce the capacity.
+    pub fn with_mem_overcommit(mut self, ratio: f64) -> Self {
+        let ratio = if !ratio.is_finite() || ratio < 1.0 {
+            tracing::warn!(
+                "ignoring memory overcommit ratio {}, not at least 1.0",
+                ratio
+            );
+            1.0
+        } else {
+            ratio
         };
 
-        // Allocate all available resources.
-        let ra = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+        // Float to integer conversion saturates on overflow.
+        let mem_limit = (*self.total_mem.get_mut() as f64 * ratio) as u64;
+        let old_limit = *self.mem_limit.get_mut();
+        if mem_limit >= old_limit {
+            let raise = mem_limit - old_limit;
+            *self.available_mem.get_mut() = self.available_mem.get_mut().saturating_add(raise);
+            *self.mem_ceiling.get_mut() = self.mem_ceiling.get_mut().saturating_add(raise);
+        } else {
+            let lower = old_limit - mem_limit;
+            *self.available_mem.get_mut() = self.available_mem.get_mut().saturating_sub(lower);
+            *self.mem_ceiling.get_mut() = self.mem_ceiling.get_mut().saturating_sub(lower);
+        }
+        *self.mem_limit.get_mut() = mem_limit;
 
-        // Assert that we are out of resources.
-        let ra2 = ResourceManager::try_allocate(rm.clone(), req);
-        assert!(ra2.is_err());
+        metrics::MEM_OVERCOMMIT_TOTAL.set(gauge_value(mem_limit));
+        self.publish_changes();
 
-        drop(ra);
+        self
+    }
 
-        // Allocate again all available resources.
-        ResourceManager::try_allocate(rm.clone(), req).unwrap();
+    /// Limits the resources each listed account may hold at once, across
+    /// all of its allocations. Accounts not listed are not limited.
+    pub fn with_account_quotas(mut self, quotas: HashMap<PublicKey, ResourceRequest>) -> Self {
+        self.quotas = AccountQuotas::new(quotas);
+        self
     }
 
-    #[test]
-    fn test_try_allocate_fails_on_mem() {
-        let rm = Arc::new(Mutex::new(ResourceManager::new(2048, 4, 0)));
-        let req = &ResourceRequest {
-            mem: 4096,
-            cpus: 2,
-            gpus: 0,
-        };
+    /// Allows each account at most `allocations` attempts per `window`,
+    /// failing further attempts with `ResourceError::RateLimited`. Failed
+    /// attempts count too, so that flooding with requests that can't be
+    /// served is held back as well. Allocations without an account are not
+    /// limited. Zero limits or windows are ignored.
+    pub fn with_rate_limit(mut self, allocations: u32, window: Duration) -> Self {
+        if allocations == 0 || window.is_zero() {
+            tracing::warn!(
+                "ignoring allocation rate limit of {} per {:?}",
+                allocations,
+                window
+            );
+        } else {
+            self.rate_limiter = RateLimiter::new(allocations, window);
+        }
+        self
+    }
 
-        let ra = ResourceManager::try_allocate(rm, req);
-        assert!(ra.is_err());
+    /// Names the pool this manager is for. Its metrics are then reported
+    /// labeled with the pool name, instead of as the node totals.
+    pub(super) fn with_pool_name(mut self, name: String) -> Self {
+        for (kind, total) in ResourceKind::ALL.map(|kind| (kind, self.capacity(kind))) {
+            metrics::POOL_TOTAL
+                .with_label_values(&[&name, kind.label()])
+                .set(metric_value(kind, total));
+        }
+        self.pool = Some(name);
+        self.publish_changes();
+        self
     }
 
-    #[test]
-    fn test_try_allocate_fails_on_cpus() {
-        let rm = Arc::new(Mutex::new(ResourceManager::new(2048, 4, 0)));
-        let req = &ResourceRequest {
-            mem: 1024,
-            cpus: 8,
-            gpus: 0,
-        };
+    /// Takes timestamps for allocation lifetimes, waiting times and
+    /// reservation expiry from `clock` instead of the Tokio runtime.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
 
-        let ra = ResourceManager::try_allocate(rm, req);
-        assert!(ra.is_err());
+    /// Reports the resources consumed by each allocation to `sink` when the
+    /// allocation is dropped. Consumption is discarded by default.
+    pub fn with_billing_sink(mut self, sink: Arc<dyn BillingSink>) -> Self {
+        self.billing = sink;
+        self
+    }
+
+    /// Registers `callback` to be run with every allocation freed, once the
+    /// resources it held are available again. Callbacks run on the thread
+    /// freeing the allocation, with no locks of the manager held, so they
+    /// may call back into it. They should be quick, as freeing waits for
+    /// them.
+    pub fn on_free(&self, callback: impl Fn(&AllocationInfo) + Send + Sync + 'static) {
+        self.on_free.0.write().push(Arc::new(callback));
+    }
+
+    /// Sets the utilization percentages at which `pressure()` turns medium
+    /// and high. A medium threshold above the high one is lowered to it.
+    pub fn with_pressure_thresholds(mut self, medium: u8, high: u8) -> Self {
+        let high = high.min(100);
+        let medium = if medium > high {
+            tracing::warn!(
+                "lowering medium pressure threshold {}% to high threshold {}%",
+                medium,
+                high
+            );
+            high
+        } else {
+            medium
+        };
+
+        self.medium_pressure = medium;
+        self.high_pressure = high;
+        self
+    }
+
+    /// Raises the priority of requests waiting in `allocate()` by `rate` per
+    /// second of waiting, so that a stream of higher priority requests can't
+    /// keep them waiting forever. Waiters are not aged by default.
+    pub fn with_aging_rate(mut self, rate: f64) -> Self {
+        self.aging_rate = if rate >= 0.0 {
+            rate
+        } else {
+            tracing::warn!("ignoring negative aging rate {}", rate);
+            0.0
+        };
+        self
+    }
+
+    /// Has requests waiting in `allocate()` check for free resources every
+    /// `interval`, besides when woken up as resources are freed, so that a
+    /// lost wakeup can't leave them waiting. Zero intervals are ignored.
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        if interval.is_zero() {
+            tracing::warn!("ignoring zero allocation poll interval");
+        } else {
+            self.poll_interval = interval;
+        }
+        self
+    }
+
+    /// Weighs each new utilization by `alpha` in the moving averages of
+    /// utilization, and what came before by `1 - alpha`. Lower values give
+    /// smoother averages. Values outside (0, 1] are ignored.
+    pub fn with_utilization_ewma_alpha(mut self, alpha: f64) -> Self {
+        if alpha > 0.0 && alpha <= 1.0 {
+            self.utilization_ewma.alpha = alpha;
+        } else {
+            tracing::warn!("ignoring utilization EWMA smoothing factor {}", alpha);
+        }
+        self
+    }
+
+    /// Sets aside `reserve` for the node's own tasks, allocated with
+    /// `try_allocate_system()`. Other allocations only get what's left
+    /// above it.
+    pub fn with_system_reserve(mut self, reserve: ResourceRequest) -> Self {
+        for kind in ResourceKind::ALL {
+            if kind.requested(&reserve) > self.capacity(kind) {
+                tracing::warn!(
+                    "system reserve of {} {} exceeds node capacity of {}",
+                    kind.requested(&reserve),
+                    kind,
+                    self.capacity(kind)
+                );
+            }
+        }
+        self.system_reserve = reserve;
+        self
+    }
+
+    /// Suggests retrying after `retry_after` when an allocation fails for
+    /// lack of resources and no lease is held whose expiry would free some.
+    pub fn with_default_retry_after(mut self, retry_after: Duration) -> Self {
+        self.default_retry_after = Some(retry_after);
+        self
+    }
+
+    /// Changes the totals of all resources to `resources`, such as after
+    /// memory was hot-added to the VM the node runs in. What is available
+    /// changes by as much as the total, and the memory overcommit ratio is
+    /// kept. Fails with `ResourceError::BelowAllocated`, changing nothing,
+    /// if a total would drop below what is allocated of it. CPU core ids
+    /// and NUMA nodes are left as they are.
+    pub fn set_total(
+        &self,
+        resources: DetectedResources,
+    ) -> std::result::Result<(), ResourceError> {
+        // Held throughout, so that changes of the totals don't interleave.
+        let mut free_gpus = self.free_gpus.lock();
+
+        let (old_total_mem, old_mem_limit) = (
+            self.total_mem.load(Ordering::SeqCst),
+            self.mem_limit.load(Ordering::SeqCst),
+        );
+        let overcommit = match old_total_mem {
+            0 => 1.0,
+            total => old_mem_limit as f64 / total as f64,
+        };
+        let mem_limit = (resources.mem as f64 * overcommit) as u64;
+        let new_total = |kind| match kind {
+            ResourceKind::Mem => mem_limit,
+            ResourceKind::Cpus => resources.cpus,
+            ResourceKind::Gpus => resources.gpus,
+            ResourceKind::GpuMem => resources.gpu_mem,
+            ResourceKind::Disk => resources.disk,
+            ResourceKind::Net => resources.net,
+        };
+        let old_totals = ResourceKind::ALL.map(|kind| match kind {
+            ResourceKind::Mem => old_mem_limit,
+            kind => self.capacity(kind),
+        });
+        let old_total = |kind: ResourceKind| old_totals[kind as usize];
+
+        // GPUs removed must be free, as their indices are given out.
+        let old_gpus = old_total(ResourceKind::Gpus);
+        let in_use = (resources.gpus as u32..old_gpus as u32)
+            .filter(|index| !free_gpus.contains(index))
+            .max();
+        if let Some(index) = in_use {
+            return Err(ResourceError::BelowAllocated {
+                kind: ResourceKind::Gpus,
+                total: resources.gpus,
+                allocated: u64::from(index) + 1,
+            });
+        }
+
+        // Available amounts change by as much as the totals. `free()`
+        // checks available amounts against the totals, so the totals are
+        // never below what is available: they're lowered after available
+        // amounts, and raised before. Shrinking fails if less is available
+        // than is removed, in which case the kinds shrunk so far are
+        // changed back. Growing can't fail, so it's done last.
+        let store_total = |kind, total: u64| match kind {
+            ResourceKind::Mem => {
+                let old = self.mem_limit.swap(total, Ordering::SeqCst);
+                if total >= old {
+                    self.mem_ceiling.fetch_add(total - old, Ordering::SeqCst);
+                } else {
+                    self.mem_ceiling.fetch_sub(old - total, Ordering::SeqCst);
+                }
+            }
+            ResourceKind::Cpus => self.total_cpus.store(total, Ordering::SeqCst),
+            ResourceKind::Gpus => self.total_gpus.store(total, Ordering::SeqCst),
+            ResourceKind::GpuMem => self.total_gpu_mem.store(total, Ordering::SeqCst),
+            ResourceKind::Disk => self.total_disk.store(total, Ordering::SeqCst),
+            ResourceKind::Net => self.total_net.store(total, Ordering::SeqCst),
+        };
+        let mut shrunk = vec![];
+        for kind in ResourceKind::ALL {
+            let (old, new) = (old_total(kind), new_total(kind));
+            if new >= old {
+                continue;
+            }
+            let counter = self.available_counter(kind);
+            if let Err(available) =
+                counter.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |available| {
+                    available.checked_sub(old - new)
+                })
+            {
+                let allocated = self.capacity(kind).saturating_sub(available);
+                for kind in shrunk {
+                    let (old, new) = (old_total(kind), new_total(kind));
+                    store_total(kind, old);
+                    self.available_counter(kind)
+                        .fetch_add(old - new, Ordering::SeqCst);
+                }
+                return Err(ResourceError::BelowAllocated {
+                    kind,
+                    total: new,
+                    allocated,
+                });
+            }
+            store_total(kind, new);
+            shrunk.push(kind);
+        }
+        for kind in ResourceKind::ALL {
+            let (old, new) = (old_total(kind), new_total(kind));
+            if new > old {
+                store_total(kind, new);
+                self.available_counter(kind)
+                    .fetch_add(new - old, Ordering::SeqCst);
+            }
+        }
+
+        free_gpus.retain(|index| u64::from(*index) < resources.gpus);
+        free_gpus.extend(old_gpus as u32..resources.gpus as u32);
+        let slots = |cpus: u64| (cpus as usize).min(Semaphore::MAX_PERMITS);
+        let (old_slots, new_slots) = (slots(old_total(ResourceKind::Cpus)), slots(resources.cpus));
+        if new_slots > old_slots {
+            self.cpu_slots.add_permits(new_slots - old_slots);
+        } else if let Ok(permits) = self
+            .cpu_slots
+            .try_acquire_many((old_slots - new_slots) as u32)
+        {
+            permits.forget();
+        } else {
+            tracing::warn!(
+                "CPU slots held beyond the new total of {} CPUs",
+                resources.cpus
+            );
+        }
+
+        self.total_mem.store(resources.mem, Ordering::SeqCst);
+        drop(free_gpus);
+        tracing::info!(?resources, "resource totals changed");
+
+        if self.pool.is_none() {
+            set_total_metrics(resources, mem_limit);
+        }
+        self.publish_changes();
+        Ok(())
+    }
+
+    /// Reaps allocations made by `try_allocate_heartbeat()` once they go
+    /// `staleness` without a heartbeat.
+    pub fn with_heartbeat_staleness(mut self, staleness: Duration) -> Self {
+        self.heartbeat_staleness = staleness;
+        self
+    }
+
+    /// Limits how long `ResourceAllocation::preempt()` waits for the
+    /// checkpoint of an allocation before freeing it.
+    pub fn with_checkpoint_timeout(mut self, timeout: Duration) -> Self {
+        self.checkpoint_timeout = timeout;
+        self
+    }
+
+    /// Adjusts the memory that can be handed out to what is free on the
+    /// system right now, up to the configured limit. Meant to be called
+    /// periodically.
+    pub fn refresh_from_system(&self, sys: &impl SystemInfo) {
+        match sys.free_memory() {
+            Some(free) => self.set_free_system_mem(free / BYTES_PER_MIB),
+            None => tracing::warn!("failed to lookup free system memory"),
+        }
+    }
+
+    /// Sets the memory ceiling to what's allocated plus `free` (in MiB) on
+    /// the system. The ceiling is never lowered below what's already
+    /// allocated; available memory bottoms out at zero instead.
+    fn set_free_system_mem(&self, free: u64) {
+        let ceiling = self.mem_ceiling.load(Ordering::SeqCst);
+        let allocated = ceiling.saturating_sub(self.available(ResourceKind::Mem));
+        let target = allocated
+            .saturating_add(free)
+            .min(self.mem_limit.load(Ordering::SeqCst));
+
+        if target > ceiling {
+            let raise = target - ceiling;
+            self.mem_ceiling.fetch_add(raise, Ordering::SeqCst);
+            self.available_mem.fetch_add(raise, Ordering::SeqCst);
+        } else if target < ceiling {
+            let lower = ceiling - target;
+            let available = self
+                .available_mem
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |available| {
+                    Some(available.saturating_sub(lower))
+                })
+                .unwrap_or_default();
+            self.mem_ceiling
+                .fetch_sub(lower.min(available), Ordering::SeqCst);
+        }
+
+        self.publish_changes();
+    }
+
+    /// With `disabled`, rejects all requests for GPUs or GPU memory with
+    /// `ResourceError::GpuDisabled`, rather than having them wait for GPUs
+    /// that never come.
+    pub fn with_gpu_disabled(mut self, disabled: bool) -> Self {
+        self.gpu_disabled = disabled;
+        self
+    }
+
+    /// Sets the UUIDs of GPU devices, by device index, so that requests can
+    /// be pinned to a specific device.
+    pub fn with_gpu_uuids(mut self, uuids: Vec<Uuid>) -> Self {
+        if !uuids.is_empty() && uuids.len() as u64 != self.total_gpus.load(Ordering::SeqCst) {
+            tracing::warn!(
+                "{} GPU UUIDs given for {} GPUs",
+                uuids.len(),
+                self.total_gpus.load(Ordering::SeqCst)
+            );
+        }
+        self.gpu_uuids = uuids;
+        self
+    }
+
+    /// Places the CPUs and memory of allocations on the NUMA `nodes`,
+    /// keeping each allocation on a single node when one has room for it.
+    /// Where allocations are placed is reported by
+    /// `ResourceAllocation::numa_node()`.
+    pub fn with_numa_topology(mut self, nodes: Vec<NumaNode>) -> Self {
+        let (cpus, mem) = nodes.iter().fold((0, 0), |(cpus, mem), node| {
+            (cpus + node.cpus, mem + node.mem)
+        });
+        if cpus < self.total_cpus.load(Ordering::SeqCst)
+            || mem < self.total_mem.load(Ordering::SeqCst)
+        {
+            tracing::warn!(
+                "NUMA nodes have {} CPUs and {} MEM of the node's {} CPUs and {} MEM",
+                cores(cpus),
+                ByteSize(mem * BYTES_PER_MIB).to_string_as(true),
+                cores(self.total_cpus.load(Ordering::SeqCst)),
+                ByteSize(self.total_mem.load(Ordering::SeqCst) * BYTES_PER_MIB).to_string_as(true)
+            );
+        }
+        self.numa = (!nodes.is_empty()).then(|| Mutex::new(NumaPools::new(nodes)));
+        self
+    }
+
+    /// Assigns allocations the CPU cores with `ids`, such as the online
+    /// cores of the host, rather than ids counted from zero. Only as many
+    /// as the manager has whole CPUs are used.
+    pub fn with_cpu_cores(mut self, mut ids: Vec<usize>) -> Self {
+        let count = whole_cpus(self.total_cpus.load(Ordering::SeqCst));
+        ids.sort();
+        ids.dedup();
+        if ids.len() < count {
+            tracing::warn!("{} CPU core ids given for {} CPUs", ids.len(), count);
+        }
+        ids.truncate(count);
+        *self.free_cores.get_mut() = ids.into_iter().collect();
+        self
+    }
+
+    /// Fails allocations of CPUs and memory that no single NUMA node has
+    /// room for with `ResourceError::Fragmented`, rather than spreading
+    /// them across nodes. Only applies after `with_numa_topology()`.
+    pub fn with_strict_numa(mut self, strict: bool) -> Self {
+        match self.numa.as_mut() {
+            Some(pools) => pools.get_mut().strict = strict,
+            None if strict => tracing::warn!("ignoring strict NUMA placement without NUMA nodes"),
+            None => {}
+        }
+        self
+    }
+
+    /// Makes `ResourceError::NotEnoughResources` list every resource the
+    /// request is short of, rather than just the first found. Costs a
+    /// check of the remaining resources on each failed allocation.
+    pub fn with_report_all_deficits(mut self, report_all: bool) -> Self {
+        self.report_all_deficits = report_all;
+        self
+    }
+
+    /// Stops handing out resources: all allocation attempts fail with
+    /// `ResourceError::Draining` until `undrain()` is called. Existing
+    /// allocations are unaffected and are freed as usual. Tasks waiting in
+    /// `allocate()` are woken up to fail as well.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+        metrics::DRAINING.set(1);
+        for waiter in self.waiters.lock().values() {
+            waiter.wakeup.notify_one();
+        }
+        tracing::info!("draining resource manager");
+    }
+
+    /// Resumes handing out resources after `drain()`.
+    pub fn undrain(&self) {
+        self.draining.store(false, Ordering::SeqCst);
+        metrics::DRAINING.set(0);
+        tracing::info!("resource manager no longer draining");
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Allocates requested resources, waiting for them to be freed if they
+    /// are not available right now. Requests that could never be satisfied,
+    /// such as ones exceeding the node's total capacity, fail immediately
+    /// with `ResourceError::ExceedsCapacity` or `ResourceError::GpuDisabled`.
+    ///
+    /// Waiting requests are served by effective priority (see
+    /// `with_aging_rate()`), and requests of equal effective priority in
+    /// the order they started waiting. A request that doesn't fit in the
+    /// resources freed is skipped in favor of the next one that does, so a
+    /// large request doesn't hold up smaller ones behind it. It is served
+    /// once enough resources are free at the same time.
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(mem = request.mem, cpus = request.cpus, gpus = request.gpus, allocated, id)
+    )]
+    pub async fn allocate(
+        resource_manager: Arc<Self>,
+        request: &ResourceRequest,
+    ) -> Result<ResourceAllocation> {
+        let started = resource_manager.clock.now();
+        if let Some(err) = resource_manager.unsatisfiable(request, Tier::User) {
+            tracing::debug!("{}", err);
+            tracing::Span::current().record("allocated", false);
+            resource_manager.finish_allocation(false);
+            return Err(err.into());
+        }
+        if let Some(uuid) = request.gpu_uuid.filter(|_| request.gpus > 0) {
+            if resource_manager.gpu_index(&uuid).is_none() {
+                tracing::debug!(%uuid, "request pinned to unknown GPU");
+                tracing::Span::current().record("allocated", false);
+                resource_manager.finish_allocation(false);
+                return Err(ResourceError::UnknownGpu(uuid).into());
+            }
+        }
+        let waiter = resource_manager.enqueue_waiter(request, None);
+
+        loop {
+            if resource_manager.has_waiter_ahead(waiter.id) {
+                // Let the waiter ahead go first, making sure it is awake.
+                resource_manager.wake_next_waiter();
+            } else {
+                // Counted once done, not on every try.
+                let res = Self::allocate_now(
+                    resource_manager.clone(),
+                    request,
+                    None,
+                    None,
+                    None,
+                    Tier::User,
+                );
+                match res {
+                    Ok(allocation) => {
+                        let waited = resource_manager.clock.now() - started;
+                        metrics::ALLOCATION_WAIT_SECONDS.observe(waited.as_secs_f64());
+                        tracing::Span::current()
+                            .record("allocated", true)
+                            .record("id", allocation.id);
+                        resource_manager.finish_allocation(true);
+                        return Ok(allocation);
+                    }
+                    Err(e)
+                        if e.downcast_ref::<ResourceError>()
+                            .is_some_and(|e| !e.is_permanent()) => {}
+                    Err(e) => {
+                        tracing::Span::current().record("allocated", false);
+                        resource_manager.finish_allocation(false);
+                        return Err(e);
+                    }
+                }
+            }
+
+            // A wakeup sent since the check is not missed, `notify_one()`
+            // keeps it until this waits. Should one get lost anyway, the
+            // timeout has the request check again.
+            let _ = tokio::time::timeout(resource_manager.poll_interval, waiter.wakeup.notified())
+                .await;
+        }
+    }
+
+    pub fn try_allocate(
+        resource_manager: Arc<Self>,
+        request: &ResourceRequest,
+    ) -> Result<ResourceAllocation> {
+        Self::try_allocate_for(resource_manager, request, None, None, None)
+    }
+
+    /// Like `try_allocate()`, but records the program and task the
+    /// allocation is made for, as reported by `list_allocations()`. If an
+    /// account is given and it has a quota, the allocation fails with
+    /// `ResourceError::QuotaExceeded` when it would take the account's
+    /// total usage over the quota.
+    pub fn try_allocate_for(
+        resource_manager: Arc<Self>,
+        request: &ResourceRequest,
+        program_id: Option<Hash>,
+        task_id: Option<TaskId>,
+        account: Option<PublicKey>,
+    ) -> Result<ResourceAllocation> {
+        let res = Self::allocate_now(
+            resource_manager.clone(),
+            request,
+            program_id,
+            task_id,
+            account,
+            Tier::User,
+        );
+        resource_manager.finish_allocation(res.is_ok());
+        res
+    }
+
+    /// Allocates requested resources for the node's own tasks, which may
+    /// also take from the system reserve (see `with_system_reserve()`).
+    pub fn try_allocate_system(
+        resource_manager: Arc<Self>,
+        request: &ResourceRequest,
+    ) -> Result<ResourceAllocation> {
+        let res = Self::allocate_now(
+            resource_manager.clone(),
+            request,
+            None,
+            None,
+            None,
+            Tier::System,
+        );
+        resource_manager.finish_allocation(res.is_ok());
+        res
+    }
+
+    /// Gauge of the requests of this pool waiting in `allocate()`.
+    fn queue_depth(&self) -> prometheus::IntGauge {
+        metrics::ALLOCATION_QUEUE_DEPTH.with_label_values(&[self.pool.as_deref().unwrap_or("")])
+    }
+
+    /// Counts an allocation made or failed in `ALLOCATIONS_TOTAL`, and
+    /// publishes the resources taken by one made.
+    fn finish_allocation(&self, success: bool) {
+        let result = if success { "success" } else { "failure" };
+        metrics::ALLOCATIONS_TOTAL
+            .with_label_values(&[self.pool.as_deref().unwrap_or(""), result])
+            .inc();
+        if success {
+            self.publish_changes();
+        }
+    }
+
+    /// Allocates `request` if it is available right away. Publishing the
+    /// change is left to `finish_allocation()`, so that a batch is
+    /// published once.
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(mem = request.mem, cpus = request.cpus, gpus = request.gpus, allocated, id)
+    )]
+    fn allocate_now(
+        resource_manager: Arc<Self>,
+        request: &ResourceRequest,
+        program_id: Option<Hash>,
+        task_id: Option<TaskId>,
+        account: Option<PublicKey>,
+        tier: Tier,
+    ) -> Result<ResourceAllocation> {
+        match Self::make_allocation(
+            resource_manager,
+            request,
+            program_id,
+            task_id,
+            account,
+            tier,
+        ) {
+            Ok(allocation) => {
+                tracing::Span::current()
+                    .record("allocated", true)
+                    .record("id", allocation.id);
+                Ok(allocation)
+            }
+            Err(err) => {
+                tracing::debug!("allocation failed: {}", err);
+                tracing::Span::current().record("allocated", false);
+                Err(err.into())
+            }
+        }
+    }
+
+    /// Implements `allocate_now()`. Whatever is taken for a request that
+    /// fails is given back by the `Rollback` guard.
+    fn make_allocation(
+        resource_manager: Arc<Self>,
+        request: &ResourceRequest,
+        program_id: Option<Hash>,
+        task_id: Option<TaskId>,
+        account: Option<PublicKey>,
+        tier: Tier,
+    ) -> std::result::Result<ResourceAllocation, ResourceError> {
+        // Charged on admission, and not refunded if the request fails.
+        if let Some(account) = &account {
+            resource_manager
+                .rate_limiter
+                .take(account, resource_manager.clock.now())?;
+        }
+
+        // Memory is reserved up to the peak, while the allocation is listed
+        // with the steady state.
+        let steady = resource_manager.resolve_gpu_mem(request);
+        let request = &steady.as_ref().unwrap_or(request).reserved();
+
+        // Allocations holding nothing would let tasks run without the
+        // resources they declare. GPUs are optional.
+        let steady = match steady {
+            Err(err) => Err(err),
+            Ok(_) if request.cpus < 1 => Err(RequestError::NoCpus),
+            Ok(_) if request.mem == 0 => Err(RequestError::NoMemory),
+            Ok(steady) => Ok(steady),
+        }
+        .map_err(ResourceError::InvalidRequest)?;
+
+        if resource_manager.is_draining() {
+            return Err(ResourceError::Draining);
+        }
+
+        if let Some(err) = resource_manager.unsatisfiable(request, tier) {
+            if let ResourceError::ExceedsCapacity { kind, .. } = err {
+                metrics::ALLOCATION_FAILURES_TOTAL
+                    .with_label_values(&[kind.label()])
+                    .inc();
+            }
+            return Err(err);
+        }
+
+        let mut rollback = Rollback {
+            resource_manager: &resource_manager,
+            request,
+            quota: None,
+            taken: vec![],
+            numa: None,
+        };
+        if let Some(account) = &account {
+            resource_manager.quotas.charge(account, request)?;
+            rollback.quota = Some(account);
+        }
+
+        for kind in ResourceKind::ALL {
+            if let Err(available) = resource_manager.take(kind, kind.requested(request), tier) {
+                metrics::ALLOCATION_FAILURES_TOTAL
+                    .with_label_values(&[kind.label()])
+                    .inc();
+                return Err(ResourceError::NotEnoughResources {
+                    kind,
+                    requested: kind.requested(request),
+                    available,
+                    deficits: resource_manager.deficits(kind, request, tier),
+                    retry_after: resource_manager.retry_after(),
+                });
+            }
+            rollback.taken.push(kind);
+        }
+
+        // CPUs and memory are taken, but may not fit on a single NUMA node.
+        rollback.numa = match &resource_manager.numa {
+            Some(pools) => pools.lock().place(request.cpus, request.mem),
+            None => Some(NumaPlacement::default()),
+        };
+        if rollback.numa.is_none() {
+            // Given back first, so that they count as allocatable.
+            drop(rollback);
+            return Err(ResourceError::Fragmented {
+                requested: Box::new(*request),
+                largest: Box::new(resource_manager.largest_allocatable()),
+            });
+        }
+
+        let assigned_gpus = resource_manager.assign_gpus(request).inspect_err(|_| {
+            metrics::ALLOCATION_FAILURES_TOTAL
+                .with_label_values(&[ResourceKind::Gpus.label()])
+                .inc();
+        })?;
+        let numa = rollback.complete();
+
+        let assigned_cores = resource_manager.assign_cores(whole_cpus(request.cpus));
+
+        let id = resource_manager
+            .next_allocation_id
+            .fetch_add(1, Ordering::SeqCst);
+        let created_at = resource_manager.clock.now();
+        resource_manager.allocations.write().insert(
+            id,
+            AllocationEntry {
+                request: steady,
+                program_id,
+                task_id,
+                account: account.clone(),
+                created_at,
+            },
+        );
+        if let Some(key) = steady.affinity_key {
+            *resource_manager.affinity.lock().entry(key).or_default() += 1;
+        }
+        if let Some(key) = steady.anti_affinity_key {
+            *resource_manager
+                .anti_affinity
+                .lock()
+                .entry(key)
+                .or_default() += 1;
+        }
+
+        Ok(ResourceAllocation {
+            resource_manager: resource_manager.clone(),
+            id,
+            program_id,
+            task_id,
+            account,
+            mem: request.mem,
+            steady_mem: steady.mem,
+            cpus: request.cpus,
+            gpus: request.gpus,
+            assigned_gpus,
+            assigned_cores,
+            disk: request.disk_bytes,
+            gpu_mem: request.gpu_mem,
+            net: request.net_bps,
+            mem_limit: request.hard_mem(),
+            cpus_limit: request.hard_cpus(),
+            numa,
+            cpu_slots: None,
+            checkpoint: None,
+            freed: AtomicBool::new(false),
+            created_at,
+            billed_since: created_at,
+        })
+    }
+
+    /// Allocates all of `requests`, or none of them. If any request can't be
+    /// allocated, the ones allocated before it are rolled back and the
+    /// error is returned. The batch counts as a single allocation made or
+    /// failed.
+    pub fn try_allocate_batch(
+        resource_manager: Arc<Self>,
+        requests: &[ResourceRequest],
+    ) -> Result<Vec<ResourceAllocation>> {
+        let mut allocations = Vec::with_capacity(requests.len());
+        for request in requests {
+            let res = Self::allocate_now(
+                resource_manager.clone(),
+                request,
+                None,
+                None,
+                None,
+                Tier::User,
+            );
+            match res {
+                Ok(allocation) => allocations.push(allocation),
+                Err(err) => {
+                    tracing::debug!(
+                        "rolling back {} of {} batch allocations: {}",
+                        allocations.len(),
+                        requests.len(),
+                        err
+                    );
+                    for allocation in allocations {
+                        resource_manager.roll_back(&allocation);
+                    }
+                    resource_manager.wake_next_waiter();
+                    resource_manager.finish_allocation(false);
+                    return Err(err);
+                }
+            }
+        }
+        resource_manager.finish_allocation(true);
+        Ok(allocations)
+    }
+
+    /// Reserves requested resources for a later `Reservation::commit()`.
+    pub fn reserve(resource_manager: Arc<Self>, request: &ResourceRequest) -> Result<Reservation> {
+        let clock = resource_manager.clock.clone();
+        let allocation = Self::try_allocate(resource_manager, request)?;
+        Ok(Reservation::new(allocation, clock))
+    }
+
+    /// Like `reserve()`, but the reservation expires and its resources are
+    /// freed if it's not committed within `ttl`. Must be called within a
+    /// Tokio runtime.
+    pub fn reserve_with_ttl(
+        resource_manager: Arc<Self>,
+        request: &ResourceRequest,
+        ttl: Duration,
+    ) -> Result<Reservation> {
+        let mut reservation = Self::reserve(resource_manager, request)?;
+        reservation.expire_after(ttl);
+        Ok(reservation)
+    }
+
+    /// Allocates requested resources like `try_allocate()`, but as a lease
+    /// that expires unless renewed within `ttl`. Expired leases are freed by
+    /// `reap_expired_leases()`, so that resources held on behalf of a peer
+    /// that went away are not leaked.
+    pub fn try_allocate_lease(
+        resource_manager: Arc<Self>,
+        request: &ResourceRequest,
+        ttl: Duration,
+    ) -> Result<Lease> {
+        let allocation = Self::try_allocate(resource_manager.clone(), request)?;
+        Ok(resource_manager
+            .leases
+            .lease(allocation, ttl, resource_manager.clock.clone()))
+    }
+
+    /// Frees the resources of leases that have expired, returning how many
+    /// were freed.
+    pub fn reap_expired_leases(&self) -> usize {
+        self.leases.reap_expired_leases(self.clock.now())
+    }
+
+    /// Allocates requested resources like `try_allocate()`, for a remote
+    /// task that is expected to send heartbeats. Allocations that go the
+    /// heartbeat staleness without one are freed by
+    /// `reap_stale_heartbeats()`, so that resources of tasks that went away
+    /// are not leaked.
+    pub fn try_allocate_heartbeat(
+        resource_manager: Arc<Self>,
+        request: &ResourceRequest,
+    ) -> Result<HeartbeatAllocation> {
+        let allocation = Self::try_allocate(resource_manager.clone(), request)?;
+        Ok(resource_manager
+            .leases
+            .heartbeat_allocation(allocation, resource_manager.clock.clone()))
+    }
+
+    /// Frees the resources of allocations whose last heartbeat is older
+    /// than the heartbeat staleness, returning how many were freed.
+    pub fn reap_stale_heartbeats(&self) -> usize {
+        self.leases
+            .reap_stale_heartbeats(self.clock.now(), self.heartbeat_staleness)
+    }
+
+    /// Time until the next lease expires, freeing its resources, or the
+    /// default retry hint if no lease is held.
+    fn retry_after(&self) -> Option<Duration> {
+        self.leases
+            .next_expiry(self.clock.now())
+            .or(self.default_retry_after)
+    }
+
+    /// Spawns a task that reaps expired leases and allocations without
+    /// heartbeats every `interval`, for as long as the resource manager
+    /// exists.
+    pub fn spawn_reaper(
+        resource_manager: &Arc<Self>,
+        interval: Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        let resource_manager = Arc::downgrade(resource_manager);
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                match resource_manager.upgrade() {
+                    Some(resource_manager) => {
+                        resource_manager.reap_expired_leases();
+                        resource_manager.reap_stale_heartbeats();
+                    }
+                    None => break,
+                }
+            }
+        })
+    }
+
+    /// Allocates requested resources like `try_allocate()`, except that
+    /// while the node is under high pressure, requests with priority below
+    /// `min_priority` are rejected even if they would fit. This leaves the
+    /// remaining headroom for critical tasks.
+    pub fn try_allocate_with_policy(
+        resource_manager: Arc<Self>,
+        request: &ResourceRequest,
+        min_priority: u8,
+    ) -> Result<ResourceAllocation> {
+        let pressure = resource_manager.pressure();
+        if pressure == ResourcePressure::High && request.priority < min_priority {
+            tracing::debug!(
+                priority = request.priority,
+                min_priority,
+                "rejecting request under high pressure"
+            );
+            return Err(ResourceError::UnderPressure(pressure).into());
+        }
+
+        Self::try_allocate(resource_manager, request)
+    }
+
+    /// Allocates requested resources if available. Otherwise, looks for
+    /// lower priority allocations that would make room for the request when
+    /// dropped, preferring the lowest priority and most recent ones. The
+    /// manager itself never frees anything on behalf of the caller.
+    pub fn try_allocate_preempt(
+        resource_manager: Arc<Self>,
+        request: &ResourceRequest,
+    ) -> Result<Preemption> {
+        let err = match Self::try_allocate(resource_manager.clone(), request) {
+            Ok(allocation) => return Ok(Preemption::Allocated(allocation)),
+            Err(err) => err,
+        };
+
+        let rm = &resource_manager;
+        let allocations = rm.allocations.read();
+
+        let request = &rm.admitted(request);
+        let mut candidates: Vec<(&u64, ResourceRequest)> = allocations
+            .iter()
+            .map(|(id, entry)| (id, entry.request.reserved()))
+            .filter(|(_, held)| held.priority < request.priority)
+            .collect();
+        candidates.sort_by(|(a_id, a), (b_id, b)| a.priority.cmp(&b.priority).then(b_id.cmp(a_id)));
+
+        let mut released = vec![];
+        let mut victims = vec![];
+        for (id, held) in candidates {
+            if rm.fits_after_release(request, &released) {
+                break;
+            }
+            released.push(held);
+            victims.push(*id);
+        }
+
+        if victims.is_empty() || !rm.fits_after_release(request, &released) {
+            return Err(err);
+        }
+
+        Ok(Preemption::Candidates(victims))
+    }
+
+    /// Resolves GPU memory requested as a fraction against the VRAM of one
+    /// GPU. GPUs aren't told apart by VRAM, so each is taken to have an
+    /// even share of the total.
+    fn resolve_gpu_mem(&self, request: &ResourceRequest) -> Result<ResourceRequest, RequestError> {
+        let gpus = self.total_gpus.load(Ordering::SeqCst).max(1);
+        request.resolve_gpu_mem(self.total_gpu_mem.load(Ordering::SeqCst) / gpus)
+    }
+
+    /// The request as admitted, see `ResourceRequest::reserved()`, with GPU
+    /// memory resolved. Invalid fractions are left for `allocate_now()` to
+    /// reject.
+    fn admitted(&self, request: &ResourceRequest) -> ResourceRequest {
+        self.resolve_gpu_mem(request).unwrap_or(*request).reserved()
+    }
+
+    /// Checks whether `request` would fit in the available resources once
+    /// the `released` allocations have been freed.
+    fn fits_after_release(&self, request: &ResourceRequest, released: &[ResourceRequest]) -> bool {
+        ResourceKind::ALL.into_iter().all(|kind| {
+            let released: u64 = released.iter().map(|r| kind.requested(r)).sum();
+            self.available(kind) + released >= kind.requested(request)
+        })
+    }
+
+    /// Checks whether `request` would fit in the available resources right
+    /// now, without allocating anything.
+    pub fn can_allocate(&self, request: &ResourceRequest) -> bool {
+        let request = &self.admitted(request);
+        let fits = ResourceKind::ALL
+            .into_iter()
+            .all(|kind| kind.requested(request) <= self.available_to(kind, Tier::User));
+
+        match request.gpu_uuid.filter(|_| request.gpus > 0) {
+            Some(uuid) if fits => self
+                .gpu_index(&uuid)
+                .is_some_and(|index| self.free_gpus.lock().contains(&index)),
+            _ => fits,
+        }
+    }
+
+    /// Reports, in order, which of `requests` would be allocated if they
+    /// were tried one after the other right now. Allocation is simulated on
+    /// a copy of the available resources; nothing is actually allocated.
+    pub fn schedulable(&self, requests: &[ResourceRequest]) -> Vec<bool> {
+        if self.is_draining() {
+            return vec![false; requests.len()];
+        }
+
+        let mut available =
+            ResourceKind::ALL.map(|kind| (kind, self.available_to(kind, Tier::User)));
+        let mut free_gpus = self.free_gpus.lock().clone();
+        requests
+            .iter()
+            .map(|request| {
+                let request = &self.admitted(request);
+                let pinned = request
+                    .gpu_uuid
+                    .filter(|_| request.gpus > 0)
+                    .map(|uuid| self.gpu_index(&uuid));
+                let fits = available
+                    .iter()
+                    .all(|(kind, available)| kind.requested(request) <= *available)
+                    && match pinned {
+                        Some(Some(index)) => free_gpus.contains(&index),
+                        Some(None) => false,
+                        None => true,
+                    };
+                if !fits {
+                    return false;
+                }
+
+                for (kind, available) in available.iter_mut() {
+                    *available -= kind.requested(request);
+                }
+                // Mirror `assign_gpus()`, so that later pinned requests see
+                // the same devices taken.
+                let mut assigned = 0;
+                if let Some(Some(index)) = pinned {
+                    free_gpus.remove(&index);
+                    assigned += 1;
+                }
+                for _ in assigned..request.gpus {
+                    free_gpus.pop_first();
+                }
+                true
+            })
+            .collect()
+    }
+
+    /// Implements `ResourceAllocation::resize()`.
+    fn resize(
+        &self,
+        allocation: &mut ResourceAllocation,
+        request: &ResourceRequest,
+    ) -> std::result::Result<(), ResourceError> {
+        let steady = self
+            .resolve_gpu_mem(request)
+            .map_err(ResourceError::InvalidRequest)?;
+        let request = &steady.reserved();
+        let held = allocation.held_request();
+        let grow = *request - held;
+        let shrink = held - *request;
+        let any = |request: &ResourceRequest| {
+            ResourceKind::ALL
+                .iter()
+                .any(|kind| kind.requested(request) > 0)
+        };
+
+        if any(&grow) {
+            if self.is_draining() {
+                return Err(ResourceError::Draining);
+            }
+            if let Some(err) = self.unsatisfiable(request, Tier::User) {
+                return Err(err);
+            }
+            if let Some(account) = &allocation.account {
+                self.quotas.charge(account, &grow)?;
+            }
+
+            let mut taken = vec![];
+            for kind in ResourceKind::ALL {
+                if let Err(available) = self.take(kind, kind.requested(&grow), Tier::User) {
+                    self.give_back(&grow, &taken);
+                    if let Some(account) = &allocation.account {
+                        self.quotas.refund(account, &grow);
+                    }
+                    metrics::ALLOCATION_FAILURES_TOTAL
+                        .with_label_values(&[kind.label()])
+                        .inc();
+                    return Err(ResourceError::NotEnoughResources {
+                        kind,
+                        requested: kind.requested(&grow),
+                        available,
+                        deficits: self.deficits(kind, &grow, Tier::User),
+                        retry_after: self.retry_after(),
+                    });
+                }
+                taken.push(kind);
+            }
+
+            if let Some(pools) = &self.numa {
+                if !pools.lock().grow(&mut allocation.numa, grow.cpus, grow.mem) {
+                    self.give_back(&grow, &ResourceKind::ALL);
+                    if let Some(account) = &allocation.account {
+                        self.quotas.refund(account, &grow);
+                    }
+                    return Err(ResourceError::Fragmented {
+                        requested: Box::new(*request),
+                        largest: Box::new(self.largest_allocatable()),
+                    });
+                }
+            }
+
+            // The GPU count is taken, so there are enough free devices.
+            let mut free_gpus = self.free_gpus.lock();
+            for _ in 0..grow.gpus {
+                allocation.assigned_gpus.extend(free_gpus.pop_first());
+            }
+            allocation.assigned_gpus.sort();
+
+            let more = whole_cpus(request.cpus).saturating_sub(allocation.assigned_cores.len());
+            allocation.assigned_cores.extend(self.assign_cores(more));
+            allocation.assigned_cores.sort();
+        }
+
+        if any(&shrink) {
+            // Return devices before the count, as in `free()`.
+            let keep = allocation
+                .assigned_gpus
+                .len()
+                .saturating_sub(shrink.gpus as usize);
+            self.free_gpus
+                .lock()
+                .extend(allocation.assigned_gpus.drain(keep..));
+            let cores = whole_cpus(request.cpus);
+            if cores < allocation.assigned_cores.len() {
+                self.free_cores
+                    .lock()
+                    .extend(allocation.assigned_cores.drain(cores..));
+            }
+            if let Some(pools) = &self.numa {
+                pools
+                    .lock()
+                    .shrink(&mut allocation.numa, shrink.cpus, shrink.mem);
+            }
+            self.give_back(&shrink, &ResourceKind::ALL);
+            if let Some(account) = &allocation.account {
+                self.quotas.refund(account, &shrink);
+            }
+        }
+
+        if any(&grow) || any(&shrink) {
+            allocation.bill_usage(self.clock.now());
+        }
+        allocation.mem = request.mem;
+        allocation.cpus = request.cpus;
+        allocation.gpus = request.gpus;
+        allocation.disk = request.disk_bytes;
+        allocation.gpu_mem = request.gpu_mem;
+        allocation.net = request.net_bps;
+        allocation.steady_mem = steady.mem;
+        allocation.mem_limit = request.hard_mem();
+        allocation.cpus_limit = request.hard_cpus();
+        if let Some(entry) = self.allocations.write().get_mut(&allocation.id) {
+            entry.request = ResourceRequest {
+                priority: entry.request.priority,
+                gpu_uuid: entry.request.gpu_uuid,
+                affinity_key: entry.request.affinity_key,
+                anti_affinity_key: entry.request.anti_affinity_key,
+                ..steady
+            };
+        }
+        tracing::debug!(
+            id = allocation.id,
+            mem = request.mem,
+            cpus = request.cpus,
+            gpus = request.gpus,
+            "resized allocation"
+        );
+
+        self.publish_changes();
+        if any(&shrink) {
+            self.wake_next_waiter();
+        }
+        Ok(())
+    }
+
+    /// Implements `ResourceAllocation::grow()`.
+    async fn grow(
+        &self,
+        allocation: &mut ResourceAllocation,
+        request: &ResourceRequest,
+    ) -> std::result::Result<(), ResourceError> {
+        if let Some(err) = self.unsatisfiable(request, Tier::User) {
+            return Err(err);
+        }
+        let priority = match self.allocations.read().get(&allocation.id) {
+            Some(entry) => entry.request.priority,
+            None => request.priority,
+        };
+        let more = ResourceRequest {
+            priority,
+            ..self.admitted(request) - allocation.held_request()
+        };
+        let waiter = self.enqueue_waiter(&more, Some(allocation.id));
+
+        loop {
+            if self.has_waiter_ahead(waiter.id) {
+                self.wake_next_waiter();
+            } else {
+                match self.resize(allocation, request) {
+                    Ok(()) => return Ok(()),
+                    Err(err) if !err.is_permanent() => {}
+                    Err(err) => return Err(err),
+                }
+            }
+
+            let _ = tokio::time::timeout(self.poll_interval, waiter.wakeup.notified()).await;
+        }
+    }
+
+    /// Returns `kinds` of resources taken for `request` back.
+    fn give_back(&self, request: &ResourceRequest, kinds: &[ResourceKind]) {
+        for kind in kinds {
+            self.available_counter(*kind)
+                .fetch_add(kind.requested(request), Ordering::SeqCst);
+        }
+    }
+
+    /// Picks free GPU devices for `request`, including the one it's pinned
+    /// to. The GPU count must have been taken already, so that there are
+    /// enough free devices.
+    fn assign_gpus(
+        &self,
+        request: &ResourceRequest,
+    ) -> std::result::Result<Vec<u32>, ResourceError> {
+        let mut free_gpus = self.free_gpus.lock();
+
+        let mut assigned = vec![];
+        if let Some(uuid) = request.gpu_uuid.filter(|_| request.gpus > 0) {
+            match self.gpu_index(&uuid) {
+                Some(index) if free_gpus.remove(&index) => assigned.push(index),
+                Some(_) => return Err(ResourceError::GpuBusy(uuid)),
+                None => return Err(ResourceError::UnknownGpu(uuid)),
+            }
+        }
+        while (assigned.len() as u64) < request.gpus {
+            match free_gpus.pop_first() {
+                Some(index) => assigned.push(index),
+                None => break,
+            }
+        }
+        assigned.sort();
+
+        Ok(assigned)
+    }
+
+    /// Picks `count` free CPU cores. The CPUs must have been taken already,
+    /// so that there are enough free cores.
+    fn assign_cores(&self, count: usize) -> Vec<usize> {
+        let mut free_cores = self.free_cores.lock();
+        let mut assigned: Vec<usize> = std::iter::from_fn(|| free_cores.pop_first())
+            .take(count)
+            .collect();
+        if assigned.len() < count {
+            tracing::warn!("{} of {} CPU cores assigned", assigned.len(), count);
+        }
+        assigned.sort();
+        assigned
+    }
+
+    fn gpu_index(&self, uuid: &Uuid) -> Option<u32> {
+        self.gpu_uuids
+            .iter()
+            .position(|u| u == uuid)
+            .map(|index| index as u32)
+    }
+
+    /// Takes `amount` of `kind` from the resources available to `tier`. On
+    /// failure, returns what was available to it.
+    fn take(&self, kind: ResourceKind, amount: u64, tier: Tier) -> std::result::Result<u64, u64> {
+        let kept = self.kept_from(kind, tier);
+        self.available_counter(kind)
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |available| {
+                // Taking nothing succeeds even if the system has dipped
+                // into its reserve.
+                available
+                    .checked_sub(amount)
+                    .filter(|left| amount == 0 || *left >= kept)
+            })
+            .map_err(|available| available.saturating_sub(kept))
+    }
+
+    /// Amount of `kind` that allocations of `tier` must leave available.
+    fn kept_from(&self, kind: ResourceKind, tier: Tier) -> u64 {
+        match tier {
+            Tier::User => kind.requested(&self.system_reserve),
+            Tier::System => 0,
+        }
+    }
+
+    /// Resources `request` is short of for `tier`, given `first` was found
+    /// short and the resources before it in `ResourceKind::ALL` were not.
+    fn deficits(
+        &self,
+        first: ResourceKind,
+        request: &ResourceRequest,
+        tier: Tier,
+    ) -> Vec<ResourceKind> {
+        let mut deficits = vec![first];
+        if self.report_all_deficits {
+            deficits.extend(
+                ResourceKind::ALL
+                    .into_iter()
+                    .skip_while(|kind| *kind != first)
+                    .skip(1)
+                    .filter(|kind| kind.requested(request) > self.available_to(*kind, tier)),
+            );
+        }
+        deficits
+    }
+
+    /// Returns the amount of `kind` available to allocations of `tier`.
+    fn available_to(&self, kind: ResourceKind, tier: Tier) -> u64 {
+        self.available(kind)
+            .saturating_sub(self.kept_from(kind, tier))
+    }
+
+    pub(self) fn free(&self, allocation: &ResourceAllocation) {
+        if allocation.freed.swap(true, Ordering::SeqCst) {
+            tracing::warn!("resource allocation {} already freed", allocation.id);
+            return;
+        }
+
+        tracing::debug!(
+            id = allocation.id,
+            mem = allocation.mem,
+            cpus = allocation.cpus,
+            gpus = allocation.gpus,
+            "freeing resources"
+        );
+        self.return_held(allocation);
+        self.wake_next_waiter();
+
+        // Copied out, so that no lock is held while the callbacks run.
+        let callbacks = self.on_free.0.read().clone();
+        if !callbacks.is_empty() {
+            let info = AllocationInfo {
+                id: allocation.id,
+                program_id: allocation.program_id,
+                task_id: allocation.task_id,
+                account: allocation.account.clone(),
+                mem: allocation.steady_mem,
+                cpus: allocation.cpus,
+                gpus: allocation.gpus,
+                age: self
+                    .clock
+                    .now()
+                    .saturating_duration_since(allocation.created_at),
+            };
+            for callback in callbacks {
+                without_panic("on-free callback", || callback(&info));
+            }
+        }
+    }
+
+    /// Undoes an allocation of `try_allocate_batch()` that the caller never
+    /// received. What it holds is returned as by `free()`, but it is not
+    /// reported as freed: no on-free callbacks run, and dropping it bills
+    /// nothing.
+    fn roll_back(&self, allocation: &ResourceAllocation) {
+        allocation.freed.store(true, Ordering::SeqCst);
+        self.return_held(allocation);
+    }
+
+    /// Returns the resources, devices and quota held by `allocation`, and
+    /// removes it from the live allocations.
+    fn return_held(&self, allocation: &ResourceAllocation) {
+        let entry = self.allocations.write().remove(&allocation.id);
+        if let Some(entry) = &entry {
+            uncount_key(&self.affinity, entry.request.affinity_key);
+            uncount_key(&self.anti_affinity, entry.request.anti_affinity_key);
+        }
+        if let (Some(entry), Some(account)) = (entry, &allocation.account) {
+            self.quotas.refund(account, &entry.request.reserved());
+        }
+
+        // Return devices before the count, so that whoever takes the count
+        // finds them free.
+        self.free_gpus
+            .lock()
+            .extend(allocation.assigned_gpus.iter().copied());
+        self.free_cores
+            .lock()
+            .extend(allocation.assigned_cores.iter().copied());
+        if let Some(pools) = &self.numa {
+            pools.lock().release(&allocation.numa);
+        }
+
+        for kind in ResourceKind::ALL {
+            let capacity = self.capacity(kind);
+            let held = allocation.held(kind);
+            let res = self.available_counter(kind).fetch_update(
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+                |available| match available.checked_add(held) {
+                    Some(freed) if freed <= capacity => Some(freed),
+                    _ => None,
+                },
+            );
+            if res.is_err() {
+                tracing::error!(
+                    "freeing {} of {} from allocation {} exceeds total of {}",
+                    held,
+                    kind,
+                    allocation.id,
+                    capacity
+                );
+                self.available_counter(kind)
+                    .store(capacity, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Adds `request` to the queue of requests waiting in `allocate()`, or
+    /// to grow the allocation `growing`. It is removed when the returned
+    /// guard is dropped.
+    fn enqueue_waiter(&self, request: &ResourceRequest, growing: Option<u64>) -> QueuedWaiter<'_> {
+        let id = self.next_waiter_id.fetch_add(1, Ordering::Relaxed);
+        let wakeup = Arc::new(Notify::new());
+        self.waiters.lock().insert(
+            id,
+            Waiter {
+                request: self.admitted(request),
+                since: self.clock.now(),
+                wakeup: wakeup.clone(),
+                growing,
+            },
+        );
+        self.queue_depth().inc();
+        QueuedWaiter {
+            resource_manager: self,
+            id,
+            wakeup,
+        }
+    }
+
+    /// Waiters in the order they are served: by effective priority, and on
+    /// a tie, by the order they started waiting. Waiters growing an
+    /// allocation inherit the priority of waiters the allocation blocks.
+    fn queue_order<'a>(&self, waiters: &'a BTreeMap<u64, Waiter>) -> Vec<(u64, &'a Waiter)> {
+        let now = self.clock.now();
+        let inherited = self.inherited_priorities(waiters, now);
+        let priority = |waiter: &Waiter| {
+            let own = self.effective_priority(waiter, now);
+            match waiter.growing.and_then(|id| inherited.get(&id)) {
+                Some(inherited) => own.max(*inherited),
+                None => own,
+            }
+        };
+        let mut order: Vec<_> = waiters.iter().map(|(id, waiter)| (*id, waiter)).collect();
+        // Sort is stable, so ties stay in the order of waiter IDs.
+        order.sort_by(|(_, a), (_, b)| priority(b).total_cmp(&priority(a)));
+        order
+    }
+
+    /// Priorities inherited by allocations waiting to grow, by allocation
+    /// ID. A waiter that can't be allocated is blocked by the lower
+    /// priority allocations holding any of what it lacks, which inherit
+    /// its effective priority so that they finish sooner, rather than
+    /// waiting behind requests of priority in between.
+    fn inherited_priorities(
+        &self,
+        waiters: &BTreeMap<u64, Waiter>,
+        now: tokio::time::Instant,
+    ) -> HashMap<u64, f64> {
+        let mut inherited = HashMap::new();
+        let growing: Vec<u64> = waiters.values().filter_map(|w| w.growing).collect();
+        if growing.is_empty() {
+            return inherited;
+        }
+
+        let allocations = self.allocations.read();
+        for waiter in waiters.values() {
+            if self.can_allocate(&waiter.request) {
+                continue;
+            }
+            let priority = self.effective_priority(waiter, now);
+            let lacking: Vec<ResourceKind> = ResourceKind::ALL
+                .into_iter()
+                .filter(|kind| {
+                    kind.requested(&waiter.request) > self.available_to(*kind, Tier::User)
+                })
+                .collect();
+            for id in &growing {
+                let blocks = waiter.growing != Some(*id)
+                    && allocations.get(id).is_some_and(|entry| {
+                        f64::from(entry.request.priority) < priority
+                            && lacking
+                                .iter()
+                                .any(|kind| kind.requested(&entry.request) > 0)
+                    });
+                if blocks {
+                    let boost = inherited.entry(*id).or_insert(priority);
+                    *boost = priority.max(*boost);
+                }
+            }
+        }
+        inherited
+    }
+
+    /// Tells whether a waiter ahead of waiter `id` in the queue could be
+    /// allocated right now.
+    fn has_waiter_ahead(&self, id: u64) -> bool {
+        let waiters = self.waiters.lock();
+        self.queue_order(&waiters)
+            .into_iter()
+            .take_while(|(other_id, _)| *other_id != id)
+            .any(|(_, other)| self.can_allocate(&other.request))
+    }
+
+    /// Wakes up the first waiter in the queue that could be allocated right
+    /// now, if any.
+    fn wake_next_waiter(&self) {
+        let waiters = self.waiters.lock();
+        if let Some((_, waiter)) = self
+            .queue_order(&waiters)
+            .into_iter()
+            .find(|(_, waiter)| self.can_allocate(&waiter.request))
+        {
+            waiter.wakeup.notify_one();
+        }
+    }
+
+    /// Priority of `waiter` including what it has gained by waiting.
+    fn effective_priority(&self, waiter: &Waiter, now: tokio::time::Instant) -> f64 {
+        let waited = now.saturating_duration_since(waiter.since).as_secs_f64();
+        waiter.request.priority as f64 + self.aging_rate * waited
+    }
+
+    /// Like `allocate()`, but gives up with `ResourceError::Cancelled` as
+    /// soon as `cancel` is cancelled, such as when the job the allocation
+    /// is for is cancelled. The request leaves the wait queue right away,
+    /// letting the next waiter go ahead, and holds no resources after.
+    /// Dropping the future of `allocate()` has the same effect.
+    pub async fn allocate_cancellable(
+        resource_manager: Arc<Self>,
+        request: &ResourceRequest,
+        cancel: &CancellationToken,
+    ) -> Result<ResourceAllocation> {
+        tokio::select! {
+            // An allocation made is returned even if cancelled at the same
+            // time, for the caller to drop.
+            biased;
+            res = Self::allocate(resource_manager, request) => res,
+            _ = cancel.cancelled() => Err(ResourceError::Cancelled.into()),
+        }
+    }
+
+    /// Like `allocate()`, but first queues for the CPUs requested on a
+    /// semaphore with a permit for each millicore of the node, in the order
+    /// requests arrive. Memory and GPUs are then waited for as in
+    /// `allocate()`. The permits are held by the allocation and returned
+    /// when it is dropped; resizing it doesn't change them.
+    pub async fn allocate_with_cpu_slots(
+        resource_manager: Arc<Self>,
+        request: &ResourceRequest,
+    ) -> Result<ResourceAllocation> {
+        if let Some(err) = resource_manager.unsatisfiable(request, Tier::User) {
+            return Err(err.into());
+        }
+        // Requests beyond `u32::MAX` millicores exceed the capacity above.
+        let permits = u32::try_from(request.cpus)?;
+        let slots = resource_manager
+            .cpu_slots
+            .clone()
+            .acquire_many_owned(permits)
+            .await?;
+
+        let mut allocation = Self::allocate(resource_manager, request).await?;
+        allocation.cpu_slots = Some(slots);
+        Ok(allocation)
+    }
+
+    /// Number of CPU slots, in millicores, not held by allocations made
+    /// with `allocate_with_cpu_slots()`.
+    pub fn available_cpu_slots(&self) -> usize {
+        self.cpu_slots.available_permits()
+    }
+
+    /// Like `allocate()`, but gives up with `ResourceError::Timeout` if the
+    /// resources don't become available within `timeout` from the call.
+    pub async fn allocate_timeout(
+        resource_manager: Arc<Self>,
+        request: &ResourceRequest,
+        timeout: Duration,
+    ) -> Result<ResourceAllocation> {
+        let started = tokio::time::Instant::now();
+        match tokio::time::timeout(timeout, Self::allocate(resource_manager, request)).await {
+            Ok(res) => res,
+            Err(_) => Err(ResourceError::Timeout(started.elapsed()).into()),
+        }
+    }
+
+    pub fn list_allocations(&self) -> Vec<AllocationInfo> {
+        let now = self.clock.now();
+        let mut allocations: Vec<AllocationInfo> = self
+            .allocations
+            .read()
+            .iter()
+            .map(|(id, entry)| entry.info(*id, now))
+            .collect();
+        allocations.sort_by_key(|a| a.id);
+        allocations
+    }
+
+    /// Name of the pool in a `ResourceRegistry` this manager is for.
+    pub fn pool_name(&self) -> Option<&str> {
+        self.pool.as_deref()
+    }
+
+    /// Details of the live allocation with `id`, if there is one.
+    pub fn allocation_info(&self, id: u64) -> Option<AllocationInfo> {
+        let now = self.clock.now();
+        self.allocations
+            .read()
+            .get(&id)
+            .map(|entry| entry.info(id, now))
+    }
+
+    /// Memory reserved for live allocations to peak to, above what they are
+    /// expected to use in steady state (in MiB). Utilization counts the
+    /// reserve as used.
+    pub fn peak_reserve(&self) -> u64 {
+        self.allocations
+            .read()
+            .values()
+            .map(|entry| entry.request.reserved_mem() - entry.request.mem)
+            .sum()
+    }
+
+    /// Memory and CPUs that live allocations may burst to above what they
+    /// hold, which is overcommitted on top of what is allocated.
+    pub fn burstable_headroom(&self) -> ResourceRequest {
+        let mut headroom = zero_request();
+        for entry in self.allocations.read().values() {
+            let held = entry.request.reserved();
+            headroom.mem += held.hard_mem() - held.mem;
+            headroom.cpus += held.hard_cpus() - held.cpus;
+        }
+        headroom
+    }
+
+    /// Returns a receiver that sees a new snapshot whenever resources are
+    /// allocated or freed.
+    pub fn subscribe(&self) -> watch::Receiver<ResourceSnapshot> {
+        self.changes.subscribe()
+    }
+
+    /// Updates metrics and subscribers with the current resource state.
+    fn publish_changes(&self) {
+        let available = self.available_all();
+        for (kind, available) in available {
+            self.peaks.record(kind, self.reserved(kind, available));
+        }
+        let current = |kind| utilization(self.capacity(kind), self.available(kind));
+        let ewma = self
+            .utilization_ewma
+            .update(current(ResourceKind::Mem), current(ResourceKind::Cpus));
+        match &self.pool {
+            Some(pool) => {
+                for (kind, amount) in available {
+                    metrics::POOL_AVAILABLE
+                        .with_label_values(&[pool, kind.label()])
+                        .set(metric_value(kind, amount));
+                }
+            }
+            None => {
+                set_available_metrics(available);
+                set_reserved_metrics(
+                    available.map(|(kind, available)| (kind, self.reserved(kind, available))),
+                );
+                set_peak_metrics(&self.peaks);
+                metrics::MEM_UTIL_EWMA.set(ewma.0);
+                metrics::CPUS_UTIL_EWMA.set(ewma.1);
+            }
+        }
+        self.changes.send_replace(self.snapshot());
+    }
+
+    /// Moving averages of memory and CPU utilization (in percent), or
+    /// `None` before anything has been allocated.
+    pub fn utilization_ewma(&self) -> Option<(f64, f64)> {
+        self.utilization_ewma.get()
+    }
+
+    /// Most memory held by allocations at once (in MiB).
+    pub fn peak_mem(&self) -> u64 {
+        self.peaks.mem()
+    }
+
+    /// Most CPUs held by allocations at once (in millicores).
+    pub fn peak_cpus(&self) -> u64 {
+        self.peaks.cpus()
+    }
+
+    /// Most GPUs held by allocations at once.
+    pub fn peak_gpus(&self) -> u64 {
+        self.peaks.gpus()
+    }
+
+    /// Starts tracking peaks over again from what is held right now.
+    pub fn reset_peaks(&self) {
+        for (kind, available) in self.available_all() {
+            self.peaks.reset(kind, self.reserved(kind, available));
+        }
+        self.publish_changes();
+    }
+
+    /// Returns the amount of `kind` held by allocations, given what is
+    /// `available` of it.
+    fn reserved(&self, kind: ResourceKind, available: u64) -> u64 {
+        self.capacity(kind).saturating_sub(available)
+    }
+
+    /// Returns the current pressure level, based on the utilization of the
+    /// most utilized resource kind.
+    pub fn pressure(&self) -> ResourcePressure {
+        let max = ResourceKind::ALL
+            .into_iter()
+            .map(|kind| utilization(self.capacity(kind), self.available(kind)))
+            .fold(0.0, f64::max);
+
+        if max >= self.high_pressure as f64 {
+            ResourcePressure::High
+        } else if max >= self.medium_pressure as f64 {
+            ResourcePressure::Medium
+        } else {
+            ResourcePressure::Low
+        }
+    }
+
+    /// Whether the node has room for more work. Every task needs memory
+    /// and CPUs, so running out of either makes the node unhealthy, as does
+    /// draining. High pressure on any resource makes it degraded.
+    pub fn health(&self) -> HealthStatus {
+        let exhausted = [ResourceKind::Mem, ResourceKind::Cpus]
+            .into_iter()
+            .any(|kind| self.capacity(kind) > 0 && self.available(kind) == 0);
+
+        if exhausted || self.is_draining() {
+            HealthStatus::Unhealthy
+        } else if self.pressure() == ResourcePressure::High {
+            HealthStatus::Degraded
+        } else {
+            HealthStatus::Healthy
+        }
+    }
+
+    /// Memory the node was created with, not counting overcommit.
+    pub fn total_mem(&self) -> u64 {
+        self.total_mem.load(Ordering::SeqCst)
+    }
+
+    /// CPUs the node was created with (in millicores).
+    pub fn total_cpus(&self) -> u64 {
+        self.total_cpus.load(Ordering::SeqCst)
+    }
+
+    pub fn total_gpus(&self) -> u64 {
+        self.total_gpus.load(Ordering::SeqCst)
+    }
+
+    pub fn available_mem(&self) -> u64 {
+        self.available(ResourceKind::Mem)
+    }
+
+    /// CPUs not allocated (in millicores).
+    pub fn available_cpus(&self) -> u64 {
+        self.available(ResourceKind::Cpus)
+    }
+
+    /// Whether a live allocation was made with the affinity key `key`.
+    pub fn has_affinity(&self, key: AffinityKey) -> bool {
+        self.affinity.lock().contains_key(&key)
+    }
+
+    /// Number of live allocations made with the anti-affinity key `key`.
+    pub fn anti_affinity_count(&self, key: AffinityKey) -> usize {
+        self.anti_affinity.lock().get(&key).copied().unwrap_or(0)
+    }
+
+    pub fn available_gpus(&self) -> u64 {
+        self.available(ResourceKind::Gpus)
+    }
+
+    /// The largest request that could be allocated right now. Besides what
+    /// is available, this accounts for placement: when allocations must
+    /// fit on a single NUMA node, CPUs and memory are those of the node
+    /// with the most free, however much is free across nodes.
+    pub fn largest_allocatable(&self) -> ResourceRequest {
+        let available = |kind| self.available_to(kind, Tier::User);
+        let mut largest = ResourceRequest {
+            mem: available(ResourceKind::Mem),
+            cpus: available(ResourceKind::Cpus),
+            gpus: available(ResourceKind::Gpus),
+            gpu_mem: available(ResourceKind::GpuMem),
+            disk_bytes: available(ResourceKind::Disk),
+            net_bps: available(ResourceKind::Net),
+            ..zero_request()
+        };
+        if let Some((cpus, mem)) = self.numa.as_ref().and_then(|pools| pools.lock().largest()) {
+            largest.cpus = largest.cpus.min(cpus);
+            largest.mem = largest.mem.min(mem);
+        }
+        largest
+    }
+
+    pub fn snapshot(&self) -> ResourceSnapshot {
+        let available_mem = self.available(ResourceKind::Mem);
+        let available_cpus = self.available(ResourceKind::Cpus);
+        let available_gpus = self.available(ResourceKind::Gpus);
+        let mem_ceiling = self.mem_ceiling.load(Ordering::SeqCst);
+        ResourceSnapshot {
+            total_mem: mem_ceiling,
+            available_mem,
+            mem_utilization: utilization(mem_ceiling, available_mem),
+            total_cpus: self.total_cpus.load(Ordering::SeqCst),
+            available_cpus,
+            cpus_utilization: utilization(self.total_cpus.load(Ordering::SeqCst), available_cpus),
+            total_gpus: self.total_gpus.load(Ordering::SeqCst),
+            available_gpus,
+            gpus_utilization: utilization(self.total_gpus.load(Ordering::SeqCst), available_gpus),
+        }
+    }
+
+    /// Returns an error if this node could never satisfy `request`, even
+    /// with nothing allocated: GPUs are disabled, or it needs more of some
+    /// resource than the node could ever hand out to `tier`.
+    fn unsatisfiable(&self, request: &ResourceRequest, tier: Tier) -> Option<ResourceError> {
+        let request = &self.admitted(request);
+        if self.gpu_disabled && (request.gpus > 0 || request.gpu_mem > 0) {
+            return Some(ResourceError::GpuDisabled);
+        }
+
+        ResourceKind::ALL.into_iter().find_map(|kind| {
+            let capacity = match kind {
+                // The ceiling may be raised back up to the limit.
+                ResourceKind::Mem => self.mem_limit.load(Ordering::SeqCst),
+                kind => self.capacity(kind),
+            }
+            .saturating_sub(self.kept_from(kind, tier));
+            (kind.requested(request) > capacity).then_some(ResourceError::ExceedsCapacity {
+                kind,
+                requested: kind.requested(request),
+                capacity,
+            })
+        })
+    }
+
+    /// Returns the total amount of `kind` that can be allocated.
+    fn capacity(&self, kind: ResourceKind) -> u64 {
+        match kind {
+            ResourceKind::Mem => self.mem_ceiling.load(Ordering::SeqCst),
+            ResourceKind::Cpus => self.total_cpus.load(Ordering::SeqCst),
+            ResourceKind::Gpus => self.total_gpus.load(Ordering::SeqCst),
+            ResourceKind::GpuMem => self.total_gpu_mem.load(Ordering::SeqCst),
+            ResourceKind::Disk => self.total_disk.load(Ordering::SeqCst),
+            ResourceKind::Net => self.total_net.load(Ordering::SeqCst),
+        }
+    }
+
+    fn available_all(&self) -> [(ResourceKind, u64); 6] {
+        ResourceKind::ALL.map(|kind| (kind, self.available(kind)))
+    }
+
+    fn available_counter(&self, kind: ResourceKind) -> &AtomicU64 {
+        match kind {
+            ResourceKind::Mem => &self.available_mem,
+            ResourceKind::Cpus => &self.available_cpus,
+            ResourceKind::Gpus => &self.available_gpus,
+            ResourceKind::GpuMem => &self.available_gpu_mem,
+            ResourceKind::Disk => &self.available_disk,
+            ResourceKind::Net => &self.available_net,
+        }
+    }
+
+    /// Returns the currently available amount of `kind`.
+    fn available(&self, kind: ResourceKind) -> u64 {
+        self.available_counter(kind).load(Ordering::SeqCst)
+    }
+}
+
+/// Resources of a node, either detected on the host or configured. CPUs
+/// are in millicores, memory in MiB like in requests, network bandwidth
+/// in bits per second and the rest in bytes, or counts of devices.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DetectedResources {
+    pub mem: u64,
+    pub cpus: u64,
+    pub gpus: u64,
+    pub disk: u64,
+    pub gpu_mem: u64,
+    pub net: u64,
+}
+
+/// Counts one allocation less for `key` in `counts`, forgetting keys no
+/// allocation has left.
+fn uncount_key(counts: &Mutex<HashMap<AffinityKey, usize>>, key: Option<AffinityKey>) {
+    let Some(key) = key else {
+        return;
+    };
+    let mut counts = counts.lock();
+    if let Some(count) = counts.get_mut(&key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(&key);
+        }
+    }
+}
+
+/// Number of whole CPUs in `cpus` millicores.
+fn whole_cpus(cpus: u64) -> usize {
+    (cpus / MILLICORES_PER_CPU) as usize
+}
+
+/// Request for nothing at all, to start summing usage from.
+pub(super) fn zero_request() -> ResourceRequest {
+    ResourceRequest {
+        mem: 0,
+        cpus: 0,
+        ..Default::default()
+    }
+}
+
+/// Percentage of `total` that is not `available`. Nothing is in use when
+/// there is nothing to use.
+fn utilization(total: u64, available: u64) -> f64 {
+    if total == 0 {
+        return 0.0;
+    }
+    total.saturating_sub(available) as f64 / total as f64 * 100.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::clock::MockClock;
+    use crate::scheduler::host::tests::{run_config, FakeSystem};
+
+    fn assert_not_enough(
+        res: Result<ResourceAllocation>,
+        expected_kind: ResourceKind,
+        expected_requested: u64,
+        expected_available: u64,
+    ) {
+        let Err(err) = res else {
+            panic!("allocation should have failed");
+        };
+        match err.downcast_ref::<ResourceError>() {
+            Some(ResourceError::NotEnoughResources {
+                kind,
+                requested,
+                available,
+                ..
+            }) => {
+                assert_eq!(*kind, expected_kind);
+                assert_eq!(*requested, expected_requested);
+                assert_eq!(*available, expected_available);
+            }
+            _ => panic!("unexpected error: {err}"),
+        }
+    }
+
+    fn assert_exceeds_capacity(
+        res: Result<ResourceAllocation>,
+        expected_requested: u64,
+        expected_capacity: u64,
+    ) {
+        let Err(err) = res else {
+            panic!("allocation should have failed");
+        };
+        match err.downcast_ref::<ResourceError>() {
+            Some(ResourceError::ExceedsCapacity {
+                kind,
+                requested,
+                capacity,
+            }) => {
+                assert_eq!(*kind, ResourceKind::Mem);
+                assert_eq!(*requested, expected_requested);
+                assert_eq!(*capacity, expected_capacity);
+            }
+            _ => panic!("unexpected error: {err}"),
+        }
+    }
+
+    #[test]
+    fn test_try_allocate_succeeds() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+
+        let req = &ResourceRequest {
+            mem: 1024,
+            cpus: 1,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        ResourceManager::try_allocate(rm.clone(), req).unwrap();
+        ResourceManager::try_allocate(rm.clone(), req).unwrap();
+    }
+
+    #[test]
+    fn test_free_succeeds() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+
+        let req = &ResourceRequest {
+            mem: 2048,
+            cpus: 4,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        // Allocate all available resources.
+        let ra = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+
+        // Assert that we are out of resources.
+        let ra2 = ResourceManager::try_allocate(rm.clone(), req);
+        assert_not_enough(ra2, ResourceKind::Mem, 2048, 0);
+
+        drop(ra);
+
+        // Allocate again all available resources.
+        ResourceManager::try_allocate(rm.clone(), req).unwrap();
+    }
+
+    #[test]
+    fn test_try_allocate_fails_on_mem() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let held = &ResourceRequest {
+            mem: 1024,
+            cpus: 1,
+            gpus: 0,
+            ..Default::default()
+        };
+        let req = &ResourceRequest {
+            mem: 2048,
+            cpus: 2,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let _held = ResourceManager::try_allocate(rm.clone(), held).unwrap();
+        let ra = ResourceManager::try_allocate(rm, req);
+        assert_not_enough(ra, ResourceKind::Mem, 2048, 1024);
+    }
+
+    #[test]
+    fn test_try_allocate_fails_on_cpus() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let held = &ResourceRequest {
+            mem: 1,
+            cpus: 2,
+            gpus: 0,
+            ..Default::default()
+        };
+        let req = &ResourceRequest {
+            mem: 1024,
+            cpus: 4,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let _held = ResourceManager::try_allocate(rm.clone(), held).unwrap();
+        let ra = ResourceManager::try_allocate(rm, req);
+        assert_not_enough(ra, ResourceKind::Cpus, 4, 2);
+    }
+
+    #[test]
+    fn test_try_allocate_fails_on_gpus() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            gpus: 1,
+            ..Default::default()
+        }));
+        let req = &ResourceRequest {
+            mem: 1024,
+            cpus: 1,
+            gpus: 1,
+            ..Default::default()
+        };
+
+        let _held = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+        let ra = ResourceManager::try_allocate(rm, req);
+        assert_not_enough(ra, ResourceKind::Gpus, 1, 0);
+    }
+
+    #[test]
+    fn test_not_enough_resources_display() {
+        let err = ResourceError::NotEnoughResources {
+            kind: ResourceKind::Mem,
+            requested: 4096,
+            available: 2048,
+            deficits: vec![ResourceKind::Mem],
+            retry_after: None,
+        };
+        assert_eq!(
+            err.to_string(),
+            "not enough mem: requested 4096, available 2048"
+        );
+    }
+
+    #[test]
+    fn test_try_allocate_millicores() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4 * MILLICORES_PER_CPU,
+            ..Default::default()
+        }));
+        let req = &ResourceRequest {
+            mem: 1024,
+            cpus: 1500,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let _ra = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+        assert_eq!(rm.available(ResourceKind::Cpus), 2500);
+    }
+
+    #[test]
+    fn test_try_allocate_fails_on_disk() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            disk: 1024,
+            ..Default::default()
+        }));
+        let held = &ResourceRequest {
+            mem: 1,
+            cpus: 1,
+            gpus: 0,
+            disk_bytes: 512,
+            ..Default::default()
+        };
+        let req = &ResourceRequest {
+            mem: 1024,
+            cpus: 1,
+            gpus: 0,
+            disk_bytes: 1024,
+            ..Default::default()
+        };
+
+        let _held = ResourceManager::try_allocate(rm.clone(), held).unwrap();
+        let ra = ResourceManager::try_allocate(rm, req);
+        assert_not_enough(ra, ResourceKind::Disk, 1024, 512);
+    }
+
+    #[test]
+    fn test_free_returns_disk() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            disk: 1024,
+            ..Default::default()
+        }));
+        let req = &ResourceRequest {
+            mem: 1024,
+            cpus: 1,
+            gpus: 0,
+            disk_bytes: 1024,
+            ..Default::default()
+        };
+
+        let ra = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+        assert!(ResourceManager::try_allocate(rm.clone(), req).is_err());
+
+        drop(ra);
+
+        ResourceManager::try_allocate(rm.clone(), req).unwrap();
+    }
+
+    #[test]
+    fn test_try_allocate_fails_on_network() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 4096,
+            cpus: 4,
+            net: 1000,
+            ..Default::default()
+        }));
+        let req = &ResourceRequest {
+            mem: 1024,
+            cpus: 1,
+            gpus: 0,
+            net_bps: 400,
+            ..Default::default()
+        };
+
+        let ra = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+        let _ra2 = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+        assert!(ResourceManager::try_allocate(rm.clone(), req).is_err());
+
+        drop(ra);
+
+        ResourceManager::try_allocate(rm.clone(), req).unwrap();
+    }
+
+    #[test]
+    fn test_gpu_mem_is_shared() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 4096,
+            cpus: 4,
+            gpus: 1,
+            gpu_mem: 40,
+            ..Default::default()
+        }));
+        let req = &ResourceRequest {
+            mem: 1024,
+            cpus: 1,
+            gpus: 0,
+            gpu_mem: 20,
+            ..Default::default()
+        };
+
+        // Two tasks fit on the same device...
+        let ra = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+        let _ra2 = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+
+        // ...but the third one doesn't.
+        assert!(ResourceManager::try_allocate(rm.clone(), req).is_err());
+
+        drop(ra);
+
+        ResourceManager::try_allocate(rm.clone(), req).unwrap();
+    }
+
+    #[test]
+    fn test_gpu_mem_fraction() {
+        const GIB: u64 = 1024 * 1024 * 1024;
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 4096,
+            cpus: 4000,
+            gpus: 1,
+            gpu_mem: 40 * GIB,
+            ..Default::default()
+        }));
+        let req = &ResourceRequest {
+            mem: 1024,
+            cpus: 1000,
+            gpus: 1,
+            gpu_mem_fraction: Some(0.5),
+            ..Default::default()
+        };
+
+        let ra = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+        assert_eq!(ra.held(ResourceKind::GpuMem), 20 * GIB);
+        assert_eq!(rm.available(ResourceKind::GpuMem), 20 * GIB);
+        drop(ra);
+
+        let mixed = &ResourceRequest {
+            gpu_mem: GIB,
+            ..*req
+        };
+        let Err(err) = ResourceManager::try_allocate(rm.clone(), mixed) else {
+            panic!("allocation should have failed");
+        };
+        assert!(matches!(
+            err.downcast_ref::<ResourceError>(),
+            Some(ResourceError::InvalidRequest(RequestError::MixedGpuMem))
+        ));
+        assert_eq!(rm.available(ResourceKind::GpuMem), 40 * GIB);
+    }
+
+    #[tokio::test]
+    async fn test_allocate_waits_for_free() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let req = ResourceRequest {
+            mem: 2048,
+            cpus: 4,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let ra = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+
+        let waiter = tokio::spawn({
+            let rm = rm.clone();
+            async move { ResourceManager::allocate(rm, &req).await }
+        });
+
+        // The waiter can't proceed while resources are held.
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+
+        drop(ra);
+
+        waiter.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_allocate_records_wait_time() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let req = ResourceRequest {
+            mem: 2048,
+            cpus: 4,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let ra = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+        let waiter = tokio::spawn({
+            let rm = rm.clone();
+            async move { ResourceManager::allocate(rm, &req).await }
+        });
+
+        // Histogram is global, so other tests may record into it as well.
+        let recorded = metrics::ALLOCATION_WAIT_SECONDS.get_sample_sum();
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        drop(ra);
+        waiter.await.unwrap().unwrap();
+
+        assert!(metrics::ALLOCATION_WAIT_SECONDS.get_sample_sum() - recorded >= 3.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_aging_serves_long_waiting_request() {
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 2048,
+                cpus: 4,
+                ..Default::default()
+            })
+            .with_aging_rate(0.1),
+        );
+        let req = |priority| ResourceRequest {
+            mem: 2048,
+            cpus: 4,
+            gpus: 0,
+            priority,
+            ..Default::default()
+        };
+        let spawn_waiter = |priority| {
+            let rm = rm.clone();
+            tokio::spawn(async move { ResourceManager::allocate(rm, &req(priority)).await })
+        };
+
+        let ra = ResourceManager::try_allocate(rm.clone(), &req(0)).unwrap();
+        let low = spawn_waiter(1);
+        tokio::time::sleep(Duration::from_secs(60)).await;
+
+        // After a minute, the low priority request has aged to priority 7.
+        let medium = spawn_waiter(5);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(ra);
+
+        let ra = low.await.unwrap().unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!medium.is_finished());
+
+        drop(ra);
+        medium.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_higher_priority_waiter_goes_first() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let req = |priority| ResourceRequest {
+            mem: 2048,
+            cpus: 4,
+            gpus: 0,
+            priority,
+            ..Default::default()
+        };
+        let spawn_waiter = |priority| {
+            let rm = rm.clone();
+            tokio::spawn(async move { ResourceManager::allocate(rm, &req(priority)).await })
+        };
+
+        // Without aging, waiting longer doesn't help.
+        let ra = ResourceManager::try_allocate(rm.clone(), &req(0)).unwrap();
+        let low = spawn_waiter(1);
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        let medium = spawn_waiter(5);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(ra);
+
+        let ra = medium.await.unwrap().unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!low.is_finished());
+
+        drop(ra);
+        low.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_equal_priority_waiters_are_served_in_order() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let req = ResourceRequest {
+            mem: 2048,
+            cpus: 4,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let mut ra = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+        let mut waiters = std::collections::VecDeque::new();
+        for _ in 0..3 {
+            let rm = rm.clone();
+            waiters.push_back(tokio::spawn(async move {
+                ResourceManager::allocate(rm, &req).await
+            }));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        while let Some(waiter) = waiters.pop_front() {
+            drop(ra);
+            ra = waiter.await.unwrap().unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert!(waiters.iter().all(|other| !other.is_finished()));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_waiter_that_fits_skips_ahead() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let half = ResourceRequest {
+            mem: 1024,
+            cpus: 2,
+            gpus: 0,
+            ..Default::default()
+        };
+        let all = ResourceRequest {
+            mem: 2048,
+            cpus: 4,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let ra1 = ResourceManager::try_allocate(rm.clone(), &half).unwrap();
+        let ra2 = ResourceManager::try_allocate(rm.clone(), &half).unwrap();
+        let large = tokio::spawn({
+            let rm = rm.clone();
+            async move { ResourceManager::allocate(rm, &all).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let small = tokio::spawn({
+            let rm = rm.clone();
+            async move { ResourceManager::allocate(rm, &half).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        // Half of the resources don't fit the older, large request, but they
+        // do fit the younger, small one.
+        drop(ra1);
+        let ra3 = small.await.unwrap().unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!large.is_finished());
+
+        drop(ra2);
+        drop(ra3);
+        large.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drop_records_lifetime() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let req = &ResourceRequest {
+            mem: 1024,
+            cpus: 1,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        // Histogram is global, so other tests may record into it as well.
+        let recorded = metrics::ALLOCATION_LIFETIME_SECONDS.get_sample_sum();
+        let ra = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+        tokio::time::sleep(Duration::from_secs(90)).await;
+        drop(ra);
+
+        // Allow for rounding, as the sum includes other tests' samples.
+        assert!(metrics::ALLOCATION_LIFETIME_SECONDS.get_sample_sum() - recorded >= 90.0 - 1e-6);
+    }
+
+    #[tokio::test]
+    async fn test_gpu_request_fails_when_gpu_disabled() {
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 2048,
+                cpus: 4,
+                ..Default::default()
+            })
+            .with_gpu_disabled(true),
+        );
+        let req = ResourceRequest {
+            mem: 1024,
+            cpus: 1,
+            gpus: 1,
+            ..Default::default()
+        };
+
+        let Err(err) = ResourceManager::allocate(rm.clone(), &req).await else {
+            panic!("allocation should have failed");
+        };
+        assert!(matches!(
+            err.downcast_ref::<ResourceError>(),
+            Some(ResourceError::GpuDisabled)
+        ));
+
+        let gpu_mem = ResourceRequest {
+            gpus: 0,
+            gpu_mem: 1024,
+            ..req
+        };
+        assert!(ResourceManager::try_allocate(rm.clone(), &gpu_mem).is_err());
+
+        // Requests without GPUs are not affected.
+        let cpu_only = ResourceRequest { gpus: 0, ..req };
+        ResourceManager::try_allocate(rm, &cpu_only).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_allocate_fails_on_request_exceeding_capacity() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let req = &ResourceRequest {
+            mem: 4096,
+            cpus: 1,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        assert_exceeds_capacity(ResourceManager::allocate(rm, req).await, 4096, 2048);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_allocate_does_not_break_waiters() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let req = ResourceRequest {
+            mem: 2048,
+            cpus: 4,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let ra = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+
+        // Cancel one waiter by timing it out.
+        let cancelled = tokio::time::timeout(
+            std::time::Duration::from_millis(10),
+            ResourceManager::allocate(rm.clone(), &req),
+        )
+        .await;
+        assert!(cancelled.is_err());
+
+        let waiter = tokio::spawn({
+            let rm = rm.clone();
+            async move { ResourceManager::allocate(rm, &req).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        drop(ra);
+
+        waiter.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_poll_recovers_missed_wakeup() {
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 2048,
+                cpus: 4,
+                ..Default::default()
+            })
+            .with_poll_interval(Duration::from_millis(250)),
+        );
+        let req = ResourceRequest {
+            mem: 2048,
+            cpus: 4,
+            gpus: 0,
+            ..Default::default()
+        };
+        let ra = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+
+        let waiter = tokio::spawn({
+            let rm = rm.clone();
+            async move { ResourceManager::allocate(rm, &req).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        // Free the resources without waking anyone up.
+        std::mem::forget(ra);
+        rm.give_back(&req, &ResourceKind::ALL);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!waiter.is_finished());
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(waiter.is_finished());
+        waiter.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cancelled_waiter_lets_next_proceed() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let req = ResourceRequest {
+            mem: 2048,
+            cpus: 4,
+            gpus: 0,
+            ..Default::default()
+        };
+        let ra = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+
+        let cancel = CancellationToken::new();
+        let first = tokio::spawn({
+            let (rm, cancel) = (rm.clone(), cancel.clone());
+            async move { ResourceManager::allocate_cancellable(rm, &req, &cancel).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let second = tokio::spawn({
+            let rm = rm.clone();
+            async move { ResourceManager::allocate(rm, &req).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(rm.waiters.lock().len(), 2);
+
+        cancel.cancel();
+        let Err(err) = first.await.unwrap() else {
+            panic!("allocation should have been cancelled");
+        };
+        assert!(matches!(
+            err.downcast_ref::<ResourceError>(),
+            Some(ResourceError::Cancelled)
+        ));
+        assert_eq!(rm.waiters.lock().len(), 1);
+        assert_eq!(rm.available_mem(), 0);
+
+        drop(ra);
+        let ra = second.await.unwrap().unwrap();
+        assert_eq!(rm.available_mem(), 0);
+        drop(ra);
+        assert_eq!(rm.available_mem(), 2048);
+        assert!(rm.waiters.lock().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_allocate_timeout() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let half = &ResourceRequest {
+            mem: 1024,
+            cpus: 2,
+            gpus: 0,
+            ..Default::default()
+        };
+        let all = &ResourceRequest {
+            mem: 2048,
+            cpus: 4,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let ra1 = ResourceManager::try_allocate(rm.clone(), half).unwrap();
+        let _ra2 = ResourceManager::try_allocate(rm.clone(), half).unwrap();
+
+        let waiter = tokio::spawn({
+            let rm = rm.clone();
+            let all = *all;
+            async move { ResourceManager::allocate_timeout(rm, &all, Duration::from_secs(10)).await }
+        });
+
+        // Partial free isn't enough to satisfy the waiter, nor does it
+        // restart the timeout.
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        drop(ra1);
+
+        let Err(err) = waiter.await.unwrap() else {
+            panic!("allocation should have timed out");
+        };
+        match err.downcast_ref::<ResourceError>() {
+            Some(ResourceError::Timeout(waited)) => assert_eq!(*waited, Duration::from_secs(10)),
+            _ => panic!("unexpected error: {err}"),
+        }
+    }
+
+    #[test]
+    fn test_try_allocate_preempt_finds_lower_priority_allocation() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let background = &ResourceRequest {
+            mem: 1024,
+            cpus: 2,
+            gpus: 0,
+            priority: 1,
+            ..Default::default()
+        };
+        let paying = &ResourceRequest {
+            mem: 2048,
+            cpus: 2,
+            gpus: 0,
+            priority: 10,
+            ..Default::default()
+        };
+
+        let ra = ResourceManager::try_allocate(rm.clone(), background).unwrap();
+
+        match ResourceManager::try_allocate_preempt(rm.clone(), paying).unwrap() {
+            Preemption::Candidates(ids) => assert_eq!(ids, vec![ra.id()]),
+            Preemption::Allocated(_) => panic!("request should not fit without preemption"),
+        }
+
+        // Nothing is freed until the caller drops the candidate.
+        assert!(ResourceManager::try_allocate(rm.clone(), paying).is_err());
+        drop(ra);
+
+        match ResourceManager::try_allocate_preempt(rm.clone(), paying).unwrap() {
+            Preemption::Allocated(_) => {}
+            Preemption::Candidates(_) => panic!("request should fit after preemption"),
+        }
+    }
+
+    #[test]
+    fn test_try_allocate_preempt_skips_higher_priority_allocations() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let req = &ResourceRequest {
+            mem: 2048,
+            cpus: 4,
+            gpus: 0,
+            priority: 5,
+            ..Default::default()
+        };
+
+        let _ra = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+
+        assert!(ResourceManager::try_allocate_preempt(rm.clone(), req).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_allocations_total() {
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 4096,
+                cpus: 4000,
+                ..Default::default()
+            })
+            .with_pool_name("test-allocations-total".to_string()),
+        );
+        let req = ResourceRequest {
+            mem: 2048,
+            cpus: 1000,
+            gpus: 0,
+            ..Default::default()
+        };
+        let count = |result| {
+            metrics::ALLOCATIONS_TOTAL
+                .with_label_values(&["test-allocations-total", result])
+                .get()
+        };
+
+        let _ra1 = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+        let ra2 = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+        assert!(ResourceManager::try_allocate(rm.clone(), &req).is_err());
+        assert!(ResourceManager::try_allocate(rm.clone(), &req).is_err());
+        assert_eq!((count("success"), count("failure")), (2, 2));
+
+        // A request served after waiting counts once.
+        let waiter = tokio::spawn({
+            let rm = rm.clone();
+            async move { ResourceManager::allocate(rm, &req).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        drop(ra2);
+        let _ra3 = waiter.await.unwrap().unwrap();
+        assert_eq!((count("success"), count("failure")), (3, 2));
+
+        let too_big = ResourceRequest { mem: 8192, ..req };
+        assert!(ResourceManager::allocate(rm.clone(), &too_big)
+            .await
+            .is_err());
+        assert_eq!((count("success"), count("failure")), (3, 3));
+    }
+
+    #[test]
+    fn test_burstable_allocation() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 4096,
+            cpus: 4000,
+            ..Default::default()
+        }));
+        let req = ResourceRequest::builder()
+            .mem(1024)
+            .mem_limit(3072)
+            .cpus(1000)
+            .cpus_limit(4000)
+            .build()
+            .unwrap();
+
+        // Only the guaranteed amounts are taken.
+        let ra = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+        assert_eq!(rm.available_mem(), 3072);
+        assert_eq!(rm.available_cpus(), 3000);
+        assert_eq!((ra.mem(), ra.mem_limit()), (1024, 3072));
+        assert_eq!((ra.cpus(), ra.cpus_limit()), (1000, 4000));
+
+        // Without limits, the limit is what's held.
+        let fixed = ResourceManager::try_allocate(
+            rm.clone(),
+            &ResourceRequest {
+                mem: 512,
+                cpus: 500,
+                gpus: 0,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(fixed.mem_limit(), 512);
+
+        let headroom = rm.burstable_headroom();
+        assert_eq!((headroom.mem, headroom.cpus), (2048, 3000));
+
+        let held = ra.release();
+        assert_eq!((held.mem, held.mem_limit), (1024, Some(3072)));
+        drop(fixed);
+        assert_eq!(rm.burstable_headroom().mem, 0);
+    }
+
+    #[test]
+    fn test_system_reserve() {
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 4096,
+                cpus: 4000,
+                ..Default::default()
+            })
+            .with_system_reserve(ResourceRequest {
+                mem: 512,
+                cpus: 500,
+                gpus: 0,
+                ..Default::default()
+            }),
+        );
+        let req = |mem, cpus| ResourceRequest {
+            mem,
+            cpus,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        // Users can't go past the reserve, nor ask for more than what's
+        // outside it.
+        let _user = ResourceManager::try_allocate(rm.clone(), &req(3072, 3000)).unwrap();
+        assert!(!rm.can_allocate(&req(1024, 500)));
+        assert_not_enough(
+            ResourceManager::try_allocate(rm.clone(), &req(1024, 500)),
+            ResourceKind::Mem,
+            1024,
+            512,
+        );
+        assert_exceeds_capacity(
+            ResourceManager::try_allocate(rm.clone(), &req(4096, 500)),
+            4096,
+            3584,
+        );
+
+        // The node's own tasks may take the reserve.
+        let system = ResourceManager::try_allocate_system(rm.clone(), &req(1024, 1000)).unwrap();
+        assert_eq!(rm.available_mem(), 0);
+        assert_eq!(rm.available_cpus(), 0);
+        assert!(ResourceManager::try_allocate_system(rm.clone(), &req(1, 1)).is_err());
+
+        drop(system);
+        assert_eq!(rm.available_mem(), 1024);
+        assert_eq!(
+            rm.schedulable(&[req(512, 500), req(1, 1)]),
+            vec![true, false]
+        );
+    }
+
+    #[test]
+    fn test_health() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 4096,
+            cpus: 4000,
+            ..Default::default()
+        }));
+        let req = |mem| ResourceRequest {
+            mem,
+            cpus: 1000,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        // Empty.
+        assert_eq!(rm.health(), HealthStatus::Healthy);
+
+        // Half full.
+        let half = ResourceManager::try_allocate(rm.clone(), &req(2048)).unwrap();
+        assert_eq!(rm.health(), HealthStatus::Healthy);
+
+        // High pressure.
+        let high = ResourceManager::try_allocate(rm.clone(), &req(1792)).unwrap();
+        assert_eq!(rm.pressure(), ResourcePressure::High);
+        assert_eq!(rm.health(), HealthStatus::Degraded);
+
+        // Full.
+        let full = ResourceManager::try_allocate(rm.clone(), &req(256)).unwrap();
+        assert_eq!(rm.health(), HealthStatus::Unhealthy);
+
+        drop((half, high, full));
+        assert_eq!(rm.health(), HealthStatus::Healthy);
+        rm.drain();
+        assert_eq!(rm.health(), HealthStatus::Unhealthy);
+    }
+
+    #[test]
+    fn test_error_status_codes() {
+        let status = |err: &ResourceError| (StatusCode::from(err), err.retry_after_header());
+
+        assert_eq!(
+            status(&ResourceError::NotEnoughResources {
+                kind: ResourceKind::Mem,
+                requested: 4096,
+                available: 2048,
+                deficits: vec![ResourceKind::Mem],
+                retry_after: Some(Duration::from_millis(4500)),
+            }),
+            (StatusCode::SERVICE_UNAVAILABLE, Some(HeaderValue::from(5)))
+        );
+        assert_eq!(
+            status(&ResourceError::Draining),
+            (StatusCode::SERVICE_UNAVAILABLE, Some(HeaderValue::from(60)))
+        );
+        assert_eq!(
+            status(&ResourceError::ExceedsCapacity {
+                kind: ResourceKind::Mem,
+                requested: 4096,
+                capacity: 2048,
+            }),
+            (StatusCode::BAD_REQUEST, None)
+        );
+        assert_eq!(
+            status(&ResourceError::QuotaExceeded {
+                kind: ResourceKind::Cpus,
+                requested: 2000,
+                remaining: 1000,
+            }),
+            (StatusCode::BAD_REQUEST, None)
+        );
+        assert_eq!(
+            status(&ResourceError::Timeout(Duration::from_secs(30))),
+            (StatusCode::GATEWAY_TIMEOUT, None)
+        );
+        assert_eq!(
+            status(&ResourceError::UnderPressure(ResourcePressure::High)),
+            (StatusCode::SERVICE_UNAVAILABLE, None)
+        );
+        assert_eq!(
+            status(&ResourceError::GpuDisabled),
+            (StatusCode::BAD_REQUEST, None)
+        );
+        assert_eq!(
+            status(&ResourceError::LeaseExpired),
+            (StatusCode::GONE, None)
+        );
+    }
+
+    #[test]
+    fn test_retry_after_default() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let req = &ResourceRequest {
+            mem: 2048,
+            cpus: 4,
+            gpus: 0,
+            ..Default::default()
+        };
+        let _ra = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+        let Err(err) = ResourceManager::try_allocate(rm.clone(), req) else {
+            panic!("allocation should have failed");
+        };
+        assert_eq!(
+            err.downcast_ref::<ResourceError>().unwrap().retry_after(),
+            None
+        );
+
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 2048,
+                cpus: 4,
+                ..Default::default()
+            })
+            .with_default_retry_after(Duration::from_millis(500)),
+        );
+        let _ra = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+        let Err(err) = ResourceManager::try_allocate(rm.clone(), req) else {
+            panic!("allocation should have failed");
+        };
+        assert_eq!(
+            err.downcast_ref::<ResourceError>().unwrap().retry_after(),
+            Some(Duration::from_millis(500))
+        );
+    }
+
+    #[test]
+    fn test_mem_overcommit() {
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 2048,
+                cpus: 4,
+                ..Default::default()
+            })
+            .with_mem_overcommit(2.0),
+        );
+        let req = &ResourceRequest {
+            mem: 3000,
+            cpus: 1,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let _ra = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+        assert_eq!(rm.available(ResourceKind::Mem), 4096 - 3000);
+    }
+
+    #[test]
+    fn test_mem_overcommit_below_one_is_ignored() {
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 2048,
+                cpus: 4,
+                ..Default::default()
+            })
+            .with_mem_overcommit(0.5),
+        );
+        let req = &ResourceRequest {
+            mem: 2048,
+            cpus: 1,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        ResourceManager::try_allocate(rm.clone(), req).unwrap();
+    }
+
+    #[test]
+    fn test_mem_overcommit_replaces_ratio() {
+        let rm = ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        });
+
+        let rm = rm.with_mem_overcommit(2.0).with_mem_overcommit(1.5);
+        assert_eq!(rm.available_mem(), 3072);
+        let rm = rm.with_mem_overcommit(f64::NAN);
+        assert_eq!(rm.available_mem(), 2048);
+    }
+
+    #[test]
+    fn test_snapshot_utilization() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let req = &ResourceRequest {
+            mem: 1024,
+            cpus: 1,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let _ra = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+        let snapshot = rm.snapshot();
+        assert_eq!(snapshot.total_mem, 2048);
+        assert_eq!(snapshot.available_mem, 1024);
+        assert_eq!(snapshot.mem_utilization, 50.0);
+        assert_eq!(snapshot.cpus_utilization, 25.0);
+        assert_eq!(snapshot.gpus_utilization, 0.0);
+    }
+
+    #[test]
+    fn test_list_allocations() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let req = &ResourceRequest {
+            mem: 1024,
+            cpus: 1,
+            gpus: 0,
+            ..Default::default()
+        };
+        let program_id = Hash::default();
+        let task_id = TaskId::new_v4();
+
+        let ra1 = ResourceManager::try_allocate_for(
+            rm.clone(),
+            req,
+            Some(program_id),
+            Some(task_id),
+            None,
+        )
+        .unwrap();
+        let ra2 = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+
+        let allocations = rm.list_allocations();
+        assert_eq!(allocations.len(), 2);
+        assert_eq!(allocations[0].id, ra1.id());
+        assert_eq!(allocations[0].program_id, Some(program_id));
+        assert_eq!(allocations[0].task_id, Some(task_id));
+        assert_eq!(allocations[0].mem, 1024);
+        assert_eq!(allocations[0].cpus, 1);
+        assert_eq!(allocations[1].id, ra2.id());
+        assert_eq!(allocations[1].program_id, None);
+
+        drop(ra1);
+        let allocations = rm.list_allocations();
+        assert_eq!(allocations.len(), 1);
+        assert_eq!(allocations[0].id, ra2.id());
+    }
+
+    #[test]
+    fn test_panicking_task_cleans_up_allocation() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let req = ResourceRequest {
+            mem: 1024,
+            cpus: 1,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let task_rm = rm.clone();
+        let res = std::thread::spawn(move || {
+            let _ra = ResourceManager::try_allocate(task_rm, &req).unwrap();
+            panic!("task failed");
+        })
+        .join();
+        assert!(res.is_err());
+
+        assert!(rm.list_allocations().is_empty());
+        assert_eq!(rm.available(ResourceKind::Mem), 2048);
+    }
+
+    #[test]
+    fn test_double_free_is_ignored() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let req = &ResourceRequest {
+            mem: 1024,
+            cpus: 1,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let ra = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+        rm.free(&ra);
+        rm.free(&ra);
+        drop(ra);
+
+        assert_eq!(rm.available(ResourceKind::Mem), 2048);
+        assert_eq!(rm.available(ResourceKind::Cpus), 4);
+    }
+
+    #[test]
+    fn test_over_free_is_capped_at_total() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let other = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 8192,
+            cpus: 16,
+            ..Default::default()
+        }));
+        let req = &ResourceRequest {
+            mem: 1024,
+            cpus: 1,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let _ra = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+        // Freeing more than was taken, as held by an allocation of another
+        // manager.
+        let foreign =
+            ResourceManager::try_allocate(other, &ResourceRequest { mem: 4096, ..*req }).unwrap();
+        rm.free(&foreign);
+
+        assert_eq!(rm.available_mem(), rm.total_mem());
+        assert_eq!(rm.available_cpus(), rm.total_cpus());
+    }
+
+    #[test]
+    fn test_concurrent_allocate_and_free_reconcile() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 4096,
+            cpus: 8,
+            ..Default::default()
+        }));
+        let held_mem = Arc::new(AtomicU64::new(0));
+        let held_cpus = Arc::new(AtomicU64::new(0));
+
+        let threads: Vec<_> = (0..16)
+            .map(|_| {
+                let rm = rm.clone();
+                let held_mem = held_mem.clone();
+                let held_cpus = held_cpus.clone();
+                std::thread::spawn(move || {
+                    let req = &ResourceRequest {
+                        mem: 1024,
+                        cpus: 3,
+                        gpus: 0,
+                        ..Default::default()
+                    };
+                    for _ in 0..1000 {
+                        if let Ok(ra) = ResourceManager::try_allocate(rm.clone(), req) {
+                            // Never more than the totals may be handed out.
+                            assert!(
+                                held_mem.fetch_add(req.mem, Ordering::SeqCst) + req.mem <= 4096
+                            );
+                            assert!(
+                                held_cpus.fetch_add(req.cpus, Ordering::SeqCst) + req.cpus <= 8
+                            );
+                            held_mem.fetch_sub(req.mem, Ordering::SeqCst);
+                            held_cpus.fetch_sub(req.cpus, Ordering::SeqCst);
+                            drop(ra);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(rm.available(ResourceKind::Mem), 4096);
+        assert_eq!(rm.available(ResourceKind::Cpus), 8);
+        assert!(rm.list_allocations().is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_readers_do_not_block() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let req = &ResourceRequest {
+            mem: 1024,
+            cpus: 1,
+            gpus: 0,
+            ..Default::default()
+        };
+        let _ra = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+
+        // Hold a read lock for the whole test; readers must still get through.
+        let _guard = rm.allocations.read();
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        for _ in 0..8 {
+            let rm = rm.clone();
+            let tx = tx.clone();
+            std::thread::spawn(move || {
+                let allocations = rm.list_allocations();
+                let snapshot = rm.snapshot();
+                tx.send((allocations.len(), snapshot.available_mem))
+                    .unwrap();
+            });
+        }
+
+        for _ in 0..8 {
+            let res = rx.recv_timeout(Duration::from_secs(5));
+            assert_eq!(res, Ok((1, 1024)));
+        }
+    }
+
+    #[test]
+    fn test_assigned_gpus_are_returned_on_free() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 4096,
+            cpus: 4,
+            gpus: 4,
+            ..Default::default()
+        }));
+        let req = |gpus| ResourceRequest {
+            mem: 1,
+            cpus: 1,
+            gpus,
+            ..Default::default()
+        };
+
+        let ra1 = ResourceManager::try_allocate(rm.clone(), &req(2)).unwrap();
+        let ra2 = ResourceManager::try_allocate(rm.clone(), &req(1)).unwrap();
+        assert_eq!(ra1.assigned_gpus(), &[0, 1]);
+        assert_eq!(ra2.assigned_gpus(), &[2]);
+
+        drop(ra1);
+        let ra3 = ResourceManager::try_allocate(rm.clone(), &req(3)).unwrap();
+        assert_eq!(ra3.assigned_gpus(), &[0, 1, 3]);
+    }
+
+    #[test]
+    fn test_concurrent_gpu_assignments_are_disjoint() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 4096,
+            cpus: 16,
+            gpus: 8,
+            ..Default::default()
+        }));
+        let barrier = Arc::new(std::sync::Barrier::new(8));
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let rm = rm.clone();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    let req = &ResourceRequest {
+                        mem: 1,
+                        cpus: 1,
+                        gpus: 1,
+                        ..Default::default()
+                    };
+                    let mut assigned = vec![];
+                    for _ in 0..100 {
+                        barrier.wait();
+                        let ra = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+                        assigned.push(ra.assigned_gpus().to_vec());
+                        // Hold on to the allocation until everyone has one.
+                        barrier.wait();
+                    }
+                    assigned
+                })
+            })
+            .collect();
+
+        let mut rounds = vec![BTreeSet::new(); 100];
+        for thread in threads {
+            for (round, assigned) in thread.join().unwrap().into_iter().enumerate() {
+                assert_eq!(assigned.len(), 1);
+                assert!(rounds[round].insert(assigned[0]), "GPU assigned twice");
+            }
+        }
+        assert_eq!(rm.available(ResourceKind::Gpus), 8);
+    }
+
+    #[test]
+    fn test_gpu_pinning() {
+        let t4 = Uuid::new_v4();
+        let a100 = Uuid::new_v4();
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 4096,
+                cpus: 4,
+                gpus: 2,
+                ..Default::default()
+            })
+            .with_gpu_uuids(vec![t4, a100]),
+        );
+        let req = |gpu_uuid| ResourceRequest {
+            mem: 1,
+            cpus: 1,
+            gpus: 1,
+            gpu_uuid,
+            ..Default::default()
+        };
+
+        let ra1 = ResourceManager::try_allocate(rm.clone(), &req(Some(a100))).unwrap();
+        assert_eq!(ra1.assigned_gpus(), &[1]);
+
+        let Err(err) = ResourceManager::try_allocate(rm.clone(), &req(Some(a100))) else {
+            panic!("allocation should have failed");
+        };
+        assert!(matches!(
+            err.downcast_ref::<ResourceError>(),
+            Some(ResourceError::GpuBusy(uuid)) if *uuid == a100
+        ));
+        assert_eq!(rm.available(ResourceKind::Gpus), 1);
+
+        let ra2 = ResourceManager::try_allocate(rm.clone(), &req(None)).unwrap();
+        assert_eq!(ra2.assigned_gpus(), &[0]);
+
+        drop(ra1);
+        let Err(err) = ResourceManager::try_allocate(rm.clone(), &req(Some(Uuid::new_v4()))) else {
+            panic!("allocation should have failed");
+        };
+        assert!(matches!(
+            err.downcast_ref::<ResourceError>(),
+            Some(ResourceError::UnknownGpu(_))
+        ));
+        assert_eq!(rm.available(ResourceKind::Gpus), 1);
+
+        let ra3 = ResourceManager::try_allocate(rm.clone(), &req(Some(a100))).unwrap();
+        assert_eq!(ra3.assigned_gpus(), &[1]);
+    }
+
+    #[test]
+    fn test_can_allocate() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            gpus: 1,
+            ..Default::default()
+        }));
+        let req = |gpus| ResourceRequest {
+            mem: 1024,
+            cpus: 1,
+            gpus,
+            ..Default::default()
+        };
+
+        assert!(rm.can_allocate(&req(1)));
+        assert!(!rm.can_allocate(&req(2)));
+        assert_eq!(rm.available(ResourceKind::Mem), 2048);
+        assert_eq!(rm.available(ResourceKind::Gpus), 1);
+
+        let _ra = ResourceManager::try_allocate(rm.clone(), &req(1)).unwrap();
+        assert!(!rm.can_allocate(&req(1)));
+        assert!(rm.can_allocate(&req(0)));
+    }
+
+    #[test]
+    fn test_refresh_free_system_mem() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 4096,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let req = &ResourceRequest {
+            mem: 3072,
+            cpus: 1,
+            gpus: 0,
+            ..Default::default()
+        };
+        let ra = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+
+        // Less free on the system than there's available in the pool.
+        rm.set_free_system_mem(512);
+        assert_eq!(rm.available(ResourceKind::Mem), 512);
+        assert_eq!(rm.capacity(ResourceKind::Mem), 3584);
+
+        // System is out of memory; available bottoms out at zero.
+        rm.set_free_system_mem(0);
+        assert_eq!(rm.available(ResourceKind::Mem), 0);
+        assert_eq!(rm.capacity(ResourceKind::Mem), 3072);
+
+        drop(ra);
+        assert_eq!(rm.available(ResourceKind::Mem), 3072);
+
+        // Memory freed up on the system, but never above the limit.
+        rm.set_free_system_mem(8192);
+        assert_eq!(rm.available(ResourceKind::Mem), 4096);
+        assert_eq!(rm.capacity(ResourceKind::Mem), 4096);
+    }
+
+    #[test]
+    fn test_reserve_system_config() {
+        let config = run_config(&[]);
+        assert_eq!(
+            (config.reserve_system.mem, config.reserve_system.cpus),
+            (0, 0)
+        );
+
+        let config = run_config(&["--reserve-system", "mem=512,cpus=0.5"]);
+        assert_eq!(config.reserve_system.mem, 512);
+        assert_eq!(config.reserve_system.cpus, 500);
+        assert_eq!(config.reserve_system.gpus, 0);
+
+        // The reserve is in MiB, as is the memory of the node.
+        let sys = FakeSystem {
+            mem: Some(16 * 1024 * 1024 * 1024),
+            cpus: 8,
+            container_mem: None,
+            container_cpus: None,
+        };
+        let config = run_config(&["--reserve-mem-mb", "0", "--reserve-system", "mem=512"]);
+        let rm = ResourceManager::from_system_config(&config, &sys).unwrap();
+        let req = |mem| ResourceRequest {
+            mem,
+            cpus: 1000,
+            gpus: 0,
+            ..Default::default()
+        };
+        assert_exceeds_capacity(
+            ResourceManager::try_allocate(rm.clone(), &req(16 * 1024 - 511)),
+            16 * 1024 - 511,
+            16 * 1024 - 512,
+        );
+        let _ra = ResourceManager::try_allocate(rm.clone(), &req(16 * 1024 - 512)).unwrap();
+        let _system = ResourceManager::try_allocate_system(rm.clone(), &req(512)).unwrap();
+
+        use clap::Parser;
+        for invalid in ["mem", "mem=lots", "cpus=-1", "disk=1"] {
+            let args = ["gevulot", "run", "--reserve-system", invalid];
+            assert!(crate::cli::Cli::try_parse_from(args).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_totals_stay_fixed() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 4096,
+            cpus: 4 * MILLICORES_PER_CPU,
+            gpus: 2,
+            ..Default::default()
+        }));
+        let req = &ResourceRequest {
+            mem: 1024,
+            cpus: 1500,
+            gpus: 1,
+            ..Default::default()
+        };
+
+        let _ra = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+        assert_eq!(rm.total_mem(), 4096);
+        assert_eq!(rm.total_cpus(), 4 * MILLICORES_PER_CPU);
+        assert_eq!(rm.total_gpus(), 2);
+        assert_eq!(rm.available_mem(), 3072);
+        assert_eq!(rm.available_cpus(), 2500);
+        assert_eq!(rm.available_gpus(), 1);
+    }
+
+    #[test]
+    fn test_subscribe_to_changes() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let mut rx = rm.subscribe();
+        assert_eq!(rx.borrow_and_update().available_mem, 2048);
+
+        let req = &ResourceRequest {
+            mem: 2048,
+            cpus: 1,
+            gpus: 0,
+            ..Default::default()
+        };
+        let ra = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+        assert!(rx.has_changed().unwrap());
+        assert_eq!(rx.borrow_and_update().available_mem, 0);
+
+        drop(ra);
+        assert!(rx.has_changed().unwrap());
+        assert_eq!(rx.borrow_and_update().available_mem, 2048);
+    }
+
+    #[test]
+    fn test_allocation_failures_are_counted_by_kind() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            gpus: 1,
+            ..Default::default()
+        }));
+        let req = &ResourceRequest {
+            mem: 1024,
+            cpus: 1,
+            gpus: 1,
+            ..Default::default()
+        };
+        let failures = || {
+            metrics::ALLOCATION_FAILURES_TOTAL
+                .with_label_values(&["gpus"])
+                .get()
+        };
+
+        let _ra = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+        // Counter is global, so other tests may increment it as well.
+        let before = failures();
+        assert!(ResourceManager::try_allocate(rm.clone(), req).is_err());
+        assert!(failures() > before);
+
+        // Mem and CPUs were given back on failure.
+        assert_eq!(rm.available(ResourceKind::Mem), 1024);
+        assert_eq!(rm.available(ResourceKind::Cpus), 3);
+    }
+
+    #[test]
+    fn test_pressure_thresholds() {
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 1024,
+                cpus: 100,
+                ..Default::default()
+            })
+            .with_pressure_thresholds(50, 80),
+        );
+        let cpus = |cpus| ResourceRequest {
+            mem: 1,
+            cpus,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        assert_eq!(rm.pressure(), ResourcePressure::Low);
+        let _ra1 = ResourceManager::try_allocate(rm.clone(), &cpus(49)).unwrap();
+        assert_eq!(rm.pressure(), ResourcePressure::Low);
+        let _ra2 = ResourceManager::try_allocate(rm.clone(), &cpus(1)).unwrap();
+        assert_eq!(rm.pressure(), ResourcePressure::Medium);
+        let _ra3 = ResourceManager::try_allocate(rm.clone(), &cpus(29)).unwrap();
+        assert_eq!(rm.pressure(), ResourcePressure::Medium);
+        let ra4 = ResourceManager::try_allocate(rm.clone(), &cpus(1)).unwrap();
+        assert_eq!(rm.pressure(), ResourcePressure::High);
+
+        drop(ra4);
+        assert_eq!(rm.pressure(), ResourcePressure::Medium);
+    }
+
+    #[test]
+    fn test_pressure_follows_most_utilized_kind() {
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 1000,
+                cpus: 100,
+                ..Default::default()
+            })
+            .with_pressure_thresholds(50, 80),
+        );
+        let req = ResourceRequest {
+            mem: 900,
+            cpus: 1,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let _ra = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+        assert_eq!(rm.pressure(), ResourcePressure::High);
+    }
+
+    #[test]
+    fn test_medium_pressure_threshold_above_high_is_lowered() {
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 1024,
+                cpus: 100,
+                ..Default::default()
+            })
+            .with_pressure_thresholds(90, 60),
+        );
+        let req = ResourceRequest {
+            mem: 1,
+            cpus: 60,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let _ra = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+        assert_eq!(rm.pressure(), ResourcePressure::High);
+    }
+
+    #[test]
+    fn test_try_allocate_with_policy() {
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 1024,
+                cpus: 100,
+                ..Default::default()
+            })
+            .with_pressure_thresholds(50, 80),
+        );
+        let req = |cpus, priority| ResourceRequest {
+            mem: 1,
+            cpus,
+            gpus: 0,
+            priority,
+            ..Default::default()
+        };
+
+        // Medium pressure still admits everything.
+        let _ra1 = ResourceManager::try_allocate_with_policy(rm.clone(), &req(79, 0), 5).unwrap();
+        assert_eq!(rm.pressure(), ResourcePressure::Medium);
+        let _ra2 = ResourceManager::try_allocate_with_policy(rm.clone(), &req(1, 0), 5).unwrap();
+        assert_eq!(rm.pressure(), ResourcePressure::High);
+
+        // High pressure only admits requests of at least the given
+        // priority, even though there is room for both.
+        let Err(err) = ResourceManager::try_allocate_with_policy(rm.clone(), &req(1, 4), 5) else {
+            panic!("allocation should have been rejected");
+        };
+        assert!(matches!(
+            err.downcast_ref::<ResourceError>(),
+            Some(ResourceError::UnderPressure(ResourcePressure::High))
+        ));
+        assert_eq!(rm.available(ResourceKind::Cpus), 20);
+
+        let _ra3 = ResourceManager::try_allocate_with_policy(rm.clone(), &req(1, 5), 5).unwrap();
+        assert_eq!(rm.available(ResourceKind::Cpus), 19);
+    }
+
+    fn assert_draining(res: Result<ResourceAllocation>) {
+        let Err(err) = res else {
+            panic!("allocation should have failed");
+        };
+        assert!(
+            matches!(
+                err.downcast_ref::<ResourceError>(),
+                Some(ResourceError::Draining)
+            ),
+            "unexpected error: {err}"
+        );
+    }
+
+    #[test]
+    fn test_drain() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let req = &ResourceRequest {
+            mem: 1024,
+            cpus: 1,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let ra = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+        rm.drain();
+        assert!(rm.is_draining());
+        assert_draining(ResourceManager::try_allocate(rm.clone(), req));
+
+        // Existing allocations are still freed while draining.
+        drop(ra);
+        assert_eq!(rm.available(ResourceKind::Mem), 2048);
+        assert!(rm.is_draining());
+        assert_draining(ResourceManager::try_allocate(rm.clone(), req));
+
+        rm.undrain();
+        ResourceManager::try_allocate(rm.clone(), req).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_drain_fails_waiting_allocations() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let req = ResourceRequest {
+            mem: 2048,
+            cpus: 4,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let _ra = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+        let waiter = tokio::spawn({
+            let rm = rm.clone();
+            async move { ResourceManager::allocate(rm, &req).await }
+        });
+
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+
+        rm.drain();
+        assert_draining(waiter.await.unwrap());
+    }
+
+    #[test]
+    fn test_resize_grows_and_shrinks() {
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 4096,
+                cpus: 4000,
+                gpus: 2,
+                ..Default::default()
+            })
+            .with_pool_name("test-resize".to_string()),
+        );
+        let req = ResourceRequest {
+            mem: 1024,
+            cpus: 1000,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let mut ra = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+        ra.resize(&ResourceRequest {
+            mem: 3072,
+            gpus: 2,
+            ..req
+        })
+        .unwrap();
+        assert_eq!(ra.mem(), 3072);
+        assert_eq!(ra.assigned_gpus(), &[0, 1]);
+        assert_eq!(rm.available_mem(), 1024);
+        assert_eq!(rm.available_gpus(), 0);
+        assert_eq!(rm.list_allocations()[0].mem, 3072);
+        let available_mem = || {
+            metrics::POOL_AVAILABLE
+                .with_label_values(&["test-resize", "mem"])
+                .get()
+        };
+        assert_eq!(available_mem(), 1024.0);
+
+        ra.resize(&ResourceRequest {
+            mem: 512,
+            cpus: 500,
+            gpus: 1,
+            ..req
+        })
+        .unwrap();
+        assert_eq!(ra.assigned_gpus(), &[0]);
+        assert_eq!(rm.available_mem(), 3584);
+        assert_eq!(rm.available_cpus(), 3500);
+        assert_eq!(rm.available_gpus(), 1);
+        assert_eq!(available_mem(), 3584.0);
+
+        drop(ra);
+        assert_eq!(rm.available_mem(), 4096);
+        assert_eq!(rm.available_gpus(), 2);
+    }
+
+    #[test]
+    fn test_failed_resize_keeps_allocation() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 4096,
+            cpus: 4000,
+            ..Default::default()
+        }));
+        let req = ResourceRequest {
+            mem: 2048,
+            cpus: 1000,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let mut ra = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+        let _other = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+        let res = ra.resize(&ResourceRequest {
+            mem: 3072,
+            cpus: 2000,
+            ..req
+        });
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<ResourceError>(),
+            Some(ResourceError::NotEnoughResources {
+                kind: ResourceKind::Mem,
+                requested: 1024,
+                available: 0,
+                ..
+            })
+        ));
+
+        assert_eq!(ra.mem(), 2048);
+        assert_eq!(ra.cpus(), 1000);
+        assert_eq!(rm.available_mem(), 0);
+        assert_eq!(rm.available_cpus(), 2000);
+        assert_eq!(rm.list_allocations()[0].mem, 2048);
+    }
+
+    #[test]
+    fn test_shared_allocation_freed_by_last_clone() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 4096,
+            cpus: 4000,
+            ..Default::default()
+        }));
+        let req = &ResourceRequest {
+            mem: 1024,
+            cpus: 1000,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let shared =
+            SharedAllocation::from(ResourceManager::try_allocate(rm.clone(), req).unwrap());
+        let clones: Vec<_> = (0..3).map(|_| shared.clone()).collect();
+        drop(shared);
+        let mut clones = clones.into_iter();
+        let last = clones.next().unwrap();
+        drop(clones);
+
+        assert_eq!(last.mem(), 1024);
+        assert_eq!(rm.available_mem(), 3072);
+        assert_eq!(rm.list_allocations().len(), 1);
+
+        drop(last);
+        assert_eq!(rm.available_mem(), 4096);
+        assert!(rm.list_allocations().is_empty());
     }
 
     #[test]
-    fn test_try_allocate_fails_on_gpus() {
-        let rm = Arc::new(Mutex::new(ResourceManager::new(2048, 4, 0)));
-        let req = &ResourceRequest {
+    fn test_schedulable() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 4096,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let req = ResourceRequest {
+            mem: 1536,
+            cpus: 1,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        assert_eq!(rm.schedulable(&[req, req, req]), vec![true, true, false]);
+
+        // Nothing was actually allocated.
+        assert_eq!(rm.available(ResourceKind::Mem), 4096);
+        assert_eq!(rm.available(ResourceKind::Cpus), 4);
+        assert!(rm.list_allocations().is_empty());
+    }
+
+    #[test]
+    fn test_try_allocate_batch() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 4096,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let req = ResourceRequest {
+            mem: 1024,
+            cpus: 1,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let allocations = ResourceManager::try_allocate_batch(rm.clone(), &[req; 3]).unwrap();
+        assert_eq!(allocations.len(), 3);
+        assert_eq!(rm.available(ResourceKind::Mem), 1024);
+        assert_eq!(rm.available(ResourceKind::Cpus), 1);
+
+        drop(allocations);
+        assert_eq!(rm.available(ResourceKind::Mem), 4096);
+        assert_eq!(rm.available(ResourceKind::Cpus), 4);
+    }
+
+    #[test]
+    fn test_try_allocate_batch_rolls_back() {
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 4096,
+                cpus: 4,
+                ..Default::default()
+            })
+            .with_pool_name("test-batch-rolls-back".to_string()),
+        );
+        let req = ResourceRequest {
+            mem: 1024,
+            cpus: 1,
+            gpus: 0,
+            ..Default::default()
+        };
+        let mut rx = rm.subscribe();
+        let freed = Arc::new(AtomicU64::new(0));
+        rm.on_free({
+            let freed = freed.clone();
+            move |_| {
+                freed.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        let ewma = rm.utilization_ewma();
+
+        assert_not_enough(
+            ResourceManager::try_allocate_batch(rm.clone(), &[req; 5]).map(|_| unreachable!()),
+            ResourceKind::Mem,
+            1024,
+            0,
+        );
+
+        assert_eq!(rm.available(ResourceKind::Mem), 4096);
+        assert_eq!(rm.available(ResourceKind::Cpus), 4);
+        assert!(rm.list_allocations().is_empty());
+        assert_eq!(rx.borrow_and_update().available_mem, 4096);
+        assert_eq!(rx.borrow().available_cpus, 4);
+
+        // The rolled back allocations were never handed out.
+        assert_eq!(freed.load(Ordering::SeqCst), 0);
+        assert_eq!(rm.peak_mem(), 0);
+        assert_eq!(rm.utilization_ewma(), ewma);
+        let count = |result| {
+            metrics::ALLOCATIONS_TOTAL
+                .with_label_values(&["test-batch-rolls-back", result])
+                .get()
+        };
+        assert_eq!((count("success"), count("failure")), (0, 1));
+    }
+
+    #[test]
+    fn test_from_config() {
+        let gib = 1024 * 1024 * 1024;
+        let sys = FakeSystem {
+            mem: Some(16 * gib),
+            cpus: 8,
+            container_mem: None,
+            container_cpus: None,
+        };
+        let config = run_config(&["--num-cpus", "4", "--mem-gb", "8", "--gpu-devices", "0-1"]);
+
+        let rm = ResourceManager::from_system_config(&config, &sys).unwrap();
+        let snapshot = rm.snapshot();
+        assert_eq!(snapshot.total_mem, 8 * 1024);
+        assert_eq!(snapshot.total_cpus, 4 * MILLICORES_PER_CPU);
+        assert_eq!(snapshot.total_gpus, 2);
+        assert_eq!(rm.available(ResourceKind::Disk), 1024);
+        assert_eq!(rm.available(ResourceKind::Net), 1000 * 1_000_000);
+    }
+
+    #[test]
+    fn test_placement_fits_detected_mem() {
+        use crate::scheduler::placement::{FirstFit, PlacementStrategy};
+
+        let sys = FakeSystem {
+            mem: Some(2 * 1024 * 1024 * 1024),
+            cpus: 8,
+            container_mem: None,
+            container_cpus: None,
+        };
+        let resources =
+            get_configured_resources(&run_config(&["--reserve-mem-mb", "0"]), &sys).unwrap();
+        let snapshots = [ResourceManager::new(resources).snapshot()];
+        let req = |mem| ResourceRequest {
+            mem,
+            cpus: 1000,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        assert_eq!(FirstFit.place(&req(2048), &snapshots), Some(0));
+        assert_eq!(FirstFit.place(&req(2049), &snapshots), None);
+    }
+
+    #[test]
+    fn test_detected_mem_limits_requests() {
+        let sys = FakeSystem {
+            mem: Some(2 * 1024 * 1024 * 1024),
+            cpus: 8,
+            container_mem: None,
+            container_cpus: None,
+        };
+        let resources =
+            get_configured_resources(&run_config(&["--reserve-mem-mb", "0"]), &sys).unwrap();
+        let rm = Arc::new(ResourceManager::new(resources));
+        let req = ResourceRequest {
             mem: 1024,
+            cpus: 1000,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let _ra1 = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+        let _ra2 = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+        assert_not_enough(
+            ResourceManager::try_allocate(rm.clone(), &req),
+            ResourceKind::Mem,
+            1024,
+            0,
+        );
+    }
+
+    #[test]
+    fn test_reserved_and_available_add_up_to_total() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 4096,
+            cpus: 4000,
+            gpus: 2,
+            ..Default::default()
+        }));
+        let req = |mem, cpus, gpus| ResourceRequest {
+            mem,
+            cpus,
+            gpus,
+            ..Default::default()
+        };
+        let assert_adds_up = || {
+            for kind in [ResourceKind::Mem, ResourceKind::Cpus, ResourceKind::Gpus] {
+                let available = rm.available(kind);
+                assert_eq!(rm.reserved(kind, available) + available, rm.capacity(kind));
+            }
+        };
+
+        let ra1 = ResourceManager::try_allocate(rm.clone(), &req(1024, 1500, 1)).unwrap();
+        assert_adds_up();
+        let ra2 = ResourceManager::try_allocate(rm.clone(), &req(2048, 500, 0)).unwrap();
+        assert_adds_up();
+        assert_eq!(
+            rm.reserved(ResourceKind::Mem, rm.available(ResourceKind::Mem)),
+            3072
+        );
+        drop(ra1);
+        assert_adds_up();
+        let _ra3 = ResourceManager::try_allocate(rm.clone(), &req(1024, 2000, 2)).unwrap();
+        assert_adds_up();
+        drop(ra2);
+        assert_adds_up();
+        assert_eq!(
+            rm.reserved(ResourceKind::Gpus, rm.available(ResourceKind::Gpus)),
+            2
+        );
+    }
+
+    #[test]
+    fn test_try_allocate_fails_on_request_exceeding_capacity() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4,
+            ..Default::default()
+        }));
+        let req = &ResourceRequest {
+            mem: 4096,
             cpus: 1,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let Err(err) = ResourceManager::try_allocate(rm.clone(), req) else {
+            panic!("allocation should have failed");
+        };
+        let err = err.downcast_ref::<ResourceError>().unwrap();
+        assert!(err.is_permanent());
+        assert_exceeds_capacity(ResourceManager::try_allocate(rm.clone(), req), 4096, 2048);
+
+        // A request that fits in an empty node is only unavailable for now.
+        let _ra = ResourceManager::try_allocate(rm.clone(), &ResourceRequest { mem: 2048, ..*req })
+            .unwrap();
+        let Err(err) =
+            ResourceManager::try_allocate(rm.clone(), &ResourceRequest { mem: 1024, ..*req })
+        else {
+            panic!("allocation should have failed");
+        };
+        assert!(!err.downcast_ref::<ResourceError>().unwrap().is_permanent());
+    }
+
+    #[test]
+    fn test_assigned_cores_are_returned_on_free() {
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 4096,
+                cpus: 4000,
+                ..Default::default()
+            })
+            .with_cpu_cores(vec![8, 9, 10, 11, 12]),
+        );
+        let req = |cpus| ResourceRequest {
+            mem: 1,
+            cpus,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let mut ra1 = ResourceManager::try_allocate(rm.clone(), &req(2000)).unwrap();
+        // Only whole CPUs get a core of their own.
+        let ra2 = ResourceManager::try_allocate(rm.clone(), &req(1500)).unwrap();
+        assert_eq!(ra1.assigned_cores(), &[8, 9]);
+        assert_eq!(ra2.assigned_cores(), &[10]);
+
+        ra1.resize(&req(1000)).unwrap();
+        assert_eq!(ra1.assigned_cores(), &[8]);
+        drop(ra2);
+        let ra3 = ResourceManager::try_allocate(rm.clone(), &req(3000)).unwrap();
+        assert_eq!(ra3.assigned_cores(), &[9, 10, 11]);
+    }
+
+    #[test]
+    fn test_concurrent_core_assignments_are_disjoint() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 4096,
+            cpus: 16000,
+            ..Default::default()
+        }));
+        let barrier = Arc::new(std::sync::Barrier::new(8));
+
+        let threads: Vec<_> = (0..8)
+            .map(|thread| {
+                let rm = rm.clone();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    // Mixed sizes, fractions included, all fitting at once.
+                    let req = &ResourceRequest {
+                        mem: 1,
+                        cpus: 1000 + thread % 2 * 500,
+                        gpus: 0,
+                        ..Default::default()
+                    };
+                    let mut assigned = vec![];
+                    for _ in 0..100 {
+                        barrier.wait();
+                        let mut ra = ResourceManager::try_allocate(rm.clone(), req).unwrap();
+                        ra.resize(&ResourceRequest {
+                            cpus: req.cpus + 500,
+                            ..*req
+                        })
+                        .unwrap();
+                        assigned.push(ra.assigned_cores().to_vec());
+                        // Hold on to the allocation until everyone has one.
+                        barrier.wait();
+                    }
+                    assigned
+                })
+            })
+            .collect();
+
+        let mut rounds = vec![BTreeSet::new(); 100];
+        for thread in threads {
+            for (round, assigned) in thread.join().unwrap().into_iter().enumerate() {
+                assert!(!assigned.is_empty());
+                for core in assigned {
+                    assert!(rounds[round].insert(core), "core assigned twice");
+                }
+            }
+        }
+        assert_eq!(rm.free_cores.lock().len(), 16);
+    }
+
+    #[test]
+    fn test_on_free_callback() {
+        let clock = Arc::new(MockClock::new());
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 4096,
+                cpus: 4000,
+                ..Default::default()
+            })
+            .with_pool_name("test-on-free".to_string())
+            .with_clock(clock.clone()),
+        );
+        let freed = Arc::new(Mutex::new(vec![]));
+        rm.on_free({
+            let rm = Arc::downgrade(&rm);
+            let freed = freed.clone();
+            move |info: &AllocationInfo| {
+                // Calling back into the manager doesn't deadlock, and sees
+                // the resources freed.
+                let rm = rm.upgrade().unwrap();
+                assert!(rm.list_allocations().is_empty());
+                freed.lock().push((info.clone(), rm.available_mem()));
+            }
+        });
+
+        let program_id = Hash::random(&mut rand::thread_rng());
+        let task_id = Uuid::new_v4();
+        let ra = ResourceManager::try_allocate_for(
+            rm.clone(),
+            &ResourceRequest {
+                mem: 1024,
+                cpus: 1000,
+                gpus: 0,
+                ..Default::default()
+            },
+            Some(program_id),
+            Some(task_id),
+            None,
+        )
+        .unwrap();
+        clock.advance(Duration::from_secs(30));
+        assert!(freed.lock().is_empty());
+        drop(ra);
+
+        let freed = freed.lock();
+        assert_eq!(freed.len(), 1);
+        let (info, available_mem) = &freed[0];
+        assert_eq!(info.id, 0);
+        assert_eq!(info.program_id, Some(program_id));
+        assert_eq!(info.task_id, Some(task_id));
+        assert_eq!((info.mem, info.cpus, info.gpus), (1024, 1000, 0));
+        assert_eq!(info.age, Duration::from_secs(30));
+        assert_eq!(*available_mem, 4096);
+    }
+
+    #[test]
+    fn test_weak_allocation_info() {
+        let clock = Arc::new(MockClock::new());
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 4096,
+                cpus: 4000,
+                ..Default::default()
+            })
+            .with_clock(clock.clone()),
+        );
+        let ra = ResourceManager::try_allocate(
+            rm.clone(),
+            &ResourceRequest {
+                mem: 1024,
+                cpus: 500,
+                gpus: 0,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let weak = ra.downgrade();
+        assert_eq!(weak.id(), ra.id());
+
+        clock.advance(Duration::from_secs(3));
+        let info = weak.info().unwrap();
+        assert_eq!(info.id, ra.id());
+        assert_eq!((info.mem, info.cpus), (1024, 500));
+        assert_eq!(info.age, Duration::from_secs(3));
+
+        drop(ra);
+        assert!(weak.info().is_none());
+        assert_eq!(rm.available_mem(), 4096);
+
+        // Nor does the handle keep the manager alive.
+        let ra = ResourceManager::try_allocate(
+            rm.clone(),
+            &ResourceRequest {
+                mem: 1,
+                cpus: 1,
+                ..zero_request()
+            },
+        )
+        .unwrap();
+        let weak = ra.downgrade();
+        drop(ra);
+        drop(rm);
+        assert!(weak.info().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_priority_inheritance_boosts_blocking_allocation() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 4096,
+            cpus: 4000,
+            ..Default::default()
+        }));
+        let req = |cpus, priority| ResourceRequest {
+            mem: 1,
+            cpus,
+            gpus: 0,
+            priority,
+            ..Default::default()
+        };
+        let spawn_waiter = |cpus, priority| {
+            let rm = rm.clone();
+            tokio::spawn(async move { ResourceManager::allocate(rm, &req(cpus, priority)).await })
+        };
+
+        let mut low = ResourceManager::try_allocate(rm.clone(), &req(2000, 1)).unwrap();
+        let other = ResourceManager::try_allocate(rm.clone(), &req(2000, 9)).unwrap();
+        // Needs the CPUs `low` holds, so `low` inherits its priority.
+        let high = spawn_waiter(4000, 10);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let growing = tokio::spawn(async move {
+            low.grow(&req(4000, 1)).await.unwrap();
+            low
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let medium = spawn_waiter(2000, 5);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // Both `low` and the medium request fit in what's freed, and `low`
+        // goes first with the priority inherited.
+        drop(other);
+        let low = growing.await.unwrap();
+        assert_eq!(low.cpus(), 4000);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!medium.is_finished());
+        assert!(!high.is_finished());
+
+        drop(low);
+        let high = high.await.unwrap().unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!medium.is_finished());
+        drop(high);
+        medium.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_growing_without_inheritance_waits_its_turn() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 4096,
+            cpus: 4000,
+            ..Default::default()
+        }));
+        let req = |cpus, priority| ResourceRequest {
+            mem: 1,
+            cpus,
+            gpus: 0,
+            priority,
+            ..Default::default()
+        };
+
+        let mut low = ResourceManager::try_allocate(rm.clone(), &req(2000, 1)).unwrap();
+        let other = ResourceManager::try_allocate(rm.clone(), &req(2000, 9)).unwrap();
+        let growing = tokio::spawn(async move {
+            low.grow(&req(4000, 1)).await.unwrap();
+            low
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let medium = {
+            let rm = rm.clone();
+            tokio::spawn(async move { ResourceManager::allocate(rm, &req(2000, 5)).await })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // Nothing of higher priority waits on `low`, so the medium request
+        // goes first.
+        drop(other);
+        let medium = medium.await.unwrap().unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!growing.is_finished());
+        drop(medium);
+        assert_eq!(growing.await.unwrap().cpus(), 4000);
+    }
+
+    #[test]
+    fn test_snapshot_diff() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 4096,
+            cpus: 4000,
+            gpus: 2,
+            ..Default::default()
+        }));
+        let req = ResourceRequest {
+            mem: 1024,
+            cpus: 2000,
             gpus: 1,
+            ..Default::default()
         };
 
-        let ra = ResourceManager::try_allocate(rm, req);
-        assert!(ra.is_err());
+        let before = rm.snapshot();
+        let ra = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+        let after = rm.snapshot();
+
+        let delta = before.diff(&after);
+        assert_eq!(
+            delta,
+            ResourceDelta {
+                available_mem: -1024,
+                mem_utilization: 25.0,
+                available_cpus: -2000,
+                cpus_utilization: 50.0,
+                available_gpus: -1,
+                gpus_utilization: 50.0,
+                ..Default::default()
+            }
+        );
+        drop(ra);
+        assert_eq!(after.diff(&rm.snapshot()).available_mem, 1024);
+        assert_eq!(before.diff(&before), ResourceDelta::default());
+
+        assert_eq!(signed_delta(0, u64::MAX), i64::MAX);
+        assert_eq!(signed_delta(u64::MAX, 0), i64::MIN);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cpu_slots_queue_until_freed() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 4096,
+            cpus: 4000,
+            ..Default::default()
+        }));
+        let req = |cpus| ResourceRequest {
+            mem: 1,
+            cpus,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let ra1 = ResourceManager::allocate_with_cpu_slots(rm.clone(), &req(3000))
+            .await
+            .unwrap();
+        let ra2 = ResourceManager::allocate_with_cpu_slots(rm.clone(), &req(1000))
+            .await
+            .unwrap();
+        assert_eq!(rm.available_cpu_slots(), 0);
+
+        let waiter = {
+            let rm = rm.clone();
+            tokio::spawn(
+                async move { ResourceManager::allocate_with_cpu_slots(rm, &req(2000)).await },
+            )
+        };
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!waiter.is_finished());
+
+        // Not enough until both are freed. The slots freed are set aside
+        // for the waiter at the head of the queue.
+        drop(ra2);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!waiter.is_finished());
+        assert_eq!(rm.available_cpu_slots(), 0);
+
+        drop(ra1);
+        let ra3 = waiter.await.unwrap().unwrap();
+        assert_eq!(ra3.cpus(), 2000);
+        assert_eq!(rm.available_cpu_slots(), 2000);
+        assert_eq!(rm.available_cpus(), 2000);
+
+        drop(ra3);
+        assert_eq!(rm.available_cpu_slots(), 4000);
+        assert!(
+            ResourceManager::allocate_with_cpu_slots(rm.clone(), &req(5000))
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_admission_reserves_peak_mem() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 4096,
+            cpus: 4000,
+            ..Default::default()
+        }));
+        let req = |mem, peak_mem| ResourceRequest {
+            mem,
+            peak_mem,
+            cpus: 1000,
+            ..Default::default()
+        };
+
+        // Steady state of both would fit, but not both peaks.
+        let ra1 = ResourceManager::try_allocate(rm.clone(), &req(1024, Some(3072))).unwrap();
+        assert_eq!(ra1.mem(), 3072);
+        assert_eq!(ra1.expected_mem(), 1024);
+        assert_eq!(rm.available_mem(), 1024);
+        assert_eq!(rm.peak_reserve(), 2048);
+        assert!(!rm.can_allocate(&req(1024, Some(2048))));
+        let Err(err) = ResourceManager::try_allocate(rm.clone(), &req(1024, Some(2048))) else {
+            panic!("peak should not fit");
+        };
+        assert!(matches!(
+            err.downcast_ref::<ResourceError>(),
+            Some(ResourceError::NotEnoughResources { .. })
+        ));
+        let _ra2 = ResourceManager::try_allocate(rm.clone(), &req(1024, None)).unwrap();
+
+        let listed = rm.list_allocations();
+        assert_eq!(listed[0].mem, 1024);
+
+        drop(ra1);
+        assert_eq!(rm.available_mem(), 3072);
+        assert_eq!(rm.peak_reserve(), 0);
+    }
+
+    #[test]
+    fn test_report_all_deficits() {
+        let resources = DetectedResources {
+            mem: 4096,
+            cpus: 4000,
+            gpus: 1,
+            ..Default::default()
+        };
+        let req = ResourceRequest {
+            mem: 2048,
+            cpus: 1000,
+            gpus: 1,
+            ..Default::default()
+        };
+        let deficits = |rm: ResourceManager| {
+            let rm = Arc::new(rm);
+            let _held = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+            let Err(err) = ResourceManager::try_allocate(rm, &ResourceRequest { mem: 4096, ..req })
+            else {
+                panic!("allocation should have failed");
+            };
+            match err.downcast_ref::<ResourceError>() {
+                Some(ResourceError::NotEnoughResources { deficits, .. }) => {
+                    (deficits.clone(), err.to_string())
+                }
+                _ => panic!("unexpected error: {}", err),
+            }
+        };
+
+        // Only the first resource checked is named by default.
+        assert_eq!(
+            deficits(ResourceManager::new(resources)),
+            (
+                vec![ResourceKind::Mem],
+                "not enough mem: requested 4096, available 2048".to_string()
+            )
+        );
+        assert_eq!(
+            deficits(ResourceManager::new(resources).with_report_all_deficits(true)),
+            (
+                vec![ResourceKind::Mem, ResourceKind::Gpus],
+                "not enough mem: requested 4096, available 2048 (also short of gpus)".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_zero_cpus_or_mem_rejected() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 4096,
+            cpus: 4000,
+            ..Default::default()
+        }));
+        let req = |mem, cpus| ResourceRequest {
+            mem,
+            cpus,
+            gpus: 0,
+            ..Default::default()
+        };
+        let invalid = |req: &ResourceRequest| {
+            let Err(err) = ResourceManager::try_allocate(rm.clone(), req) else {
+                panic!("allocation should have failed");
+            };
+            match err.downcast_ref::<ResourceError>() {
+                Some(err @ ResourceError::InvalidRequest(_)) => {
+                    assert!(err.is_permanent());
+                    assert_eq!(StatusCode::from(err), StatusCode::BAD_REQUEST);
+                    err.to_string()
+                }
+                _ => panic!("unexpected error: {}", err),
+            }
+        };
+
+        assert_eq!(
+            invalid(&req(1024, 0)),
+            "invalid resource request: no CPUs requested"
+        );
+        assert_eq!(
+            invalid(&req(0, 1000)),
+            "invalid resource request: no memory requested"
+        );
+        assert_eq!(rm.available_mem(), 4096);
+        assert_eq!(rm.available_cpus(), 4000);
+
+        // GPUs are optional.
+        let _ra = ResourceManager::try_allocate(rm.clone(), &req(1024, 1000)).unwrap();
+        assert_eq!(rm.available_gpus(), 0);
+    }
+
+    #[test]
+    fn test_allocation_ids_increase() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 4096,
+            cpus: 4000,
+            ..Default::default()
+        }));
+        let req = |mem| ResourceRequest {
+            mem,
+            cpus: 1000,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        let ra1 = ResourceManager::try_allocate(rm.clone(), &req(1024)).unwrap();
+        let ra2 = ResourceManager::try_allocate(rm.clone(), &req(1024)).unwrap();
+        assert!(ra2.id() > ra1.id());
+
+        // Failures take no ID, and freed IDs aren't handed out again.
+        assert!(ResourceManager::try_allocate(rm.clone(), &req(8192)).is_err());
+        let (id1, id2) = (ra1.id(), ra2.id());
+        drop(ra1);
+        drop(ra2);
+        let ra3 = ResourceManager::try_allocate(rm.clone(), &req(1024)).unwrap();
+        assert_eq!(ra3.id(), id2 + 1);
+        assert_ne!(ra3.id(), id1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_allocation_queue_depth() {
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 2048,
+                cpus: 4000,
+                ..Default::default()
+            })
+            .with_pool_name("test-queue-depth".to_string()),
+        );
+        let req = ResourceRequest {
+            mem: 1024,
+            cpus: 1000,
+            gpus: 0,
+            ..Default::default()
+        };
+        let depth = || {
+            metrics::ALLOCATION_QUEUE_DEPTH
+                .with_label_values(&["test-queue-depth"])
+                .get()
+        };
+
+        let ra1 = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+        let _ra2 = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+        let waiters: Vec<_> = (0..2)
+            .map(|_| {
+                let rm = rm.clone();
+                tokio::spawn(async move { ResourceManager::allocate(rm, &req).await })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(depth(), 2);
+
+        drop(ra1);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(depth(), 1);
+        assert_eq!(waiters.iter().filter(|w| w.is_finished()).count(), 1);
+
+        for waiter in waiters {
+            waiter.abort();
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(depth(), 0);
+    }
+
+    #[test]
+    fn test_set_total_grows_pool() {
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 4096,
+                cpus: 4000,
+                gpus: 1,
+                ..Default::default()
+            })
+            .with_pool_name("test-set-total".to_string()),
+        );
+        let req = |mem, gpus| ResourceRequest {
+            mem,
+            cpus: 1000,
+            gpus,
+            ..Default::default()
+        };
+
+        let ra1 = ResourceManager::try_allocate(rm.clone(), &req(3072, 1)).unwrap();
+        assert!(ResourceManager::try_allocate(rm.clone(), &req(4096, 1)).is_err());
+
+        rm.set_total(DetectedResources {
+            mem: 8192,
+            cpus: 8000,
+            gpus: 2,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(rm.total_mem(), 8192);
+        assert_eq!(rm.total_cpus(), 8000);
+        assert_eq!(rm.available_mem(), 8192 - 3072);
+        assert_eq!(rm.available_cpus(), 7000);
+        assert_eq!(rm.available_gpus(), 1);
+        assert_eq!(rm.available_cpu_slots(), 8000);
+
+        let ra2 = ResourceManager::try_allocate(rm.clone(), &req(4096, 1)).unwrap();
+        assert_eq!(ra2.assigned_gpus(), &[1]);
+        assert_eq!(rm.snapshot().total_mem, 8192);
+
+        drop(ra1);
+        drop(ra2);
+        assert_eq!(rm.available_mem(), 8192);
+        assert_eq!(rm.available_gpus(), 2);
+    }
+
+    #[test]
+    fn test_set_total_below_allocated() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 8192,
+            cpus: 8000,
+            gpus: 2,
+            ..Default::default()
+        }));
+        let req = ResourceRequest {
+            mem: 4096,
+            cpus: 1000,
+            gpus: 0,
+            ..Default::default()
+        };
+        let below = |resources| match rm.set_total(resources) {
+            Err(ResourceError::BelowAllocated {
+                kind,
+                total,
+                allocated,
+            }) => (kind, total, allocated),
+            res => panic!("unexpected result: {:?}", res),
+        };
+
+        let _ra1 = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+        let _ra2 =
+            ResourceManager::try_allocate(rm.clone(), &ResourceRequest { gpus: 2, ..req }).unwrap();
+        let resources = DetectedResources {
+            mem: 8192,
+            cpus: 4000,
+            gpus: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            below(DetectedResources {
+                mem: 6144,
+                ..resources
+            }),
+            (ResourceKind::Mem, 6144, 8192)
+        );
+        assert_eq!(
+            below(DetectedResources {
+                gpus: 1,
+                ..resources
+            }),
+            (ResourceKind::Gpus, 1, 2)
+        );
+        // Memory grown before CPUs were found short is changed back.
+        assert_eq!(
+            below(DetectedResources {
+                mem: 16384,
+                cpus: 1000,
+                ..resources
+            }),
+            (ResourceKind::Cpus, 1000, 2000)
+        );
+
+        // Nothing changed by the failed attempts.
+        assert_eq!(rm.total_mem(), 8192);
+        assert_eq!(rm.total_cpus(), 8000);
+        assert_eq!(rm.available_cpus(), 6000);
+        assert_eq!(rm.available_mem(), 0);
+
+        rm.set_total(resources).unwrap();
+        assert_eq!(rm.total_cpus(), 4000);
+        assert_eq!(rm.available_cpus(), 2000);
+    }
+
+    #[test]
+    fn test_panics_dont_break_allocation() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 4000,
+            ..Default::default()
+        }));
+        let req = ResourceRequest {
+            mem: 2048,
+            cpus: 1000,
+            gpus: 0,
+            ..Default::default()
+        };
+
+        // Locks aren't poisoned by a thread panicking while holding them.
+        let res = std::thread::spawn({
+            let rm = rm.clone();
+            move || {
+                let _allocations = rm.allocations.write();
+                let _waiters = rm.waiters.lock();
+                panic!("holding resource manager locks");
+            }
+        })
+        .join();
+        assert!(res.is_err());
+        let ra = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+
+        // Nor does a panicking callback keep the allocation from being
+        // freed, or propagate out of the drop.
+        rm.on_free(|_| panic!("on-free callback"));
+        drop(ra);
+        assert_eq!(rm.available_mem(), 2048);
+        assert!(rm.list_allocations().is_empty());
+        let _ra = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+    }
+
+    #[test]
+    fn test_affinity_reflects_live_allocations() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 4096,
+            cpus: 4000,
+            ..Default::default()
+        }));
+        let key = AffinityKey::new("proof-job-1");
+        let req = ResourceRequest {
+            mem: 1024,
+            cpus: 1000,
+            gpus: 0,
+            affinity_key: Some(key),
+            ..Default::default()
+        };
+        assert!(!rm.has_affinity(key));
+
+        let mut ra1 = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+        let ra2 = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+        assert!(rm.has_affinity(key));
+        assert!(!rm.has_affinity(AffinityKey::new("proof-job-2")));
+
+        // Resizing keeps the key.
+        ra1.resize(&ResourceRequest {
+            mem: 2048,
+            affinity_key: None,
+            ..req
+        })
+        .unwrap();
+        drop(ra2);
+        assert!(rm.has_affinity(key));
+        drop(ra1);
+        assert!(!rm.has_affinity(key));
+    }
+
+    #[test]
+    fn test_anti_affinity_counts() {
+        let rm = Arc::new(ResourceManager::new(DetectedResources {
+            mem: 4096,
+            cpus: 4000,
+            ..Default::default()
+        }));
+        let key = AffinityKey::new("verifier");
+        let req = ResourceRequest {
+            mem: 1024,
+            cpus: 1000,
+            gpus: 0,
+            anti_affinity_key: Some(key),
+            ..Default::default()
+        };
+        assert_eq!(rm.anti_affinity_count(key), 0);
+
+        let ra1 = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+        assert_eq!(rm.anti_affinity_count(key), 1);
+        let ra2 = ResourceManager::try_allocate(rm.clone(), &req).unwrap();
+        assert_eq!(rm.anti_affinity_count(key), 2);
+        assert_eq!(rm.anti_affinity_count(AffinityKey::new("prover")), 0);
+
+        // Failed allocations aren't counted.
+        let too_big = ResourceRequest { mem: 8192, ..req };
+        assert!(ResourceManager::try_allocate(rm.clone(), &too_big).is_err());
+        assert_eq!(rm.anti_affinity_count(key), 2);
+
+        drop(ra1);
+        assert_eq!(rm.anti_affinity_count(key), 1);
+        drop(ra2);
+        assert_eq!(rm.anti_affinity_count(key), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_preempt_checkpoints_before_freeing() {
+        // Records the memory available when the checkpoint ran, then takes
+        // `delay` to complete.
+        struct Recorder {
+            rm: Arc<ResourceManager>,
+            available_mem: Arc<Mutex<Option<u64>>>,
+            delay: Duration,
+        }
+
+        #[async_trait::async_trait]
+        impl Checkpoint for Recorder {
+            async fn checkpoint(&self) -> Result<()> {
+                *self.available_mem.lock() = Some(self.rm.available_mem());
+                tokio::time::sleep(self.delay).await;
+                Ok(())
+            }
+        }
+
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 2048,
+                cpus: 4000,
+                ..Default::default()
+            })
+            .with_checkpoint_timeout(Duration::from_secs(10)),
+        );
+        let background = &ResourceRequest {
+            mem: 1024,
+            cpus: 1000,
+            gpus: 0,
+            priority: 1,
+            ..Default::default()
+        };
+
+        for (delay, waited) in [(1, 1), (60, 10)] {
+            let available_mem = Arc::new(Mutex::new(None));
+            let mut ra = ResourceManager::try_allocate(rm.clone(), background).unwrap();
+            ra.set_checkpoint(Recorder {
+                rm: rm.clone(),
+                available_mem: available_mem.clone(),
+                delay: Duration::from_secs(delay),
+            });
+
+            // Checkpoints running past the timeout are given up on.
+            let start = tokio::time::Instant::now();
+            ra.preempt().await;
+            assert_eq!(start.elapsed(), Duration::from_secs(waited));
+            assert_eq!(*available_mem.lock(), Some(1024));
+            assert_eq!(rm.available_mem(), 2048);
+        }
     }
 }
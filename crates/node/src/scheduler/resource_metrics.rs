@@ -0,0 +1,249 @@
+use super::resource_manager::{DetectedResources, ResourceKind};
+use crate::metrics;
+use crate::types::program::MILLICORES_PER_CPU;
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Most memory, CPUs and GPUs held at once since start or the last reset.
+/// Other kinds of resources are not tracked.
+#[derive(Debug, Default)]
+pub(super) struct Peaks {
+    mem: AtomicU64,
+    cpus: AtomicU64,
+    gpus: AtomicU64,
+}
+
+impl Peaks {
+    fn counter(&self, kind: ResourceKind) -> Option<&AtomicU64> {
+        match kind {
+            ResourceKind::Mem => Some(&self.mem),
+            ResourceKind::Cpus => Some(&self.cpus),
+            ResourceKind::Gpus => Some(&self.gpus),
+            _ => None,
+        }
+    }
+
+    /// Raises the peak of `kind` to `held`, if that's more.
+    pub(super) fn record(&self, kind: ResourceKind, held: u64) {
+        if let Some(peak) = self.counter(kind) {
+            peak.fetch_max(held, Ordering::SeqCst);
+        }
+    }
+
+    /// Starts the peak of `kind` over from `held`.
+    pub(super) fn reset(&self, kind: ResourceKind, held: u64) {
+        if let Some(peak) = self.counter(kind) {
+            peak.store(held, Ordering::SeqCst);
+        }
+    }
+
+    pub(super) fn mem(&self) -> u64 {
+        self.mem.load(Ordering::SeqCst)
+    }
+
+    pub(super) fn cpus(&self) -> u64 {
+        self.cpus.load(Ordering::SeqCst)
+    }
+
+    pub(super) fn gpus(&self) -> u64 {
+        self.gpus.load(Ordering::SeqCst)
+    }
+}
+
+/// Exponentially weighted moving averages of memory and CPU utilization,
+/// from the first update on.
+#[derive(Debug)]
+pub(super) struct UtilizationEwma {
+    // Weight of the latest utilization in the averages.
+    pub(super) alpha: f64,
+    averages: Mutex<Option<(f64, f64)>>,
+}
+
+impl UtilizationEwma {
+    pub(super) fn new(alpha: f64) -> Self {
+        Self {
+            alpha,
+            averages: Mutex::new(None),
+        }
+    }
+
+    /// Moves the averages toward the current `mem` and `cpus` utilization,
+    /// and returns them.
+    pub(super) fn update(&self, mem: f64, cpus: f64) -> (f64, f64) {
+        let mut averages = self.averages.lock();
+        let updated = match *averages {
+            Some((avg_mem, avg_cpus)) => (
+                avg_mem + self.alpha * (mem - avg_mem),
+                avg_cpus + self.alpha * (cpus - avg_cpus),
+            ),
+            None => (mem, cpus),
+        };
+        *averages = Some(updated);
+        updated
+    }
+
+    /// The averages, or `None` before the first update.
+    pub(super) fn get(&self) -> Option<(f64, f64)> {
+        *self.averages.lock()
+    }
+}
+
+pub(super) fn set_total_metrics(resources: DetectedResources, mem_limit: u64) {
+    metrics::CPUS_TOTAL.set(cores(resources.cpus));
+    metrics::MEM_TOTAL.set(gauge_value(resources.mem));
+    metrics::MEM_OVERCOMMIT_TOTAL.set(gauge_value(mem_limit));
+    metrics::GPUS_TOTAL.set(gauge_value(resources.gpus));
+    metrics::DISK_TOTAL.set(gauge_value(resources.disk));
+    metrics::GPU_MEM_TOTAL.set(gauge_value(resources.gpu_mem));
+    metrics::NET_TOTAL.set(gauge_value(resources.net));
+}
+
+pub(super) fn set_available_metrics(available: [(ResourceKind, u64); 6]) {
+    for (kind, amount) in available {
+        match kind {
+            ResourceKind::Mem => metrics::MEM_AVAILABLE.set(gauge_value(amount)),
+            ResourceKind::Cpus => metrics::CPUS_AVAILABLE.set(cores(amount)),
+            ResourceKind::Gpus => metrics::GPUS_AVAILABLE.set(gauge_value(amount)),
+            ResourceKind::GpuMem => metrics::GPU_MEM_AVAILABLE.set(gauge_value(amount)),
+            ResourceKind::Disk => metrics::DISK_AVAILABLE.set(gauge_value(amount)),
+            ResourceKind::Net => metrics::NET_AVAILABLE.set(gauge_value(amount)),
+        }
+    }
+}
+
+/// Converts an amount of `kind` into a float gauge value, with CPUs in
+/// whole cores.
+pub(super) fn metric_value(kind: ResourceKind, amount: u64) -> f64 {
+    match kind {
+        ResourceKind::Cpus => cores(amount),
+        _ => amount as f64,
+    }
+}
+
+/// Converts an amount into an integer gauge value, clamping what doesn't
+/// fit instead of wrapping around to a negative value.
+pub(super) fn gauge_value(amount: u64) -> i64 {
+    i64::try_from(amount).unwrap_or(i64::MAX)
+}
+
+pub(super) fn set_reserved_metrics(reserved: [(ResourceKind, u64); 6]) {
+    for (kind, amount) in reserved {
+        match kind {
+            ResourceKind::Mem => metrics::MEM_RESERVED.set(gauge_value(amount)),
+            ResourceKind::Cpus => metrics::CPUS_RESERVED.set(cores(amount)),
+            ResourceKind::Gpus => metrics::GPUS_RESERVED.set(gauge_value(amount)),
+            _ => {}
+        }
+    }
+}
+
+pub(super) fn set_peak_metrics(peaks: &Peaks) {
+    metrics::MEM_PEAK.set(gauge_value(peaks.mem()));
+    metrics::CPUS_PEAK.set(cores(peaks.cpus()));
+    metrics::GPUS_PEAK.set(gauge_value(peaks.gpus()));
+}
+
+/// Converts millicores into (fractional) whole CPU cores.
+pub(super) fn cores(millicores: u64) -> f64 {
+    millicores as f64 / MILLICORES_PER_CPU as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::host::get_configured_resources;
+    use crate::scheduler::host::tests::{run_config, FakeSystem};
+    use crate::scheduler::resource_manager::ResourceManager;
+    use crate::types::program::ResourceRequest;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_utilization_ewma_converges() {
+        let rm = Arc::new(
+            ResourceManager::new(DetectedResources {
+                mem: 4000,
+                cpus: 4000,
+                ..Default::default()
+            })
+            .with_utilization_ewma_alpha(0.5),
+        );
+        let req = |mem| ResourceRequest {
+            mem,
+            cpus: 1000,
+            gpus: 0,
+            ..Default::default()
+        };
+        assert_eq!(rm.utilization_ewma(), None);
+
+        // Seeded to the first utilization.
+        let mut base = ResourceManager::try_allocate(rm.clone(), &req(1000)).unwrap();
+        assert_eq!(rm.utilization_ewma(), Some((25.0, 25.0)));
+
+        // Memory steps up to 75%, CPUs stay at 25%.
+        base.resize(&req(3000)).unwrap();
+        assert_eq!(rm.utilization_ewma(), Some((50.0, 25.0)));
+
+        // Publishing changes updates without changing usage.
+        let mut last = 50.0;
+        for _ in 0..16 {
+            rm.reset_peaks();
+            let (mem, cpus) = rm.utilization_ewma().unwrap();
+            assert!(mem > last && mem < 75.0);
+            assert_eq!(cpus, 25.0);
+            last = mem;
+        }
+        assert!(75.0 - last < 0.01);
+    }
+
+    #[test]
+    fn test_peaks_stay_until_reset() {
+        // Peaks are in the units of requests, memory in MiB rather than
+        // the bytes detected on the host.
+        let sys = FakeSystem {
+            mem: Some(8 * 1024 * 1024 * 1024),
+            cpus: 8,
+            container_mem: None,
+            container_cpus: None,
+        };
+        let resources = get_configured_resources(&run_config(&["--reserve-mem-mb", "0"]), &sys)
+            .map(|resources| DetectedResources {
+                gpus: 2,
+                ..resources
+            })
+            .unwrap();
+        let rm = Arc::new(ResourceManager::new(resources));
+        let req = |mem, cpus, gpus| ResourceRequest {
+            mem,
+            cpus,
+            gpus,
+            ..Default::default()
+        };
+        let peaks = || (rm.peak_mem(), rm.peak_cpus(), rm.peak_gpus());
+        assert_eq!(peaks(), (0, 0, 0));
+
+        let ra1 = ResourceManager::try_allocate(rm.clone(), &req(4096, 2000, 1)).unwrap();
+        let ra2 = ResourceManager::try_allocate(rm.clone(), &req(2048, 4000, 1)).unwrap();
+        assert_eq!(peaks(), (6144, 6000, 2));
+
+        drop((ra1, ra2));
+        let ra = ResourceManager::try_allocate(rm.clone(), &req(1024, 1000, 0)).unwrap();
+        assert_eq!(peaks(), (6144, 6000, 2));
+
+        rm.reset_peaks();
+        assert_eq!(peaks(), (1024, 1000, 0));
+        drop(ra);
+        assert_eq!(peaks(), (1024, 1000, 0));
+    }
+
+    #[test]
+    fn test_gauge_value_clamps() {
+        let gauge = prometheus::IntGauge::new("test_gauge", "Test gauge").unwrap();
+
+        gauge.set(gauge_value(u64::MAX));
+        assert_eq!(gauge.get(), i64::MAX);
+        gauge.set(gauge_value(i64::MAX as u64 + 1));
+        assert_eq!(gauge.get(), i64::MAX);
+        gauge.set(gauge_value(1024));
+        assert_eq!(gauge.get(), 1024);
+    }
+}
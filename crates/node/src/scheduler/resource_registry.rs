@@ -0,0 +1,320 @@
+use super::placement::PlacementStrategy;
+use super::resource_manager::{
+    ResourceAllocation, ResourceError, ResourceManager, ResourceSnapshot,
+};
+use crate::types::program::ResourceRequest;
+use eyre::Result;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Named pools of resources, each tracked by its own `ResourceManager`.
+/// Used when a node has resources that shouldn't be counted together, such
+/// as GPUs of different speeds.
+#[derive(Debug, Default)]
+pub struct ResourceRegistry {
+    pools: BTreeMap<String, Arc<ResourceManager>>,
+}
+
+impl ResourceRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `resource_manager` as the pool `name`, replacing any pool of the
+    /// same name. Metrics of the pool are labeled with its name.
+    pub fn add_pool(
+        &mut self,
+        name: impl Into<String>,
+        resource_manager: ResourceManager,
+    ) -> Arc<ResourceManager> {
+        let name = name.into();
+        let resource_manager = Arc::new(resource_manager.with_pool_name(name.clone()));
+        self.pools.insert(name, resource_manager.clone());
+        resource_manager
+    }
+
+    pub fn pool(&self, name: &str) -> Option<&Arc<ResourceManager>> {
+        self.pools.get(name)
+    }
+
+    /// Allocates requested resources from the pool `name`, if available.
+    pub fn try_allocate(
+        &self,
+        name: &str,
+        request: &ResourceRequest,
+    ) -> Result<ResourceAllocation> {
+        let Some(resource_manager) = self.pools.get(name) else {
+            return Err(ResourceError::UnknownPool(name.to_string()).into());
+        };
+        let allocation = ResourceManager::try_allocate(resource_manager.clone(), request)?;
+        tracing::debug!(pool = name, id = allocation.id(), "allocated from pool");
+        Ok(allocation)
+    }
+
+    /// Allocates requested resources from the pool `primary`, or from the
+    /// pool `secondary` if `primary` can't allocate them. Which pool served
+    /// the request is given by `ResourceAllocation::pool()`, and the
+    /// allocation is freed back to it. When neither can allocate, the
+    /// error of `secondary` is returned.
+    pub fn try_allocate_spillover(
+        &self,
+        primary: &str,
+        secondary: &str,
+        request: &ResourceRequest,
+    ) -> Result<ResourceAllocation> {
+        match self.try_allocate(primary, request) {
+            Err(err)
+                if !matches!(
+                    err.downcast_ref::<ResourceError>(),
+                    Some(ResourceError::UnknownPool(_))
+                ) =>
+            {
+                tracing::debug!(primary, secondary, "spilling over: {}", err);
+                self.try_allocate(secondary, request)
+            }
+            res => res,
+        }
+    }
+
+    /// Names of pools hosting a live allocation with the affinity key of
+    /// `request`, ordered by name.
+    pub fn pools_with_affinity(&self, request: &ResourceRequest) -> Vec<&str> {
+        let Some(key) = request.affinity_key else {
+            return Vec::new();
+        };
+        self.pools
+            .iter()
+            .filter(|(_, resource_manager)| resource_manager.has_affinity(key))
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    /// Chooses the pool to place `request` on with `strategy`. Pools already
+    /// hosting allocations with the same affinity key are preferred, so that
+    /// related tasks share what they cache; other pools are only considered
+    /// when the request fits on none of them. Pools hosting allocations with
+    /// the same anti-affinity key are avoided, unless the request fits
+    /// nowhere else.
+    pub fn place(
+        &self,
+        strategy: &dyn PlacementStrategy,
+        request: &ResourceRequest,
+    ) -> Option<&str> {
+        let apart = |name: &&str| {
+            request
+                .anti_affinity_key
+                .is_none_or(|key| self.pools[*name].anti_affinity_count(key) == 0)
+        };
+        let all = || self.pools.keys().map(String::as_str);
+        let together = self.pools_with_affinity(request);
+        self.place_among(
+            strategy,
+            request,
+            together.into_iter().filter(apart).collect(),
+        )
+        .or_else(|| self.place_among(strategy, request, all().filter(apart).collect()))
+        .or_else(|| self.place_among(strategy, request, all().collect()))
+    }
+
+    fn place_among<'a>(
+        &self,
+        strategy: &dyn PlacementStrategy,
+        request: &ResourceRequest,
+        names: Vec<&'a str>,
+    ) -> Option<&'a str> {
+        let snapshots: Vec<_> = names
+            .iter()
+            .map(|name| self.pools[*name].snapshot())
+            .collect();
+        strategy
+            .place(request, &snapshots)
+            .map(|index| names[index])
+    }
+
+    /// Snapshots of all pools, ordered by name.
+    pub fn snapshots(&self) -> Vec<(String, ResourceSnapshot)> {
+        self.pools
+            .iter()
+            .map(|(name, resource_manager)| (name.clone(), resource_manager.snapshot()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics;
+    use crate::scheduler::placement::FirstFit;
+    use crate::scheduler::resource_manager::DetectedResources;
+    use crate::types::program::AffinityKey;
+
+    fn registry() -> ResourceRegistry {
+        let mut registry = ResourceRegistry::new();
+        registry.add_pool(
+            "fast",
+            ResourceManager::new(DetectedResources {
+                mem: 8192,
+                cpus: 4000,
+                gpus: 2,
+                ..Default::default()
+            }),
+        );
+        registry.add_pool(
+            "slow",
+            ResourceManager::new(DetectedResources {
+                mem: 4096,
+                cpus: 2000,
+                gpus: 1,
+                ..Default::default()
+            }),
+        );
+        registry
+    }
+
+    fn gpu_request() -> ResourceRequest {
+        ResourceRequest {
+            mem: 1024,
+            cpus: 1000,
+            gpus: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_pools_are_independent() {
+        let registry = registry();
+
+        let _slow = registry.try_allocate("slow", &gpu_request()).unwrap();
+        assert!(registry.try_allocate("slow", &gpu_request()).is_err());
+
+        // Running out of slow GPUs doesn't affect the fast ones.
+        let _fast1 = registry.try_allocate("fast", &gpu_request()).unwrap();
+        let _fast2 = registry.try_allocate("fast", &gpu_request()).unwrap();
+        assert!(registry.try_allocate("fast", &gpu_request()).is_err());
+
+        let snapshots = registry.snapshots();
+        assert_eq!(snapshots[0].0, "fast");
+        assert_eq!(snapshots[0].1.available_mem, 6144);
+        assert_eq!(snapshots[1].0, "slow");
+        assert_eq!(snapshots[1].1.available_mem, 3072);
+    }
+
+    #[test]
+    fn test_unknown_pool() {
+        let registry = registry();
+
+        let Err(err) = registry.try_allocate("fsat", &gpu_request()) else {
+            panic!("allocation should have failed");
+        };
+        assert!(matches!(
+            err.downcast_ref::<ResourceError>(),
+            Some(ResourceError::UnknownPool(name)) if name == "fsat"
+        ));
+    }
+
+    #[test]
+    fn test_pool_metrics_are_labeled() {
+        let mut registry = ResourceRegistry::new();
+        registry.add_pool(
+            "test-metrics",
+            ResourceManager::new(DetectedResources {
+                mem: 2048,
+                cpus: 2000,
+                ..Default::default()
+            }),
+        );
+        let available = || {
+            metrics::POOL_AVAILABLE
+                .with_label_values(&["test-metrics", "mem"])
+                .get()
+        };
+        assert_eq!(available(), 2048.0);
+
+        let req = ResourceRequest {
+            gpus: 0,
+            ..gpu_request()
+        };
+        let ra = registry.try_allocate("test-metrics", &req).unwrap();
+        assert_eq!(available(), 1024.0);
+        drop(ra);
+        assert_eq!(available(), 2048.0);
+    }
+
+    #[test]
+    fn test_spillover_to_secondary_pool() {
+        let registry = registry();
+
+        let _fast1 = registry
+            .try_allocate_spillover("fast", "slow", &gpu_request())
+            .unwrap();
+        let fast2 = registry
+            .try_allocate_spillover("fast", "slow", &gpu_request())
+            .unwrap();
+        assert_eq!(fast2.pool(), Some("fast"));
+
+        // The fast GPUs are taken.
+        let slow = registry
+            .try_allocate_spillover("fast", "slow", &gpu_request())
+            .unwrap();
+        assert_eq!(slow.pool(), Some("slow"));
+        assert_eq!(registry.pool("slow").unwrap().available_gpus(), 0);
+        assert!(registry
+            .try_allocate_spillover("fast", "slow", &gpu_request())
+            .is_err());
+
+        // Each frees back to its own pool.
+        drop(slow);
+        assert_eq!(registry.pool("slow").unwrap().available_gpus(), 1);
+        assert_eq!(registry.pool("fast").unwrap().available_gpus(), 0);
+        drop(fast2);
+        let fast3 = registry
+            .try_allocate_spillover("fast", "slow", &gpu_request())
+            .unwrap();
+        assert_eq!(fast3.pool(), Some("fast"));
+
+        assert!(registry
+            .try_allocate_spillover("fsat", "slow", &gpu_request())
+            .is_err());
+        assert_eq!(registry.pool("slow").unwrap().available_gpus(), 1);
+    }
+
+    #[test]
+    fn test_place_prefers_affinity() {
+        let registry = registry();
+        let req = ResourceRequest {
+            affinity_key: Some(AffinityKey::new("proof-job")),
+            ..gpu_request()
+        };
+        assert_eq!(registry.place(&FirstFit, &req), Some("fast"));
+
+        let _slow = registry.try_allocate("slow", &req).unwrap();
+        assert_eq!(registry.pools_with_affinity(&req), vec!["slow"]);
+        let fast = registry.try_allocate("fast", &gpu_request()).unwrap();
+
+        // The slow pool hosts the key, but has no GPU left.
+        assert_eq!(registry.place(&FirstFit, &req), Some("fast"));
+        let req = ResourceRequest { gpus: 0, ..req };
+        assert_eq!(registry.place(&FirstFit, &req), Some("slow"));
+
+        drop(fast);
+        assert_eq!(registry.place(&FirstFit, &gpu_request()), Some("fast"));
+    }
+
+    #[test]
+    fn test_place_spreads_anti_affinity() {
+        let registry = registry();
+        let req = ResourceRequest {
+            anti_affinity_key: Some(AffinityKey::new("verifier")),
+            ..gpu_request()
+        };
+
+        let _fast = registry
+            .try_allocate(registry.place(&FirstFit, &req).unwrap(), &req)
+            .unwrap();
+        assert_eq!(registry.place(&FirstFit, &req), Some("slow"));
+        let _slow = registry.try_allocate("slow", &req).unwrap();
+
+        // Every pool hosts a replica, so any that fits will do.
+        assert_eq!(registry.place(&FirstFit, &req), Some("fast"));
+    }
+}
@@ -0,0 +1,138 @@
+use super::resource_manager::{ResourceAllocation, ResourceError, ResourceManager};
+use crate::types::program::ResourceRequest;
+use eyre::Result;
+use rand::Rng;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How `retry_allocate()` spaces out its attempts. Delays start at
+/// `initial` and are multiplied by `multiplier` after each failed attempt,
+/// up to `max`. Each delay is jittered down by up to half, so that callers
+/// that failed together don't all retry together.
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: f64,
+    /// Attempts made before giving up, including the first one.
+    pub max_attempts: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(5),
+            multiplier: 2.0,
+            max_attempts: 10,
+        }
+    }
+}
+
+impl Backoff {
+    /// Delay before the attempt following the `attempt`th one, counting
+    /// from 1, before jitter.
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.max(1.0).powi(attempt as i32 - 1);
+        self.initial.mul_f64(factor).min(self.max)
+    }
+}
+
+/// Allocates requested resources, retrying with `backoff` while they aren't
+/// available. Gives up right away on errors retrying can't fix, such as
+/// requests exceeding the capacity, and otherwise returns the error of the
+/// last attempt once attempts run out.
+pub async fn retry_allocate(
+    resource_manager: Arc<ResourceManager>,
+    request: &ResourceRequest,
+    backoff: Backoff,
+) -> Result<ResourceAllocation> {
+    let mut attempt = 1;
+    loop {
+        let err = match ResourceManager::try_allocate(resource_manager.clone(), request) {
+            Ok(allocation) => return Ok(allocation),
+            Err(err) => err,
+        };
+        let permanent = err
+            .downcast_ref::<ResourceError>()
+            .is_some_and(ResourceError::is_permanent);
+        if permanent || attempt >= backoff.max_attempts {
+            return Err(err);
+        }
+
+        let delay = backoff.delay(attempt);
+        let delay = delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0));
+        tracing::debug!(attempt, ?delay, "retrying allocation: {}", err);
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::resource_manager::DetectedResources;
+
+    fn resource_manager() -> Arc<ResourceManager> {
+        Arc::new(ResourceManager::new(DetectedResources {
+            mem: 2048,
+            cpus: 2000,
+            ..Default::default()
+        }))
+    }
+
+    fn request() -> ResourceRequest {
+        ResourceRequest {
+            mem: 2048,
+            cpus: 1000,
+            gpus: 0,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_until_freed() {
+        let rm = resource_manager();
+        let held = ResourceManager::try_allocate(rm.clone(), &request()).unwrap();
+
+        // Attempts are made at 0ms, after 50-100ms, and then 100-200ms
+        // later, so the third one finds the resources freed.
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(120)).await;
+            drop(held);
+        });
+
+        let start = tokio::time::Instant::now();
+        let backoff = Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_attempts: 5,
+        };
+        retry_allocate(rm.clone(), &request(), backoff)
+            .await
+            .unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(150), "{:?}", elapsed);
+        assert!(elapsed <= Duration::from_millis(300), "{:?}", elapsed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_exceeding_capacity_isnt_retried() {
+        let rm = resource_manager();
+        let request = ResourceRequest {
+            mem: 4096,
+            ..request()
+        };
+
+        let start = tokio::time::Instant::now();
+        let Err(err) = retry_allocate(rm, &request, Backoff::default()).await else {
+            panic!("allocation should have failed");
+        };
+        assert!(matches!(
+            err.downcast_ref::<ResourceError>(),
+            Some(ResourceError::ExceedsCapacity { .. })
+        ));
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+}
@@ -56,11 +56,23 @@ impl Database {
         .await?;
 
         if let Some(ref program_resource_requirements) = p.limits {
-            sqlx::query("INSERT INTO program_resource_requirements ( program_hash, memory, cpus, gpus ) VALUES ( $1, $2, $3, $4 ) ON CONFLICT (program_hash) DO NOTHING")
+            let opt = |v: Option<u64>| v.map(|v| v as i64);
+            sqlx::query("INSERT INTO program_resource_requirements ( program_hash, memory, cpus, gpus, disk_bytes, gpu_mem, gpu_mem_fraction, net_bps, priority, gpu_uuid, mem_limit, cpus_limit, peak_mem, affinity_key, anti_affinity_key ) VALUES ( $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15 ) ON CONFLICT (program_hash) DO NOTHING")
                 .bind(p.hash)
                 .bind(program_resource_requirements.mem as i64)
                 .bind(program_resource_requirements.cpus as i64)
                 .bind(program_resource_requirements.gpus as i64)
+                .bind(program_resource_requirements.disk_bytes as i64)
+                .bind(program_resource_requirements.gpu_mem as i64)
+                .bind(program_resource_requirements.gpu_mem_fraction)
+                .bind(program_resource_requirements.net_bps as i64)
+                .bind(program_resource_requirements.priority as i16)
+                .bind(program_resource_requirements.gpu_uuid)
+                .bind(opt(program_resource_requirements.mem_limit))
+                .bind(opt(program_resource_requirements.cpus_limit))
+                .bind(opt(program_resource_requirements.peak_mem))
+                .bind(program_resource_requirements.affinity_key.map(|key| key.digest() as i64))
+                .bind(program_resource_requirements.anti_affinity_key.map(|key| key.digest() as i64))
             .execute(&mut *db_tx)
             .await?;
         }
@@ -597,6 +609,7 @@ impl Database {
 #[cfg(test)]
 mod tests {
     use crate::types::transaction::Payload;
+    use gevulot_node::types::program::AffinityKey;
     use gevulot_node::types::transaction::Created;
     use libsecp256k1::SecretKey;
     use rand::{rngs::StdRng, Rng, SeedableRng};
@@ -891,6 +904,12 @@ mod tests {
                 mem: 53912,
                 cpus: 13,
                 gpus: 3,
+                disk_bytes: 1 << 30,
+                gpu_mem_fraction: Some(0.5),
+                priority: 7,
+                cpus_limit: Some(26),
+                affinity_key: Some(AffinityKey::new("proof-job")),
+                ..Default::default()
             }),
         };
 
@@ -981,6 +1000,7 @@ mod tests {
                 mem: 53912,
                 cpus: 13,
                 gpus: 3,
+                ..Default::default()
             }),
         };
 
@@ -1,30 +1,683 @@
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sqlx::{postgres::PgRow, Row};
+use std::fmt;
+use std::ops::{Add, AddAssign, Sub, SubAssign};
+use thiserror::Error;
+use uuid::Uuid;
 
 use super::{
     hash::{deserialize_hash_from_json, Hash},
     transaction,
 };
 
-#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, sqlx::FromRow)]
+/// Number of millicores in one whole CPU core.
+pub const MILLICORES_PER_CPU: u64 = 1000;
+
+/// Number of bytes in one MiB, the unit of memory in requests.
+pub const BYTES_PER_MIB: u64 = 1024 * 1024;
+
+#[derive(Clone, Error, Debug, PartialEq)]
+pub enum RequestError {
+    #[error("invalid resource request: no CPUs requested")]
+    NoCpus,
+    #[error("invalid resource request: no memory requested")]
+    NoMemory,
+    #[error("invalid resource request: {0} limit below request")]
+    LimitBelowRequest(&'static str),
+    #[error("invalid resource request: peak memory below request")]
+    PeakBelowRequest,
+    #[error("invalid resource request: GPU memory requested both in bytes and as a fraction")]
+    MixedGpuMem,
+    #[error("invalid resource request: GPU memory fraction {0} not between 0 and 1")]
+    GpuMemFraction(f64),
+}
+
+/// Resources needed by a task. New code should construct requests with
+/// `ResourceRequest::builder()`, which rejects nonsensical requests.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct ResourceRequest {
-    #[sqlx(rename = "memory", try_from = "i64")]
+    /// Memory needed by the task (in MiB).
     pub mem: u64,
-    #[sqlx(try_from = "i64")]
+    /// CPU needed by the task (in millicores, see `MILLICORES_PER_CPU`).
     pub cpus: u64,
-    #[sqlx(try_from = "i64")]
     pub gpus: u64,
+    /// Scratch disk space needed for task's working files (in bytes).
+    pub disk_bytes: u64,
+    /// GPU memory needed by the task (in bytes). Tasks requesting GPU memory
+    /// without whole GPUs share devices with each other.
+    pub gpu_mem: u64,
+    /// GPU memory needed by the task as a fraction of the VRAM of each GPU,
+    /// in place of `gpu_mem`. Resolved into `gpu_mem` on allocation.
+    pub gpu_mem_fraction: Option<f64>,
+    /// Network bandwidth needed by the task (in bits per second).
+    pub net_bps: u64,
+    /// Scheduling priority of the task. Higher priority tasks may preempt
+    /// lower priority ones.
+    pub priority: u8,
+    /// UUID of the GPU device the task must run on. Only honored for tasks
+    /// requesting GPUs; others get any free devices.
+    pub gpu_uuid: Option<Uuid>,
+    /// Memory the task may burst to (in MiB). Only `mem` is guaranteed;
+    /// the limit is what's enforced on the task. No limit means `mem`.
+    pub mem_limit: Option<u64>,
+    /// CPU the task may burst to (in millicores). Only `cpus` is
+    /// guaranteed; the limit is what's enforced on the task. No limit means
+    /// `cpus`.
+    pub cpus_limit: Option<u64>,
+    /// Memory the task needs at its peak (in MiB), when it needs more for
+    /// part of its run than `mem`. The peak is reserved for the task, while
+    /// `mem` is what it is expected to use most of the time.
+    pub peak_mem: Option<u64>,
+    /// Tasks with the same affinity key are preferably placed together,
+    /// such as tasks of a proof job sharing cached input data.
+    pub affinity_key: Option<AffinityKey>,
+    /// Tasks with the same anti-affinity key are preferably placed apart,
+    /// such as redundant verifier replicas.
+    pub anti_affinity_key: Option<AffinityKey>,
+}
+
+/// `ResourceRequest` as exchanged with clients and other nodes. Nodes
+/// predating millicores read `cpus` as whole cores, so it's kept that way,
+/// rounded up, and the exact amount goes in `cpu_millis`.
+///
+/// Human readable formats serialize requests this way, while binary ones,
+/// which can't skip fields they don't know, use `LegacyResourceRequest`
+/// for nodes to keep understanding each other. Serializing this type
+/// directly keeps all fields in any format.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct WireResourceRequest {
+    /// Memory (in MiB). Human readable formats accept also strings with
+    /// units, such as "2GiB".
+    #[serde(deserialize_with = "deserialize_mem")]
+    mem: u64,
+    /// Whole CPU cores, ignored when `cpu_millis` is given.
+    cpus: u64,
+    gpus: u64,
+    /// CPU (in millicores, see `MILLICORES_PER_CPU`).
+    #[serde(default)]
+    cpu_millis: Option<u64>,
+    #[serde(default)]
+    disk_bytes: u64,
+    #[serde(default)]
+    gpu_mem: u64,
+    #[serde(default)]
+    gpu_mem_fraction: Option<f64>,
+    #[serde(default)]
+    net_bps: u64,
+    #[serde(default)]
+    priority: u8,
+    #[serde(default)]
+    gpu_uuid: Option<Uuid>,
+    #[serde(default)]
+    mem_limit: Option<u64>,
+    #[serde(default)]
+    cpus_limit: Option<u64>,
+    #[serde(default)]
+    peak_mem: Option<u64>,
+    #[serde(default)]
+    affinity_key: Option<AffinityKey>,
+    #[serde(default)]
+    anti_affinity_key: Option<AffinityKey>,
+}
+
+/// `ResourceRequest` as known to all nodes, in binary formats.
+#[derive(Deserialize, Serialize)]
+struct LegacyResourceRequest {
+    mem: u64,
+    /// Whole CPU cores.
+    cpus: u64,
+    gpus: u64,
+}
+
+impl Serialize for ResourceRequest {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        if serializer.is_human_readable() {
+            WireResourceRequest::from(*self).serialize(serializer)
+        } else {
+            LegacyResourceRequest {
+                mem: self.mem,
+                cpus: self.cpus.div_ceil(MILLICORES_PER_CPU),
+                gpus: self.gpus,
+            }
+            .serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ResourceRequest {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        if deserializer.is_human_readable() {
+            WireResourceRequest::deserialize(deserializer).map(Into::into)
+        } else {
+            let legacy = LegacyResourceRequest::deserialize(deserializer)?;
+            Ok(ResourceRequest {
+                mem: legacy.mem,
+                cpus: legacy.cpus.saturating_mul(MILLICORES_PER_CPU),
+                gpus: legacy.gpus,
+                ..Default::default()
+            })
+        }
+    }
+}
+
+/// Reads requests stored in the `program_resource_requirements` table.
+impl<'r> sqlx::FromRow<'r, PgRow> for ResourceRequest {
+    fn from_row(row: &'r PgRow) -> Result<Self, sqlx::Error> {
+        let unsigned = |column: &str, v: i64| {
+            u64::try_from(v).map_err(|err| sqlx::Error::ColumnDecode {
+                index: column.to_string(),
+                source: Box::new(err),
+            })
+        };
+        let get = |column: &str| unsigned(column, row.try_get(column)?);
+        let get_opt = |column: &str| {
+            row.try_get::<Option<i64>, _>(column)?
+                .map(|v| unsigned(column, v))
+                .transpose()
+        };
+        let get_key = |column: &str| {
+            Ok::<_, sqlx::Error>(
+                row.try_get::<Option<i64>, _>(column)?
+                    .map(|digest| AffinityKey(digest as u64)),
+            )
+        };
+
+        Ok(ResourceRequest {
+            mem: get("memory")?,
+            cpus: get("cpus")?,
+            gpus: get("gpus")?,
+            disk_bytes: get("disk_bytes")?,
+            gpu_mem: get("gpu_mem")?,
+            gpu_mem_fraction: row.try_get("gpu_mem_fraction")?,
+            net_bps: get("net_bps")?,
+            priority: u8::try_from(row.try_get::<i16, _>("priority")?).map_err(|err| {
+                sqlx::Error::ColumnDecode {
+                    index: "priority".to_string(),
+                    source: Box::new(err),
+                }
+            })?,
+            gpu_uuid: row.try_get("gpu_uuid")?,
+            mem_limit: get_opt("mem_limit")?,
+            cpus_limit: get_opt("cpus_limit")?,
+            peak_mem: get_opt("peak_mem")?,
+            affinity_key: get_key("affinity_key")?,
+            anti_affinity_key: get_key("anti_affinity_key")?,
+        })
+    }
+}
+
+impl From<WireResourceRequest> for ResourceRequest {
+    fn from(wire: WireResourceRequest) -> Self {
+        ResourceRequest {
+            mem: wire.mem,
+            cpus: wire
+                .cpu_millis
+                .unwrap_or(wire.cpus.saturating_mul(MILLICORES_PER_CPU)),
+            gpus: wire.gpus,
+            disk_bytes: wire.disk_bytes,
+            gpu_mem: wire.gpu_mem,
+            gpu_mem_fraction: wire.gpu_mem_fraction,
+            net_bps: wire.net_bps,
+            priority: wire.priority,
+            gpu_uuid: wire.gpu_uuid,
+            mem_limit: wire.mem_limit,
+            cpus_limit: wire.cpus_limit,
+            peak_mem: wire.peak_mem,
+            affinity_key: wire.affinity_key,
+            anti_affinity_key: wire.anti_affinity_key,
+        }
+    }
+}
+
+impl From<ResourceRequest> for WireResourceRequest {
+    fn from(request: ResourceRequest) -> Self {
+        WireResourceRequest {
+            mem: request.mem,
+            cpus: request.cpus.div_ceil(MILLICORES_PER_CPU),
+            gpus: request.gpus,
+            cpu_millis: Some(request.cpus),
+            disk_bytes: request.disk_bytes,
+            gpu_mem: request.gpu_mem,
+            gpu_mem_fraction: request.gpu_mem_fraction,
+            net_bps: request.net_bps,
+            priority: request.priority,
+            gpu_uuid: request.gpu_uuid,
+            mem_limit: request.mem_limit,
+            cpus_limit: request.cpus_limit,
+            peak_mem: request.peak_mem,
+            affinity_key: request.affinity_key,
+            anti_affinity_key: request.anti_affinity_key,
+        }
+    }
+}
+
+/// Key of tasks to place together, or apart. Kept as a digest of the key, so that
+/// requests stay `Copy`. Deserializes from the key itself, or from the
+/// digest it serializes to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+pub struct AffinityKey(u64);
+
+impl AffinityKey {
+    pub fn new(key: &str) -> Self {
+        let digest = blake3::hash(key.as_bytes());
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&digest.as_bytes()[..8]);
+        AffinityKey(u64::from_le_bytes(bytes))
+    }
+
+    /// The digest the key is kept as.
+    pub fn digest(&self) -> u64 {
+        self.0
+    }
+}
+
+impl<'de> Deserialize<'de> for AffinityKey {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct KeyVisitor;
+
+        impl<'de> de::Visitor<'de> for KeyVisitor {
+            type Value = AffinityKey;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("an affinity key or its digest")
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                Ok(AffinityKey(v))
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                Ok(AffinityKey::new(v))
+            }
+        }
+
+        // Binary formats such as bincode can't tell integers from strings.
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(KeyVisitor)
+        } else {
+            deserializer.deserialize_u64(KeyVisitor)
+        }
+    }
 }
 
 impl Default for ResourceRequest {
     fn default() -> Self {
         Self {
             mem: 2048,
-            cpus: 2,
+            cpus: 2 * MILLICORES_PER_CPU,
             gpus: 0,
+            disk_bytes: 0,
+            gpu_mem: 0,
+            gpu_mem_fraction: None,
+            net_bps: 0,
+            priority: 0,
+            gpu_uuid: None,
+            mem_limit: None,
+            cpus_limit: None,
+            peak_mem: None,
+            affinity_key: None,
+            anti_affinity_key: None,
+        }
+    }
+}
+
+/// Deserializes memory amount (in MiB) from either an integer or, in human
+/// readable formats, a string with a unit such as "512MiB" or "2GiB".
+/// Amounts that aren't whole MiBs are rounded up.
+fn deserialize_mem<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct MemVisitor;
+
+    impl<'de> de::Visitor<'de> for MemVisitor {
+        type Value = u64;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("memory in MiB or a string like \"2GiB\"")
+        }
+
+        fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(v)
+        }
+
+        fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            u64::try_from(v).map_err(|_| E::invalid_value(de::Unexpected::Signed(v), &self))
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            parse_mem(v).map_err(E::custom)
+        }
+    }
+
+    // Binary formats such as bincode can't tell integers from strings.
+    if deserializer.is_human_readable() {
+        deserializer.deserialize_any(MemVisitor)
+    } else {
+        u64::deserialize(deserializer)
+    }
+}
+
+/// Parses memory amount with a unit into MiBs. Numbers without a unit are
+/// taken as MiBs.
+fn parse_mem(v: &str) -> Result<u64, String> {
+    const MIB: u64 = 1024 * 1024;
+    match v.trim().parse::<u64>() {
+        Ok(mib) => Ok(mib),
+        Err(_) => parse_bytes(v).map(|bytes| bytes.div_ceil(MIB)),
+    }
+}
+
+/// Parses a human-readable memory amount such as "16GiB" or "2TB" into
+/// bytes. Binary (KiB, MiB, GiB, TiB, or K, M, G, T) and decimal (KB, MB,
+/// GB, TB) units are told apart. The unit is required.
+pub fn parse_bytes(v: &str) -> Result<u64, String> {
+    let v = v.trim();
+    let split = v.find(|c: char| !c.is_ascii_digit()).unwrap_or(v.len());
+    let (amount, unit) = v.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("invalid memory amount {v:?}"))?;
+
+    const MIB: u64 = 1024 * 1024;
+    let bytes_per_unit = match unit.trim() {
+        "" => return Err(format!("missing memory unit in {v:?}")),
+        "B" => 1,
+        "M" | "MiB" => MIB,
+        "K" | "KiB" => 1024,
+        "G" | "GiB" => 1024 * MIB,
+        "T" | "TiB" => 1024 * 1024 * MIB,
+        "KB" => 1000,
+        "MB" => 1000 * 1000,
+        "GB" => 1000 * 1000 * 1000,
+        "TB" => 1000 * 1000 * 1000 * 1000,
+        unit => {
+            return Err(format!(
+                "invalid memory unit {unit:?} in {v:?}, expected one of B, KiB, MiB, GiB, TiB, KB, MB, GB, TB"
+            ))
+        }
+    };
+
+    amount
+        .checked_mul(bytes_per_unit)
+        .ok_or_else(|| format!("memory amount {v:?} is too large"))
+}
+
+impl ResourceRequest {
+    pub fn builder() -> ResourceRequestBuilder {
+        ResourceRequestBuilder::default()
+    }
+
+    /// Checks whether this request fits in the `available` resources.
+    pub fn fits(&self, available: &ResourceRequest) -> bool {
+        self.mem <= available.mem
+            && self.cpus <= available.cpus
+            && self.gpus <= available.gpus
+            && self.disk_bytes <= available.disk_bytes
+            && self.gpu_mem <= available.gpu_mem
+            && self.net_bps <= available.net_bps
+    }
+
+    /// Memory the task may use at most (in MiB), never less than `mem`.
+    pub fn hard_mem(&self) -> u64 {
+        self.mem_limit.unwrap_or(self.mem).max(self.mem)
+    }
+
+    /// CPU the task may use at most (in millicores), never less than
+    /// `cpus`.
+    pub fn hard_cpus(&self) -> u64 {
+        self.cpus_limit.unwrap_or(self.cpus).max(self.cpus)
+    }
+
+    /// Memory reserved for the task (in MiB): its peak, never less than
+    /// `mem`.
+    pub fn reserved_mem(&self) -> u64 {
+        self.peak_mem.unwrap_or(self.mem).max(self.mem)
+    }
+
+    /// The request with `gpu_mem_fraction` turned into bytes of GPU memory,
+    /// given the VRAM (in bytes) of each GPU. The fraction is of every GPU
+    /// requested, or of a single one shared by tasks requesting none.
+    pub fn resolve_gpu_mem(&self, device_vram: u64) -> Result<ResourceRequest, RequestError> {
+        let Some(fraction) = self.gpu_mem_fraction else {
+            return Ok(*self);
+        };
+        if self.gpu_mem > 0 {
+            return Err(RequestError::MixedGpuMem);
+        }
+        if !(0.0..=1.0).contains(&fraction) {
+            return Err(RequestError::GpuMemFraction(fraction));
+        }
+        let per_gpu = (device_vram as f64 * fraction) as u64;
+        Ok(ResourceRequest {
+            gpu_mem: per_gpu.saturating_mul(self.gpus.max(1)),
+            gpu_mem_fraction: None,
+            ..*self
+        })
+    }
+
+    /// The request as admitted: memory raised to the peak, which then
+    /// is the steady state.
+    pub fn reserved(&self) -> ResourceRequest {
+        ResourceRequest {
+            mem: self.reserved_mem(),
+            peak_mem: None,
+            ..*self
         }
     }
 }
 
+/// Sums the resources of both requests. The sum has the higher priority of
+/// the two, and is pinned to a GPU or has (anti-)affinity keys if either
+/// one does. Limits and peaks are summed if either one has them.
+impl Add for ResourceRequest {
+    type Output = ResourceRequest;
+
+    fn add(mut self, rhs: ResourceRequest) -> ResourceRequest {
+        self += rhs;
+        self
+    }
+}
+
+impl AddAssign for ResourceRequest {
+    fn add_assign(&mut self, rhs: ResourceRequest) {
+        if self.mem_limit.is_some() || rhs.mem_limit.is_some() {
+            self.mem_limit = Some(self.hard_mem().saturating_add(rhs.hard_mem()));
+        }
+        if self.cpus_limit.is_some() || rhs.cpus_limit.is_some() {
+            self.cpus_limit = Some(self.hard_cpus().saturating_add(rhs.hard_cpus()));
+        }
+        if self.peak_mem.is_some() || rhs.peak_mem.is_some() {
+            self.peak_mem = Some(self.reserved_mem().saturating_add(rhs.reserved_mem()));
+        }
+        self.mem = self.mem.saturating_add(rhs.mem);
+        self.cpus = self.cpus.saturating_add(rhs.cpus);
+        self.gpus = self.gpus.saturating_add(rhs.gpus);
+        self.disk_bytes = self.disk_bytes.saturating_add(rhs.disk_bytes);
+        self.gpu_mem = self.gpu_mem.saturating_add(rhs.gpu_mem);
+        self.net_bps = self.net_bps.saturating_add(rhs.net_bps);
+        self.priority = self.priority.max(rhs.priority);
+        self.gpu_uuid = self.gpu_uuid.or(rhs.gpu_uuid);
+        self.affinity_key = self.affinity_key.or(rhs.affinity_key);
+        self.anti_affinity_key = self.anti_affinity_key.or(rhs.anti_affinity_key);
+    }
+}
+
+/// Subtracts the resources of `rhs`, stopping at zero. Priority, GPU pin,
+/// limits, peaks and (anti-)affinity keys are kept as is.
+impl Sub for ResourceRequest {
+    type Output = ResourceRequest;
+
+    fn sub(mut self, rhs: ResourceRequest) -> ResourceRequest {
+        self -= rhs;
+        self
+    }
+}
+
+impl SubAssign for ResourceRequest {
+    fn sub_assign(&mut self, rhs: ResourceRequest) {
+        self.mem = self.mem.saturating_sub(rhs.mem);
+        self.cpus = self.cpus.saturating_sub(rhs.cpus);
+        self.gpus = self.gpus.saturating_sub(rhs.gpus);
+        self.disk_bytes = self.disk_bytes.saturating_sub(rhs.disk_bytes);
+        self.gpu_mem = self.gpu_mem.saturating_sub(rhs.gpu_mem);
+        self.net_bps = self.net_bps.saturating_sub(rhs.net_bps);
+    }
+}
+
+/// Builder for `ResourceRequest`. Memory and CPUs must be set; everything
+/// else defaults to none.
+#[derive(Debug)]
+pub struct ResourceRequestBuilder {
+    request: ResourceRequest,
+}
+
+impl Default for ResourceRequestBuilder {
+    fn default() -> Self {
+        Self {
+            request: ResourceRequest {
+                mem: 0,
+                cpus: 0,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl ResourceRequestBuilder {
+    pub fn mem(mut self, mem: u64) -> Self {
+        self.request.mem = mem;
+        self
+    }
+
+    /// CPUs in millicores, see `MILLICORES_PER_CPU`.
+    pub fn cpus(mut self, cpus: u64) -> Self {
+        self.request.cpus = cpus;
+        self
+    }
+
+    pub fn gpus(mut self, gpus: u64) -> Self {
+        self.request.gpus = gpus;
+        self
+    }
+
+    pub fn disk_bytes(mut self, disk_bytes: u64) -> Self {
+        self.request.disk_bytes = disk_bytes;
+        self
+    }
+
+    pub fn gpu_mem(mut self, gpu_mem: u64) -> Self {
+        self.request.gpu_mem = gpu_mem;
+        self
+    }
+
+    /// GPU memory as a fraction of each GPU's VRAM, such as 0.5 for half.
+    pub fn gpu_mem_fraction(mut self, fraction: f64) -> Self {
+        self.request.gpu_mem_fraction = Some(fraction);
+        self
+    }
+
+    pub fn net_bps(mut self, net_bps: u64) -> Self {
+        self.request.net_bps = net_bps;
+        self
+    }
+
+    pub fn priority(mut self, priority: u8) -> Self {
+        self.request.priority = priority;
+        self
+    }
+
+    pub fn gpu_uuid(mut self, gpu_uuid: Uuid) -> Self {
+        self.request.gpu_uuid = Some(gpu_uuid);
+        self
+    }
+
+    /// Memory (in MiB) the task may burst to, above what is requested.
+    pub fn mem_limit(mut self, mem_limit: u64) -> Self {
+        self.request.mem_limit = Some(mem_limit);
+        self
+    }
+
+    /// CPUs (in millicores) the task may burst to, above what is requested.
+    pub fn cpus_limit(mut self, cpus_limit: u64) -> Self {
+        self.request.cpus_limit = Some(cpus_limit);
+        self
+    }
+
+    /// Memory (in MiB) the task needs at its peak, above what is requested.
+    pub fn peak_mem(mut self, peak_mem: u64) -> Self {
+        self.request.peak_mem = Some(peak_mem);
+        self
+    }
+
+    pub fn affinity_key(mut self, key: &str) -> Self {
+        self.request.affinity_key = Some(AffinityKey::new(key));
+        self
+    }
+
+    pub fn anti_affinity_key(mut self, key: &str) -> Self {
+        self.request.anti_affinity_key = Some(AffinityKey::new(key));
+        self
+    }
+
+    pub fn build(self) -> Result<ResourceRequest, RequestError> {
+        if self.request.cpus < 1 {
+            return Err(RequestError::NoCpus);
+        }
+        if self.request.mem == 0 {
+            return Err(RequestError::NoMemory);
+        }
+        if self
+            .request
+            .mem_limit
+            .is_some_and(|limit| limit < self.request.mem)
+        {
+            return Err(RequestError::LimitBelowRequest("memory"));
+        }
+        if self
+            .request
+            .cpus_limit
+            .is_some_and(|limit| limit < self.request.cpus)
+        {
+            return Err(RequestError::LimitBelowRequest("CPU"));
+        }
+        if self
+            .request
+            .peak_mem
+            .is_some_and(|peak| peak < self.request.mem)
+        {
+            return Err(RequestError::PeakBelowRequest);
+        }
+        // Resolving against no VRAM validates the fraction.
+        self.request.resolve_gpu_mem(0)?;
+        Ok(self.request)
+    }
+}
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize, sqlx::FromRow)]
 pub struct Program {
     #[serde(deserialize_with = "deserialize_hash_from_json")]
@@ -62,3 +715,232 @@ impl From<Program> for transaction::ProgramMetadata {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder() {
+        let req = ResourceRequest::builder()
+            .mem(1024)
+            .cpus(MILLICORES_PER_CPU)
+            .build()
+            .unwrap();
+
+        assert_eq!(req.mem, 1024);
+        assert_eq!(req.cpus, MILLICORES_PER_CPU);
+        assert_eq!(req.gpus, 0);
+    }
+
+    #[test]
+    fn test_builder_rejects_no_cpus() {
+        let res = ResourceRequest::builder().mem(1024).build();
+        assert_eq!(res, Err(RequestError::NoCpus));
+    }
+
+    #[test]
+    fn test_builder_rejects_no_memory() {
+        let res = ResourceRequest::builder().cpus(500).gpus(1).build();
+        assert_eq!(res, Err(RequestError::NoMemory));
+    }
+
+    #[test]
+    fn test_builder_limits() {
+        let req = ResourceRequest::builder()
+            .mem(1024)
+            .mem_limit(4096)
+            .cpus(500)
+            .build()
+            .unwrap();
+        assert_eq!((req.mem, req.hard_mem()), (1024, 4096));
+        assert_eq!((req.cpus, req.hard_cpus()), (500, 500));
+
+        let res = ResourceRequest::builder()
+            .mem(1024)
+            .cpus(500)
+            .cpus_limit(250)
+            .build();
+        assert_eq!(res, Err(RequestError::LimitBelowRequest("CPU")));
+    }
+
+    #[test]
+    fn test_peak_mem() {
+        let req = ResourceRequest::builder()
+            .mem(1024)
+            .peak_mem(3072)
+            .cpus(500)
+            .build()
+            .unwrap();
+        assert_eq!((req.mem, req.reserved_mem()), (1024, 3072));
+        assert_eq!(req.reserved().mem, 3072);
+        assert_eq!(req.reserved().peak_mem, None);
+
+        let sum = req
+            + ResourceRequest {
+                mem: 512,
+                ..Default::default()
+            };
+        assert_eq!((sum.mem, sum.peak_mem), (1536, Some(3584)));
+
+        let res = ResourceRequest::builder()
+            .mem(1024)
+            .peak_mem(512)
+            .cpus(500)
+            .build();
+        assert_eq!(res, Err(RequestError::PeakBelowRequest));
+    }
+
+    #[test]
+    fn test_sum_of_limits() {
+        let burstable = ResourceRequest {
+            mem: 1024,
+            mem_limit: Some(2048),
+            ..Default::default()
+        };
+        let fixed = ResourceRequest {
+            mem: 512,
+            ..Default::default()
+        };
+
+        let sum = burstable + fixed;
+        assert_eq!((sum.mem, sum.mem_limit), (1536, Some(2560)));
+        assert_eq!(sum.cpus_limit, None);
+    }
+
+    #[test]
+    fn test_sum_of_requests() {
+        let req = |mem, cpus, gpus| ResourceRequest {
+            mem,
+            cpus,
+            gpus,
+            ..Default::default()
+        };
+
+        let mut sum = req(1024, 500, 0) + req(2048, 1000, 1);
+        sum += req(512, 250, 2);
+
+        assert_eq!(sum, req(3584, 1750, 3));
+        assert!(sum.fits(&req(4096, 2000, 3)));
+        assert!(!sum.fits(&req(4096, 2000, 2)));
+    }
+
+    #[test]
+    fn test_saturating_sub() {
+        let small = ResourceRequest {
+            mem: 1024,
+            cpus: 500,
+            gpus: 0,
+            ..Default::default()
+        };
+        let large = ResourceRequest {
+            mem: 2048,
+            cpus: 1000,
+            gpus: 1,
+            disk_bytes: 10,
+            ..Default::default()
+        };
+
+        let diff = small - large;
+        assert_eq!(diff.mem, 0);
+        assert_eq!(diff.cpus, 0);
+        assert_eq!(diff.gpus, 0);
+        assert_eq!(diff.disk_bytes, 0);
+
+        let mut diff = large;
+        diff -= small;
+        assert_eq!(diff.mem, 1024);
+        assert_eq!(diff.cpus, 500);
+        assert_eq!(diff.gpus, 1);
+    }
+
+    #[test]
+    fn test_serde_mem_integer_round_trip() {
+        let req = ResourceRequest::builder()
+            .mem(2048)
+            .cpus(MILLICORES_PER_CPU)
+            .build()
+            .unwrap();
+
+        let json = serde_json::to_string(&req).unwrap();
+        assert!(json.contains("\"mem\":2048"));
+        assert_eq!(serde_json::from_str::<ResourceRequest>(&json).unwrap(), req);
+
+        let bytes = bincode::serialize(&req).unwrap();
+        assert_eq!(
+            bincode::deserialize::<ResourceRequest>(&bytes).unwrap(),
+            req
+        );
+    }
+
+    #[test]
+    fn test_serde_mem_string_round_trip() {
+        let json = r#"{"mem": "2GiB", "cpus": 1, "gpus": 0}"#;
+        let req: ResourceRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(req.mem, 2048);
+        assert_eq!(req.cpus, 1000);
+
+        let json = serde_json::to_string(&req).unwrap();
+        assert_eq!(serde_json::from_str::<ResourceRequest>(&json).unwrap(), req);
+
+        let req: ResourceRequest =
+            serde_json::from_str(r#"{"mem": "1500KB", "cpus": 1, "gpus": 0}"#).unwrap();
+        assert_eq!(req.mem, 2);
+    }
+
+    #[test]
+    fn test_serde_cpus_in_whole_cores() {
+        // Requests from clients predating millicores give whole cores.
+        let req: ResourceRequest =
+            serde_json::from_str(r#"{"mem": 1024, "cpus": 4, "gpus": 0}"#).unwrap();
+        assert_eq!(req.cpus, 4 * MILLICORES_PER_CPU);
+
+        let req = ResourceRequest {
+            cpus: 1500,
+            ..Default::default()
+        };
+        let json = serde_json::to_value(req).unwrap();
+        assert_eq!(json["cpus"], 2);
+        assert_eq!(json["cpu_millis"], 1500);
+        assert_eq!(
+            serde_json::from_value::<ResourceRequest>(json).unwrap(),
+            req
+        );
+    }
+
+    #[test]
+    fn test_serde_mem_invalid_unit() {
+        let err =
+            serde_json::from_str::<ResourceRequest>(r#"{"mem": "2XB", "cpus": 1, "gpus": 0}"#)
+                .unwrap_err();
+        assert!(
+            err.to_string().contains("invalid memory unit \"XB\""),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_parse_bytes() {
+        assert_eq!(parse_bytes("16GiB"), Ok(16 * 1024 * 1024 * 1024));
+        assert_eq!(parse_bytes("16GB"), Ok(16_000_000_000));
+        assert_eq!(parse_bytes("512MiB"), Ok(512 * 1024 * 1024));
+        assert_eq!(parse_bytes("2TB"), Ok(2_000_000_000_000));
+        assert_eq!(parse_bytes("4 G"), Ok(4 * 1024 * 1024 * 1024));
+    }
+
+    #[test]
+    fn test_parse_bytes_rejects_bad_input() {
+        assert!(parse_bytes("16Gb")
+            .unwrap_err()
+            .contains("invalid memory unit"));
+        assert!(parse_bytes("16")
+            .unwrap_err()
+            .contains("missing memory unit"));
+        assert!(parse_bytes("GiB")
+            .unwrap_err()
+            .contains("invalid memory amount"));
+        assert!(parse_bytes("99999999TiB")
+            .unwrap_err()
+            .contains("too large"));
+    }
+}
@@ -34,7 +34,7 @@ use vsock::get_local_cid;
 use super::{vm_server::ProgramRegistry, Provider, VMClient, VMHandle, VMId};
 use crate::{
     cli::Config,
-    types::{Hash, Program},
+    types::{program::MILLICORES_PER_CPU, Hash, Program},
     vmm::ResourceRequest,
 };
 
@@ -164,7 +164,8 @@ impl Provider for Qemu {
             .collect::<String>()
             .to_lowercase();
 
-        let cpus = req.cpus;
+        // QEMU only deals with whole CPUs, so round fractional requests up.
+        let cpus = req.cpus.div_ceil(MILLICORES_PER_CPU).max(1);
         let mem_req = req.mem;
         let cid = self.allocate_cid();
 
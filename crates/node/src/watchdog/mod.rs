@@ -1,3 +1,4 @@
+use crate::scheduler::HealthStatus;
 use eyre::Result;
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
@@ -21,6 +22,7 @@ pub const NO_LOOP_DETECT_TIMEOUT_MILLIS: Duration = Duration::from_millis(1000);
 pub enum HealthCheckSignal {
     SchedulerLoopOk,
     SchedulerMempoolLen(usize),
+    ResourceHealth(HealthStatus),
 }
 
 #[derive(Clone, Debug, Copy, PartialEq, PartialOrd)]
@@ -30,6 +32,16 @@ enum WatchDogState {
     Critical,
 }
 
+// Status of the readiness check: whether the node should be given more
+// work, as opposed to whether it's alive.
+fn readiness_status(health: HealthStatus) -> StatusCode {
+    match health {
+        HealthStatus::Healthy => StatusCode::NO_CONTENT,
+        HealthStatus::Degraded => StatusCode::TOO_MANY_REQUESTS,
+        HealthStatus::Unhealthy => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
 impl From<WatchDogState> for StatusCode {
     fn from(state: WatchDogState) -> Self {
         match state {
@@ -43,21 +55,24 @@ impl From<WatchDogState> for StatusCode {
 pub async fn start_healthcheck(bind_addr: SocketAddr) -> Result<mpsc::Sender<HealthCheckSignal>> {
     let (scheduler_health_tx, scheduler_health_rx) = mpsc::channel::<HealthCheckSignal>(100);
     let watchdog_state = Arc::new(Mutex::new(WatchDogState::Alive));
+    let resource_health = Arc::new(Mutex::new(HealthStatus::Healthy));
     tokio::spawn({
         let watchdog_state = watchdog_state.clone();
+        let resource_health = resource_health.clone();
         async move {
             run_healthcheck(
                 GRACEFULL_SIGNAL_COUNTER_LIMIT,
                 SCHEDULER_HEALTH_SIGNAL_TIMEOUT_MILLIS,
                 NO_LOOP_DETECT_TIMEOUT_MILLIS,
                 watchdog_state,
+                resource_health,
                 scheduler_health_rx,
             )
             .await;
         }
     });
 
-    serve_healthcheck(bind_addr, watchdog_state).await?;
+    serve_healthcheck(bind_addr, watchdog_state, resource_health).await?;
     Ok(scheduler_health_tx)
 }
 
@@ -82,6 +97,7 @@ async fn run_healthcheck(
     scheduler_health_signal_timeout: Duration,
     no_loop_detect_timeout: Duration,
     watchdog_state: Arc<Mutex<WatchDogState>>,
+    resource_health: Arc<Mutex<HealthStatus>>,
     mut scheduler_health_rx: mpsc::Receiver<HealthCheckSignal>,
 ) {
     let mut scheduler_state = HealthcheckState::default();
@@ -114,6 +130,7 @@ async fn run_healthcheck(
                                 new_state.scheduler_alive = true
                             },
                             HealthCheckSignal::SchedulerMempoolLen(len) => new_state.scheduler_mempool_empty = len == 0,
+                            HealthCheckSignal::ResourceHealth(health) => *resource_health.lock().await = health,
                         },
                         None => {
                             tracing::error!(
@@ -162,9 +179,14 @@ async fn run_healthcheck(
 // WatchDogState::Alive => 200
 // WatchDogState::Graceful => 204
 // WatchDogState::Critical => 503
+// The readiness check reports resource health instead:
+// HealthStatus::Healthy => 204
+// HealthStatus::Degraded => 429
+// HealthStatus::Unhealthy => 503
 async fn serve_healthcheck(
     bind_addr: SocketAddr,
     watchdog_state: Arc<Mutex<WatchDogState>>,
+    resource_health: Arc<Mutex<HealthStatus>>,
 ) -> Result<()> {
     let listener = TcpListener::bind(bind_addr).await?;
 
@@ -183,12 +205,14 @@ async fn serve_healthcheck(
                         let io = TokioIo::new(stream);
                         tokio::task::spawn({
                             let watchdog_state = watchdog_state.clone();
+                            let resource_health = resource_health.clone();
                             async move {
                                 if let Err(err) = http1::Builder::new()
                                     .serve_connection(
                                         io,
                                         service_fn(|req| {
                                             let watchdog_state = watchdog_state.clone();
+                                            let resource_health = resource_health.clone();
                                             async move {
                                                 let status: StatusCode =
                                                     match (req.method(), req.uri().path()) {
@@ -198,7 +222,9 @@ async fn serve_healthcheck(
                                                         }
                                                         (&Method::GET, "/ready")
                                                         | (&Method::HEAD, "/ready") => {
-                                                            StatusCode::NO_CONTENT
+                                                            readiness_status(
+                                                                *resource_health.lock().await,
+                                                            )
                                                         }
                                                         _ => StatusCode::OK,
                                                     };
@@ -236,6 +262,7 @@ mod tests {
     async fn test_watchdog() {
         let (scheduler_health_tx, scheduler_health_rx) = mpsc::channel::<HealthCheckSignal>(100);
         let watchdog_state = Arc::new(Mutex::new(WatchDogState::Alive));
+        let resource_health = Arc::new(Mutex::new(HealthStatus::Healthy));
         tokio::spawn({
             let watchdog_state = watchdog_state.clone();
             async move {
@@ -244,6 +271,7 @@ mod tests {
                     Duration::from_millis(100),
                     Duration::from_millis(300),
                     watchdog_state,
+                    resource_health,
                     scheduler_health_rx,
                 )
                 .await;
@@ -301,4 +329,41 @@ mod tests {
         sleep(Duration::from_millis(20)).await;
         assert_eq!(WatchDogState::Alive, *watchdog_state.lock().await);
     }
+
+    #[tokio::test]
+    async fn test_readiness_follows_resource_health() {
+        let (scheduler_health_tx, scheduler_health_rx) = mpsc::channel::<HealthCheckSignal>(100);
+        let watchdog_state = Arc::new(Mutex::new(WatchDogState::Alive));
+        let resource_health = Arc::new(Mutex::new(HealthStatus::Healthy));
+        tokio::spawn({
+            let resource_health = resource_health.clone();
+            async move {
+                run_healthcheck(
+                    2,
+                    Duration::from_millis(100),
+                    Duration::from_millis(300),
+                    watchdog_state,
+                    resource_health,
+                    scheduler_health_rx,
+                )
+                .await;
+            }
+        });
+        let readiness = || async { readiness_status(*resource_health.lock().await) };
+        assert_eq!(readiness().await, StatusCode::NO_CONTENT);
+
+        scheduler_health_tx
+            .send(HealthCheckSignal::ResourceHealth(HealthStatus::Degraded))
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(20)).await;
+        assert_eq!(readiness().await, StatusCode::TOO_MANY_REQUESTS);
+
+        scheduler_health_tx
+            .send(HealthCheckSignal::ResourceHealth(HealthStatus::Unhealthy))
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(20)).await;
+        assert_eq!(readiness().await, StatusCode::SERVICE_UNAVAILABLE);
+    }
 }
@@ -2,7 +2,7 @@ use clap::Parser;
 use gevulot_node::{
     rpc_client::{RpcClient, RpcClientBuilder},
     types::{
-        program::ResourceRequest,
+        program::{ResourceRequest, MILLICORES_PER_CPU},
         transaction::{Payload, ProgramData, ProgramMetadata, Workflow, WorkflowStep},
         Hash, Transaction,
     },
@@ -162,9 +162,10 @@ fn from_img_file_to_metadata(img_file: &Path, img_file_url: &str) -> ProgramMeta
         image_file_url: img_file_url.to_string(),
         image_file_checksum: checksum.to_string(),
         resource_requirements: Some(ResourceRequest {
-            cpus: 1,
+            cpus: MILLICORES_PER_CPU,
             mem: 128,
             gpus: 0,
+            ..Default::default()
         }),
     };
 